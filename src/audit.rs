@@ -0,0 +1,92 @@
+//! Optional audit log of the mappings installed in the shadow page tables.
+//!
+//! With the `shadow_audit` feature every mapping created or removed is recorded in a small ring
+//! buffer, which can be printed with `AuditLog::dump`. Without it the buffer has no entries and
+//! recording compiles to nothing. `check_shadow_permissions` can be used (with or without the log)
+//! to confirm that no shadow mapping grants the guest more than its own page tables do.
+
+use crate::context::Context;
+use crate::pmap::*;
+
+#[cfg(feature = "shadow_audit")]
+pub const AUDIT_LOG_ENTRIES: usize = 256;
+#[cfg(not(feature = "shadow_audit"))]
+pub const AUDIT_LOG_ENTRIES: usize = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuditOp {
+    Map,
+    Unmap,
+    /// Every guest mapping in the root was removed.
+    Flush,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AuditEntry {
+    pub op: AuditOp,
+    pub root: PageTableRoot,
+    pub va: u64,
+    /// Host physical address mapped, or zero for an unmap.
+    pub pa: u64,
+    /// Low bits of the installed PTE, or zero for an unmap.
+    pub flags: u64,
+}
+
+/// The most recent `AUDIT_LOG_ENTRIES` changes to the shadow page tables.
+pub struct AuditLog {
+    entries: [Option<AuditEntry>; AUDIT_LOG_ENTRIES],
+    next: usize,
+    total: u64,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self { entries: [None; AUDIT_LOG_ENTRIES], next: 0, total: 0 }
+    }
+
+    #[inline]
+    pub fn record(&mut self, op: AuditOp, root: PageTableRoot, va: u64, pa: u64, flags: u64) {
+        if !cfg!(feature = "shadow_audit") {
+            return;
+        }
+        self.entries[self.next] = Some(AuditEntry { op, root, va, pa, flags });
+        self.next += 1;
+        if self.next == self.entries.len() {
+            self.next = 0;
+        }
+        self.total += 1;
+    }
+
+    /// Total number of entries recorded, including ones that have since been overwritten.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Print the retained entries, oldest first.
+    pub fn dump(&self) {
+        println!("Shadow mapping audit log ({} recorded):", self.total);
+        let (newer, older) = self.entries.split_at(self.next);
+        for entry in older.iter().chain(newer).filter_map(|e| e.as_ref()) {
+            match entry.op {
+                AuditOp::Map => println!("  {:?} map   {:#x} -> {:#x} [{:#x}]",
+                                         entry.root, entry.va, entry.pa, entry.flags),
+                AuditOp::Unmap => println!("  {:?} unmap {:#x}", entry.root, entry.va),
+                AuditOp::Flush => println!("  {:?} flush", entry.root),
+            }
+        }
+    }
+}
+
+/// Check every shadow page table for the current guest address space against the guest's own page
+/// tables with `diff_shadow_vs_guest`, printing each divergence found. Returns how many there were.
+pub fn check_shadow_permissions(state: &Context) -> u64 {
+    let mut violations = 0;
+    for &root in PageTableRoot::SHADOWS {
+        diff_shadow_vs_guest(state, root, state.csrs.satp, &mut |d| {
+            println!("{:?} shadow of {:#x} -> {:#x} allows {:?} but guest translation is {:x?}",
+                     root, d.va, d.shadow_pa, d.access, d.expected_pa);
+            violations += 1;
+        });
+    }
+    violations
+}
@@ -1,8 +1,16 @@
-use crate::context::Context;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::context::{Context, CONTEXT, CONTEXT_INITIALIZED};
 use crate::memory_region::MemoryRegion;
-use crate::riscv::bits;
 use crate::pmap;
 
+/// Number of words printed from the top of the guest's stack by `dump_guest_state`.
+const STACK_DUMP_WORDS: u64 = 8;
+/// Maximum number of frames `dump_guest_state` will try to unwind.
+const MAX_BACKTRACE_FRAMES: usize = 16;
+
+/// Set once a panic has started dumping guest state, so a panic during the dump doesn't recurse.
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
 #[allow(unused)]
 pub unsafe fn print_guest_backtrace(guest_memory: &MemoryRegion, state: &mut Context, pc: u64) {
     println!(" {:x}", pc);
@@ -11,21 +19,91 @@ pub unsafe fn print_guest_backtrace(guest_memory: &MemoryRegion, state: &mut Con
     let mut sp = state.saved_registers.get(2);
     let mut fp = state.saved_registers.get(8);
 
-    let page_table_ppn = state.csrs.satp & bits::SATP_PPN;
+    let satp = state.csrs.satp;
+    let cache = &mut state.translation_cache;
 
     let mut old_fp = 0;
     while old_fp != fp {
         println!(" {:x}", ra);
 
-        ra = match fp.checked_sub(8).and_then(|a| pmap::read64(guest_memory, page_table_ppn, a)) {
+        ra = match fp.checked_sub(8).and_then(|a| pmap::read64(guest_memory, cache, satp, a)) {
             Some(v) => v,
             None => break,
         };
 
         old_fp = fp;
-        fp = match fp.checked_sub(16).and_then(|a| pmap::read64(guest_memory, page_table_ppn, a)) {
+        fp = match fp.checked_sub(16).and_then(|a| pmap::read64(guest_memory, cache, satp, a)) {
             Some(v) => v,
             None => break,
         };
     }
 }
+
+/// Read a word of guest memory at guest virtual address `va`. The guest's page tables are walked in
+/// software and guest memory is only accessed through the direct map with bounds checks, so this
+/// can't fault even if the guest's state is corrupt.
+fn read_guest_word(state: &Context, va: u64) -> Option<u64> {
+    let (guest_pa, _, _) = pmap::guest_va_to_pa(&state.guest_memory, state.csrs.satp, va)?;
+    state.guest_memory.get(guest_pa)
+}
+
+/// Print the guest's registers, the top of its stack, and a frame pointer backtrace starting from
+/// `pc`. Nothing here can fault, so it's safe to call when the hypervisor is already in trouble.
+pub fn dump_guest_state(state: &Context, pc: u64) {
+    println!("Guest state (mode={:?}):", state.current_mode());
+    println!("  pc      = {:#x}", pc);
+    println!("  sstatus = {:#x}", state.csrs.sstatus);
+    println!("  sepc    = {:#x}", state.csrs.sepc);
+    println!("  scause  = {:#x}", state.csrs.scause);
+    println!("  stval   = {:#x}", state.csrs.stval);
+    println!("  satp    = {:#x}", state.csrs.satp);
+    for i in 1..32 {
+        println!("  x{:<2}     = {:#x}", i, state.saved_registers.get(i));
+    }
+
+    let sp = state.saved_registers.get(2);
+    println!("Top of guest stack:");
+    for i in 0..STACK_DUMP_WORDS {
+        let va = sp.wrapping_add(i * 8);
+        match read_guest_word(state, va) {
+            Some(value) => println!("  {:#x}: {:#x}", va, value),
+            None => {
+                println!("  {:#x}: <unmapped>", va);
+                break;
+            }
+        }
+    }
+
+    println!("Guest backtrace:");
+    println!("  {:#x}", pc);
+    let mut ra = state.saved_registers.get(1);
+    let mut fp = state.saved_registers.get(8);
+    for _ in 0..MAX_BACKTRACE_FRAMES {
+        println!("  {:#x}", ra);
+        let next_fp = match fp.checked_sub(16).and_then(|a| read_guest_word(state, a)) {
+            Some(next_fp) if next_fp != fp => next_fp,
+            _ => break,
+        };
+        ra = match fp.checked_sub(8).and_then(|a| read_guest_word(state, a)) {
+            Some(ra) => ra,
+            None => break,
+        };
+        fp = next_fp;
+    }
+}
+
+/// Dump the state of this hart's guest, if it has been initialized. Meant to be called from the panic handler:
+/// the lock on `CONTEXT` is forcibly released since the panic may have happened while it was held,
+/// which is only acceptable because this hart will never return to the guest.
+pub unsafe fn dump_guest_state_on_panic() {
+    if !CONTEXT_INITIALIZED.load(Ordering::SeqCst) || DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    // Panics only happen while handling a trap from the guest, so sepc still holds (or has already
+    // been advanced past) the guest pc.
+    CONTEXT.force_unlock();
+    if let Some(state) = CONTEXT.lock().as_ref() {
+        dump_guest_state(state, csrr!(sepc));
+    }
+}
@@ -0,0 +1,57 @@
+//! Emulation of the core local interruptor (CLINT) seen by the guest.
+//!
+//! Each guest has a single hart, so there is one `msip` register and one `mtimecmp` register. The
+//! value of `mtimecmp` is kept in `ControlRegisters` since it is shared with the SBI timer call, and
+//! `mtime` is just the host time (which is also what the guest sees when reading the `time` CSR).
+
+const MSIP_OFFSET: u64 = 0x0;
+const MTIMECMP_OFFSET: u64 = 0x4000;
+const MTIME_OFFSET: u64 = 0xbff8;
+
+/// Size of the CLINT's MMIO window.
+pub const CLINT_SIZE: u64 = 0x10000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClintRegister {
+    Msip,
+    Mtimecmp,
+    Mtime,
+}
+impl ClintRegister {
+    /// Size of the register in bytes.
+    pub fn size(&self) -> u64 {
+        match *self {
+            ClintRegister::Msip => 4,
+            ClintRegister::Mtimecmp | ClintRegister::Mtime => 8,
+        }
+    }
+}
+
+pub struct Clint {
+    base: u64,
+}
+
+impl Clint {
+    pub fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    /// Whether `addr` falls within the CLINT's MMIO window.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr < self.base + CLINT_SIZE
+    }
+
+    /// Returns the register containing `addr` along with the byte offset of `addr` within it, or
+    /// None if `addr` doesn't correspond to a register of hart 0.
+    pub fn register(&self, addr: u64) -> Option<(ClintRegister, u64)> {
+        let offset = addr.wrapping_sub(self.base);
+        for &(start, register) in &[(MSIP_OFFSET, ClintRegister::Msip),
+                                    (MTIMECMP_OFFSET, ClintRegister::Mtimecmp),
+                                    (MTIME_OFFSET, ClintRegister::Mtime)] {
+            if offset >= start && offset < start + register.size() {
+                return Some((register, offset - start));
+            }
+        }
+        None
+    }
+}
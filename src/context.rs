@@ -1,17 +1,28 @@
 use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv_decode::types;
 use spin::Mutex;
 use crate::fdt::MachineMeta;
+use crate::gdb::GdbState;
 use crate::memory_region::MemoryRegion;
+use crate::mmio::{MmioBus, MmioDevice, UnclaimedPolicy};
+use crate::clint::Clint;
 use crate::plic::PlicState;
-use crate::pmap::{PageTables, PageTableRoot};
+use crate::pmap::{GuestMemoryBank, PageTables, ProtectedPageTable, TranslationCache};
 use crate::riscv::bits::*;
-use crate::riscv::csr;
-use crate::statics::SHARED_STATICS;
 use crate::trap::U64Bits;
-use crate::{pmap, print, riscv, virtio};
+use crate::uart_device::Uart;
+use crate::{clint, csr, elf, plic, pmap, riscv, uart_device, virtio};
 
+/// State for the guest running on the current hart. Since this static lives in the data segment,
+/// which is mapped separately for each hart, every hart sees a different instance.
 pub static CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
 
+/// Set once `initialize` has stored this hart's guest into `CONTEXT`. Before then `CONTEXT` may hold
+/// garbage, so code that can run first (like the panic handler) must check this.
+pub static CONTEXT_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 pub struct ControlRegisters {
     // sedeleg: u64, -- Hard-wired to zero
     // sideleg: u64, -- Hard-wired to zero
@@ -20,7 +31,7 @@ pub struct ControlRegisters {
     pub sie: u64,
     pub sip: u64,
     pub stvec: u64,
-    // scounteren: u64, -- Hard-wired to zero
+    pub scounteren: u64,
     pub sscratch: u64,
     pub sepc: u64,
     pub scause: u64,
@@ -33,21 +44,9 @@ pub struct ControlRegisters {
 pub struct VirtIO {
     pub devices: ArrayVec<[virtio::Device; virtio::MAX_DEVICES]>,
     pub queue_guest_pages: ArrayVec<[u64; virtio::MAX_DEVICES * virtio::MAX_QUEUES]>,
-}
-
-pub struct Uart {
-    pub dlab: bool,
-
-    pub divisor_latch: u16,
-    pub interrupt_enable: u8,
-
-    pub next_interrupt_time: u64,
-
-    pub input_fifo: [u8; 16],
-    pub input_bytes_ready: usize,
-
-    pub line_buffer: ArrayVec<[u8; 256]>,
-    pub guestid: Option<u64>,
+    /// Range of guest physical addresses spanned by `queue_guest_pages`, so that most accesses can
+    /// be ruled out without searching the list.
+    pub queue_bounds: (u64, u64),
 }
 
 pub enum HostClint {
@@ -68,33 +67,127 @@ pub struct SavedRegisters {
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum IrqMapping {
     Virtio { device_index: u8, guest_irq: u16 },
+    /// Receive interrupt from the host UART, whose input is passed on to the guest's console.
+    HostUart,
     Ignored,
 }
 
+/// Privilege level the guest is executing at. The guest never runs in machine mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PrivilegeMode {
+    User,
+    Supervisor,
+}
+impl PrivilegeMode {
+    /// The mode recorded by a value of `sstatus.SPP`.
+    pub fn from_spp(spp: bool) -> Self {
+        if spp { PrivilegeMode::Supervisor } else { PrivilegeMode::User }
+    }
+
+    /// The value of `sstatus.SPP` that records this mode.
+    pub fn spp(self) -> bool {
+        self == PrivilegeMode::Supervisor
+    }
+}
+
+/// Size of the buffer holding the device tree generated for the guest.
+pub const GUEST_FDT_SIZE: usize = 4096;
+/// Size of the read-only region of guest memory the device tree is placed in.
+pub const GUEST_DTB_REGION_SIZE: u64 = (GUEST_FDT_SIZE as u64 + 0xfff) & !0xfff;
+
+/// What the guest was booted with, kept so that it can be rebooted.
+pub struct BootImage {
+    /// Address of the guest kernel's ELF image. This is the copy at the start of the hart's heap,
+    /// which is never modified after boot.
+    pub kernel: u64,
+    /// Guest physical address the device tree is placed at. The `GUEST_DTB_REGION_SIZE` bytes from
+    /// here are reserved in the device tree, and the guest can't write to them.
+    pub guest_dtb: u64,
+    pub fdt: [u8; GUEST_FDT_SIZE],
+    pub fdt_len: usize,
+}
+
+impl BootImage {
+    /// Whether `[guest_pa, guest_pa+len)` overlaps the read-only device tree region.
+    pub fn overlaps_dtb(&self, guest_pa: u64, len: u64) -> bool {
+        guest_pa < self.guest_dtb + GUEST_DTB_REGION_SIZE && self.guest_dtb < guest_pa + len
+    }
+}
+
 pub struct TestFinisher {
     registers: MemoryRegion<u32>,
 }
 
+/// Identifies a checkpoint produced by `Context::checkpoint` ("RVCP").
+const CHECKPOINT_MAGIC: u32 = 0x50435652;
+/// Version of the checkpoint format. New fields are only ever appended, and the header records the
+/// length of the body, so a checkpoint written by an older version can always be restored.
+const CHECKPOINT_VERSION: u32 = 1;
+const CHECKPOINT_HEADER_SIZE: usize = 12;
+const CHECKPOINT_V1_SIZE: usize = 8 * (1 + 31 + 11 + 1) + 4 * 16;
+/// Number of bytes needed to hold a checkpoint.
+pub const CHECKPOINT_SIZE: usize = CHECKPOINT_HEADER_SIZE + CHECKPOINT_V1_SIZE;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheckpointError {
+    BufferTooSmall,
+    BadMagic,
+    Truncated,
+    /// The guest was using Sv48, which this host can't shadow.
+    UnsupportedSatp,
+    /// The checkpoint was taken from a guest with a different XLEN.
+    XlenMismatch,
+    /// The header names a format version that was never produced.
+    UnsupportedVersion,
+}
+
 pub struct Context {
     pub csrs: ControlRegisters,
     pub plic: PlicState,
+    pub clint: Option<Clint>,
     pub uart: Uart,
     pub virtio: VirtIO,
 
     pub saved_registers: SavedRegisters,
     pub guest_memory: MemoryRegion,
+    /// The parts of `guest_memory` the guest can actually use, which excludes any gaps between banks.
+    pub memory_banks: ArrayVec<[GuestMemoryBank; pmap::MAX_GUEST_MEMORY_BANKS]>,
     pub shadow_page_tables: PageTables,
 
     pub guest_shift: u64,
 
-    /// Whether the guest is in S-Mode.
-    pub smode: bool,
+    /// Host hart this guest runs on.
+    pub hartid: u64,
+
+    /// Host `cycle` and `instret` values when the guest started, so its counters begin at zero.
+    pub cycle_base: u64,
+    pub instret_base: u64,
+
+    /// Privilege level the guest is currently in. Updated on every trap into the guest and by `sret`.
+    pub guest_mode: PrivilegeMode,
+
+    /// Whether the guest runs with XLEN=32 and so uses the RV32 `satp` layout and Sv32 page tables.
+    /// Set when the guest kernel is a 32-bit ELF image, in which case `initialize` also switches the
+    /// host's UXL so that the guest really executes as RV32.
+    pub rv32: bool,
 
     /// If set, hypervisor exits do not need to check for pending interrupts
     pub no_interrupt: bool,
 
+    /// Guest physical address reserved by an emulated `lr` instruction, if any.
+    pub reservation: Option<u64>,
+
     pub tlb_caches_invalid_ptes: bool,
+    /// Guest page tables which are write protected when `protect_guest_page_tables` is enabled.
+    pub protected_page_tables: ArrayVec<[ProtectedPageTable; pmap::MAX_PROTECTED_PAGE_TABLES]>,
     pub consecutive_page_fault_count: u64,
+    /// Recent guest translations made on the guest's behalf, such as by the debugger.
+    pub translation_cache: TranslationCache,
+
+    /// Breakpoints inserted by GDB when the `gdb_stub` feature is enabled.
+    pub gdb: GdbState,
+    /// If set, the guest is stopped again after executing a single instruction.
+    pub single_step: bool,
 
     pub host_clint: HostClint,
     pub host_plic: HostPlic,
@@ -103,182 +196,234 @@ pub struct Context {
 
     /// Map from host external interrupt number to guest external interrupt nmuber
     pub irq_map: [IrqMapping; 512],
+
+    /// Guest physical address ranges of the emulated devices.
+    pub mmio: MmioBus,
+
+    pub boot: BootImage,
 }
 
 
-impl ControlRegisters {
-    pub fn push_sie(&mut self) {
-        self.sstatus.set(STATUS_SPIE, self.sstatus.get(STATUS_SIE));
-        self.sstatus.set(STATUS_SIE, false);
+impl Context {
+    /// Save the guest's registers, CSRs and pending interrupts into `buf`, returning the number of
+    /// bytes written. Must be called from the trap handler, since the guest pc is taken from
+    /// `sepc`. Guest memory isn't included and must be saved separately. The shadow page tables are
+    /// also omitted: only the guest's satp is recorded and they are rebuilt lazily after a restore.
+    pub fn checkpoint(&self, buf: &mut [u8]) -> Result<usize, CheckpointError> {
+        if buf.len() < CHECKPOINT_SIZE {
+            return Err(CheckpointError::BufferTooSmall);
+        }
+
+        LittleEndian::write_u32(&mut buf[0..], CHECKPOINT_MAGIC);
+        LittleEndian::write_u32(&mut buf[4..], CHECKPOINT_VERSION);
+        LittleEndian::write_u32(&mut buf[8..], CHECKPOINT_V1_SIZE as u32);
+
+        let mut values = ArrayVec::<[u64; 44]>::new();
+        values.push(csrr!(sepc));
+        for reg in 1..32 {
+            values.push(self.saved_registers.get(reg));
+        }
+        values.push(self.csrs.sstatus);
+        values.push(self.csrs.sie);
+        values.push(self.csrs.sip);
+        values.push(self.csrs.stvec);
+        values.push(self.csrs.scounteren);
+        values.push(self.csrs.sscratch);
+        values.push(self.csrs.sepc);
+        values.push(self.csrs.scause);
+        values.push(self.csrs.stval);
+        values.push(self.csrs.satp);
+        values.push(self.csrs.mtimecmp);
+        values.push(self.guest_mode.spp() as u64 | (self.rv32 as u64) << 1);
+
+        let body = &mut buf[CHECKPOINT_HEADER_SIZE..];
+        for (i, value) in values.iter().enumerate() {
+            LittleEndian::write_u64(&mut body[i * 8..], *value);
+        }
+        for (i, word) in self.plic.pending_words().iter().enumerate() {
+            LittleEndian::write_u32(&mut body[values.len() * 8 + i * 4..], *word);
+        }
+
+        Ok(CHECKPOINT_SIZE)
     }
 
-    pub fn pop_sie(&mut self) {
-        self.sstatus.set(STATUS_SIE, self.sstatus.get(STATUS_SPIE));
-        self.sstatus.set(STATUS_SPIE, true);
+    /// Load state saved by `checkpoint` into this context, and resume the guest at the saved pc
+    /// once the trap handler returns. Fields from newer format versions are ignored.
+    pub fn restore(&mut self, buf: &[u8]) -> Result<(), CheckpointError> {
+        if buf.len() < CHECKPOINT_HEADER_SIZE {
+            return Err(CheckpointError::Truncated);
+        }
+        if LittleEndian::read_u32(&buf[0..]) != CHECKPOINT_MAGIC {
+            return Err(CheckpointError::BadMagic);
+        }
+        // Later versions only append fields, which the body length lets us skip, so any version
+        // from the first onwards can be restored.
+        if LittleEndian::read_u32(&buf[4..]) == 0 {
+            return Err(CheckpointError::UnsupportedVersion);
+        }
+        let body_len = LittleEndian::read_u32(&buf[8..]) as usize;
+        if body_len < CHECKPOINT_V1_SIZE || buf.len() < CHECKPOINT_HEADER_SIZE + body_len {
+            return Err(CheckpointError::Truncated);
+        }
+
+        let body = &buf[CHECKPOINT_HEADER_SIZE..];
+        let value = |i: usize| LittleEndian::read_u64(&body[i * 8..]);
+        let sv48 = pmap::SatpMode::from_satp(value(41)) == Some(pmap::SatpMode::Sv48);
+        if sv48 && !self.shadow_page_tables.supports_sv48() {
+            return Err(CheckpointError::UnsupportedSatp);
+        }
+        if (value(43) & 0x2 != 0) != self.rv32 {
+            return Err(CheckpointError::XlenMismatch);
+        }
+        let pc = value(0);
+        for reg in 1..32 {
+            self.saved_registers.set(reg, value(reg as usize));
+        }
+        csr::restore(self, value(32), value(35));
+        self.csrs.sie = value(33);
+        self.csrs.sip = value(34);
+        self.csrs.scounteren = value(36);
+        self.csrs.sscratch = value(37);
+        self.csrs.sepc = value(38);
+        self.csrs.scause = value(39);
+        self.csrs.stval = value(40);
+        self.csrs.satp = value(41);
+        self.csrs.mtimecmp = value(42);
+        self.guest_mode = PrivilegeMode::from_spp(value(43) & 0x1 != 0);
+
+        let mut pending = [0; 16];
+        for (i, word) in pending.iter_mut().enumerate() {
+            *word = LittleEndian::read_u32(&body[44 * 8 + i * 4..]);
+        }
+        self.plic.set_pending_words(pending);
+
+        self.reservation = None;
+        self.no_interrupt = false;
+        self.protected_page_tables.clear();
+        self.translation_cache.invalidate(None, None);
+        self.shadow_page_tables.set_sv48(sv48);
+        self.shadow_page_tables.set_asid((self.csrs.satp & SATP_ASID) >> 44);
+        self.shadow_page_tables.install_root(pmap::active_root(self));
+        unsafe { csrw!(sepc, pc) };
+        Ok(())
     }
-}
 
-impl Uart {
-    const IRQ: u32 = 10;
+    /// Reboot the guest: reset its devices and registers, reload its kernel and device tree, and
+    /// discard every shadow mapping. Must be called from the trap handler, and the guest starts
+    /// again at the kernel's entry point once the trap returns. Guest memory is otherwise left as
+    /// it was, so this behaves like a warm reboot.
+    pub fn reset(&mut self) {
+        self.uart.output.flush();
+        virtio::reset_devices(self);
+        self.uart.reset();
+        self.plic.reset();
+
+        let (entry, _) = unsafe { elf::load_elf(self.boot.kernel as *const u8, &mut self.guest_memory) };
+        self.guest_memory.copy_from_slice(self.boot.guest_dtb, &self.boot.fdt[..self.boot.fdt_len])
+            .expect("Guest device tree doesn't fit in guest memory");
+
+        self.csrs = ControlRegisters::new();
+        // The old guest's MXR and FS are live in the hardware sstatus. Its SUM only ever selects
+        // the shadow root, which is switched below, since the hypervisor itself always runs with
+        // SUM set.
+        riscv::set_sstatus_mxr(self.csrs.sstatus);
+        riscv::set_sstatus_fs(self.csrs.sstatus);
+        for reg in 1..32 {
+            self.saved_registers.set(reg, 0);
+        }
+        self.saved_registers.set(11, self.boot.guest_dtb);
+        self.guest_mode = PrivilegeMode::Supervisor;
+
+        self.reservation = None;
+        self.no_interrupt = true;
+        self.single_step = false;
+        self.protected_page_tables.clear();
+        self.translation_cache.invalidate(None, None);
+        self.consecutive_page_fault_count = 0;
+        self.shadow_page_tables.set_sv48(false);
+        self.shadow_page_tables.set_asid(0);
+        self.shadow_page_tables.install_root(pmap::active_root(self));
+        riscv::set_sepc(entry);
+    }
 
-    fn tx_interrupt(&self, current_time: u64) -> bool {
-        self.next_interrupt_time  <= current_time && self.interrupt_enable & 0x2 != 0
+    pub fn current_mode(&self) -> PrivilegeMode {
+        self.guest_mode
     }
-    fn rx_interrupt(&self) -> bool {
-        self.input_bytes_ready >= 1 && self.interrupt_enable & 0x1 != 0
+
+    /// Move the guest into supervisor mode to take a trap, recording the mode it came from in
+    /// `sstatus.SPP`.
+    pub fn trap_to_supervisor(&mut self) {
+        self.csrs.sstatus.set(STATUS_SPP, self.guest_mode.spp());
+        self.guest_mode = PrivilegeMode::Supervisor;
     }
-    pub fn timer(state: &mut Context, current_time: u64) {
-        state.uart.fill_fifo();
-        if state.uart.tx_interrupt(current_time) || state.uart.rx_interrupt() {
-            state.plic.set_pending(Uart::IRQ, true);
-            state.no_interrupt = false;
-        }
+
+    /// Read guest register `x<index>`. x0 always reads as zero.
+    pub fn get_reg(&self, index: u32) -> u64 {
+        self.saved_registers.get(index)
     }
 
-    pub fn fill_fifo(&mut self) {
-        while self.input_bytes_ready < self.input_fifo.len() {
-            if let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
-                self.input_fifo[self.input_bytes_ready] = ch;
-                self.input_bytes_ready += 1;
-            } else {
-                break;
-            }
-        }
+    /// Write guest register `x<index>`. Writes to x0 are ignored.
+    pub fn set_reg(&mut self, index: u32, value: u64) {
+        self.saved_registers.set(index, value)
     }
 
-    const TRANSMIT_HOLDING_REGISTER: u64 = 0x10000000;
-    const RECEIVE_BUFFER_REGISTER: u64 = 0x10000000;
-    const DIVISOR_LATCH_LSB: u64 = 0x10000000;
-    const INTERRUPT_ENABLE_REGISTER: u64 = 0x10000001;
-    const DIVISOR_LATCH_MSB: u64 = 0x10000001;
-    const FIFO_CONTROL_REGISTER: u64 = 0x10000002;
-    const INTERRUPT_IDENTIFICATION_REGISTER: u64 = 0x10000002;
-    const LINE_CONTROL_REGISTER: u64 = 0x10000003;
-    const MODEM_CONTROL_REGISTER: u64 = 0x10000004;
-    const LINE_STATUS_REGISTER: u64 = 0x10000005;
-    const MODEM_STATUS_REGISTER: u64 = 0x10000006;
-    #[allow(unused)]
-    const SCRATCH_REGISTER: u64 = 0x10000007;
-
-    // bits for interrupt identification register
-    const IIR_FIFOS_ENABLED: u8 = 0xC0;
-    const IIR_INTERRUPT_NOT_PENDING: u8 = 0x01; // set to zero for interrupt pending
-    // note: bits 1-3 are an enumeration as follows, not a bitmask
-    const IIR_TX_INTERRUPT: u8 = 0x02; // transmit fifo has room for more data
-    const IIR_RX_INTERRUPT: u8 = 0x04; // receive fifo contains data
-
-    // bits for line control register
-    const LCR_EIGHT_BIT_WORDS: u8 = 0x03; // eight bit words
-    const LCR_DIVISOR_LATCH_ACCESS: u8 = 0x80; // divisor latch access bit (DLAB)
-
-    // bits for line status register
-    const LSR_DATA_READY: u8 = 0x01;
-    #[allow(unused)]
-    const LSR_BREAK_INTERRUPT: u8 = 0x10;
-    const LSR_TRANSMITTER_HAS_ROOM: u8 = 0x20;
-    const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
-
-    // bits for modem status register
-    const MSR_CLEAR_TO_SEND: u8 = 0x10;
-
-    // bits for modem control register
-    const MCR_LOOPBACK_ENABLE: u8 = 0x10;
-    const MCR_RESERVED_BITS: u8 = 0xe0;
-
-    pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> u8 {
-        match (self.dlab, addr) {
-            (false, Uart::RECEIVE_BUFFER_REGISTER) => {
-                if self.input_bytes_ready > 0 {
-                    let ret = self.input_fifo[0];
-                    self.input_bytes_ready -= 1;
-                    for i in 0..(self.input_bytes_ready) {
-                        self.input_fifo[i] = self.input_fifo[i+1];
-                    }
-                    ret
-                } else {
-                    0
-                }
-            }
-            (true, Uart::DIVISOR_LATCH_LSB) => (self.divisor_latch & 0xff) as u8,
-            (true, Uart::DIVISOR_LATCH_MSB) => (self.divisor_latch >> 8) as u8,
-            (false, Uart::INTERRUPT_ENABLE_REGISTER) => self.interrupt_enable, // (top four should always be zero)
-            (_, Uart::INTERRUPT_IDENTIFICATION_REGISTER) => {
-                if self.rx_interrupt() {
-                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_RX_INTERRUPT
-                } else if self.tx_interrupt(host_clint.get_mtime()) {
-                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_TX_INTERRUPT
-                } else {
-                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_INTERRUPT_NOT_PENDING
-                }
-            },
-            (true, Uart::LINE_CONTROL_REGISTER) => Uart::LCR_EIGHT_BIT_WORDS,
-            (false, Uart::LINE_CONTROL_REGISTER) => Uart::LCR_EIGHT_BIT_WORDS | Uart::LCR_DIVISOR_LATCH_ACCESS,
-            (_, Uart::LINE_STATUS_REGISTER) => {
-                self.fill_fifo();
-
-                let mut lsr = 0;
-                if self.input_bytes_ready > 0 {
-                    lsr |= Uart::LSR_DATA_READY;
-                }
-                if host_clint.get_mtime() >= self.next_interrupt_time {
-                    lsr |= Uart::LSR_TRANSMITTER_HAS_ROOM | Uart::LSR_TRANSMITTER_EMPTY;
-                }
-                lsr
-            }
-            (_, Uart::MODEM_STATUS_REGISTER) => Uart::MSR_CLEAR_TO_SEND, // other bits don't matter to Linux
-            (dlab, _) => {
-                println!("UART: Read uimplemented ?? <- {:#x} (dlab={})", addr, dlab);
-                loop {}
-            }
-        }
+    pub fn rs1_value<I: Rs1>(&self, instruction: &I) -> u64 {
+        self.get_reg(instruction.rs1())
     }
-    pub fn write(&mut self, host_clint: &HostClint, addr: u64, value: u8) {
-        match (self.dlab, addr, value) {
-            (false, Uart::TRANSMIT_HOLDING_REGISTER, _) => {
-                self.output_byte(value as u8);
-
-                let current_time = host_clint.get_mtime();
-                let transmit_time = self.divisor_latch as u64 * 5;
-                self.next_interrupt_time =
-                    self.next_interrupt_time.max(current_time) + transmit_time;
-            }
-            (false, Uart::INTERRUPT_ENABLE_REGISTER, _) => {
-                self.interrupt_enable = value;
-            }
-            (true, Uart::DIVISOR_LATCH_LSB, _) => {
-                self.divisor_latch = (self.divisor_latch & 0xff00) | (value as u16);
-            }
-            (true, Uart::DIVISOR_LATCH_MSB, _) => {
-                self.divisor_latch = (self.divisor_latch & 0x00ff) | ((value as u16) << 8);
-            }
-            (_, Uart::FIFO_CONTROL_REGISTER, _) => {}
-            (_, Uart::LINE_CONTROL_REGISTER, _) => self.dlab = (value & Uart::LCR_DIVISOR_LATCH_ACCESS) != 0,
-            (_, Uart::MODEM_CONTROL_REGISTER, _) if value & (Uart::MCR_LOOPBACK_ENABLE | Uart::MCR_RESERVED_BITS) == 0 => {}
-            _ => {
-                println!("UART: Write unimplemented {:#x} -> {:#x} (dlab={})",
-                         value, addr, self.dlab);
-                loop {}
-            }
-        }
+
+    pub fn rs2_value<I: Rs2>(&self, instruction: &I) -> u64 {
+        self.get_reg(instruction.rs2())
     }
 
-    pub fn output_byte(&mut self, value: u8) {
-        if let Some(guestid) = self.guestid {
-            let len = self.line_buffer.len();
-            if len > 0 && self.line_buffer[len - 1] == '\r' as u8 && value != '\n' as u8 {
-                print::guest_println(guestid, &self.line_buffer);
-                self.line_buffer.clear();
-            }
-            if value == '\n' as u8 || self.line_buffer.is_full() {
-                print::guest_println(guestid, &self.line_buffer);
-                self.line_buffer.clear();
-            } else {
-                self.line_buffer.push(value);
-            }
-        } else {
-            SHARED_STATICS.uart_writer.lock().putchar(value);
+    pub fn set_rd<I: Rd>(&mut self, instruction: &I, value: u64) {
+        self.set_reg(instruction.rd(), value)
+    }
+}
+
+/// Decoded instruction formats with an `rs1` operand.
+pub trait Rs1 { fn rs1(&self) -> u32; }
+/// Decoded instruction formats with an `rs2` operand.
+pub trait Rs2 { fn rs2(&self) -> u32; }
+/// Decoded instruction formats with an `rd` operand.
+pub trait Rd { fn rd(&self) -> u32; }
+
+// The inherent methods of the decoded types take precedence, so these don't recurse.
+macro_rules! impl_operand {
+    ($operand:ident :: $method:ident for $($t:ident),*) => {$(
+        impl $operand for types::$t { fn $method(&self) -> u32 { self.$method() } }
+    )*}
+}
+impl_operand!(Rs1::rs1 for RType, IType, SType, BType, CsrType, ShiftType);
+impl_operand!(Rs2::rs2 for RType, SType, BType);
+impl_operand!(Rd::rd for RType, IType, UType, JType, CsrType, CsrIType, ShiftType);
+
+impl ControlRegisters {
+    pub fn new() -> Self {
+        Self {
+            sstatus: 0,
+            stvec: 0,
+            sie: 0,
+            sip: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            scounteren: 0,
+
+            mtimecmp: u64::max_value(),
         }
     }
+
+    pub fn push_sie(&mut self) {
+        self.sstatus.set(STATUS_SPIE, self.sstatus.get(STATUS_SIE));
+        self.sstatus.set(STATUS_SIE, false);
+    }
+
+    pub fn pop_sie(&mut self) {
+        self.sstatus.set(STATUS_SIE, self.sstatus.get(STATUS_SPIE));
+        self.sstatus.set(STATUS_SPIE, true);
+    }
 }
 
 impl HostClint {
@@ -329,139 +474,53 @@ impl SavedRegisters {
     }
 }
 
-impl Context {
-    pub fn get_csr(&mut self, csr: u32) -> Option<u64> {
-        Some(match csr as u64 {
-            csr::sstatus => {
-                let real = csrr!(sstatus);
-                self.csrs.sstatus = (self.csrs.sstatus & !SSTATUS_DYNAMIC_MASK) | (real & SSTATUS_DYNAMIC_MASK);
-                self.csrs.sstatus
-            }
-            csr::satp => self.csrs.satp,
-            csr::sie => self.csrs.sie,
-            csr::stvec => self.csrs.stvec,
-            csr::sscratch => self.csrs.sscratch,
-            csr::sepc => self.csrs.sepc,
-            csr::scause => self.csrs.scause,
-            csr::stval => self.csrs.stval,
-            csr::sip => self.csrs.sip,
-            csr::sedeleg => 0,
-            csr::sideleg => 0,
-            csr::scounteren => 0,
-            csr::time if self.smode => self.host_clint.get_mtime(),
-            csr::time => unimplemented!(),
-            c => {
-                println!("Read from unrecognized CSR: {:#x}", c);
-                return None;
-            }
-        })
-    }
-
-    pub fn set_csr(&mut self, csr: u32, value: u64) -> bool {
-        match csr as u64 {
-            csr::sstatus => {
-                // User interrupts not supported
-                let value = value & SSTATUS_WRITABLE_MASK;
-
-                let changed = self.csrs.sstatus ^ value;
-                self.csrs.sstatus = value;
-
-                if changed & STATUS_MXR != 0 {
-                    unimplemented!("STATUS.MXR");
-                }
-                if changed & STATUS_FS != 0 {
-                    riscv::set_sstatus_fs(value);
-                }
-
-                if changed.get(STATUS_SIE) && value.get(STATUS_SIE) {
-                    // Enabling interrupts might cause one to happen right away.
-                    self.no_interrupt = false;
-                }
-            }
-            csr::satp => {
-                let mode = (value & SATP_MODE) >> 60;
-                if mode == 0 || mode == 8 {
-                    self.csrs.satp = value & !SATP_ASID;
-                } else {
-                    println!("Attempted to install page table with unsupported mode");
-                }
-                // This should not be necessary. However, currently QEMU doesn't trap when
-                // sfence.vma is executed from user mode so flush here to compensate.
-                pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
-            }
-            csr::sie => {
-                let value = value & (IE_SEIE | IE_STIE | IE_SSIE);
-                if !self.csrs.sie & value != 0 {
-                    self.no_interrupt = false;
-                }
-                self.csrs.sie = value;
-            }
-            csr::stvec => self.csrs.stvec = value & !0x2,
-            csr::sscratch => self.csrs.sscratch = value,
-            csr::sepc => self.csrs.sepc = value,
-            csr::scause => self.csrs.scause = value,
-            csr::stval => self.csrs.stval = value,
-            csr::sip => {
-                if value & IP_SSIP != 0 {
-                    self.no_interrupt = false;
-                }
-                self.csrs.sip = (self.csrs.sip & !IP_SSIP) | (value & IP_SSIP)
-            }
-            csr::sedeleg |
-            csr::sideleg |
-            csr::scounteren => {}
-            c => {
-                println!("Write to unrecognized CSR: {:#x}", c);
-                return false;
-            }
-        }
-
-        return true;
-    }
-
-    pub fn shadow(&self) -> PageTableRoot {
-        if (self.csrs.satp & SATP_MODE) == 0 {
-            PageTableRoot::MPA
-        } else if !self.smode {
-            PageTableRoot::UVA
-        } else if self.csrs.sstatus & STATUS_SUM == 0 {
-            PageTableRoot::KVA
-        } else {
-            PageTableRoot::MVA
-        }
-    }
-}
-
 pub unsafe fn initialize(machine: &MachineMeta,
                          guest_machine: &MachineMeta,
                          shadow_page_tables: PageTables,
                          guest_memory: MemoryRegion,
                          guest_shift: u64,
                          hartid: u64,
-                         guestid: Option<u64>) {
+                         guestid: Option<u64>,
+                         boot: BootImage,
+                         mut disk: Option<MemoryRegion<u8>>) {
+    let guest_irq = |slot: usize| guest_machine.virtio.iter()
+        .find(|d| d.base_address == virtio::slot_address(slot))
+        .map(|d| d.irq);
+
     let mut irq_map = [IrqMapping::Ignored; 512];
     let mut virtio_devices = ArrayVec::new();
-    for i in 0..4 {
+    for i in 0..virtio::FIRST_EMULATED_SLOT {
         let index = (guestid.unwrap_or(1) as usize - 1) * 4 + i;
         if index < machine.virtio.len() {
             virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address));
             let host_irq = machine.virtio[index].irq;
-            let mut guest_irq = None;
-            for j in 0..4 {
-                if guest_machine.virtio[j].base_address == 0x10001000 + 0x1000 * i as u64 {
-                    guest_irq = Some(guest_machine.virtio[j].irq);
-                    break;
-                }
-            }
             assert_eq!(irq_map[host_irq as usize], IrqMapping::Ignored);
             irq_map[host_irq as usize] = IrqMapping::Virtio {
                 device_index: i as u8,
-                guest_irq: guest_irq.unwrap() as u16
+                guest_irq: guest_irq(i).unwrap() as u16
             };
         } else {
             virtio_devices.push(virtio::Device::Unmapped);
         }
     }
+    for &kind in &virtio::emulated_devices(disk.is_some()) {
+        let slot = virtio_devices.len();
+        let guest_irq = guest_irq(slot).expect("No guest device tree node for emulated virtio device") as u32;
+        virtio_devices.push(match kind {
+            virtio::EmulatedDevice::Block => virtio::Device::new_block(disk.take().unwrap(), guest_irq),
+            virtio::EmulatedDevice::Console => virtio::Device::new_console(guestid, guest_irq),
+            virtio::EmulatedDevice::Rng => virtio::Device::new_rng(guest_irq).unwrap(),
+            virtio::EmulatedDevice::Net => virtio::Device::new_net(virtio::guest_mac(guestid),
+                                                                   virtio::discard_frame, guest_irq),
+            virtio::EmulatedDevice::Balloon => virtio::Device::new_balloon(guest_irq),
+        });
+    }
+
+    // Only one guest can own the host UART's receive interrupt.
+    if let (Some(irq), None) | (Some(irq), Some(1)) = (machine.uart_irq, guestid) {
+        assert_eq!(irq_map[irq as usize], IrqMapping::Ignored);
+        irq_map[irq as usize] = IrqMapping::HostUart;
+    }
 
     let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
 
@@ -479,42 +538,50 @@ pub unsafe fn initialize(machine: &MachineMeta,
         _ => None,
     };
 
-    let context = Context {
-        csrs: ControlRegisters {
-            sstatus: 0,
-            stvec: 0,
-            sie: 0,
-            sip: 0,
-            sscratch: 0,
-            sepc: 0,
-            scause: 0,
-            stval: 0,
-            satp: 0,
+    let rv32 = elf::is_elf32(boot.kernel as *const u8);
+    if rv32 {
+        csrc!(sstatus, STATUS_UXL);
+        csrs!(sstatus, STATUS_UXL_32);
+        assert_eq!(csrr!(sstatus) & STATUS_UXL, STATUS_UXL_32, "Host doesn't support RV32 guests");
+    }
 
-            mtimecmp: u64::max_value(),
-        },
+    let mut mmio = MmioBus::new();
+    mmio.register(guest_machine.uart_address, uart_device::UART_SIZE, MmioDevice::Uart);
+    mmio.register(guest_machine.plic_address, plic::PLIC_SIZE, MmioDevice::Plic);
+    if let Some(address) = guest_machine.clint_address {
+        mmio.register(address, clint::CLINT_SIZE, MmioDevice::Clint);
+    }
+    let virtio_size = 0x1000 * virtio_devices.len() as u64;
+    mmio.register(virtio::slot_address(0), virtio_size, MmioDevice::Virtio);
+    // Empty virtio slots read as all ones, so that guests probing for devices find a bad magic
+    // value rather than faulting.
+    if virtio_devices.len() < virtio::MAX_DEVICES {
+        mmio.register(virtio::slot_address(virtio_devices.len()), 0x1000 * virtio::MAX_DEVICES as u64 - virtio_size,
+                      MmioDevice::Unclaimed(UnclaimedPolicy::ReadOnes));
+    }
+
+    let context = Context {
+        csrs: ControlRegisters::new(),
         saved_registers: SavedRegisters {
             registers: MemoryRegion::with_base_address(SSTACK_BASE, 0, 32 * 8)
         },
+        memory_banks: pmap::guest_memory_banks(guest_machine, guest_memory.len(), guest_shift),
         guest_memory,
         shadow_page_tables,
-        plic: PlicState::new(),
-        uart: Uart {
-            dlab: false,
-            interrupt_enable: 0,
-            divisor_latch: 1,
-            next_interrupt_time: 0,
-            input_fifo: [0; 16],
-            input_bytes_ready: 0,
-            line_buffer: ArrayVec::new(),
-            guestid,
-        },
+        plic: PlicState::new(guest_machine.plic_address),
+        clint: guest_machine.clint_address.map(Clint::new),
+        uart: Uart::new(guest_machine.uart_address, guestid),
         virtio: VirtIO {
             devices: virtio_devices,
             queue_guest_pages: ArrayVec::new(),
+            queue_bounds: (0, 0),
         },
         guest_shift,
-        smode: true,
+        hartid,
+        cycle_base: csrr!(cycle),
+        instret_base: csrr!(instret),
+        guest_mode: PrivilegeMode::Supervisor,
+        rv32,
         no_interrupt: true,
         host_clint,
         host_plic: HostPlic {
@@ -522,9 +589,16 @@ pub unsafe fn initialize(machine: &MachineMeta,
                 pmap::pa2va(machine.plic_address + 0x200004 + 0x1000 * plic_context), 0, 8),
         },
         consecutive_page_fault_count: 0,
+        reservation: None,
         tlb_caches_invalid_ptes: false,
+        protected_page_tables: ArrayVec::new(),
+        translation_cache: TranslationCache::new(),
+        gdb: GdbState::new(),
+        single_step: false,
         test_finisher,
         irq_map,
+        mmio,
+        boot,
     };
 
     // Memory backing for CONTEXT might not be in a valid state, so force_unlock() first, and avoid
@@ -533,4 +607,5 @@ pub unsafe fn initialize(machine: &MachineMeta,
     CONTEXT.force_unlock();
     let old = CONTEXT.lock().replace(context);
     core::mem::forget(old);
+    CONTEXT_INITIALIZED.store(true, Ordering::SeqCst);
 }
@@ -0,0 +1,317 @@
+//! Emulation of the supervisor CSRs seen by the guest.
+//!
+//! Every CSR the guest can access has an entry in `CSRS` giving the functions used to read and
+//! write it. Accesses to CSRs not in the table, or writes to CSRs without a write function, are
+//! illegal and should be reported to the guest as such.
+//!
+//! With the `mret_compat` feature the guest's supervisor mode can also be treated as machine mode by
+//! guests that expect to own it, as described in `M_MODE_CSRS`.
+//!
+//! The `cycle`, `time` and `instret` counters are provided as well, and can also be read from guest
+//! user mode if enabled in the guest's `scounteren`. RV32 guests see the low halves of them there and
+//! the high halves in `cycleh`, `timeh` and `instreth`, which are illegal for RV64 guests.
+
+use crate::context::Context;
+use crate::riscv::bits::*;
+use crate::riscv::csr;
+use crate::trap::U64Bits;
+use crate::{pmap, riscv};
+use riscv_decode::Instruction;
+
+struct CsrHandler {
+    csr: u64,
+    read: fn(&mut Context) -> u64,
+    write: Option<fn(&mut Context, u64)>,
+}
+
+/// The operation performed by a Zicsr instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CsrOp {
+    Write(u64),
+    Set(u64),
+    Clear(u64),
+}
+
+const CSRS: &[CsrHandler] = &[
+    CsrHandler { csr: csr::sstatus, read: read_sstatus, write: Some(write_sstatus) },
+    CsrHandler { csr: csr::satp, read: read_satp, write: Some(write_satp) },
+    CsrHandler { csr: csr::sie, read: read_sie, write: Some(write_sie) },
+    CsrHandler { csr: csr::sip, read: read_sip, write: Some(write_sip) },
+    CsrHandler { csr: csr::stvec, read: read_stvec, write: Some(write_stvec) },
+    CsrHandler { csr: csr::sscratch, read: read_sscratch, write: Some(write_sscratch) },
+    CsrHandler { csr: csr::sepc, read: read_sepc, write: Some(write_sepc) },
+    CsrHandler { csr: csr::scause, read: read_scause, write: Some(write_scause) },
+    CsrHandler { csr: csr::stval, read: read_stval, write: Some(write_stval) },
+    CsrHandler { csr: csr::scounteren, read: read_scounteren, write: Some(write_scounteren) },
+    // Delegation registers are hard-wired to zero.
+    CsrHandler { csr: csr::sedeleg, read: read_zero, write: Some(write_ignored) },
+    CsrHandler { csr: csr::sideleg, read: read_zero, write: Some(write_ignored) },
+    CsrHandler { csr: csr::cycle, read: read_cycle, write: None },
+    CsrHandler { csr: csr::time, read: read_time, write: None },
+    CsrHandler { csr: csr::instret, read: read_instret, write: None },
+];
+
+/// Upper halves of the counters, only present for RV32 guests.
+const RV32_CSRS: &[CsrHandler] = &[
+    CsrHandler { csr: csr::cycleh, read: read_cycleh, write: None },
+    CsrHandler { csr: csr::timeh, read: read_timeh, write: None },
+    CsrHandler { csr: csr::instreth, read: read_instreth, write: None },
+];
+
+/// Machine mode aliases of the supervisor CSRs, used with the `mret_compat` feature. This is purely a
+/// compatibility fiction for firmware-style guests that are knowingly run in supervisor mode: traps
+/// are still delivered through `stvec` and `sepc`, which is why `mtvec`, `mepc` and friends simply
+/// alias them, and `mret` behaves exactly like `sret`. In `mstatus` the MIE, MPIE and MPP fields are
+/// views of SIE, SPIE and SPP, so MPP can only distinguish user mode from the guest's "machine"
+/// mode. `mie`, `mip`, delegation and every other machine mode feature remain inaccessible.
+const M_MODE_CSRS: &[CsrHandler] = &[
+    CsrHandler { csr: csr::mstatus, read: read_mstatus, write: Some(write_mstatus) },
+    CsrHandler { csr: csr::mtvec, read: read_stvec, write: Some(write_stvec) },
+    CsrHandler { csr: csr::mscratch, read: read_sscratch, write: Some(write_sscratch) },
+    CsrHandler { csr: csr::mepc, read: read_sepc, write: Some(write_sepc) },
+    CsrHandler { csr: csr::mcause, read: read_scause, write: Some(write_scause) },
+    CsrHandler { csr: csr::mtval, read: read_stval, write: Some(write_stval) },
+];
+
+const STATUS_MIE: u64 = 1 << 3;
+const STATUS_MPIE: u64 = 1 << 7;
+
+/// Bits of `scounteren` that enable user mode access to `cycle`, `time` and `instret`.
+const COUNTEREN_MASK: u64 = 0x7;
+
+fn read_zero(_: &mut Context) -> u64 { 0 }
+fn write_ignored(_: &mut Context, _: u64) {}
+
+fn read_satp(state: &mut Context) -> u64 {
+    if state.rv32 { pmap::satp_to_rv32(state.csrs.satp) } else { state.csrs.satp }
+}
+fn read_sie(state: &mut Context) -> u64 { state.csrs.sie }
+fn read_sip(state: &mut Context) -> u64 { state.csrs.sip }
+fn read_stvec(state: &mut Context) -> u64 { state.csrs.stvec }
+fn read_sscratch(state: &mut Context) -> u64 { state.csrs.sscratch }
+fn read_sepc(state: &mut Context) -> u64 { state.csrs.sepc }
+fn read_scause(state: &mut Context) -> u64 { state.csrs.scause }
+fn read_stval(state: &mut Context) -> u64 { state.csrs.stval }
+fn read_scounteren(state: &mut Context) -> u64 { state.csrs.scounteren }
+fn read_cycle(state: &mut Context) -> u64 { low_half(state, cycle(state)) }
+fn read_time(state: &mut Context) -> u64 { low_half(state, state.host_clint.get_mtime()) }
+fn read_instret(state: &mut Context) -> u64 { low_half(state, instret(state)) }
+fn read_cycleh(state: &mut Context) -> u64 { high_half(cycle(state)) }
+fn read_timeh(state: &mut Context) -> u64 { high_half(state.host_clint.get_mtime()) }
+fn read_instreth(state: &mut Context) -> u64 { high_half(instret(state)) }
+
+fn cycle(state: &Context) -> u64 { csrr!(cycle).wrapping_sub(state.cycle_base) }
+fn instret(state: &Context) -> u64 { csrr!(instret).wrapping_sub(state.instret_base) }
+
+/// Registers of an RV32 guest hold sign extended 32-bit values.
+fn low_half(state: &Context, value: u64) -> u64 {
+    if state.rv32 { value as i32 as u64 } else { value }
+}
+fn high_half(value: u64) -> u64 { (value >> 32) as i32 as u64 }
+
+fn write_stvec(state: &mut Context, value: u64) { state.csrs.stvec = value & !0x2 }
+fn write_sscratch(state: &mut Context, value: u64) { state.csrs.sscratch = value }
+fn write_sepc(state: &mut Context, value: u64) { state.csrs.sepc = value }
+fn write_scause(state: &mut Context, value: u64) { state.csrs.scause = value }
+fn write_stval(state: &mut Context, value: u64) { state.csrs.stval = value }
+fn write_scounteren(state: &mut Context, value: u64) { state.csrs.scounteren = value & COUNTEREN_MASK }
+
+fn read_sstatus(state: &mut Context) -> u64 {
+    let real = csrr!(sstatus);
+    state.csrs.sstatus = (state.csrs.sstatus & !SSTATUS_DYNAMIC_MASK) | (real & SSTATUS_DYNAMIC_MASK);
+    state.csrs.sstatus
+}
+
+fn read_mstatus(state: &mut Context) -> u64 {
+    let sstatus = read_sstatus(state);
+    let mut value = sstatus & !(STATUS_SIE | STATUS_SPIE | STATUS_SPP);
+    value.set(STATUS_MIE, sstatus.get(STATUS_SIE));
+    value.set(STATUS_MPIE, sstatus.get(STATUS_SPIE));
+    if sstatus.get(STATUS_SPP) {
+        value |= STATUS_MPP_M;
+    }
+    value
+}
+
+fn write_mstatus(state: &mut Context, value: u64) {
+    let mut sstatus = value & !(STATUS_SIE | STATUS_SPIE | STATUS_SPP);
+    sstatus.set(STATUS_SIE, value.get(STATUS_MIE));
+    sstatus.set(STATUS_SPIE, value.get(STATUS_MPIE));
+    sstatus.set(STATUS_SPP, value & STATUS_MPP_M != STATUS_MPP_U);
+    write_sstatus(state, sstatus);
+}
+
+fn write_sstatus(state: &mut Context, value: u64) {
+    // User interrupts not supported
+    let value = value & SSTATUS_WRITABLE_MASK;
+
+    let changed = state.csrs.sstatus ^ value;
+    state.csrs.sstatus = value;
+
+    if changed & STATUS_MXR != 0 {
+        // Shadow PTEs carry the guest's R and X bits unchanged, so the hardware can apply MXR
+        // directly.
+        riscv::set_sstatus_mxr(value);
+    }
+    if changed & STATUS_FS != 0 {
+        riscv::set_sstatus_fs(value);
+    }
+
+    if changed.get(STATUS_SIE) && value.get(STATUS_SIE) {
+        // Enabling interrupts might cause one to happen right away.
+        state.no_interrupt = false;
+    }
+}
+
+/// With the Bare mode guest virtual addresses are guest physical addresses, so `active_root` switches
+/// to the MPA root, which maps guest memory directly and never walks the guest's tables. Every write
+/// flushes the shadow page tables, so nothing shadowed before paging was disabled (or enabled)
+/// survives the transition. Sv48 is only accepted if the host supports it, since the shadow page
+/// tables must then use Sv48 as well.
+fn write_satp(state: &mut Context, value: u64) {
+    match legalize_satp(value, state.rv32, state.shadow_page_tables.supports_sv48()) {
+        Some(satp) => state.csrs.satp = satp,
+        None => println!("Attempted to install page table with unsupported mode"),
+    }
+    let sv48 = pmap::SatpMode::from_satp(state.csrs.satp) == Some(pmap::SatpMode::Sv48);
+    state.shadow_page_tables.set_sv48(sv48);
+    // Switching ASIDs always requires a flush since the shadow page tables are not ASID tagged.
+    // Flushing even when the ASID is unchanged should not be necessary. However, currently QEMU
+    // doesn't trap when sfence.vma is executed from user mode so flush here to compensate.
+    let asid = (state.csrs.satp & SATP_ASID) >> 44;
+    state.shadow_page_tables.set_asid(asid);
+    pmap::forget_protected_page_tables(state);
+    state.translation_cache.invalidate(None, None);
+    state.shadow_page_tables.install_root(pmap::active_root(state));
+}
+
+/// Returns the satp the guest ends up with after writing `value`, converted from the RV32 layout if
+/// needed, or None if the requested mode isn't supported.
+fn legalize_satp(value: u64, rv32: bool, sv48: bool) -> Option<u64> {
+    let value = if rv32 { pmap::satp_from_rv32(value) } else { value };
+    let mode = (value & SATP_MODE) >> 60;
+    if mode == 0 {
+        // The ASID and PPN fields are meant to be zero with Bare, and what happens otherwise is
+        // unspecified. Clearing them means paging off always runs with ASID 0.
+        Some(0)
+    } else if mode == 8 || (mode == 9 && !rv32 && sv48) || (rv32 && mode == pmap::SATP_MODE_SV32) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Check the satp legalization against known results, panicking on any mismatch.
+pub fn selftest() {
+    assert_eq!(legalize_satp(0x0000_1234_0000_5678, false, true), Some(0));
+    assert_eq!(legalize_satp(0x8000_1234_0000_5678, false, false), Some(0x8000_1234_0000_5678));
+    assert_eq!(legalize_satp(0x9000_0000_0000_5678, false, false), None);
+    assert_eq!(legalize_satp(0x9000_0000_0000_5678, false, true), Some(0x9000_0000_0000_5678));
+    assert_eq!(legalize_satp(0x7fc0_5678, true, true), Some(0));
+    assert_eq!(legalize_satp(0x8000_5678, true, false), Some(pmap::satp_from_rv32(0x8000_5678)));
+    assert_eq!(legalize_satp(0xa000_0000_0000_5678, false, true), None);
+}
+
+/// Install the guest's `sstatus` and `stvec` from a checkpoint, legalizing them like guest writes
+/// would. The hardware MXR and FS fields still hold the values from before the restore, so they
+/// are always updated rather than only when they change. SUM is applied through the choice of
+/// shadow root, which the caller installs afterwards.
+pub fn restore(state: &mut Context, sstatus: u64, stvec: u64) {
+    write_stvec(state, stvec);
+    write_sstatus(state, sstatus);
+    riscv::set_sstatus_mxr(state.csrs.sstatus);
+    riscv::set_sstatus_fs(state.csrs.sstatus);
+}
+
+fn write_sie(state: &mut Context, value: u64) {
+    state.csrs.sie = value & (IE_SEIE | IE_STIE | IE_SSIE);
+    state.no_interrupt = false;
+}
+
+fn write_sip(state: &mut Context, value: u64) {
+    // Only the software interrupt is writable by the guest.
+    state.csrs.sip = (state.csrs.sip & !IP_SSIP) | (value & IP_SSIP);
+    state.no_interrupt = false;
+}
+
+fn lookup(state: &Context, csr: u32) -> Option<&'static CsrHandler> {
+    let m_mode_csrs: &[CsrHandler] = if cfg!(feature = "mret_compat") { M_MODE_CSRS } else { &[] };
+    let rv32_csrs: &[CsrHandler] = if state.rv32 { RV32_CSRS } else { &[] };
+    CSRS.iter().chain(m_mode_csrs).chain(rv32_csrs).find(|h| h.csr == csr as u64)
+}
+
+/// Read the guest's value of `csr`, or return None if the guest can't access it.
+pub fn read(state: &mut Context, csr: u32) -> Option<u64> {
+    match lookup(state, csr) {
+        Some(handler) => Some((handler.read)(state)),
+        None => {
+            println!("Read from unrecognized CSR: {:#x}", csr);
+            None
+        }
+    }
+}
+
+/// Write `value` to the guest's `csr`. Returns false if the guest can't write to it.
+pub fn write(state: &mut Context, csr: u32, value: u64) -> bool {
+    match lookup(state, csr).and_then(|h| h.write) {
+        Some(write) => {
+            write(state, value);
+            true
+        }
+        None => {
+            println!("Write to unrecognized or read-only CSR: {:#x}", csr);
+            false
+        }
+    }
+}
+
+/// Emulate a Zicsr instruction that performs `op` on `csr` and stores the old value into register
+/// `rd`. Returns false, without changing any state, if the access is illegal.
+pub fn emulate(state: &mut Context, csr: u32, rd: u32, op: CsrOp) -> bool {
+    let handler = match lookup(state, csr) {
+        Some(handler) => handler,
+        None => {
+            println!("Access to unrecognized CSR: {:#x}", csr);
+            return false;
+        }
+    };
+
+    let prev = (handler.read)(state);
+    let value = match op {
+        CsrOp::Write(value) => Some(value),
+        CsrOp::Set(0) | CsrOp::Clear(0) => None,
+        CsrOp::Set(mask) => Some(prev | mask),
+        CsrOp::Clear(mask) => Some(prev & !mask),
+    };
+
+    if let Some(value) = value {
+        if !write(state, csr, value) {
+            return false;
+        }
+    }
+    state.saved_registers.set(rd, prev);
+    true
+}
+
+/// Emulate `instruction` if it is a read of a counter CSR from guest user mode that the guest's
+/// `scounteren` permits. Returns false if the instruction is anything else.
+pub fn emulate_user_counter_read(state: &mut Context, instruction: u32) -> bool {
+    let (csr, rd) = match riscv_decode::decode(instruction) {
+        Ok(Instruction::Csrrs(i)) if i.rs1() == 0 => (i.csr(), i.rd()),
+        _ => return false,
+    };
+
+    let enable_bit = match csr as u64 {
+        csr::cycle => 0,
+        csr::time => 1,
+        csr::instret => 2,
+        csr::cycleh if state.rv32 => 0,
+        csr::timeh if state.rv32 => 1,
+        csr::instreth if state.rv32 => 2,
+        _ => return false,
+    };
+    if !state.csrs.scounteren.get(1 << enable_bit) {
+        return false;
+    }
+    emulate(state, csr, rd, CsrOp::Set(0))
+}
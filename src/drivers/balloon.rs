@@ -0,0 +1,295 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2790005
+
+use arrayvec::ArrayVec;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{self, PageTableLevel, PageTables};
+use super::*;
+
+const INFLATEQ: u32 = 0;
+const DEFLATEQ: u32 = 1;
+const STATSQ: u32 = 2;
+
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+
+/// Balloon PFNs are always in units of 4KB, independent of the guest's page size.
+const BALLOON_PAGE_SIZE: u64 = 4096;
+
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// Enough bits to track every page of a 1GB hart segment.
+const MAX_BALLOON_PAGES: usize = 1 << 18;
+
+/// Most page frame numbers handled from a single inflate or deflate request, which is also the most
+/// Linux ever sends at once.
+const MAX_REQUEST_PFNS: usize = 256;
+
+/// Memory statistics most recently reported by the guest, in bytes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BalloonStats {
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+}
+
+/// Emulated virtio balloon. Pages the guest places in the balloon are unmapped from MPA and zeroed
+/// until the guest takes them back out again.
+///
+/// The host pages are never reused for hypervisor data. Virtqueue buffers, used rings and guest PTE
+/// updates are all written through `guest_memory` at guest chosen addresses, so a guest could aim
+/// those writes at a page it had placed in the balloon.
+///
+/// VIRTIO_BALLOON_F_MUST_TELL_HOST is offered so that the guest doesn't touch a deflated page until
+/// its request completes.
+pub struct BalloonDriver {
+    /// Number of pages the host would like the balloon to hold.
+    target_pages: u32,
+    /// Number of pages the guest reports holding in the balloon.
+    actual_pages: u32,
+
+    /// One bit per guest page, set while the page is in the balloon and its host page reclaimed.
+    reclaimed: [u64; MAX_BALLOON_PAGES / 64],
+    reclaimed_count: u64,
+    /// Set on reset, after which every reclaimed page is handed back to the guest.
+    restore_all: bool,
+
+    stats: BalloonStats,
+    /// Head of the descriptor chain holding the guest's stats buffer, which is returned to the
+    /// guest to ask for a fresh report.
+    stats_buffer: Option<u32>,
+}
+
+impl BalloonDriver {
+    pub fn new() -> Self {
+        Self {
+            target_pages: 0,
+            actual_pages: 0,
+            reclaimed: [0; MAX_BALLOON_PAGES / 64],
+            reclaimed_count: 0,
+            restore_all: false,
+            stats: BalloonStats::default(),
+            stats_buffer: None,
+        }
+    }
+
+    fn is_reclaimed(&self, index: usize) -> bool {
+        self.reclaimed[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_reclaimed(&mut self, index: usize, reclaimed: bool) {
+        if reclaimed {
+            self.reclaimed[index / 64] |= 1 << (index % 64);
+            self.reclaimed_count += 1;
+        } else {
+            self.reclaimed[index / 64] &= !(1 << (index % 64));
+            self.reclaimed_count -= 1;
+        }
+    }
+}
+
+impl GuestDevice<BalloonDriver> {
+    /// Number of host pages currently reclaimed from the guest.
+    pub fn reclaimed_pages(&self) -> u64 {
+        self.host_driver.reclaimed_count
+    }
+
+    /// Number of pages the host has asked the balloon to hold.
+    pub fn target_pages(&self) -> u32 {
+        self.host_driver.target_pages
+    }
+
+    pub fn stats(&self) -> BalloonStats {
+        self.host_driver.stats
+    }
+
+    /// Whether any page in `[start, start+len)` has been reclaimed, where `base` is the guest
+    /// physical address of the start of guest memory.
+    pub fn reclaimed_in_range(&self, base: u64, start: u64, len: u64) -> bool {
+        if self.host_driver.reclaimed_count == 0 || start.saturating_add(len) <= base {
+            return false;
+        }
+        let first = (start.saturating_sub(base) / BALLOON_PAGE_SIZE) as usize;
+        let last = (((start + len - 1 - base) / BALLOON_PAGE_SIZE) as usize).min(MAX_BALLOON_PAGES - 1);
+        (first..=last).any(|index| self.host_driver.is_reclaimed(index))
+    }
+
+    /// Ask the guest to grow or shrink the balloon to `pages` 4KB pages.
+    pub fn set_target_pages(&mut self, pages: u32) {
+        self.host_driver.target_pages = pages;
+        self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        self.interrupt_pending = true;
+    }
+
+    /// Return the guest's stats buffer so that it sends an updated report.
+    pub fn request_stats(&mut self, guest_memory: &mut MemoryRegion) {
+        if let Some(id) = self.host_driver.stats_buffer.take() {
+            self.push_used(guest_memory, STATSQ, id, 0);
+        }
+    }
+
+    /// Handle any buffers the guest has placed on the balloon's queues. Inflated pages are unmapped
+    /// from MPA, and `reserved` is consulted so that pages the hypervisor depends on (like the
+    /// guest's page tables or virtqueues) are never reclaimed. Returns whether any shadow mappings
+    /// might now point at reclaimed pages, in which case the caller must flush them.
+    pub fn process_requests<F: Fn(u64) -> bool>(&mut self, guest_memory: &mut MemoryRegion,
+                                                 page_tables: &mut PageTables, guest_shift: u64,
+                                                 reserved: F) -> bool {
+        // Each page inflated or deflated would otherwise get its own TLB flush.
+        page_tables.with_batch(|page_tables| {
+            self.process_queues(guest_memory, page_tables, guest_shift, &reserved)
+        })
+    }
+
+    fn process_queues<F: Fn(u64) -> bool>(&mut self, guest_memory: &mut MemoryRegion,
+                                          page_tables: &mut PageTables, guest_shift: u64,
+                                          reserved: &F) -> bool {
+        if self.host_driver.restore_all {
+            for index in 0..MAX_BALLOON_PAGES {
+                if self.host_driver.is_reclaimed(index) {
+                    let guest_pa = guest_memory.base() + index as u64 * BALLOON_PAGE_SIZE;
+                    self.deflate(guest_memory, page_tables, guest_pa, guest_shift);
+                }
+            }
+            self.host_driver.restore_all = false;
+        }
+
+        let mut needs_flush = false;
+        while let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, INFLATEQ) {
+            for pfn in Self::request_pfns(guest_memory, &descriptors) {
+                needs_flush |= self.inflate(guest_memory, page_tables, pfn as u64 * BALLOON_PAGE_SIZE, reserved);
+            }
+            self.push_used(guest_memory, INFLATEQ, id, 0);
+        }
+
+        while let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, DEFLATEQ) {
+            for pfn in Self::request_pfns(guest_memory, &descriptors) {
+                self.deflate(guest_memory, page_tables, pfn as u64 * BALLOON_PAGE_SIZE, guest_shift);
+            }
+            self.push_used(guest_memory, DEFLATEQ, id, 0);
+        }
+
+        if self.host_driver.stats_buffer.is_none() {
+            if let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, STATSQ) {
+                self.host_driver.stats_buffer = Some(id);
+                for descriptor in descriptors.iter().filter(|d| !d.writable) {
+                    self.read_stats(guest_memory, descriptor);
+                }
+            }
+        }
+
+        needs_flush
+    }
+
+    /// Read the page frame numbers out of the device readable buffers of a request.
+    fn request_pfns(guest_memory: &MemoryRegion, descriptors: &[Descriptor]) -> ArrayVec<[u32; MAX_REQUEST_PFNS]> {
+        let mut pfns = ArrayVec::new();
+        for descriptor in descriptors.iter().filter(|d| !d.writable) {
+            let count = (descriptor.len as usize / 4).min(MAX_REQUEST_PFNS - pfns.len());
+            pfns.extend((0..count as u64).filter_map(|i| {
+                pmap::read_guest::<u32>(guest_memory, GUEST_PHYSICAL, descriptor.addr.wrapping_add(4 * i))
+            }));
+        }
+        pfns
+    }
+
+    fn page_index(guest_memory: &MemoryRegion, guest_pa: u64) -> Option<usize> {
+        if !guest_memory.in_region(guest_pa) {
+            return None;
+        }
+        let index = ((guest_pa - guest_memory.base()) / BALLOON_PAGE_SIZE) as usize;
+        if index < MAX_BALLOON_PAGES { Some(index) } else { None }
+    }
+
+    fn inflate<F: Fn(u64) -> bool>(&mut self, guest_memory: &mut MemoryRegion, page_tables: &mut PageTables,
+                                   guest_pa: u64, reserved: &F) -> bool {
+        let index = match Self::page_index(guest_memory, guest_pa) {
+            Some(index) if !self.host_driver.is_reclaimed(index) && !reserved(guest_pa)
+                && !self.queues_overlap(guest_pa, BALLOON_PAGE_SIZE) => index,
+            _ => return false,
+        };
+
+        if page_tables.mpa_unmap(guest_pa, PageTableLevel::Level4KB).is_none() {
+            // Not enough memory to split the superpage around this page, so leave it mapped.
+            return false;
+        }
+        // Scrub the host page so that whatever the guest left in it can't leak to its next user.
+        if !cfg!(feature = "skip_zero_on_free") {
+            guest_memory.zero_range(guest_pa, BALLOON_PAGE_SIZE).expect("Balloon page outside guest memory");
+        }
+        self.host_driver.set_reclaimed(index, true);
+        true
+    }
+
+    /// Return a reclaimed page to the guest by mapping it again. Does nothing if the page was never
+    /// reclaimed.
+    fn deflate(&mut self, guest_memory: &mut MemoryRegion, page_tables: &mut PageTables, guest_pa: u64,
+               guest_shift: u64) {
+        let index = match Self::page_index(guest_memory, guest_pa) {
+            Some(index) if self.host_driver.is_reclaimed(index) => index,
+            _ => return,
+        };
+
+        page_tables.mpa_set_mapping(guest_pa, guest_pa + guest_shift, PageTableLevel::Level4KB)
+            .expect("Out of hypervisor memory for page tables");
+        self.host_driver.set_reclaimed(index, false);
+    }
+
+    /// Parse a stats buffer, which holds a sequence of 10 byte (tag, value) pairs.
+    fn read_stats(&mut self, guest_memory: &MemoryRegion, descriptor: &Descriptor) {
+        // Only the first 16 stats are looked at.
+        let count = (descriptor.len as u64 / 10).min(16);
+        for addr in (0..count).map(|i| descriptor.addr.wrapping_add(10 * i)) {
+            let tag = pmap::read_guest::<u16>(guest_memory, GUEST_PHYSICAL, addr);
+            let value = pmap::read_guest::<u64>(guest_memory, GUEST_PHYSICAL, addr.wrapping_add(2));
+            if value.is_none() {
+                return;
+            }
+            match tag {
+                Some(VIRTIO_BALLOON_S_MEMFREE) => self.host_driver.stats.free_memory = value,
+                Some(VIRTIO_BALLOON_S_MEMTOT) => self.host_driver.stats.total_memory = value,
+                Some(VIRTIO_BALLOON_S_AVAIL) => self.host_driver.stats.available_memory = value,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Driver for BalloonDriver {
+    const DEVICE_ID: u32 = 5;
+    const FEATURES: u64 = VIRTIO_BALLOON_F_MUST_TELL_HOST | VIRTIO_BALLOON_F_STATS_VQ;
+    const QUEUE_NUM_MAX: u32 = 256;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    // Requests need access to the page tables, so they're handled by `process_requests` instead.
+    fn doorbell(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _queue: u32) {}
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=3 => device.host_driver.target_pages.to_le_bytes()[offset as usize],
+            4..=7 => device.host_driver.actual_pages.to_le_bytes()[offset as usize - 4],
+            _ => 0,
+        }
+    }
+    fn write_config_u8(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64, value: u8) {
+        if let 4..=7 = offset {
+            let mut bytes = device.host_driver.actual_pages.to_le_bytes();
+            bytes[offset as usize - 4] = value;
+            device.host_driver.actual_pages = u32::from_le_bytes(bytes);
+        }
+    }
+
+    fn reset(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {
+        // A freshly reset guest assumes it owns all of its memory again.
+        device.host_driver.restore_all = device.host_driver.reclaimed_count > 0;
+        device.host_driver.actual_pages = 0;
+        device.host_driver.stats_buffer = None;
+    }
+}
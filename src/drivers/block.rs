@@ -0,0 +1,173 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
+
+use crate::memory_region::MemoryRegion;
+use crate::pmap;
+use super::*;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Length of the string returned by a `GetId` request.
+const ID_LENGTH: usize = 20;
+const ID: &[u8] = b"rvirt-blk";
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RequestType {
+    /// Read from the device into the guest's buffers.
+    In,
+    /// Write the guest's buffers out to the device.
+    Out,
+    /// Return the device's identifying string.
+    GetId,
+    Unsupported(u32),
+}
+impl RequestType {
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            VIRTIO_BLK_T_IN => RequestType::In,
+            VIRTIO_BLK_T_OUT => RequestType::Out,
+            VIRTIO_BLK_T_GET_ID => RequestType::GetId,
+            t => RequestType::Unsupported(t),
+        }
+    }
+}
+
+/// Emulated virtio block device whose contents are stored in a region of host memory. Addresses in
+/// `backing` are byte offsets into the disk.
+pub struct BlockDriver {
+    backing: MemoryRegion<u8>,
+}
+
+impl BlockDriver {
+    pub fn new(backing: MemoryRegion<u8>) -> Self {
+        assert_eq!(backing.base(), 0);
+        Self { backing }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.backing.len() / SECTOR_SIZE
+    }
+
+    /// Service a single request, returning the status to report along with the number of bytes
+    /// written into the guest's data buffers.
+    fn handle_request(&mut self, guest_memory: &mut MemoryRegion, request: RequestType, sector: u64,
+                      data: &[Descriptor]) -> (u8, u32) {
+        let mut offset = match sector.checked_mul(SECTOR_SIZE) {
+            Some(offset) => offset,
+            None => return (VIRTIO_BLK_S_IOERR, 0),
+        };
+
+        let mut written = 0;
+        match request {
+            RequestType::In | RequestType::Out => for descriptor in data {
+                let len = descriptor.len as u64;
+                if len == 0 {
+                    continue;
+                }
+                if offset.checked_add(len).map(|end| end > self.backing.len()).unwrap_or(true) {
+                    return (VIRTIO_BLK_S_IOERR, written);
+                }
+
+                let result = if request == RequestType::In {
+                    if !descriptor.writable {
+                        return (VIRTIO_BLK_S_IOERR, written);
+                    }
+                    guest_memory.copy_from_slice(descriptor.addr, self.backing.slice(offset, len))
+                } else {
+                    guest_memory.copy_to_slice(descriptor.addr, self.backing.slice_mut(offset, len))
+                };
+                if result.is_err() {
+                    return (VIRTIO_BLK_S_IOERR, written);
+                }
+
+                if request == RequestType::In {
+                    written += descriptor.len;
+                }
+                offset += len;
+            }
+            RequestType::GetId => {
+                let mut id = [0u8; ID_LENGTH];
+                id[..ID.len()].copy_from_slice(ID);
+
+                let descriptor = match data.first() {
+                    Some(descriptor) if descriptor.writable => descriptor,
+                    _ => return (VIRTIO_BLK_S_IOERR, 0),
+                };
+                let len = ID_LENGTH.min(descriptor.len as usize);
+                if guest_memory.copy_from_slice(descriptor.addr, &id[..len]).is_err() {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                }
+                written = len as u32;
+            }
+            RequestType::Unsupported(_) => return (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+
+        (VIRTIO_BLK_S_OK, written)
+    }
+}
+
+impl Driver for BlockDriver {
+    const DEVICE_ID: u32 = 2;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 256;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue != 0 {
+            return;
+        }
+
+        while let Some((id, descriptors)) = device.next_descriptor_chain(guest_memory, queue) {
+            // Every request consists of a 16 byte header, followed by any data buffers, and then a
+            // single writable status byte.
+            let mut len = 0;
+            if descriptors.len() >= 2 {
+                let header = descriptors[0];
+                let status = descriptors[descriptors.len() - 1];
+
+                let request = pmap::read_guest::<u32>(guest_memory, GUEST_PHYSICAL, header.addr);
+                let sector = pmap::read_guest::<u64>(guest_memory, GUEST_PHYSICAL, header.addr.wrapping_add(8));
+                let status_value = if header.len < 16 || !status.writable || status.len < 1 {
+                    VIRTIO_BLK_S_IOERR
+                } else if let (Some(request), Some(sector)) = (request, sector) {
+                    let request = RequestType::from_u32(request);
+                    let data = &descriptors[1..descriptors.len() - 1];
+
+                    let (status_value, written) = device.host_driver.handle_request(guest_memory, request,
+                                                                                    sector, data);
+                    len = written;
+                    status_value
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                };
+
+                if guest_memory.copy_from_slice(status.addr, &[status_value]).is_ok() {
+                    len += 1;
+                }
+            }
+
+            device.push_used(guest_memory, queue, id, len);
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=7 => device.host_driver.capacity().to_le_bytes()[offset as usize],
+            _ => 0,
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
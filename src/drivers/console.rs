@@ -0,0 +1,107 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2900003
+
+use crate::memory_region::MemoryRegion;
+use crate::print::GuestOutput;
+use crate::input;
+use super::*;
+
+const RECEIVEQ: u32 = 0;
+const TRANSMITQ: u32 = 1;
+
+/// Emulated virtio console connected to the host UART.
+///
+/// Only a single port is provided: additional ports require VIRTIO_CONSOLE_F_MULTIPORT and with it
+/// the control queues, which aren't implemented.
+pub struct ConsoleDriver {
+    output: GuestOutput,
+}
+
+impl ConsoleDriver {
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            output: GuestOutput::new(guestid),
+        }
+    }
+}
+
+impl GuestDevice<ConsoleDriver> {
+    /// Write out any output still buffered for the host UART.
+    pub fn flush_output(&mut self) {
+        self.host_driver.output.flush();
+    }
+
+    /// Move any input waiting on the host UART into buffers from the guest's receive queue. Input is
+    /// left on the host UART if the guest hasn't made any buffers available.
+    pub fn poll_input(&mut self, guest_memory: &mut MemoryRegion) {
+        while let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, RECEIVEQ) {
+            let buffer = match descriptors.first() {
+                Some(descriptor) if descriptor.writable => *descriptor,
+                _ => {
+                    self.push_used(guest_memory, RECEIVEQ, id, 0);
+                    continue;
+                }
+            };
+
+            let mut input = [0u8; 16];
+            let mut len = 0;
+            while len < input.len() && len < buffer.len as usize {
+                match input::getchar() {
+                    Some(ch) => input[len] = ch,
+                    None => break,
+                }
+                len += 1;
+            }
+
+            if len == 0 {
+                break;
+            }
+            if guest_memory.copy_from_slice(buffer.addr, &input[..len]).is_err() {
+                len = 0;
+            }
+            self.push_used(guest_memory, RECEIVEQ, id, len as u32);
+        }
+    }
+}
+
+impl Driver for ConsoleDriver {
+    const DEVICE_ID: u32 = 3;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 256;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        match queue {
+            RECEIVEQ => device.poll_input(guest_memory),
+            TRANSMITQ => while let Some((id, descriptors)) = device.next_descriptor_chain(guest_memory, queue) {
+                for descriptor in descriptors.iter().filter(|d| !d.writable) {
+                    let mut chunk = [0u8; 64];
+                    let mut addr = descriptor.addr;
+                    let end = descriptor.addr.saturating_add(descriptor.len as u64);
+                    while addr < end {
+                        let len = (end - addr).min(chunk.len() as u64) as usize;
+                        if guest_memory.copy_to_slice(addr, &mut chunk[..len]).is_err() {
+                            break;
+                        }
+                        for &b in &chunk[..len] {
+                            device.host_driver.output.output_byte(b);
+                        }
+                        addr += len as u64;
+                    }
+                }
+                device.push_used(guest_memory, queue, id, 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_config_u8(_device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64) -> u8 {
+        0
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
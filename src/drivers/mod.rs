@@ -3,8 +3,15 @@
 use arrayvec::ArrayVec;
 use byteorder::{ByteOrder, LittleEndian};
 use crate::memory_region::MemoryRegion;
+use self::queue::Virtqueue;
 
+pub mod balloon;
+pub mod block;
+pub mod console;
 pub mod macb;
+pub mod net;
+pub mod queue;
+pub mod rng;
 
 #[allow(unused)]
 mod constants {
@@ -37,6 +44,11 @@ mod constants {
     pub const STATUS_DRIVER_OK: u32 = 4;
     pub const STATUS_NEEDS_RESET: u32 = 64;
 
+    pub const INTERRUPT_USED_BUFFER: u32 = 1;
+
+    /// Offset of the device specific configuration space.
+    pub const CONFIG_OFFSET: u64 = 0x100;
+
     pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
     pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
 
@@ -44,9 +56,23 @@ mod constants {
     pub const VIRTQ_DESC_F_WRITE: u16 = 2;
 
     pub const MAX_QUEUES: usize = 4;
+
+    /// `satp` value under which `pmap::read_guest` and `pmap::write_guest` take guest physical
+    /// addresses, which is what every address a guest hands to a device is.
+    pub const GUEST_PHYSICAL: u64 = 0;
 }
 pub use constants::*;
 
+/// A single buffer from a descriptor chain.
+#[derive(Copy, Clone, Debug)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: u64,
+    pub len: u32,
+    /// Whether the buffer is for the device to write into, rather than read from.
+    pub writable: bool,
+}
+
 pub trait Driver: Sized {
     const DEVICE_ID: u32;
     const FEATURES: u64;
@@ -75,34 +101,6 @@ pub trait Driver: Sized {
     fn reset(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion);
 }
 
-pub struct DescriptorTable<'a> {
-    desc: &'a [u8],
-    avail: &'a [u8],
-    used: &'a mut [u8],
-    queue_size: usize,
-}
-#[allow(unused)]
-impl<'a> DescriptorTable<'a> {
-    fn desc_addr(&self, index: usize) -> u64 { LittleEndian::read_u64(&self.desc[16*index..]) }
-    fn desc_len(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.desc[8+16*index..]) }
-    fn desc_flags(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.desc[12+16*index..]) }
-    fn desc_next(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.desc[14+16*index..]) }
-
-    fn avail_flags(&self) -> u16 { LittleEndian::read_u16(&self.avail) }
-    fn avail_idx(&self) -> u16 { LittleEndian::read_u16(&self.avail[2..]) }
-    fn avail_ring(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.avail[4+2*index..]) }
-
-    fn used_flags(&self) -> u16 { LittleEndian::read_u16(&self.used) }
-    fn used_idx(&self) -> u16 { LittleEndian::read_u16(&self.used[2..]) }
-    fn used_ring_id(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.used[4+8*index..]) }
-    fn used_ring_len(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.used[8+8*index..]) }
-
-    fn set_used_flags(&mut self, value: u16) { LittleEndian::write_u16(&mut self.used, value) }
-    fn set_used_idx(&mut self, value: u16) { LittleEndian::write_u16(&mut self.used[2..], value) }
-    fn set_used_ring_id(&mut self, index: usize, value: u32) { LittleEndian::write_u32(&mut self.used[4+8*index..], value) }
-    fn set_used_ring_len(&mut self, index: usize, value: u32) { LittleEndian::write_u32(&mut self.used[8+8*index..], value) }
-}
-
 pub struct GuestDevice<D: Driver> {
     host_features_sel: u32,
 
@@ -112,13 +110,14 @@ pub struct GuestDevice<D: Driver> {
     guest_page_size: u32,
 
     queue_sel: u32,
-    queue_num: [u32; MAX_QUEUES],
-    queue_align: [u32; MAX_QUEUES],
-    queue_pfn: [u32; MAX_QUEUES],
+    queues: [Virtqueue; MAX_QUEUES],
 
     interrupt_status: u32,
     status: u32,
 
+    /// Set when buffers have been returned to the guest since the last call to `take_interrupt`.
+    interrupt_pending: bool,
+
     host_driver: D,
 }
 
@@ -130,18 +129,17 @@ impl<D: Driver> GuestDevice<D> {
             guest_features: 0,
             guest_page_size: 4096,
             queue_sel: 0,
-            queue_num: [0; MAX_QUEUES],
-            queue_align: [0; MAX_QUEUES],
-            queue_pfn: [0; MAX_QUEUES],
+            queues: [Virtqueue::default(); MAX_QUEUES],
             interrupt_status: 0,
             status: 0,
+            interrupt_pending: false,
             host_driver,
         }
     }
 
     pub fn read_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
-        if offset > 0x100 {
-            D::read_config_u8(self, guest_memory, offset)
+        if offset >= CONFIG_OFFSET {
+            D::read_config_u8(self, guest_memory, offset - CONFIG_OFFSET)
         } else {
             0
         }
@@ -152,8 +150,8 @@ impl<D: Driver> GuestDevice<D> {
             return 0;
         }
 
-        if offset > 0x100 {
-            return D::read_config_u32(self, guest_memory, offset);
+        if offset >= CONFIG_OFFSET {
+            return D::read_config_u32(self, guest_memory, offset - CONFIG_OFFSET);
         }
 
         match offset {
@@ -170,11 +168,11 @@ impl<D: Driver> GuestDevice<D> {
             REG_GUEST_PAGE_SIZE => self.guest_page_size,
             REG_QUEUE_SEL => self.queue_sel,
             REG_QUEUE_NUM_MAX => D::QUEUE_NUM_MAX,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize],
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize],
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize],
+            REG_QUEUE_NUM => self.selected_queue().map(|q| q.num).unwrap_or(0),
+            REG_QUEUE_ALIGN => self.selected_queue().map(|q| q.align).unwrap_or(0),
+            REG_QUEUE_PFN => self.selected_queue().map(|q| q.pfn).unwrap_or(0),
             REG_QUEUE_NOTIFY => 0,
-            REG_INTERRUPT_STATUS => 0,
+            REG_INTERRUPT_STATUS => self.interrupt_status,
             REG_INTERRUPT_ACK => 0,
             REG_STATUS => self.status,
             _ => 0,
@@ -182,8 +180,8 @@ impl<D: Driver> GuestDevice<D> {
     }
 
     pub fn write_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64, value: u8)  {
-        if offset > 0x100 {
-            D::write_config_u8(self, guest_memory, offset, value);
+        if offset >= CONFIG_OFFSET {
+            D::write_config_u8(self, guest_memory, offset - CONFIG_OFFSET, value);
         }
     }
 
@@ -192,8 +190,8 @@ impl<D: Driver> GuestDevice<D> {
             return;
         }
 
-        if offset > 0x100 {
-            D::write_config_u32(self, guest_memory, offset, value);
+        if offset >= CONFIG_OFFSET {
+            D::write_config_u32(self, guest_memory, offset - CONFIG_OFFSET, value);
             return;
         }
 
@@ -204,15 +202,14 @@ impl<D: Driver> GuestDevice<D> {
             REG_GUEST_FEATURES_SEL => self.guest_features_sel = value,
             REG_GUEST_PAGE_SIZE => self.guest_page_size = value,
             REG_QUEUE_SEL => self.queue_sel = value,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize] = value,
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize] = value,
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize] = value,
+            REG_QUEUE_NUM => if let Some(q) = self.selected_queue_mut() { q.num = value.min(D::QUEUE_NUM_MAX) },
+            REG_QUEUE_ALIGN => if let Some(q) = self.selected_queue_mut() { q.align = value },
+            REG_QUEUE_PFN => if let Some(q) = self.selected_queue_mut() { q.pfn = value },
             REG_QUEUE_NOTIFY => D::doorbell(self, guest_memory, value),
             REG_INTERRUPT_ACK => self.interrupt_status &= !value,
             REG_STATUS => {
                 if value == 0 {
-                    self.reset();
-                    D::reset(self, guest_memory);
+                    self.reset_device(guest_memory);
                 } else {
                     self.status = value;
                 }
@@ -221,11 +218,30 @@ impl<D: Driver> GuestDevice<D> {
         }
     }
 
+    /// Reset the device, as though the guest had written zero to its status register.
+    pub fn reset_device(&mut self, guest_memory: &mut MemoryRegion) {
+        self.reset();
+        D::reset(self, guest_memory);
+    }
+
     /// Returns true if the interrupt should be forwarded onto the guest, false otherwise.
     pub fn interrupt(&mut self, guest_memory: &mut MemoryRegion) -> bool {
         D::interrupt(self, guest_memory)
     }
 
+    /// Whether the rings of any of the device's queues lie within `[start, start+len)` of guest
+    /// physical memory.
+    pub fn queues_overlap(&self, start: u64, len: u64) -> bool {
+        self.queues.iter().any(|queue| queue.overlaps(start, len))
+    }
+
+    /// Returns whether the guest should be sent an interrupt because buffers were returned to it.
+    pub fn take_interrupt(&mut self) -> bool {
+        let pending = self.interrupt_pending;
+        self.interrupt_pending = false;
+        pending
+    }
+
     fn reset(&mut self) {
         self.host_features_sel = 0;
         self.guest_features_sel = 0;
@@ -233,78 +249,59 @@ impl<D: Driver> GuestDevice<D> {
         self.guest_page_size = 4096;
 
         self.queue_sel = 0;
-        self.queue_num = [0; MAX_QUEUES];
-        self.queue_align = [0; MAX_QUEUES];
-        self.queue_pfn = [0; MAX_QUEUES];
+        self.queues = [Virtqueue::default(); MAX_QUEUES];
 
         self.interrupt_status = 0;
+        self.interrupt_pending = false;
     }
 
-    fn with_buffer<F: FnOnce(&[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) {
-        let dt = self.get_queue(guest_memory, queue);
-
-        if dt.avail_idx() == dt.used_idx() {
-            return;
-        }
-
-        let mut ranges = ArrayVec::<[(u64, u32); 16]>::new();
-
-        let idx = (dt.used_idx() as usize + 1) % dt.queue_size;
-        let id = dt.avail_ring(idx) as usize;
+    fn selected_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+    fn selected_queue_mut(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
 
-        let mut flags = VIRTQ_DESC_F_NEXT;
-        let mut next_id = id;
-        while flags & VIRTQ_DESC_F_NEXT != 0 {
-            let addr = dt.desc_addr(next_id);
-            let len = dt.desc_len(next_id);
-            flags = dt.desc_flags(next_id);
-            next_id = dt.desc_next(next_id) as usize;
+    /// Returns the head index and buffers of the next descriptor chain the guest has made available
+    /// on `queue`, or None if there isn't one. See `Virtqueue::pop_chain`.
+    fn next_descriptor_chain(&mut self, guest_memory: &mut MemoryRegion, queue: u32)
+                             -> Option<(u32, ArrayVec<[Descriptor; queue::MAX_CHAIN_LENGTH]>)> {
+        let chain = self.queues.get_mut(queue as usize)?.pop_chain(guest_memory)?;
+        Some((chain.id, chain.descriptors))
+    }
 
-            ranges.push((addr, len));
+    /// Return the descriptor chain starting at `id` to the guest, recording that `len` bytes were
+    /// written into it.
+    fn push_used(&mut self, guest_memory: &mut MemoryRegion, queue: u32, id: u32, len: u32) {
+        let pushed = match self.queues.get_mut(queue as usize) {
+            Some(q) => q.push_used(guest_memory, id, len),
+            None => false,
+        };
+        if pushed {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            self.interrupt_pending = true;
         }
+    }
+
+    fn with_buffer<F: FnOnce(&[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) {
+        let (id, descriptors) = match self.next_descriptor_chain(guest_memory, queue) {
+            Some(chain) => chain,
+            None => return,
+        };
 
-        // Handling the borrow checker is a bit tricky here. At this point, we let the lifetime of
-        // `dt` end so that its borrow of `guest_memory` ends. Then we borrow a bunch of slices from
-        // `guest_memory` and pass them to `f`. Once that function returns, we have `buffers` go out
-        // of scope so that we can borrow `guest_memory` again to make a DescriptorTable.
+        // Borrow a slice for each buffer from `guest_memory` and pass them to `f`. Once that
+        // function returns `buffers` goes out of scope so that we can borrow `guest_memory` again.
         let consume_buffers = {
             let mut buffers = ArrayVec::<[&[u8]; 16]>::new();
-            for (addr, len) in ranges {
-                buffers.push(guest_memory.slice(addr, len as u64));
+            for descriptor in &descriptors {
+                buffers.push(guest_memory.slice(descriptor.addr, descriptor.len as u64));
             }
 
             f(&*buffers)
         };
 
         if let Some(len) = consume_buffers {
-            let mut dt = self.get_queue(guest_memory, queue);
-            dt.set_used_ring_id(idx, id as u32);
-            dt.set_used_ring_len(idx, len);
-            dt.set_used_idx(dt.used_idx().wrapping_add(1));
-        }
-    }
-
-    fn get_queue<'a>(&'a mut self, guest_memory: &'a mut MemoryRegion, queue: u32) -> DescriptorTable<'a> {
-        let pfn = self.queue_pfn[queue as usize];
-        let queue_size = self.queue_num[queue as usize] as usize;
-        let align = self.queue_align[queue as usize] as usize;
-
-        let desc_size = 16 * queue_size;
-        let avail_size = 6 + 2 * queue_size;
-        let used_size = 6 + 8 * queue_size;
-
-        let used_start = ((desc_size + avail_size + (align - 1)) % align) - align;
-
-        let slice = guest_memory.slice_mut(pfn as u64 * 4096, (used_start + used_size) as u64);
-        let (desc, slice) = slice.split_at_mut(desc_size);
-        let (avail, slice) = slice.split_at_mut(used_size);
-        let (_, used) = slice.split_at_mut(used_start - desc_size - avail_size);
-
-        DescriptorTable {
-            desc,
-            avail,
-            used,
-            queue_size
+            self.push_used(guest_memory, queue, id, len);
         }
     }
 }
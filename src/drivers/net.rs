@@ -0,0 +1,115 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2170001
+
+use crate::memory_region::MemoryRegion;
+use super::*;
+
+const RECEIVEQ: u32 = 0;
+const TRANSMITQ: u32 = 1;
+
+/// Size of `struct virtio_net_hdr` without VIRTIO_NET_F_MRG_RXBUF, which precedes every packet.
+const NET_HEADER_SIZE: usize = 10;
+
+/// Largest Ethernet frame (without FCS) that will be sent or received.
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// Emulated virtio network device. Frames sent by the guest are passed to a host provided
+/// callback, and frames from the host are delivered with `GuestDevice::<NetDriver>::receive`.
+///
+/// No offloads are offered, so the packet header is always zero in both directions.
+pub struct NetDriver {
+    mac: [u8; 6],
+    transmit: fn(&[u8]),
+}
+
+impl NetDriver {
+    pub fn new(mac: [u8; 6], transmit: fn(&[u8])) -> Self {
+        Self { mac, transmit }
+    }
+}
+
+impl GuestDevice<NetDriver> {
+    /// Copy `frame` into the next buffer the guest has made available on the receive queue. Returns
+    /// false (and drops the frame) if the guest hasn't provided a buffer large enough to hold it.
+    pub fn receive(&mut self, guest_memory: &mut MemoryRegion, frame: &[u8]) -> bool {
+        if frame.len() > MAX_FRAME_SIZE {
+            return false;
+        }
+        let (id, descriptors) = match self.next_descriptor_chain(guest_memory, RECEIVEQ) {
+            Some(chain) => chain,
+            None => return false,
+        };
+
+        let mut packet = [0u8; NET_HEADER_SIZE + MAX_FRAME_SIZE];
+        packet[NET_HEADER_SIZE..][..frame.len()].copy_from_slice(frame);
+        let mut remaining = &packet[..NET_HEADER_SIZE + frame.len()];
+
+        let mut written = 0;
+        for descriptor in descriptors.iter().filter(|d| d.writable) {
+            let len = remaining.len().min(descriptor.len as usize);
+            if guest_memory.copy_from_slice(descriptor.addr, &remaining[..len]).is_err() {
+                break;
+            }
+            remaining = &remaining[len..];
+            written += len;
+            if remaining.is_empty() {
+                break;
+            }
+        }
+
+        // Buffers that are too small still have to be returned, but the frame is lost.
+        if !remaining.is_empty() {
+            written = 0;
+        }
+        self.push_used(guest_memory, RECEIVEQ, id, written as u32);
+        remaining.is_empty()
+    }
+
+    /// Gather each packet the guest has queued for transmission and pass it to the host.
+    fn transmit(&mut self, guest_memory: &mut MemoryRegion) {
+        while let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, TRANSMITQ) {
+            let mut packet = [0u8; NET_HEADER_SIZE + MAX_FRAME_SIZE];
+            let mut len = 0;
+            for descriptor in descriptors.iter().filter(|d| !d.writable) {
+                let n = (descriptor.len as usize).min(packet.len() - len);
+                if guest_memory.copy_to_slice(descriptor.addr, &mut packet[len..][..n]).is_err() {
+                    len = 0;
+                    break;
+                }
+                len += n;
+            }
+
+            if len > NET_HEADER_SIZE {
+                (self.host_driver.transmit)(&packet[NET_HEADER_SIZE..len]);
+            }
+            self.push_used(guest_memory, TRANSMITQ, id, 0);
+        }
+    }
+}
+
+impl Driver for NetDriver {
+    const DEVICE_ID: u32 = 1;
+    const FEATURES: u64 = VIRTIO_NET_F_MAC;
+    const QUEUE_NUM_MAX: u32 = 256;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        // Receive buffers are only consumed once the host has a frame for them.
+        if queue == TRANSMITQ {
+            device.transmit(guest_memory);
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=5 => device.host_driver.mac[offset as usize],
+            _ => 0,
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
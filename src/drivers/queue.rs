@@ -0,0 +1,210 @@
+//! Device side processing of legacy split virtqueues.
+//!
+//! Everything in a virtqueue is written by the guest, so ring indices and descriptor ids are
+//! validated here before use, and a queue the guest has placed (partly) outside of guest memory
+//! simply appears empty.
+
+use arrayvec::ArrayVec;
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{self, FromBytes};
+use super::{Descriptor, GUEST_PHYSICAL, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+/// Longest descriptor chain that will be followed. Longer chains are truncated.
+pub const MAX_CHAIN_LENGTH: usize = 16;
+
+/// A chain of buffers the guest has made available on a queue.
+pub struct DescriptorChain {
+    /// Index of the head descriptor, which identifies the chain when it is returned to the guest.
+    pub id: u32,
+    pub descriptors: ArrayVec<[Descriptor; MAX_CHAIN_LENGTH]>,
+}
+
+/// The rings of a virtqueue, which `Virtqueue::table` has checked lie entirely within guest memory.
+pub struct DescriptorTable<'a> {
+    guest_memory: &'a mut MemoryRegion,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    queue_size: usize,
+}
+#[allow(unused)]
+impl<'a> DescriptorTable<'a> {
+    fn read<T: FromBytes>(&self, guest_pa: u64) -> T {
+        pmap::read_guest(self.guest_memory, GUEST_PHYSICAL, guest_pa).unwrap()
+    }
+    fn write<T: FromBytes>(&mut self, guest_pa: u64, value: T) {
+        pmap::write_guest(self.guest_memory, GUEST_PHYSICAL, guest_pa, value).unwrap()
+    }
+
+    fn desc_addr(&self, index: usize) -> u64 { self.read(self.desc + 16 * index as u64) }
+    fn desc_len(&self, index: usize) -> u32 { self.read(self.desc + 8 + 16 * index as u64) }
+    fn desc_flags(&self, index: usize) -> u16 { self.read(self.desc + 12 + 16 * index as u64) }
+    fn desc_next(&self, index: usize) -> u16 { self.read(self.desc + 14 + 16 * index as u64) }
+
+    fn avail_flags(&self) -> u16 { self.read(self.avail) }
+    fn avail_idx(&self) -> u16 { self.read(self.avail + 2) }
+    fn avail_ring(&self, index: usize) -> u16 { self.read(self.avail + 4 + 2 * index as u64) }
+
+    fn used_flags(&self) -> u16 { self.read(self.used) }
+    fn used_idx(&self) -> u16 { self.read(self.used + 2) }
+    fn used_ring_id(&self, index: usize) -> u32 { self.read(self.used + 4 + 8 * index as u64) }
+    fn used_ring_len(&self, index: usize) -> u32 { self.read(self.used + 8 + 8 * index as u64) }
+
+    fn set_used_flags(&mut self, value: u16) { self.write(self.used, value) }
+    fn set_used_idx(&mut self, value: u16) { self.write(self.used + 2, value) }
+    fn set_used_ring_id(&mut self, index: usize, value: u32) { self.write(self.used + 4 + 8 * index as u64, value) }
+    fn set_used_ring_len(&mut self, index: usize, value: u32) { self.write(self.used + 8 + 8 * index as u64, value) }
+}
+
+/// Guest programmed layout of a single virtqueue.
+#[derive(Copy, Clone, Default)]
+pub struct Virtqueue {
+    pub num: u32,
+    pub align: u32,
+    pub pfn: u32,
+}
+
+impl Virtqueue {
+    /// Returns the head index and buffers of the next descriptor chain the guest has made available,
+    /// or None if there isn't one. Buffers are processed in order, so the chain is only consumed
+    /// once it is passed to `push_used`. Chains longer than `MAX_CHAIN_LENGTH` are truncated.
+    ///
+    /// A chain that loops back on itself (detected by it being longer than the queue) or that
+    /// references a descriptor outside the table is invalid. It is returned with no buffers, so
+    /// that devices hand it straight back to the guest without touching memory.
+    ///
+    /// Descriptors hold guest physical addresses and guest memory is contiguous, so buffers that
+    /// span multiple pages need no further translation. They are however guest controlled, so any
+    /// access to them must be bounds checked against `guest_memory`.
+    pub fn pop_chain(&mut self, guest_memory: &mut MemoryRegion) -> Option<DescriptorChain> {
+        let dt = self.table(guest_memory)?;
+
+        // The ring indices are free running 16 bit counters, so compare them with wrapping
+        // arithmetic. A guest claiming more outstanding entries than the queue holds is broken.
+        let outstanding = dt.avail_idx().wrapping_sub(dt.used_idx()) as usize;
+        if outstanding == 0 || outstanding > dt.queue_size {
+            return None;
+        }
+
+        let id = dt.avail_ring(dt.used_idx() as usize % dt.queue_size) as usize;
+
+        let mut descriptors = ArrayVec::new();
+        let mut flags = VIRTQ_DESC_F_NEXT;
+        let mut next_id = id;
+        let mut length = 0;
+        while flags & VIRTQ_DESC_F_NEXT != 0 {
+            if next_id >= dt.queue_size || length == dt.queue_size {
+                descriptors.clear();
+                break;
+            }
+            length += 1;
+
+            flags = dt.desc_flags(next_id);
+            let descriptor = Descriptor {
+                addr: dt.desc_addr(next_id),
+                len: dt.desc_len(next_id),
+                writable: flags & VIRTQ_DESC_F_WRITE != 0,
+            };
+            // Descriptors past the limit are dropped, but the rest of the chain is still followed
+            // so that loops are caught.
+            let _ = descriptors.try_push(descriptor);
+            next_id = dt.desc_next(next_id) as usize;
+        }
+
+        Some(DescriptorChain { id: id as u32, descriptors })
+    }
+
+    /// Return the descriptor chain starting at `id` to the guest, recording that `len` bytes were
+    /// written into it. Returns false if the queue is no longer valid.
+    pub fn push_used(&mut self, guest_memory: &mut MemoryRegion, id: u32, len: u32) -> bool {
+        let mut dt = match self.table(guest_memory) {
+            Some(dt) => dt,
+            None => return false,
+        };
+        let idx = dt.used_idx() as usize % dt.queue_size;
+        dt.set_used_ring_id(idx, id);
+        dt.set_used_ring_len(idx, len);
+        dt.set_used_idx(dt.used_idx().wrapping_add(1));
+        true
+    }
+
+    /// Guest physical address of the queue's descriptor table along with the offset and size of its
+    /// used ring, or None if the guest hasn't set the queue up. Queue sizes must be powers of two so
+    /// that ring positions stay consistent when the 16 bit indices wrap.
+    fn layout(&self) -> Option<(u64, usize, usize)> {
+        let queue_size = self.num as usize;
+        if self.pfn == 0 || queue_size == 0 || !queue_size.is_power_of_two() || queue_size > 1 << 15 {
+            return None;
+        }
+        let align = (self.align as usize).max(1);
+
+        let desc_size = 16 * queue_size;
+        let avail_size = 6 + 2 * queue_size;
+        let used_size = 6 + 8 * queue_size;
+
+        let used_start = (desc_size + avail_size + (align - 1)) / align * align;
+        Some((self.pfn as u64 * 4096, used_start, used_size))
+    }
+
+    /// Whether any of the queue's rings lie within `[start, start+len)` of guest physical memory.
+    pub fn overlaps(&self, start: u64, len: u64) -> bool {
+        match self.layout() {
+            Some((desc, used_start, used_size)) => {
+                let end = desc + (used_start + used_size) as u64;
+                desc < start.saturating_add(len) && start < end
+            }
+            None => false,
+        }
+    }
+
+    /// Locate the queue's rings in guest memory, or return None if the guest hasn't set the queue
+    /// up or placed it somewhere invalid.
+    fn table<'a>(&self, guest_memory: &'a mut MemoryRegion) -> Option<DescriptorTable<'a>> {
+        let (desc, used_start, used_size) = self.layout()?;
+        let queue_size = self.num as usize;
+        let desc_size = 16 * queue_size;
+
+        let end = desc + (used_start + used_size) as u64;
+        if !guest_memory.in_region(desc) || !guest_memory.in_region(end - 1) {
+            return None;
+        }
+
+        Some(DescriptorTable {
+            guest_memory,
+            desc,
+            avail: desc + desc_size as u64,
+            used: desc + used_start as u64,
+            queue_size
+        })
+    }
+}
+
+/// Check that descriptor chains which loop back on themselves or leave the descriptor table are
+/// returned with no buffers, using the start of `guest_memory` as scratch space. Panics on any
+/// mismatch.
+pub fn selftest(guest_memory: &mut MemoryRegion) {
+    // A one entry queue: descriptor table at the start of the page, the avail ring right after
+    // it, and the used ring on the next page.
+    let base = guest_memory.base();
+    let queue = Virtqueue { num: 1, align: 4096, pfn: (base / 4096) as u32 };
+    let write_u16 = |guest_memory: &mut MemoryRegion, guest_pa: u64, value: u16| {
+        pmap::write_guest(guest_memory, GUEST_PHYSICAL, guest_pa, value).unwrap()
+    };
+    guest_memory.zero_range(base, 0x2000).unwrap();
+    write_u16(guest_memory, base + 16 + 2, 1);
+
+    // A descriptor without VIRTQ_DESC_F_NEXT is a valid chain of one buffer.
+    let chain = queue.pop_chain(guest_memory).unwrap();
+    assert_eq!((chain.id, chain.descriptors.len()), (0, 1));
+
+    // next pointing back at the same descriptor, and next past the end of the table.
+    write_u16(guest_memory, base + 12, VIRTQ_DESC_F_NEXT);
+    for &next in &[0, 5] {
+        write_u16(guest_memory, base + 14, next);
+        let chain = queue.pop_chain(guest_memory).unwrap();
+        assert_eq!(chain.id, 0);
+        assert!(chain.descriptors.is_empty());
+    }
+
+    guest_memory.zero_range(base, 0x2000).unwrap();
+}
@@ -0,0 +1,147 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2700004
+
+use crate::memory_region::MemoryRegion;
+use super::*;
+
+const REQUESTQ: u32 = 0;
+
+/// Values of the OPST field in bits 31:30 of the `seed` CSR.
+const SEED_OPST_MASK: u64 = 0x3 << 30;
+const SEED_OPST_ES16: u64 = 0x2 << 30;
+const SEED_OPST_DEAD: u64 = 0x3 << 30;
+
+/// Where guest entropy comes from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntropySource {
+    /// The Zkr `seed` CSR, which provides entropy suitable for seeding a cryptographic generator.
+    SeedCsr,
+    /// An xorshift generator seeded from the cycle counter. This is entirely predictable and must
+    /// never be relied on for anything security sensitive.
+    Insecure { state: u64 },
+}
+
+/// Emulated virtio entropy device.
+///
+/// virtio-rng has no way to tell the guest how good its entropy is, so when the insecure fallback
+/// is in use a warning is printed on the guest's console instead, and `is_cryptographic` lets the
+/// host check.
+pub struct RngDriver {
+    source: EntropySource,
+}
+
+impl RngDriver {
+    /// Whether an entropy source was enabled at build time.
+    pub fn is_available() -> bool {
+        cfg!(feature = "seed_csr") || cfg!(feature = "insecure_rng")
+    }
+
+    /// Returns None if no entropy source was enabled at build time.
+    pub fn new() -> Option<Self> {
+        let source = if cfg!(feature = "seed_csr") {
+            EntropySource::SeedCsr
+        } else if cfg!(feature = "insecure_rng") {
+            println!("WARNING: virtio-rng is using a non-cryptographic entropy source");
+            EntropySource::Insecure { state: csrr!(cycle) | 1 }
+        } else {
+            return None;
+        };
+        Some(Self { source })
+    }
+
+    pub fn is_cryptographic(&self) -> bool {
+        self.source == EntropySource::SeedCsr
+    }
+
+    /// Fill `buffer` with entropy, returning how many bytes were written. May write fewer bytes than
+    /// requested if the entropy source isn't ready.
+    fn fill(&mut self, buffer: &mut [u8]) -> usize {
+        match self.source {
+            EntropySource::SeedCsr => {
+                let mut len = 0;
+                while len < buffer.len() {
+                    match read_seed() {
+                        Some(bits) => {
+                            let n = (buffer.len() - len).min(2);
+                            buffer[len..][..n].copy_from_slice(&bits.to_le_bytes()[..n]);
+                            len += n;
+                        }
+                        None => break,
+                    }
+                }
+                len
+            }
+            EntropySource::Insecure { ref mut state } => {
+                for chunk in buffer.chunks_mut(8) {
+                    *state ^= *state << 13;
+                    *state ^= *state >> 7;
+                    *state ^= *state << 17;
+                    chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+                }
+                buffer.len()
+            }
+        }
+    }
+}
+
+/// Read 16 bits of entropy from the `seed` CSR, retrying a bounded number of times while the
+/// source is still gathering entropy. Returns None if it isn't ready or has failed.
+fn read_seed() -> Option<u16> {
+    for _ in 0..1000 {
+        let value: u64;
+        // The seed CSR must be accessed with a read-write instruction.
+        unsafe { asm!("csrrw $0, $1, x0" : "=r"(value) : "i"(crate::riscv::csr::seed) :: "volatile") };
+        match value & SEED_OPST_MASK {
+            SEED_OPST_ES16 => return Some(value as u16),
+            SEED_OPST_DEAD => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+impl GuestDevice<RngDriver> {
+    fn process_requests(&mut self, guest_memory: &mut MemoryRegion) {
+        while let Some((id, descriptors)) = self.next_descriptor_chain(guest_memory, REQUESTQ) {
+            let mut written = 0;
+            for descriptor in descriptors.iter().filter(|d| d.writable) {
+                let mut entropy = [0u8; 64];
+                let mut addr = descriptor.addr;
+                let end = descriptor.addr.saturating_add(descriptor.len as u64);
+                while addr < end {
+                    let len = (end - addr).min(entropy.len() as u64) as usize;
+                    let filled = self.host_driver.fill(&mut entropy[..len]);
+                    if filled == 0 || guest_memory.copy_from_slice(addr, &entropy[..filled]).is_err() {
+                        break;
+                    }
+                    addr += filled as u64;
+                    written += filled;
+                }
+            }
+            self.push_used(guest_memory, REQUESTQ, id, written as u32);
+        }
+    }
+}
+
+impl Driver for RngDriver {
+    const DEVICE_ID: u32 = 4;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 256;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue == REQUESTQ {
+            device.process_requests(guest_memory);
+        }
+    }
+
+    fn read_config_u8(_device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64) -> u8 {
+        0
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
@@ -50,6 +50,38 @@ pub struct Elf64 {
 	shstrndx: u16,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct Elf32 {
+    ident: Ident,
+    type_: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ProgramHeader32 {
+    type_: u32,
+    offset: u32,
+    va: u32,
+    pa: u32,
+    file_size: u32,
+    memory_size: u32,
+    flags: u32,
+    align: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct ProgramHeader64 {
@@ -63,8 +95,21 @@ pub struct ProgramHeader64 {
     align: u64,
 }
 
+use crate::memory_region::MemoryRegion;
+
+/// Whether `data` holds a 32-bit ELF image, which must be run as an RV32 guest.
+pub unsafe fn is_elf32(data: *const u8) -> bool {
+    let ident = &*(data as *const Ident);
+    assert_eq!(ident.magic, 0x464C457F);
+    ident.class == 1
+}
+
 // Returns (program entry point, max_address)
-pub unsafe fn load_elf(data: *const u8, base_address: *mut u8) -> (u64, u64) {
+pub unsafe fn load_elf(data: *const u8, guest_memory: &mut MemoryRegion) -> (u64, u64) {
+    if is_elf32(data) {
+        return load_elf32(data, guest_memory);
+    }
+
     let elf = &*(data as *const Elf64);
     assert_eq!(elf.ident.magic, 0x464C457F);
     assert_eq!(elf.ident.class, 2); // 64-bit
@@ -78,16 +123,7 @@ pub unsafe fn load_elf(data: *const u8, base_address: *mut u8) -> (u64, u64) {
         let ph = &*(data.add(elf.phoff as usize + i * elf.phentsize as usize) as *const ProgramHeader64);
 
         if ph.type_ == ELF_PROG_LOAD {
-            if ph.file_size > 0 {
-                let dst = base_address.add(ph.pa as usize);
-                let src = data.add(ph.offset as usize);
-                core::ptr::copy(src, dst, ph.file_size as usize);
-            }
-            if ph.memory_size > ph.file_size {
-                let dst = base_address.add((ph.pa + ph.file_size) as usize);
-                core::ptr::write_bytes(dst, 0, (ph.memory_size - ph.file_size) as usize);
-            }
-
+            load_segment(data, guest_memory, ph.offset, ph.pa, ph.file_size, ph.memory_size);
             if max_addr < ph.pa + ph.memory_size {
                 max_addr = ph.pa + ph.memory_size;
             }
@@ -95,5 +131,43 @@ pub unsafe fn load_elf(data: *const u8, base_address: *mut u8) -> (u64, u64) {
     }
 
     //    base_address.add(elf.entry as usize)
-    (0x80000000, 0x80000000 + max_addr)
+    (guest_memory.base(), guest_memory.base() + max_addr)
+}
+
+unsafe fn load_elf32(data: *const u8, guest_memory: &mut MemoryRegion) -> (u64, u64) {
+    let elf = &*(data as *const Elf32);
+    assert_eq!(elf.ident.class, 1); // 32-bit
+    assert_eq!(elf.ident.data, 1); // Little endian
+    assert_eq!(elf.machine, 243); // Machine = RISCV
+    assert_eq!(elf.type_, 2); // Executable
+    assert_eq!(elf.version, 1);
+
+    let mut max_addr = 0;
+    for i in 0..(elf.phnum as usize) {
+        let ph = &*(data.add(elf.phoff as usize + i * elf.phentsize as usize) as *const ProgramHeader32);
+
+        if ph.type_ == ELF_PROG_LOAD {
+            let (pa, memory_size) = (ph.pa as u64, ph.memory_size as u64);
+            load_segment(data, guest_memory, ph.offset as u64, pa, ph.file_size as u64, memory_size);
+            if max_addr < pa + memory_size {
+                max_addr = pa + memory_size;
+            }
+        }
+    }
+
+    (guest_memory.base(), guest_memory.base() + max_addr)
+}
+
+unsafe fn load_segment(data: *const u8, guest_memory: &mut MemoryRegion, offset: u64, pa: u64, file_size: u64,
+                       memory_size: u64) {
+    let base_address = guest_memory.base();
+    if file_size > 0 {
+        let src = core::slice::from_raw_parts(data.add(offset as usize), file_size as usize);
+        guest_memory.copy_from_slice(base_address + pa, src)
+            .expect("Guest kernel segment doesn't fit in guest memory");
+    }
+    if memory_size > file_size {
+        guest_memory.zero_range(base_address + pa + file_size, memory_size - file_size)
+            .expect("Guest kernel segment doesn't fit in guest memory");
+    }
 }
@@ -1,5 +1,6 @@
 use arrayvec::{ArrayString, ArrayVec};
 use byteorder::{BigEndian, ByteOrder};
+use core::fmt::Write;
 use core::slice;
 
 const FDT_BEGIN_NODE: u32 = 0x01;
@@ -42,17 +43,53 @@ pub struct Device {
 pub struct Hart {
     pub hartid: u64,
     pub plic_context: u64,
+    /// ISA string, e.g. "rv64imafdcsu".
+    pub isa: ArrayString<[u8; 32]>,
+    /// Supported address translation mode, e.g. "riscv,sv39". Empty if not specified.
+    pub mmu_type: ArrayString<[u8; 16]>,
 }
 
+impl Hart {
+    /// Describe this hart as RV32 with the same extensions, for guests running with XLEN=32.
+    pub fn set_rv32(&mut self) {
+        let extensions = self.isa.get(4..).unwrap_or("");
+        let mut isa = ArrayString::new();
+        isa.push_str("rv32");
+        isa.push_str(if extensions.is_empty() { "imafdcsu" } else { extensions });
+        self.isa = isa;
+        self.mmu_type = ArrayString::from("riscv,sv32").unwrap();
+    }
+}
+
+/// Properties collected from a `/cpus/cpu` node while walking the tree.
+#[derive(Copy, Clone, Default)]
+struct CpuNode {
+    hartid: Option<u64>,
+    phandle: Option<u64>,
+    isa: ArrayString<[u8; 32]>,
+    mmu_type: ArrayString<[u8; 16]>,
+    disabled: bool,
+}
+
+/// Maximum number of separate ranges of physical memory that are recorded.
+pub const MAX_MEMORY_BANKS: usize = 4;
+
 #[derive(Clone, Debug, Default)]
 pub struct MachineMeta {
+    /// Location of the first bank of physical memory.
     pub physical_memory_offset: u64,
     pub physical_memory_size: u64,
+    /// Every bank of physical memory as (base, size) pairs, in the order they were found. Banks past
+    /// `MAX_MEMORY_BANKS` are ignored.
+    pub memory_banks: ArrayVec<[(u64, u64); MAX_MEMORY_BANKS]>,
 
     pub harts: ArrayVec<[Hart; 16]>,
+    pub timebase_frequency: u64,
 
     pub uart_type: Option<UartType>,
     pub uart_address: u64,
+    /// Host interrupt raised when the UART receives data.
+    pub uart_irq: Option<u64>,
 
     pub plic_address: u64,
     pub clint_address: Option<u64>,
@@ -65,6 +102,11 @@ pub struct MachineMeta {
 
     pub initrd_start: u64,
     pub initrd_end: u64,
+
+    /// Image given to guests as an emulated block device, from the `rvirt,disk-start` and
+    /// `rvirt,disk-end` properties of `/chosen`. Empty if there is none.
+    pub disk_start: u64,
+    pub disk_end: u64,
 }
 
 #[repr(C)]
@@ -177,6 +219,8 @@ impl<'a> Fdt<'a> {
     pub fn parse(&mut self) -> MachineMeta {
         let mut initrd_start: Option<u64> = None;
         let mut initrd_end: Option<u64> = None;
+        let mut disk_start: Option<u64> = None;
+        let mut disk_end: Option<u64> = None;
         let mut plic: Option<u64> = None;
 
         let mut meta = MachineMeta::default();
@@ -184,8 +228,7 @@ impl<'a> Fdt<'a> {
         let mut virtio_address_map = AddressMap::default();
         let mut virtio = [(None, None); AddressMap::MAX_LEN];
 
-        // (hartid, phandle)
-        let mut cpus = [(None, None); AddressMap::MAX_LEN];
+        let mut cpus = [CpuNode::default(); AddressMap::MAX_LEN];
         let mut cpu_address_map = AddressMap::default();
 
         // hart phandle for each plic S-mode context
@@ -196,20 +239,31 @@ impl<'a> Fdt<'a> {
                 FdtVisit::Property { name, prop } => match (path, name) {
                     ("/chosen", "linux,initrd-end") => initrd_end = Some(prop.read_int()),
                     ("/chosen", "linux,initrd-start") => initrd_start = Some(prop.read_int()),
+                    ("/chosen", "rvirt,disk-end") => disk_end = Some(prop.read_int()),
+                    ("/chosen", "rvirt,disk-start") => disk_start = Some(prop.read_int()),
                     ("/chosen", "bootargs") => {
                         meta.bootargs.push_str(prop.value_str()
                                                .expect("Unable to parse bootargs string"))
                     }
                     ("/memory", "reg") => {
-                        let region = prop.read_range();
-                        meta.physical_memory_offset = region.0;
-                        meta.physical_memory_size = region.1;
+                        for region in prop.read_ranges() {
+                            if meta.memory_banks.is_empty() {
+                                meta.physical_memory_offset = region.0;
+                                meta.physical_memory_size = region.1;
+                            }
+                            let _ = meta.memory_banks.try_push(region);
+                        }
                     }
                     ("/uart", "reg") |
                     ("/soc/uart", "reg") |
                     ("/soc/serial", "reg") => if meta.uart_address == 0 {
                         meta.uart_address = prop.read_range().0
                     }
+                    ("/uart", "interrupts") |
+                    ("/soc/uart", "interrupts") |
+                    ("/soc/serial", "interrupts") => if meta.uart_irq.is_none() {
+                        meta.uart_irq = Some(prop.read_int())
+                    }
                     ("/uart", "compatible") |
                     ("/soc/uart", "compatible") |
                     ("/soc/serial", "compatible") => if meta.uart_type.is_none() {
@@ -239,13 +293,31 @@ impl<'a> Fdt<'a> {
                         let index = virtio_address_map.index_of(unit_addresses[1].unwrap_or(0));
                         virtio[index].1 = Some(prop.read_int());
                     }
+                    ("/cpus", "timebase-frequency") => meta.timebase_frequency = prop.read_int(),
                     ("/cpus/cpu", "reg") => {
-                        let index = virtio_address_map.index_of(unit_addresses[2].unwrap_or(0));
-                        cpus[index].0 = Some(prop.read_int());
+                        let index = cpu_address_map.index_of(unit_addresses[2].unwrap_or(0));
+                        cpus[index].hartid = Some(prop.read_int());
+                    }
+                    ("/cpus/cpu", "riscv,isa") => {
+                        let index = cpu_address_map.index_of(unit_addresses[2].unwrap_or(0));
+                        if let Some(isa) = prop.value_str().map(|s| s.trim_end_matches('\0')) {
+                            let _ = cpus[index].isa.try_push_str(isa);
+                        }
+                    }
+                    ("/cpus/cpu", "mmu-type") => {
+                        let index = cpu_address_map.index_of(unit_addresses[2].unwrap_or(0));
+                        if let Some(mmu_type) = prop.value_str().map(|s| s.trim_end_matches('\0')) {
+                            let _ = cpus[index].mmu_type.try_push_str(mmu_type);
+                        }
+                    }
+                    ("/cpus/cpu", "status") => {
+                        let index = cpu_address_map.index_of(unit_addresses[2].unwrap_or(0));
+                        let status = prop.value_str().map(|s| s.trim_end_matches('\0'));
+                        cpus[index].disabled = status == Some("disabled");
                     }
                     ("/cpus/cpu/interrupt-controller", "phandle") => {
-                        let index = virtio_address_map.index_of(unit_addresses[2].unwrap_or(0));
-                        cpus[index].1 = Some(prop.read_int());
+                        let index = cpu_address_map.index_of(unit_addresses[2].unwrap_or(0));
+                        cpus[index].phandle = Some(prop.read_int());
                     }
                     _ => {},
                 }
@@ -257,15 +329,22 @@ impl<'a> Fdt<'a> {
             meta.initrd_start = start;
             meta.initrd_end = end;
         }
+        if let (Some(start), Some(end)) = (disk_start, disk_end) {
+            assert!(start <= end, "Disk image ends before it starts ({:#x}..{:#x})", start, end);
+            meta.disk_start = start;
+            meta.disk_end = end;
+        }
 
         meta.plic_address = plic.expect("PLIC address not specified");
 
-        for &c in cpus.iter() {
-            if let (Some(hartid), Some(phandle)) = c {
+        for c in cpus.iter().filter(|c| !c.disabled) {
+            if let (Some(hartid), Some(phandle)) = (c.hartid, c.phandle) {
                 if let Some(plic_context) = plic_context_phandles.iter().position(|&p| p == Some(phandle as u32)) {
                     meta.harts.push(Hart {
                         hartid,
                         plic_context: plic_context as u64,
+                        isa: c.isa,
+                        mmu_type: c.mmu_type,
                     })
                 }
             }
@@ -437,6 +516,13 @@ impl<'a> Property<'a> {
 
         (BigEndian::read_u64(&self.0[12..20]), BigEndian::read_u64(&self.0[20..28]))
     }
+    /// Read a property holding any number of (address, size) pairs, each with two cells.
+    pub fn read_ranges<'b>(&'b self) -> impl Iterator<Item = (u64, u64)> + 'b {
+        assert_eq!(self.len() % 16, 0);
+
+        self.0[12..][..self.len()].chunks(16)
+            .map(|r| (BigEndian::read_u64(&r[0..8]), BigEndian::read_u64(&r[8..16])))
+    }
     pub fn mask(&mut self) {
         for i in (0..self.0.len()).step_by(4) {
             BigEndian::write_u32(&mut self.0[i..], FDT_NOP);
@@ -478,6 +564,298 @@ enum FdtVisit<'a> {
     }
 }
 
+/// Writer for a flattened device tree. The structure block is written directly after the header
+/// and the memory reservation map, while property names are collected separately and appended once
+/// the structure block is complete.
+struct FdtBuilder<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+    off_dt_struct: usize,
+    strings: ArrayVec<[u8; 1024]>,
+}
+
+impl<'a> FdtBuilder<'a> {
+    const HEADER_SIZE: usize = 40;
+    const MEM_RSVMAP_ENTRY_SIZE: usize = 16;
+
+    /// Start a tree whose memory reservation map holds the given (address, size) ranges.
+    fn new(buffer: &'a mut [u8], reservations: &[(u64, u64)]) -> Self {
+        // The map is terminated by an all zero entry.
+        let off_dt_struct = Self::HEADER_SIZE + Self::MEM_RSVMAP_ENTRY_SIZE * (reservations.len() + 1);
+        assert!(buffer.len() >= off_dt_struct);
+        for b in &mut buffer[..off_dt_struct] {
+            *b = 0;
+        }
+        for (i, &(address, size)) in reservations.iter().enumerate() {
+            let entry = Self::HEADER_SIZE + Self::MEM_RSVMAP_ENTRY_SIZE * i;
+            BigEndian::write_u64(&mut buffer[entry..], address);
+            BigEndian::write_u64(&mut buffer[entry + 8..], size);
+        }
+        Self { buffer, offset: off_dt_struct, off_dt_struct, strings: ArrayVec::new() }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        BigEndian::write_u32(&mut self.buffer[self.offset..], value);
+        self.offset += 4;
+    }
+
+    /// Write each of `parts` in sequence and then pad to a multiple of 4 bytes.
+    fn write_bytes(&mut self, parts: &[&[u8]]) {
+        for part in parts {
+            self.buffer[self.offset..][..part.len()].copy_from_slice(part);
+            self.offset += part.len();
+        }
+        while self.offset % 4 != 0 {
+            self.buffer[self.offset] = 0;
+            self.offset += 1;
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        while offset < self.strings.len() {
+            if Fdt::get_string(&self.strings, offset) == name {
+                return offset as u32;
+            }
+            offset += Fdt::get_string(&self.strings, offset).len() + 1;
+        }
+
+        for &b in name.as_bytes().iter().chain(&[0]) {
+            self.strings.try_push(b).expect("FDT strings block full");
+        }
+        offset as u32
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.write_u32(FDT_BEGIN_NODE);
+        self.write_bytes(&[name.as_bytes(), &b"\0"[..]]);
+    }
+
+    fn end_node(&mut self) {
+        self.write_u32(FDT_END_NODE);
+    }
+
+    fn property_parts(&mut self, name: &str, parts: &[&[u8]]) {
+        let name_offset = self.string_offset(name);
+        self.write_u32(FDT_PROP);
+        self.write_u32(parts.iter().map(|p| p.len()).sum::<usize>() as u32);
+        self.write_u32(name_offset);
+        self.write_bytes(parts);
+    }
+
+    fn property_empty(&mut self, name: &str) {
+        self.property_parts(name, &[]);
+    }
+
+    fn property_str(&mut self, name: &str, value: &str) {
+        self.property_parts(name, &[value.as_bytes(), &b"\0"[..]]);
+    }
+
+    fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let mut bytes = ArrayVec::<[u8; 256]>::new();
+        for &cell in cells {
+            for &b in &cell.to_be_bytes() {
+                bytes.try_push(b).expect("FDT property too long");
+            }
+        }
+        self.property_parts(name, &[&bytes[..]]);
+    }
+
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    fn property_u64(&mut self, name: &str, value: u64) {
+        self.property_parts(name, &[&value.to_be_bytes()[..]]);
+    }
+
+    /// A `reg` property for a node whose parent has two address cells and two size cells.
+    fn property_reg(&mut self, base: u64, size: u64) {
+        self.property_parts("reg", &[&base.to_be_bytes()[..], &size.to_be_bytes()[..]]);
+    }
+
+    /// A `reg` property listing several (base, size) ranges, with two address and two size cells.
+    fn property_regs(&mut self, ranges: &[(u64, u64)]) {
+        let mut bytes = ArrayVec::<[u8; 16 * MAX_MEMORY_BANKS]>::new();
+        for &(base, size) in ranges {
+            for &b in base.to_be_bytes().iter().chain(&size.to_be_bytes()) {
+                bytes.try_push(b).expect("FDT property too long");
+            }
+        }
+        self.property_parts("reg", &[&bytes[..]]);
+    }
+
+    /// Complete the tree and fill in the header. Returns the total size of the tree.
+    fn finish(mut self) -> usize {
+        self.write_u32(FDT_END);
+
+        let off_dt_struct = self.off_dt_struct;
+        let off_dt_strings = self.offset;
+        let total_size = off_dt_strings + self.strings.len();
+        self.buffer[off_dt_strings..total_size].copy_from_slice(&self.strings);
+
+        let header = [
+            0xd00dfeed,                              // magic
+            total_size as u32,                       // totalsize
+            off_dt_struct as u32,                    // off_dt_struct
+            off_dt_strings as u32,                   // off_dt_strings
+            Self::HEADER_SIZE as u32,                // off_mem_rsvmap
+            17,                                      // version
+            16,                                      // last_comp_version
+            0,                                       // boot_cpuid_phys
+            self.strings.len() as u32,               // size_dt_strings
+            (off_dt_strings - off_dt_struct) as u32, // size_dt_struct
+        ];
+        for (i, &value) in header.iter().enumerate() {
+            BigEndian::write_u32(&mut self.buffer[4 * i..], value);
+        }
+        total_size
+    }
+}
+
+/// External interrupt number of the guest's UART.
+const GUEST_UART_IRQ: u32 = 10;
+/// Timebase frequency to report if the host device tree doesn't specify one.
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Write a device tree describing `meta` into `buffer`, returning its size. Every address in `meta`
+/// must be a guest physical address. The (address, size) ranges in `reservations` are listed in the
+/// memory reservation map, so the guest won't allocate from them. Panics if `buffer` is too small.
+pub fn build_guest_fdt(meta: &MachineMeta, reservations: &[(u64, u64)], buffer: &mut [u8]) -> usize {
+    let mut fdt = FdtBuilder::new(buffer, reservations);
+    let mut name = ArrayString::<[u8; 48]>::new();
+
+    // Phandles: each hart's interrupt controller is numbered from 1, followed by the PLIC.
+    let intc_phandle = |i: usize| i as u32 + 1;
+    let plic_phandle = meta.harts.len() as u32 + 1;
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "riscv-virtio");
+    fdt.property_str("model", "riscv-virtio,qemu");
+
+    fdt.begin_node("chosen");
+    if !meta.bootargs.is_empty() {
+        fdt.property_str("bootargs", meta.bootargs.as_str());
+    }
+    name.clear();
+    write!(name, "/uart@{:x}", meta.uart_address).unwrap();
+    fdt.property_str("stdout-path", name.as_str());
+    if meta.initrd_end > meta.initrd_start {
+        fdt.property_u64("linux,initrd-start", meta.initrd_start);
+        fdt.property_u64("linux,initrd-end", meta.initrd_end);
+    }
+    fdt.end_node();
+
+    name.clear();
+    write!(name, "uart@{:x}", meta.uart_address).unwrap();
+    fdt.begin_node(name.as_str());
+    fdt.property_u32("interrupts", GUEST_UART_IRQ);
+    fdt.property_u32("interrupt-parent", plic_phandle);
+    fdt.property_u32("clock-frequency", 0x384000);
+    fdt.property_reg(meta.uart_address, 0x100);
+    fdt.property_str("compatible", match meta.uart_type {
+        Some(UartType::SiFive) => "sifive,uart0",
+        _ => "ns16550a",
+    });
+    fdt.end_node();
+
+    for device in &meta.virtio {
+        name.clear();
+        write!(name, "virtio_mmio@{:x}", device.base_address).unwrap();
+        fdt.begin_node(name.as_str());
+        fdt.property_u32("interrupts", device.irq as u32);
+        fdt.property_u32("interrupt-parent", plic_phandle);
+        fdt.property_reg(device.base_address, device.size);
+        fdt.property_str("compatible", "virtio,mmio");
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", match meta.timebase_frequency {
+        0 => DEFAULT_TIMEBASE_FREQUENCY,
+        f => f,
+    } as u32);
+    for (i, hart) in meta.harts.iter().enumerate() {
+        name.clear();
+        write!(name, "cpu@{:x}", hart.hartid).unwrap();
+        fdt.begin_node(name.as_str());
+        fdt.property_str("device_type", "cpu");
+        fdt.property_u32("reg", hart.hartid as u32);
+        fdt.property_str("status", "okay");
+        fdt.property_str("compatible", "riscv");
+        fdt.property_str("riscv,isa", if hart.isa.is_empty() { "rv64imafdcsu" } else { hart.isa.as_str() });
+        fdt.property_str("mmu-type", if hart.mmu_type.is_empty() { "riscv,sv39" } else { hart.mmu_type.as_str() });
+
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_str("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc_phandle(i));
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    name.clear();
+    write!(name, "memory@{:x}", meta.physical_memory_offset).unwrap();
+    fdt.begin_node(name.as_str());
+    fdt.property_str("device_type", "memory");
+    if meta.memory_banks.is_empty() {
+        fdt.property_reg(meta.physical_memory_offset, meta.physical_memory_size);
+    } else {
+        fdt.property_regs(&meta.memory_banks);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    // Each hart has an M-mode context followed by an S-mode context.
+    let mut cells = ArrayVec::<[u32; 64]>::new();
+    for i in 0..meta.harts.len() {
+        cells.extend([intc_phandle(i), 11, intc_phandle(i), 9].iter().cloned());
+    }
+    name.clear();
+    write!(name, "interrupt-controller@{:x}", meta.plic_address).unwrap();
+    fdt.begin_node(name.as_str());
+    fdt.property_u32("phandle", plic_phandle);
+    fdt.property_u32("riscv,ndev", 0x35);
+    fdt.property_u32("riscv,max-priority", 7);
+    fdt.property_reg(meta.plic_address, 0x4000000);
+    fdt.property_cells("interrupts-extended", &cells);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_str("compatible", "riscv,plic0");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_u32("#address-cells", 0);
+    fdt.end_node();
+
+    if let Some(clint_address) = meta.clint_address {
+        cells.clear();
+        for i in 0..meta.harts.len() {
+            cells.extend([intc_phandle(i), 3, intc_phandle(i), 7].iter().cloned());
+        }
+        name.clear();
+        write!(name, "clint@{:x}", clint_address).unwrap();
+        fdt.begin_node(name.as_str());
+        fdt.property_cells("interrupts-extended", &cells);
+        fdt.property_reg(clint_address, 0x10000);
+        fdt.property_str("compatible", "riscv,clint0");
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
 /// Round up to the next multiple of 4
 const fn round4(i: usize) -> usize {
     4 * ((i + 3) / 4)
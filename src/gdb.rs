@@ -0,0 +1,605 @@
+//! Minimal GDB remote serial protocol stub, multiplexed over the host UART.
+//!
+//! With the `gdb_stub` feature enabled, a complete packet with a valid checksum on the host UART stops
+//! the guest and hands the UART over to GDB until it continues or detaches. The guest still receives
+//! those bytes, since they can't be told apart from console input until the packet is complete.
+//! Once GDB has attached, a Ctrl-C also stops the guest. Registers come from the
+//! trapped guest state and memory accesses go through the guest's page tables, so addresses are
+//! guest virtual addresses. Breakpoints are implemented by patching `ebreak` into guest memory.
+//!
+//! Supported packets: `?`, `g`, `G`, `p`, `P`, `m`, `M`, `Z0`, `z0`, `c`, `s`, `D`, `k` and a handful
+//! of queries. Anything else gets an empty reply, which tells GDB the packet is unsupported. The
+//! `monitor balloon [pages]` command shows the guest's balloon or sets how many pages it should hold.
+//!
+//! There is no hardware single-step available in S-mode, so stepping (`Context::single_step`) is
+//! done by decoding the instruction at `sepc` and placing temporary breakpoints on every possible
+//! successor. A step also completes if the hypervisor itself moves `sepc`, for instance after
+//! emulating the instruction or delivering a trap to the guest.
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use crate::context::Context;
+use crate::statics::SHARED_STATICS;
+use crate::{input, pmap, riscv, virtio};
+use riscv_decode::Instruction;
+
+const MAX_PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 16;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const EBREAK: u32 = 0x00100073;
+const C_EBREAK: u32 = 0x9002;
+
+type Packet = ArrayVec<[u8; MAX_PACKET_SIZE]>;
+
+#[derive(Copy, Clone, Debug)]
+struct Breakpoint {
+    va: u64,
+    pa: u64,
+    /// Length of the patched instruction, either 2 or 4 bytes.
+    kind: u64,
+    original: u32,
+}
+
+pub struct GdbState {
+    breakpoints: ArrayVec<[Breakpoint; MAX_BREAKPOINTS]>,
+    /// Temporary breakpoints on the successors of the instruction being stepped.
+    step_breakpoints: ArrayVec<[Breakpoint; 2]>,
+    /// Address of the instruction being stepped, valid while `step_breakpoints` is non-empty.
+    step_pc: u64,
+}
+
+impl GdbState {
+    pub fn new() -> Self {
+        Self { breakpoints: ArrayVec::new(), step_breakpoints: ArrayVec::new(), step_pc: 0 }
+    }
+}
+
+/// What GDB sent to interrupt the running guest.
+pub enum Request {
+    Interrupt,
+    Packet(Packet),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ScanState {
+    Idle,
+    Body,
+    Checksum(Option<u8>),
+}
+
+/// Watches input from the host UART for GDB trying to get the hypervisor's attention.
+pub struct Listener {
+    state: ScanState,
+    packet: Packet,
+    /// Whether GDB has connected and not yet detached, in which case a bare Ctrl-C is meant for it.
+    attached: bool,
+    pub request: Option<Request>,
+}
+
+impl Listener {
+    pub fn new() -> Self {
+        Self { state: ScanState::Idle, packet: Packet::new(), attached: false, request: None }
+    }
+
+    /// Look at the next byte of input. Returns true if it was meant only for GDB, in which case it
+    /// shouldn't be passed on to the guest.
+    pub fn receive(&mut self, ch: u8) -> bool {
+        if ch == 0x03 && self.attached {
+            self.request = Some(Request::Interrupt);
+            return true;
+        }
+
+        self.state = match (self.state, ch) {
+            (_, b'$') => {
+                self.packet.clear();
+                ScanState::Body
+            }
+            (ScanState::Idle, _) => ScanState::Idle,
+            (ScanState::Body, b'#') => ScanState::Checksum(None),
+            (ScanState::Body, _) => match self.packet.try_push(ch) {
+                Ok(()) => ScanState::Body,
+                Err(_) => ScanState::Idle,
+            },
+            (ScanState::Checksum(None), _) => match parse_hex_digit(ch) {
+                Some(high) => ScanState::Checksum(Some(high)),
+                None => ScanState::Idle,
+            },
+            (ScanState::Checksum(Some(high)), _) => {
+                let checksum = self.packet.iter().fold(0u8, |sum, &ch| sum.wrapping_add(ch));
+                if parse_hex_digit(ch).map(|low| high << 4 | low) == Some(checksum) {
+                    self.request = Some(Request::Packet(core::mem::replace(&mut self.packet, Packet::new())));
+                }
+                ScanState::Idle
+            }
+        };
+        false
+    }
+}
+
+fn getchar() -> u8 {
+    loop {
+        if let Some(ch) = input::getchar() {
+            return ch;
+        }
+    }
+}
+
+fn putchar(ch: u8) {
+    SHARED_STATICS.uart_writer.lock().putchar(ch);
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[(value & 0xf) as usize]
+}
+
+fn parse_hex_digit(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0, |value, &ch| Some(value << 4 | parse_hex_digit(ch)? as u64))
+}
+
+/// Parse "addr,len" and return the two values.
+fn parse_addr_len(s: &[u8]) -> Option<(u64, u64)> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    Some((parse_hex(&s[..comma])?, parse_hex(&s[comma + 1..])?))
+}
+
+fn push_bytes(reply: &mut Packet, bytes: &[u8]) {
+    for &byte in bytes {
+        reply.push(byte);
+    }
+}
+
+fn push_hex_byte(reply: &mut Packet, byte: u8) {
+    reply.push(hex_digit(byte >> 4));
+    reply.push(hex_digit(byte));
+}
+
+/// Registers are sent in target byte order, which is little endian.
+fn push_hex_u64(reply: &mut Packet, value: u64) {
+    for &byte in &value.to_le_bytes() {
+        push_hex_byte(reply, byte);
+    }
+}
+
+fn parse_hex_u64_le(s: &[u8]) -> Option<u64> {
+    if s.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for i in 0..8 {
+        bytes[i] = parse_hex_digit(s[2 * i])? << 4 | parse_hex_digit(s[2 * i + 1])?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Read a packet, acknowledging it once the checksum has been verified.
+fn read_packet() -> Packet {
+    loop {
+        while getchar() != b'$' {}
+
+        let mut packet = Packet::new();
+        let mut checksum = 0u8;
+        loop {
+            match getchar() {
+                b'#' => break,
+                ch => {
+                    checksum = checksum.wrapping_add(ch);
+                    let _ = packet.try_push(ch);
+                }
+            }
+        }
+
+        let expected = parse_hex_digit(getchar()).unwrap_or(0) << 4 | parse_hex_digit(getchar()).unwrap_or(0);
+        if expected == checksum && !packet.is_full() {
+            putchar(b'+');
+            return packet;
+        }
+        putchar(b'-');
+    }
+}
+
+fn write_packet(data: &[u8]) {
+    loop {
+        let checksum = data.iter().fold(0u8, |sum, &ch| sum.wrapping_add(ch));
+        putchar(b'$');
+        for &ch in data {
+            putchar(ch);
+        }
+        putchar(b'#');
+        putchar(hex_digit(checksum >> 4));
+        putchar(hex_digit(checksum));
+
+        if getchar() == b'+' {
+            return;
+        }
+    }
+}
+
+fn get_register(state: &Context, reg: u64) -> Option<u64> {
+    match reg {
+        0..=31 => Some(state.saved_registers.get(reg as u32)),
+        32 => Some(csrr!(sepc)),
+        _ => None,
+    }
+}
+
+fn set_register(state: &mut Context, reg: u64, value: u64) -> bool {
+    match reg {
+        0..=31 => state.saved_registers.set(reg as u32, value),
+        32 => riscv::set_sepc(value),
+        _ => return false,
+    }
+    true
+}
+
+/// Replace the instruction at `va` with an `ebreak` of length `kind`.
+fn patch_breakpoint(state: &mut Context, va: u64, kind: u64) -> Option<Breakpoint> {
+    let instruction = match kind {
+        2 => C_EBREAK,
+        4 => EBREAK,
+        _ => return None,
+    };
+    let (pa, _) = state.translation_cache.translate(&state.guest_memory, state.csrs.satp, va)?;
+
+    let mut original = [0u8; 4];
+    state.guest_memory.copy_to_slice(pa, &mut original[..kind as usize]).ok()?;
+    state.guest_memory.copy_from_slice(pa, &instruction.to_le_bytes()[..kind as usize]).ok()?;
+    riscv::fence_i();
+
+    Some(Breakpoint { va, pa, kind, original: u32::from_le_bytes(original) })
+}
+
+fn unpatch_breakpoint(state: &mut Context, breakpoint: &Breakpoint) {
+    let original = breakpoint.original.to_le_bytes();
+    let _ = state.guest_memory.copy_from_slice(breakpoint.pa, &original[..breakpoint.kind as usize]);
+    riscv::fence_i();
+}
+
+fn insert_breakpoint(state: &mut Context, va: u64, kind: u64) -> bool {
+    if state.gdb.breakpoints.iter().any(|b| b.va == va) {
+        return true;
+    }
+    if state.gdb.breakpoints.is_full() {
+        return false;
+    }
+
+    match patch_breakpoint(state, va, kind) {
+        Some(breakpoint) => {
+            state.gdb.breakpoints.push(breakpoint);
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove_breakpoint(state: &mut Context, va: u64) -> bool {
+    let index = match state.gdb.breakpoints.iter().position(|b| b.va == va) {
+        Some(index) => index,
+        None => return false,
+    };
+
+    let breakpoint = state.gdb.breakpoints.remove(index);
+    unpatch_breakpoint(state, &breakpoint);
+    true
+}
+
+/// Fetch the instruction at guest virtual address `va` along with its length.
+fn read_instruction(state: &Context, va: u64) -> Option<(u32, u64)> {
+    let low = pmap::read_guest::<u16>(&state.guest_memory, state.csrs.satp, va)?;
+    match riscv_decode::instruction_length(low) {
+        2 => Some((low as u32, 2)),
+        4 => {
+            let high = pmap::read_guest::<u16>(&state.guest_memory, state.csrs.satp, va.wrapping_add(2))?;
+            Some((low as u32 | (high as u32) << 16, 4))
+        }
+        _ => None,
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value as u64) << shift) as i64 >> shift) as u64
+}
+
+/// Every address the instruction at `pc` could transfer control to, assuming it doesn't trap.
+fn successors(state: &mut Context, pc: u64) -> Option<ArrayVec<[u64; 2]>> {
+    let (instruction, len) = read_instruction(state, pc)?;
+    let next = pc.wrapping_add(len);
+
+    let mut targets = ArrayVec::new();
+    match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Jal(j)) => targets.push(pc.wrapping_add(sign_extend(j.imm(), 21))),
+        Some(Instruction::Jalr(i)) => {
+            let base = state.rs1_value(&i);
+            targets.push(base.wrapping_add(sign_extend(i.imm(), 12)) & !1);
+        }
+        Some(Instruction::Beq(b)) | Some(Instruction::Bne(b)) | Some(Instruction::Blt(b)) |
+        Some(Instruction::Bge(b)) | Some(Instruction::Bltu(b)) | Some(Instruction::Bgeu(b)) => {
+            targets.push(next);
+            let target = pc.wrapping_add(sign_extend(b.imm(), 13));
+            if target != next {
+                targets.push(target);
+            }
+        }
+        _ => targets.push(next),
+    }
+    Some(targets)
+}
+
+/// Place temporary breakpoints after the instruction at `sepc`. Returns false if the instruction
+/// or one of its successors couldn't be read.
+fn arm_single_step(state: &mut Context) -> bool {
+    let pc = csrr!(sepc);
+    let targets = match successors(state, pc) {
+        Some(targets) => targets,
+        None => return false,
+    };
+
+    for &va in &targets {
+        let armed = read_instruction(state, va).and_then(|(_, kind)| patch_breakpoint(state, va, kind));
+        match armed {
+            Some(breakpoint) => state.gdb.step_breakpoints.push(breakpoint),
+            None => {
+                disarm_single_step(state);
+                return false;
+            }
+        }
+    }
+    state.gdb.step_pc = pc;
+    true
+}
+
+fn disarm_single_step(state: &mut Context) {
+    // Remove in reverse order so that overlapping breakpoints restore the original instruction.
+    while let Some(breakpoint) = state.gdb.step_breakpoints.pop() {
+        unpatch_breakpoint(state, &breakpoint);
+    }
+}
+
+fn finish_single_step(state: &mut Context) {
+    disarm_single_step(state);
+    state.single_step = false;
+    session(state, Some(SIGTRAP));
+}
+
+/// Run a debugging session until GDB resumes the guest. If `signal` is set, the guest stopped on
+/// its own and GDB is told why; otherwise a packet has just started arriving.
+/// Talk to GDB until it resumes the guest. `first` is a packet that has already been received, but
+/// not yet acknowledged.
+fn session(state: &mut Context, signal: Option<u8>, mut first: Option<Packet>) {
+    state.uart.gdb.attached = true;
+    if let Some(signal) = signal {
+        // Report the stop along with the new pc (register 32) to save GDB a round trip.
+        let mut reply = Packet::new();
+        reply.push(b'T');
+        push_hex_byte(&mut reply, signal);
+        push_bytes(&mut reply, b"20:");
+        push_hex_u64(&mut reply, csrr!(sepc));
+        reply.push(b';');
+        write_packet(&reply);
+    }
+
+    loop {
+        let packet = match first.take() {
+            Some(packet) => {
+                putchar(b'+');
+                packet
+            }
+            None => read_packet(),
+        };
+
+        let mut reply = Packet::new();
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => {
+                write_packet(&reply);
+                continue;
+            }
+        };
+
+        match command {
+            b'?' => {
+                reply.push(b'S');
+                push_hex_byte(&mut reply, SIGTRAP);
+            }
+            b'g' => for reg in 0..33 {
+                push_hex_u64(&mut reply, get_register(state, reg).unwrap());
+            },
+            b'G' => {
+                let ok = args.len() >= 33 * 16 && (0..33).all(|reg| {
+                    let start = reg as usize * 16;
+                    parse_hex_u64_le(&args[start..start + 16]).map(|v| set_register(state, reg, v)).unwrap_or(false)
+                });
+                push_bytes(&mut reply, if ok { b"OK" } else { b"E01" });
+            }
+            b'p' => match parse_hex(args).and_then(|reg| get_register(state, reg)) {
+                Some(value) => push_hex_u64(&mut reply, value),
+                None => push_bytes(&mut reply, b"E01"),
+            },
+            b'P' => {
+                let ok = args.iter().position(|&c| c == b'=').and_then(|eq| {
+                    let value = parse_hex_u64_le(&args[eq + 1..])?;
+                    Some(set_register(state, parse_hex(&args[..eq])?, value))
+                });
+                push_bytes(&mut reply, if ok == Some(true) { b"OK" } else { b"E01" });
+            }
+            b'm' => {
+                let mut buf = ArrayVec::<[u8; MAX_PACKET_SIZE / 2]>::new();
+                let ok = parse_addr_len(args).filter(|&(_, len)| len as usize <= buf.capacity()).map(|(va, len)| {
+                    let satp = state.csrs.satp;
+                    (0..len).all(|i| match pmap::read_guest(&state.guest_memory, satp, va.wrapping_add(i)) {
+                        Some(byte) => {
+                            buf.push(byte);
+                            true
+                        }
+                        None => false,
+                    })
+                });
+                if ok == Some(true) {
+                    for &byte in &buf {
+                        push_hex_byte(&mut reply, byte);
+                    }
+                } else {
+                    push_bytes(&mut reply, b"E01");
+                }
+            }
+            b'M' => {
+                let mut buf = [0u8; MAX_PACKET_SIZE / 2];
+                let ok = args.iter().position(|&c| c == b':').and_then(|colon| {
+                    let (va, len) = parse_addr_len(&args[..colon])?;
+                    let data = &args[colon + 1..];
+                    if len as usize > buf.len() || data.len() != 2 * len as usize {
+                        return None;
+                    }
+                    for i in 0..len as usize {
+                        buf[i] = parse_hex_digit(data[2 * i])? << 4 | parse_hex_digit(data[2 * i + 1])?;
+                    }
+                    let satp = state.csrs.satp;
+                    let written = buf[..len as usize].iter().enumerate().all(|(i, &byte)| {
+                        pmap::write_guest(&mut state.guest_memory, satp, va.wrapping_add(i as u64), byte).is_some()
+                    });
+                    riscv::fence_i();
+                    Some(written)
+                });
+                push_bytes(&mut reply, if ok == Some(true) { b"OK" } else { b"E01" });
+            }
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let ok = parse_addr_len(&args[2..]).map(|(va, kind)| if command == b'Z' {
+                    insert_breakpoint(state, va, kind)
+                } else {
+                    remove_breakpoint(state, va)
+                });
+                push_bytes(&mut reply, if ok == Some(true) { b"OK" } else { b"E01" });
+            }
+            b'c' => {
+                if let Some(va) = parse_hex(args) {
+                    riscv::set_sepc(va);
+                }
+                return;
+            }
+            b's' => {
+                if let Some(va) = parse_hex(args) {
+                    riscv::set_sepc(va);
+                }
+                state.single_step = true;
+                return;
+            }
+            b'D' => {
+                while let Some(breakpoint) = state.gdb.breakpoints.last().cloned() {
+                    remove_breakpoint(state, breakpoint.va);
+                }
+                write_packet(b"OK");
+                state.uart.gdb.attached = false;
+                return;
+            }
+            b'k' => {
+                state.uart.gdb.attached = false;
+                return;
+            }
+            b'H' => push_bytes(&mut reply, b"OK"),
+            b'q' if args.starts_with(b"Supported") => {
+                push_bytes(&mut reply, b"PacketSize=400");
+            }
+            b'q' if args.starts_with(b"Attached") => reply.push(b'1'),
+            b'q' if args.starts_with(b"Rcmd,") => monitor_command(state, &args[5..], &mut reply),
+            _ => {}
+        }
+        write_packet(&reply);
+    }
+}
+
+/// Run a `monitor` command, which arrives hex encoded. The reply is the command's output, also hex
+/// encoded.
+fn monitor_command(state: &mut Context, hex: &[u8], reply: &mut Packet) {
+    let mut command = [0u8; MAX_PACKET_SIZE / 2];
+    let len = hex.len() / 2;
+    if hex.len() % 2 != 0 || len > command.len() {
+        push_bytes(reply, b"E01");
+        return;
+    }
+    for i in 0..len {
+        command[i] = match (parse_hex_digit(hex[2 * i]), parse_hex_digit(hex[2 * i + 1])) {
+            (Some(high), Some(low)) => high << 4 | low,
+            _ => {
+                push_bytes(reply, b"E01");
+                return;
+            }
+        };
+    }
+
+    let mut output = ArrayString::<[u8; 256]>::new();
+    let mut words = core::str::from_utf8(&command[..len]).unwrap_or("").split_whitespace();
+    let _ = match (words.next(), words.next()) {
+        (Some("balloon"), None) => match virtio::balloon_status(state) {
+            Some((target, reclaimed, stats)) => writeln!(output, "target {} pages, {} reclaimed\n{:?}",
+                                                         target, reclaimed, stats),
+            None => writeln!(output, "no balloon device"),
+        },
+        (Some("balloon"), Some(pages)) => match pages.parse() {
+            Ok(pages) if virtio::set_balloon_target(state, pages) => writeln!(output, "target {} pages", pages),
+            Ok(_) => writeln!(output, "no balloon device"),
+            Err(_) => writeln!(output, "usage: balloon [pages]"),
+        },
+        _ => writeln!(output, "unknown command, try: balloon [pages]"),
+    };
+    for &byte in output.as_bytes() {
+        push_hex_byte(reply, byte);
+    }
+}
+
+/// Called when the guest executes `ebreak`. Returns whether it hit one of GDB's breakpoints, in
+/// which case the guest stays stopped until GDB resumes it. Otherwise the exception belongs to the
+/// guest.
+pub fn handle_breakpoint(state: &mut Context) -> bool {
+    let pc = csrr!(sepc);
+    if state.gdb.step_breakpoints.iter().any(|b| b.va == pc) {
+        finish_single_step(state);
+        return true;
+    }
+    if !state.gdb.breakpoints.iter().any(|b| b.va == pc) {
+        return false;
+    }
+
+    session(state, Some(SIGTRAP));
+    true
+}
+
+/// Called on the way back into the guest. Stops the guest if a pending single step has completed,
+/// and arms one if `Context::single_step` is set.
+pub fn update_single_step(state: &mut Context) {
+    if !state.gdb.step_breakpoints.is_empty() {
+        if csrr!(sepc) == state.gdb.step_pc {
+            // The trap happened before the instruction ran, so the step is still pending.
+            return;
+        }
+        finish_single_step(state);
+    }
+
+    if state.single_step && !arm_single_step(state) {
+        println!("Unable to single step at pc={:#x}", csrr!(sepc));
+        state.single_step = false;
+    }
+}
+
+/// Enter a debugging session if the host UART has received a request from GDB.
+pub fn poll(state: &mut Context) {
+    match state.uart.gdb.request.take() {
+        Some(Request::Interrupt) => session(state, Some(SIGINT), None),
+        Some(Request::Packet(packet)) => session(state, None, Some(packet)),
+        None => {}
+    }
+}
@@ -0,0 +1,139 @@
+//! A small allocator for hypervisor data structures, so that `alloc` types like `Vec` and `Box` can
+//! be used.
+//!
+//! Blocks are handed out first fit from an address ordered free list, and neighbouring free blocks
+//! are merged when memory is returned. Each hart has its own heap: the allocator lives in the data
+//! segment, which is mapped separately for every hart, and `init` is called from `hart_entry4` with
+//! that hart's region. Until then (and always in machine mode) every allocation fails.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use spin::Mutex;
+
+/// Granularity of allocations. Every block starts and ends on a multiple of this, so leftover space
+/// is always large enough to hold a `FreeBlock`.
+const BLOCK_SIZE: usize = 16;
+
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("Out of hypervisor heap memory allocating {:?}", layout)
+}
+
+/// Hand the `size` bytes at virtual address `start` to this hart's heap. Must only be called once,
+/// and the memory must not be used for anything else.
+pub unsafe fn init(start: u64, size: u64) {
+    HEAP.init(start as usize, size as usize)
+}
+
+/// Header stored at the start of each free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct HeapInner {
+    head: *mut FreeBlock,
+    start: usize,
+    end: usize,
+}
+unsafe impl Send for HeapInner {}
+
+pub struct Heap {
+    inner: Mutex<HeapInner>,
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: &Layout) -> usize {
+    round_up(layout.size().max(1), BLOCK_SIZE)
+}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Self { inner: Mutex::new(HeapInner { head: ptr::null_mut(), start: 0, end: 0 }) }
+    }
+
+    unsafe fn init(&self, start: usize, size: usize) {
+        let mut heap = self.inner.lock();
+        assert_eq!(heap.end, 0, "Hypervisor heap initialized twice");
+
+        heap.start = round_up(start, BLOCK_SIZE);
+        heap.end = (start + size) & !(BLOCK_SIZE - 1);
+        assert!(heap.start < heap.end);
+        let (start, end) = (heap.start, heap.end);
+        heap.insert(start, end - start);
+    }
+}
+
+impl HeapInner {
+    /// Return `[start, start+size)` to the free list, merging it with adjacent free blocks.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+        assert!(next.is_null() || start + size <= next as usize, "Hypervisor heap block freed twice");
+        assert!(prev.is_null() || prev as usize + (*prev).size <= start, "Hypervisor heap block freed twice");
+
+        let block = start as *mut FreeBlock;
+        ptr::write(block, FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.inner.lock();
+        let size = block_size(&layout);
+        let align = layout.align().max(BLOCK_SIZE);
+
+        let mut link: *mut *mut FreeBlock = &mut heap.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let start = block as usize;
+            let end = start + (*block).size;
+            let aligned = round_up(start, align);
+
+            if aligned < end && end - aligned >= size {
+                // Take the block off the list, then give back whatever wasn't needed on either side.
+                *link = (*block).next;
+                heap.insert(start, aligned - start);
+                heap.insert(aligned + size, end - (aligned + size));
+                return aligned as *mut u8;
+            }
+            link = &mut (*block).next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heap = self.inner.lock();
+        let start = ptr as usize;
+        let size = block_size(&layout);
+        assert!(start >= heap.start && start + size <= heap.end, "Freeing memory not from the hypervisor heap");
+        heap.insert(start, size);
+    }
+}
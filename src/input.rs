@@ -0,0 +1,75 @@
+use spin::Mutex;
+use crate::statics::SHARED_STATICS;
+
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// Bytes received from the host UART that haven't yet been delivered to the guest. Since this
+/// static lives in the data segment, each hart buffers the input that it happened to receive.
+static INPUT: Mutex<InputBuffer> = Mutex::new(InputBuffer::new());
+
+/// Ring buffer of input bytes. When full, the oldest byte is dropped to make room.
+pub struct InputBuffer {
+    bytes: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    /// Set when a byte has been dropped, until collected by `take_overrun`.
+    overrun: bool,
+}
+
+impl InputBuffer {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            overrun: false,
+        }
+    }
+
+    pub fn push(&mut self, ch: u8) {
+        if self.len == INPUT_BUFFER_SIZE {
+            self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+            self.len -= 1;
+            self.overrun = true;
+        }
+        self.bytes[(self.head + self.len) % INPUT_BUFFER_SIZE] = ch;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let ch = self.bytes[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(ch)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Move every byte waiting on the host UART into the input buffer. Called from the host UART's
+/// receive interrupt, but also whenever the guest polls for input.
+pub fn receive() {
+    let mut input = INPUT.lock();
+    while let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
+        input.push(ch);
+    }
+}
+
+/// Return the next byte of input, if any.
+pub fn getchar() -> Option<u8> {
+    receive();
+    INPUT.lock().pop()
+}
+
+/// Returns whether input has been dropped since the last call.
+pub fn take_overrun() -> bool {
+    let mut input = INPUT.lock();
+    let overrun = input.overrun;
+    input.overrun = false;
+    overrun
+}
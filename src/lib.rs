@@ -82,6 +82,7 @@
 //! ```
 
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(asm)]
 #![feature(const_fn)]
 #![feature(const_raw_ptr_deref)]
@@ -94,24 +95,37 @@
 #![feature(start)]
 #![feature(try_blocks)]
 
+extern crate alloc;
+
 #[macro_use]
 pub mod riscv;
 #[macro_use]
 pub mod print;
 
+pub mod audit;
 pub mod backtrace;
+pub mod clint;
 pub mod constants;
 pub mod context;
+pub mod csr;
 pub mod drivers;
 pub mod elf;
 pub mod fdt;
+pub mod gdb;
+pub mod heap;
+pub mod input;
 pub mod memory_region;
+pub mod mmio;
 pub mod pfault;
 pub mod plic;
 pub mod pmap;
+pub mod ramdump;
+pub mod sbi;
 pub mod statics;
 pub mod sum;
+pub mod trace;
 pub mod trap;
+pub mod uart_device;
 pub mod virtio;
 
 pub use core::sync::atomic::{AtomicBool, Ordering};
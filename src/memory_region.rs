@@ -1,11 +1,57 @@
 use core::mem;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use crate::pmap;
 
+const MAX_REGISTERED_REGIONS: usize = 32;
+
+/// Host address ranges of the regions constructed so far, followed by the number of valid entries.
+/// Only maintained in debug builds. Lives in the data segment, so each hart has its own registry.
+static REGISTRY: Mutex<([(u64, u64); MAX_REGISTERED_REGIONS], usize)> =
+    Mutex::new(([(0, 0); MAX_REGISTERED_REGIONS], 0));
+
+/// Record the host range `[start, end)` and panic if it partially overlaps any region constructed
+/// earlier. Identical ranges are allowed, since the same device registers or memory may legitimately
+/// be wrapped more than once (for instance when a guest is restarted).
+fn register(start: u64, end: u64) {
+    if !cfg!(debug_assertions) || start == end {
+        return;
+    }
+
+    let mut registry = REGISTRY.lock();
+    let (ref mut ranges, ref mut count) = *registry;
+    for &(s, e) in &ranges[..*count] {
+        if (s, e) == (start, end) {
+            return;
+        }
+        if start < e && s < end {
+            panic!("Memory region {:#x}..{:#x} overlaps existing region {:#x}..{:#x}", start, end, s, e);
+        }
+    }
+
+    // Once the registry fills up, further regions simply go unchecked.
+    if *count < ranges.len() {
+        ranges[*count] = (start, end);
+        *count += 1;
+    }
+}
+
+/// Reasons an access to a memory region can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionError {
+    /// The range of addresses doesn't lie entirely inside the region.
+    OutOfBounds,
+    /// The region was created read-only.
+    ReadOnly,
+}
+
 pub struct MemoryRegion<T: Copy = u64> {
     ptr: *mut T,
     base_address: u64,
     length_bytes: u64,
+    /// Set for regions that must never be modified, like a firmware image or device tree blob.
+    read_only: bool,
 }
 
 unsafe impl<T: Copy + Send> Send for MemoryRegion<T> {}
@@ -13,20 +59,49 @@ unsafe impl<T: Copy + Send> Send for MemoryRegion<T> {}
 impl<T: Copy> MemoryRegion<T> {
     pub unsafe fn new(address: u64, length: u64) -> Self {
         assert_eq!(length % mem::size_of::<T>() as u64, 0);
+        register(address, address + length);
         Self {
             ptr: address as *mut T,
             base_address: pmap::va2pa(address),
             length_bytes: length,
+            read_only: false,
         }
     }
 
     pub unsafe fn with_base_address(address: u64, base_address: u64, length: u64) -> Self {
         assert_eq!(length % mem::size_of::<T>() as u64, 0);
+        register(address, address + length);
         Self {
             ptr: address as *mut T,
             base_address,
             length_bytes: length,
+            read_only: false,
+        }
+    }
+
+    /// Like `new`, but any attempt to write to the region fails. Reads are unaffected.
+    pub unsafe fn read_only(address: u64, length: u64) -> Self {
+        Self::new(address, length).into_read_only()
+    }
+
+    /// Make the region read-only from now on.
+    pub fn into_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fail writes to read-only regions. Such a write is a hypervisor bug, so debug builds panic
+    /// instead of returning an error.
+    fn check_writable(&self) -> Result<(), RegionError> {
+        debug_assert!(!self.read_only, "Write to read-only memory region at {:#x}", self.base_address);
+        if self.read_only {
+            return Err(RegionError::ReadOnly);
         }
+        Ok(())
     }
 
     pub fn get(&self, index: u64) -> Option<T> {
@@ -54,6 +129,118 @@ impl<T: Copy> MemoryRegion<T> {
         addr >= self.base_address && addr < self.base_address + self.length_bytes
     }
 
+    /// Whether this region and `other` share any host memory, regardless of the base addresses
+    /// they are indexed by.
+    pub fn overlaps<U: Copy>(&self, other: &MemoryRegion<U>) -> bool {
+        let (start, end) = (self.ptr as u64, self.ptr as u64 + self.length_bytes);
+        let (other_start, other_end) = (other.ptr as u64, other.ptr as u64 + other.length_bytes);
+        start < end && other_start < other_end && start < other_end && other_start < end
+    }
+
+    /// Iterate over the bytes in `[start, start+len)` yielding `(address, value)` pairs. Iteration
+    /// stops at the end of the region instead of panicking.
+    pub fn iter_bytes<'a>(&'a self, start: u64, len: u64) -> impl Iterator<Item=(u64, u8)> + 'a {
+        let end = if start >= self.base_address {
+            start.saturating_add(len).min(self.base_address + self.length_bytes)
+        } else {
+            start
+        };
+
+        (start..end).map(move |addr| {
+            let offset = addr - self.base_address;
+            (addr, unsafe { *(self.ptr as *const u8).add(offset as usize) })
+        })
+    }
+
+    /// Returns the byte offset of `[address, address+len)` within the region, or None if any part of
+    /// the range falls outside of it.
+    fn range_offset(&self, address: u64, len: u64) -> Option<usize> {
+        let offset = address.checked_sub(self.base_address)?;
+        if offset.checked_add(len)? > self.length_bytes {
+            return None;
+        }
+        Some(offset as usize)
+    }
+
+    /// Read a `U` from `address`, which need not be aligned. Aligned reads are done with a single
+    /// access, while unaligned ones are composed from smaller accesses.
+    fn load<U: Copy>(&self, address: u64) -> Option<U> {
+        let offset = self.range_offset(address, mem::size_of::<U>() as u64)?;
+        unsafe {
+            let ptr = (self.ptr as *const u8).add(offset) as *const U;
+            if ptr as usize % mem::align_of::<U>() == 0 {
+                Some(*ptr)
+            } else {
+                Some(ptr.read_unaligned())
+            }
+        }
+    }
+
+    /// Write `value` to `address`, which need not be aligned. Nothing is written unless the entire
+    /// destination is inside the region.
+    fn store<U: Copy>(&mut self, address: u64, value: U) -> Result<(), RegionError> {
+        self.check_writable()?;
+        let offset = self.range_offset(address, mem::size_of::<U>() as u64).ok_or(RegionError::OutOfBounds)?;
+        unsafe {
+            let ptr = (self.ptr as *mut u8).add(offset) as *mut U;
+            if ptr as usize % mem::align_of::<U>() == 0 {
+                *ptr = value;
+            } else {
+                ptr.write_unaligned(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the byte at `address`, or None if it is outside the region.
+    pub fn get_u8(&self, address: u64) -> Option<u8> { self.load(address) }
+    /// Read the u16 at `address`, which may be unaligned. Returns None unless all of it is inside
+    /// the region.
+    pub fn get_u16(&self, address: u64) -> Option<u16> { self.load(address) }
+    /// Read the u32 at `address`, which may be unaligned. Returns None unless all of it is inside
+    /// the region.
+    pub fn get_u32(&self, address: u64) -> Option<u32> { self.load(address) }
+    /// Read the u64 at `address`, which may be unaligned. Returns None unless all of it is inside
+    /// the region.
+    pub fn get_u64(&self, address: u64) -> Option<u64> { self.load(address) }
+
+    pub fn set_u8(&mut self, address: u64, value: u8) -> Result<(), RegionError> { self.store(address, value) }
+    pub fn set_u16(&mut self, address: u64, value: u16) -> Result<(), RegionError> { self.store(address, value) }
+    pub fn set_u32(&mut self, address: u64, value: u32) -> Result<(), RegionError> { self.store(address, value) }
+    pub fn set_u64(&mut self, address: u64, value: u64) -> Result<(), RegionError> { self.store(address, value) }
+
+    /// Copy `src` into the region starting at `address`. Nothing is written unless the entire
+    /// destination range is inside the region.
+    pub fn copy_from_slice(&mut self, address: u64, src: &[u8]) -> Result<(), RegionError> {
+        self.check_writable()?;
+        let offset = self.range_offset(address, src.len() as u64).ok_or(RegionError::OutOfBounds)?;
+        unsafe {
+            core::ptr::copy(src.as_ptr(), (self.ptr as *mut u8).add(offset), src.len());
+        }
+        Ok(())
+    }
+
+    /// Fill `dst` with the contents of the region starting at `address`. Nothing is read unless the
+    /// entire source range is inside the region.
+    pub fn copy_to_slice(&self, address: u64, dst: &mut [u8]) -> Result<(), RegionError> {
+        let offset = self.range_offset(address, dst.len() as u64).ok_or(RegionError::OutOfBounds)?;
+        unsafe {
+            core::ptr::copy((self.ptr as *const u8).add(offset), dst.as_mut_ptr(), dst.len());
+        }
+        Ok(())
+    }
+
+    /// Set every byte in `[address, address+len)` to zero. Nothing is written unless the entire
+    /// range is inside the region.
+    pub fn zero_range(&mut self, address: u64, len: u64) -> Result<(), RegionError> {
+        self.check_writable()?;
+        let offset = self.range_offset(address, len).ok_or(RegionError::OutOfBounds)?;
+        unsafe {
+            core::ptr::write_bytes((self.ptr as *mut u8).add(offset), 0, len as usize);
+        }
+        Ok(())
+    }
+
     pub fn slice(&self, index: u64, len: u64) -> &[u8] {
         assert!(index >= self.base_address);
 
@@ -69,6 +256,7 @@ impl<T: Copy> MemoryRegion<T> {
     }
 
     pub fn slice_mut(&mut self, index: u64, len: u64) -> &mut [u8] {
+        assert!(!self.read_only);
         assert!(index >= self.base_address);
 
         let offset = index - self.base_address;
@@ -81,6 +269,41 @@ impl<T: Copy> MemoryRegion<T> {
                                             len as usize)
         }
     }
+
+    /// Like `slice_mut`, but returns None instead of panicking if any part of the range falls
+    /// outside the region.
+    pub fn try_slice_mut(&mut self, index: u64, len: u64) -> Option<&mut [u8]> {
+        self.check_writable().ok()?;
+        let offset = self.range_offset(index, len)?;
+        unsafe {
+            Some(core::slice::from_raw_parts_mut((self.ptr as *mut u8).add(offset), len as usize))
+        }
+    }
+}
+
+impl MemoryRegion<u64> {
+    /// Iterate over the u64s in `[start, start+len)` yielding `(address, value)` pairs. Iteration
+    /// stops at the end of the region instead of panicking.
+    pub fn iter_u64<'a>(&'a self, start: u64, len: u64) -> impl Iterator<Item=(u64, u64)> + 'a {
+        (start..start.saturating_add(len)).step_by(8)
+            .map(move |addr| (addr, self.get(addr)))
+            .take_while(|&(_, value)| value.is_some())
+            .map(|(addr, value)| (addr, value.unwrap()))
+    }
+
+    /// Atomically store `new` to the u64 at byte offset `index` if it currently holds `current`.
+    /// Returns the previous value on success, or the value actually found on failure.
+    pub fn compare_exchange(&mut self, index: u64, current: u64, new: u64) -> Result<u64, u64> {
+        assert!(!self.read_only);
+        assert_eq!(index % 8, 0);
+        assert!(index >= self.base_address);
+
+        let offset = index - self.base_address;
+        assert!(offset < self.length_bytes);
+
+        let atomic = unsafe { &*(self.ptr.add(offset as usize / 8) as *const AtomicU64) };
+        atomic.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+    }
 }
 
 impl<T: Copy> Index<u64> for MemoryRegion<T> {
@@ -102,6 +325,7 @@ impl<T: Copy> IndexMut<u64> for MemoryRegion<T> {
     /// Return a reference to a u64 index many *bytes* into the memory region. The value of index
     /// must be divisible by sizeof(T).
     fn index_mut(&mut self, index: u64) -> &mut T {
+        assert!(!self.read_only);
         assert_eq!(index % mem::size_of::<T>() as u64, 0);
         assert!(index >= self.base_address);
 
@@ -112,6 +336,12 @@ impl<T: Copy> IndexMut<u64> for MemoryRegion<T> {
     }
 }
 
+/// A PTE write that was refused because its address isn't an aligned slot in the page table region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PteOutOfRegion {
+    pub pte_address: u64,
+}
+
 /// Use to represent a region containing page tables. All addresses are in terms of *physical
 /// addresses* to simplify usage.
 pub struct PageTableRegion {
@@ -131,27 +361,65 @@ impl PageTableRegion {
         }
     }
 
+    /// Physical address of the start of the region.
+    pub fn base(&self) -> u64 {
+        self.region.base()
+    }
+
+    /// Physical address of the end of the region.
+    pub fn end_pa(&self) -> u64 {
+        self.end_pa
+    }
+
     pub unsafe fn set_pte_unchecked(&mut self, pte_address: u64, pte_value: u64) {
         self.region[pte_address] = pte_value;
     }
 
     pub fn set_leaf_pte(&mut self, pte_address: u64, pte_value: u64) {
+        self.check_pte_address(pte_address);
         assert!(pte_value & 0xf != 0x1);
         assert!(!self.inside_region(pte_value));
         self.region[pte_address] = pte_value;
     }
 
     pub fn set_nonleaf_pte(&mut self, pte_address: u64, pte_value: u64) {
+        self.check_pte_address(pte_address);
         assert_eq!(pte_value & 0xf, 0x1);
         assert!(self.inside_region(pte_value));
         self.region[pte_address] = pte_value;
     }
 
     pub fn set_invalid_pte(&mut self, pte_address: u64, pte_value: u64) {
+        self.check_pte_address(pte_address);
         assert_eq!(pte_value & 0x1, 0);
         self.region[pte_address] = pte_value;
     }
 
+    /// Like `set_invalid_pte`, but returns the offending address instead of panicking if
+    /// `pte_address` isn't a PTE slot inside this region.
+    pub fn try_set_invalid_pte(&mut self, pte_address: u64, pte_value: u64)
+                               -> Result<(), PteOutOfRegion> {
+        if !self.contains_pte(pte_address) {
+            return Err(PteOutOfRegion { pte_address });
+        }
+        self.set_invalid_pte(pte_address, pte_value);
+        Ok(())
+    }
+
+    /// Whether `pte_address` is an aligned PTE slot inside this region.
+    pub fn contains_pte(&self, pte_address: u64) -> bool {
+        pte_address % 8 == 0 && self.region.in_region(pte_address)
+    }
+
+    /// Panic unless `pte_address` may be written. A write anywhere else would corrupt hypervisor
+    /// memory, so this is checked even though callers only compute addresses within the region.
+    fn check_pte_address(&self, pte_address: u64) {
+        if !self.contains_pte(pte_address) {
+            panic!("PTE write to {:#x} outside page table region {:#x}..{:#x}",
+                   pte_address, self.region.base(), self.end_pa);
+        }
+    }
+
     // Returns a conservative answer of whether the pte could map some memory that overlapped this
     // region.
     fn inside_region(&self, pte: u64) -> bool {
@@ -0,0 +1,127 @@
+//! Handling of guest accesses to device memory.
+//!
+//! Each emulated device claims a range of guest physical addresses on the guest's `MmioBus`, which
+//! page faults consult to decide where an access should be sent.
+
+use arrayvec::ArrayVec;
+use crate::context::Context;
+use crate::pmap::AccessType;
+use crate::{pfault, trace, trap};
+
+pub const MAX_MMIO_REGIONS: usize = 16;
+
+/// Emulated devices that can claim addresses on the bus. The devices themselves are held in the
+/// guest's `Context`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MmioDevice {
+    Uart,
+    Plic,
+    Clint,
+    /// The block of virtio MMIO slots, with one device per 4KB.
+    Virtio,
+    /// Addresses with no device but a specific `UnclaimedPolicy`.
+    Unclaimed(UnclaimedPolicy),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRegion {
+    pub base: u64,
+    pub len: u64,
+    pub device: MmioDevice,
+}
+
+impl MmioRegion {
+    fn contains(&self, guest_pa: u64) -> bool {
+        guest_pa >= self.base && guest_pa - self.base < self.len
+    }
+}
+
+/// Table of the address ranges claimed by emulated devices, sorted by base address.
+pub struct MmioBus {
+    regions: ArrayVec<[MmioRegion; MAX_MMIO_REGIONS]>,
+}
+
+impl MmioBus {
+    pub fn new() -> Self {
+        Self { regions: ArrayVec::new() }
+    }
+
+    /// Claim `[base, base+len)` for `device`. Panics if the range overlaps one that is already
+    /// claimed or the table is full.
+    pub fn register(&mut self, base: u64, len: u64, device: MmioDevice) {
+        assert!(len > 0 && base.checked_add(len).is_some());
+        let index = match self.regions.binary_search_by(|r| r.base.cmp(&base)) {
+            Ok(i) => panic!("MMIO region {:#x}..{:#x} overlaps {:?}", base, base + len, self.regions[i]),
+            Err(i) => i,
+        };
+        if index > 0 {
+            let prev = self.regions[index - 1];
+            assert!(prev.base + prev.len <= base, "MMIO region {:#x}..{:#x} overlaps {:?}", base, base + len, prev);
+        }
+        if let Some(next) = self.regions.get(index) {
+            assert!(base + len <= next.base, "MMIO region {:#x}..{:#x} overlaps {:?}", base, base + len, next);
+        }
+        self.regions.insert(index, MmioRegion { base, len, device });
+    }
+
+    /// Returns the region containing `guest_pa`, if any.
+    pub fn find(&self, guest_pa: u64) -> Option<MmioRegion> {
+        let index = match self.regions.binary_search_by(|r| r.base.cmp(&guest_pa)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some(self.regions[index]).filter(|r| r.contains(guest_pa))
+    }
+}
+
+/// What happens when the guest accesses an MMIO address that no emulated device claims. Real
+/// platforms differ, so this can be chosen for each range of addresses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnclaimedPolicy {
+    /// Loads return all ones and stores are discarded, as with an empty slot on a bus.
+    ReadOnes,
+    /// A load or store access fault is reflected to the guest.
+    AccessFault,
+}
+
+/// Policy used for unclaimed addresses that aren't covered by any region on the bus.
+const DEFAULT_POLICY: UnclaimedPolicy = UnclaimedPolicy::AccessFault;
+
+/// Handle an access by `instruction` to `guest_pa` (mapped by the guest at `guest_va`), which isn't
+/// guest memory and isn't claimed by any emulated device. `region` is the `MmioDevice::Unclaimed`
+/// region containing the address, if any. Returns false if the access should instead be forwarded to
+/// the guest as a page fault.
+pub fn handle_unclaimed_access(state: &mut Context, region: Option<MmioRegion>, guest_pa: u64, guest_va: u64,
+                               access: AccessType, instruction: u32) -> bool {
+    let policy = match region.map(|r| r.device) {
+        Some(MmioDevice::Unclaimed(policy)) => policy,
+        _ => DEFAULT_POLICY,
+    };
+
+    if cfg!(debug_assertions) {
+        let (start, end) = region.map(|r| (r.base, r.base + r.len))
+            .unwrap_or((guest_pa & !0xfff, (guest_pa & !0xfff) + 0x1000));
+        println!("Unclaimed MMIO {:?} of {:#x} (in {:#x}..{:#x}) from pc {:#x}: {:?}",
+                 access, guest_pa, start, end, csrr!(sepc), policy);
+    }
+
+    match policy {
+        UnclaimedPolicy::ReadOnes => {
+            if pfault::mmio_access_width(instruction, state.rv32).is_none() {
+                return false;
+            }
+            if pfault::emulate_mmio_store(state, instruction).is_none() {
+                pfault::emulate_mmio_load(state, instruction, !0);
+            }
+            trace::record_mmio(state, guest_va, guest_pa, instruction);
+            trap::skip_instruction(instruction);
+        }
+        UnclaimedPolicy::AccessFault => {
+            // Load or store/AMO access fault.
+            let cause = if access == AccessType::Write { 7 } else { 5 };
+            trap::reflect_exception(state, cause, csrr!(sepc), guest_va);
+        }
+    }
+    true
+}
@@ -1,173 +1,665 @@
-use crate::context::Context;
-use crate::riscv::bits::SATP_PPN;
-use crate::{pmap::*, riscv, virtio};
+use byteorder::{ByteOrder, NativeEndian};
+use crate::clint::ClintRegister;
+use crate::context::{Context, PrivilegeMode};
+use crate::mmio::MmioDevice;
+use crate::riscv::bits::{IP_SSIP, SATP_PPN, SCAUSE_ATOMIC_MISALIGNED, SCAUSE_LOAD_ACCESS_FAULT,
+                         SCAUSE_LOAD_MISALIGNED, SCAUSE_STORE_ACCESS_FAULT};
+use crate::trap::U64Bits;
+use crate::{mmio, pmap::*, riscv, trace, trap, virtio};
 use riscv_decode::Instruction;
+use riscv_decode::types::RType;
 
 /// Perform any handling required in response to a guest page fault. Returns true if the fault could
 /// be handled, or false if it should be forwarded on to the guest.
 pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u32>) -> bool {
-    let shadow = state.shadow();
+    let shadow = active_root(state);
+    let guest_va = csrr!(stval);
     if shadow == PageTableRoot::MPA {
+        if handle_mpa_fault(state, guest_va) {
+            return true;
+        }
         println!("Page fault without guest paging enabled?");
         return false;
     }
 
-    let guest_va = csrr!(stval);
     //assert!((guest_va & SV39_MASK) < (511 << 30));
 
-    let access = match cause {
-        12 => PTE_EXECUTE,
-        13 => PTE_READ,
-        15 => PTE_WRITE,
-        _ => unreachable!(),
+    let access = AccessType::for_page_fault(cause, instruction, state.rv32).unwrap();
+
+    let mode = match SatpMode::from_satp(state.csrs.satp) {
+        Some(mode) => mode,
+        None => return false,
     };
 
     let page = guest_va & !0xfff;
-    if let Some(translation) = translate_guest_address(&state.guest_memory, (state.csrs.satp & SATP_PPN) << 12, page) {
-        // Check R/W/X bits
-        if translation.pte_value & access == 0 {
-            return false;
-        }
+    let root = (state.csrs.satp & SATP_PPN) << 12;
+    let user_mode = shadow == PageTableRoot::UVA;
+    // Walking the guest page table also sets the A and D bits in the guest PTE. If the PTE changed
+    // underneath us, just return to the guest and let the access fault again.
+    let translation = match translate_guest_address_and_set_ad(&mut state.guest_memory, mode, root, page,
+                                                               access, user_mode, state.csrs.sstatus) {
+        Ok(translation) => translation,
+        Err(TranslationError::Retry) => return true,
+        Err(_) => return false,
+    };
 
-        // Check U bit
-        match shadow {
-            PageTableRoot::UVA => if translation.pte_value & PTE_USER == 0 { return false; }
-            PageTableRoot::KVA => if translation.pte_value & PTE_USER != 0 { return false; }
-            PageTableRoot::MVA => {}
-            _ => unreachable!(),
-        }
+    if cfg!(feature = "protect_guest_page_tables") {
+        protect_guest_page_tables(state, mode, root, page);
 
-        if state.guest_memory.in_region(translation.guest_pa) {
-            let host_pa = translation.guest_pa + state.guest_shift;
+        let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+        if access == AccessType::Write && is_protected_page_table(state, guest_pa) {
+            let instruction = instruction.expect("store page fault without instruction");
+            return emulate_page_table_write(state, guest_pa, instruction);
+        }
+    }
 
-            // Set A and D bits
-            let new_pte = if (translation.pte_value & PTE_DIRTY) == 0 && access == PTE_WRITE {
-                translation.pte_value | PTE_DIRTY | PTE_ACCESSED
-            } else if (translation.pte_value & PTE_ACCESSED) == 0 {
-                translation.pte_value | PTE_ACCESSED
-            } else {
-                translation.pte_value
-            };
+    if state.guest_memory.in_region(translation.guest_pa)
+        && !virtio::is_reclaimed(state, translation.guest_pa & !0xfff, 0x1000)
+    {
+        let host_pa = translation.guest_pa + state.guest_shift;
 
-            if new_pte != translation.pte_value {
-                // TODO: do this atomically
-                state.guest_memory[translation.pte_addr] = new_pte;
+        let new_pte = translation.pte_value;
+        let mut perm = if (new_pte & PTE_DIRTY) == 0 && access != AccessType::Write {
+            (new_pte & (PTE_READ | PTE_EXECUTE))
+        } else {
+            (new_pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE))
+        };
+        if is_protected_page_table(state, translation.guest_pa) {
+            perm &= !PTE_WRITE;
+        }
+        // The device tree is read-only no matter how the guest maps it.
+        if state.boot.overlaps_dtb(translation.guest_pa & !0xfff, 0x1000) {
+            if access == AccessType::Write {
+                return false;
             }
+            perm &= !PTE_WRITE;
+        }
 
-            let perm = if (new_pte & PTE_DIRTY) == 0 && access != PTE_WRITE {
-                (new_pte & (PTE_READ | PTE_EXECUTE))
-            } else {
-                (new_pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE))
-            };
+        if virtio::is_queue_access(state, translation.guest_pa) {
+            let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+            let host_pa = (host_pa & !0xfff) | (guest_va & 0xfff);
+            let instruction = instruction.expect("attempted to execute code from virtio queue page");
+            return virtio::handle_queue_access(state, guest_pa, host_pa, instruction);
+        }
 
-            if virtio::is_queue_access(state, translation.guest_pa) {
-                let guest_pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-                let host_pa = (host_pa & !0xfff) | (guest_va & 0xfff);
-                let instruction = instruction.expect("attempted to execute code from virtio queue page");
-                return virtio::handle_queue_access(state, guest_pa, host_pa, instruction);
-            }
+        let reserved_bits = match translation.level {
+            PageTableLevel::Level4KB => 0x000,
+            PageTableLevel::Level2MB => 0x100,
+            // A 4MB guest page is larger than the span of a single shadow 4KB table, so record it
+            // as a gigapage to make targeted fences invalidate enough.
+            PageTableLevel::Level4MB | PageTableLevel::Level1GB => 0x200,
+            PageTableLevel::Level512GB => 0x300,
+        };
 
-            let reserved_bits = match translation.level {
-                PageTableLevel::Level4KB => 0x000,
-                PageTableLevel::Level2MB => 0x100,
-                PageTableLevel::Level1GB => 0x200,
-            };
+        let level = shadow_level(state, &translation);
+        let offset_mask = level.page_size() - 1;
+        let new_shadow_pte = ((host_pa & !offset_mask) >> 2) | reserved_bits | perm | (new_pte & PTE_GLOBAL)
+            | PTE_AD | PTE_USER | PTE_VALID;
+        let va = page & !offset_mask;
+        let old_shadow_pte = match state.shadow_page_tables.rmw_mapping(shadow, va, new_shadow_pte, level) {
+            Ok(old_shadow_pte) => old_shadow_pte,
+            Err(MappingError::ReservedAddress) => {
+                // The guest mapped an address overlapping the hypervisor's own address space.
+                // There is no way to run the guest with that mapping, so it gets a page fault.
+                println!("Guest attempted to access reserved virtual address: {:#x}", guest_va);
+                return false;
+            }
+            Err(MappingError::OutOfMemory) => {
+                // Out of memory for shadow page tables. Flushing them releases every page other
+                // than the roots, so the retry cannot fail.
+                flush_shadow_page_table(&mut state.shadow_page_tables);
+                state.shadow_page_tables.rmw_mapping(shadow, va, new_shadow_pte, level)
+                    .expect("Out of hypervisor memory for page tables")
+            }
+        };
 
-            let new_shadow_pte = (host_pa >> 2) | reserved_bits | perm | PTE_AD | PTE_USER | PTE_VALID;
-            let old_shadow_pte = state.shadow_page_tables.rmw_mapping(shadow, page, new_shadow_pte);
-
-            // Flushing the TLB entry for a virtual address can be very expensive and we only need
-            // to do one here if the processor cache invalid TLB entries. The logic below attempts
-            // to detect whether invalid PTEs are being cached, and if so sets a flag so that future
-            // page faults will trigger a flush.
-            if state.tlb_caches_invalid_ptes {
-                riscv::sfence_vma_addr(guest_va);
-            } else if new_shadow_pte == old_shadow_pte {
-                state.consecutive_page_fault_count += 1;
-                if state.consecutive_page_fault_count == 10 {
-                    state.tlb_caches_invalid_ptes = true;
-                }
-            } else {
-                state.consecutive_page_fault_count = 1;
+        // Flushing the TLB entry for a virtual address can be very expensive and we only need
+        // to do one here if the processor cache invalid TLB entries. The logic below attempts
+        // to detect whether invalid PTEs are being cached, and if so sets a flag so that future
+        // page faults will trigger a flush.
+        if state.tlb_caches_invalid_ptes {
+            riscv::sfence_vma_addr(guest_va);
+        } else if new_shadow_pte == old_shadow_pte {
+            state.consecutive_page_fault_count += 1;
+            if state.consecutive_page_fault_count == 10 {
+                state.tlb_caches_invalid_ptes = true;
             }
+        } else {
+            state.consecutive_page_fault_count = 1;
+        }
 
-            return true;
-        } else if access != PTE_EXECUTE && state.smode {
-            let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-            if let Some(instruction) = instruction {
-                if is_uart_access(pa) {
-                    return handle_uart_access(state, pa, instruction);
-                }
-
-                if is_plic_access(pa) {
-                    return handle_plic_access(state, pa, instruction)
-                }
-
-                if virtio::is_device_access(state, pa) {
-                    return virtio::handle_device_access(state, pa, instruction);
-                }
+        return true;
+    } else if access != AccessType::Execute && state.current_mode() == PrivilegeMode::Supervisor {
+        let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+        if let Some(instruction) = instruction {
+            let region = state.mmio.find(pa);
+            let handled = match region.map(|r| r.device) {
+                Some(MmioDevice::Uart) => handle_uart_access(state, pa, instruction),
+                Some(MmioDevice::Plic) => handle_plic_access(state, pa, instruction),
+                Some(MmioDevice::Clint) => handle_clint_access(state, pa, instruction),
+                Some(MmioDevice::Virtio) => virtio::handle_device_access(state, pa, instruction),
+                // Traced by the handler, since it may reflect a fault rather than serve the access.
+                Some(MmioDevice::Unclaimed(_)) | None if !state.guest_memory.in_region(pa) =>
+                    return mmio::handle_unclaimed_access(state, region, pa, guest_va, access, instruction),
+                _ => false,
+            };
+            if handled {
+                trace::record_mmio(state, guest_va, pa, instruction);
             }
+            return handled;
         }
     }
 
     false
 }
 
-#[inline(always)]
-fn is_uart_access(guest_pa: u64) -> bool {
-    guest_pa >= 0x10000000 && guest_pa < 0x10000100
+/// Returns the largest page size that can be used to shadow the guest mapping described by
+/// `translation`. A superpage is only used if the entire guest superpage is backed by guest memory,
+/// is suitably aligned in host memory, and contains no virtio queue pages (since accesses to those
+/// must always trap) or pages reclaimed by a balloon.
+fn shadow_level(state: &Context, translation: &AddressTranslation) -> PageTableLevel {
+    for &level in &[PageTableLevel::Level1GB, PageTableLevel::Level2MB] {
+        let size = level.page_size();
+        let start = translation.guest_pa & !(size - 1);
+        if size <= translation.page_size()
+            && state.guest_shift % size == 0
+            && state.guest_memory.in_region(start)
+            && state.guest_memory.in_region(start + size - 1)
+            && !state.virtio.queue_guest_pages.iter().any(|&p| p >= start && p < start + size)
+            && !state.protected_page_tables.iter().any(|p| p.guest_pa >= start && p.guest_pa < start + size)
+            && !virtio::is_reclaimed(state, start, size)
+            && !state.boot.overlaps_dtb(start, size)
+        {
+            return level;
+        }
+    }
+    PageTableLevel::Level4KB
 }
-fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    match riscv_decode::decode(instruction).ok() {
-        Some(Instruction::Lb(i)) => {
-            let value = state.uart.read(&state.host_clint, guest_pa) as u64;
-            state.saved_registers.set(i.rd(), value);
+
+fn is_protected_page_table(state: &Context, guest_pa: u64) -> bool {
+    state.protected_page_tables.iter().any(|p| p.guest_pa == guest_pa & !0xfff)
+}
+
+/// Write protect every guest page table used to translate `va`. Once `MAX_PROTECTED_PAGE_TABLES`
+/// is reached every shadow mapping is flushed, so that the protections can be dropped and rebuilt
+/// on demand.
+fn protect_guest_page_tables(state: &mut Context, mode: SatpMode, root: u64, va: u64) {
+    let walk = {
+        let guest_memory = &state.guest_memory;
+        match walk_page_table(root, va, mode, |pa| guest_memory.get(pa)) {
+            Some(walk) => walk,
+            None => return,
         }
-        Some(Instruction::Sb(i)) => {
-            let value = (state.saved_registers.get(i.rs2()) & 0xff) as u8;
-            state.uart.write(&state.host_clint, guest_pa, value);
+    };
+    let va_bits = match mode {
+        SatpMode::Sv32 => 32,
+        SatpMode::Sv48 => 48,
+        _ => 39,
+    };
+
+    if state.protected_page_tables.len() + walk.path.len() > MAX_PROTECTED_PAGE_TABLES {
+        println!("Protected {} guest page tables, flushing shadow page tables", MAX_PROTECTED_PAGE_TABLES);
+        flush_shadow_page_table(&mut state.shadow_page_tables);
+        forget_protected_page_tables(state);
+    }
+
+    let mut added = false;
+    for pte in &walk.path {
+        let guest_pa = pte.addr & !0xfff;
+        if is_protected_page_table(state, guest_pa) {
+            continue;
         }
-        Some(instr) => {
-            println!("UART: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
-            loop {}
+
+        let entry_size = pte.level.page_size();
+        let entries = if mode == SatpMode::Sv32 { 1024 } else { 512 };
+        let va_mask = (1u64 << va_bits) - 1;
+        let table = ProtectedPageTable {
+            guest_pa,
+            va_base: va & va_mask & !(entry_size * entries - 1),
+            entry_size,
+            va_bits,
+        };
+        state.protected_page_tables.push(table);
+        added = true;
+    }
+
+    if added {
+        // The page tables might already be mapped writable somewhere, and without a reverse map
+        // the only way to find those mappings is to remove all of them.
+        flush_shadow_page_table(&mut state.shadow_page_tables);
+    }
+}
+
+/// Perform a store or AMO by the guest to one of its write protected page tables, and then remove
+/// any shadow mappings derived from the modified entry.
+fn emulate_page_table_write(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+    match (mmio_access_width(instruction, state.rv32), emulate_mmio_store(state, instruction)) {
+        (Some(width), Some(value)) => {
+            let bytes = value.to_le_bytes();
+            if state.guest_memory.copy_from_slice(guest_pa, &bytes[..width as usize]).is_err() {
+                return false;
+            }
+            trap::skip_instruction(instruction);
+        }
+        _ => if !emulate_atomic(state, guest_pa, instruction) {
+            return false;
         }
-        _ => return false,
     }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+
+    let table = *state.protected_page_tables.iter().find(|p| p.guest_pa == guest_pa & !0xfff).unwrap();
+    if table.entry_size == PageTableLevel::Level4KB.page_size() {
+        let va = table.va_for_pte(guest_pa & !0x7);
+        for &root in PageTableRoot::SHADOWS {
+            state.shadow_page_tables.unmap(root, va);
+        }
+    } else {
+        // A non-leaf or superpage entry covers too many shadow mappings to remove one at a time.
+        flush_shadow_page_table(&mut state.shadow_page_tables);
+    }
     true
 }
 
-#[inline(always)]
-fn is_plic_access(guest_pa: u64) -> bool {
-    guest_pa >= 0x0c000000 && guest_pa < 0x10000000
+/// Size in bytes of the value loaded or stored by `instruction`, or None if it isn't a load or store.
+pub fn mmio_access_width(instruction: u32, rv32: bool) -> Option<u64> {
+    Some(match trap::decode(instruction, rv32).ok()? {
+        Instruction::Lb(_) | Instruction::Lbu(_) | Instruction::Sb(_) => 1,
+        Instruction::Lh(_) | Instruction::Lhu(_) | Instruction::Sh(_) => 2,
+        Instruction::Lw(_) | Instruction::Lwu(_) | Instruction::Sw(_) => 4,
+        Instruction::Ld(_) | Instruction::Sd(_) => 8,
+        _ => return None,
+    })
 }
-fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    match riscv_decode::decode(instruction).ok() {
-        Some(Instruction::Lw(i)) => {
-            let value = state.plic.read_u32(guest_pa) as i32 as i64 as u64;
-            // println!("PLIC: Read value {:#x} at address {:#x}", value, guest_pa);
-            state.saved_registers.set(i.rd(), value)
+
+/// Complete an emulated load by placing `device_value` into the destination register, sign or zero
+/// extended from the width of the load. Returns false if `instruction` isn't a load.
+pub fn emulate_mmio_load(state: &mut Context, instruction: u32, device_value: u64) -> bool {
+    match mmio_load_result(instruction, state.rv32, device_value) {
+        Some((rd, value)) => {
+            // Writes to x0 are discarded by `SavedRegisters::set`.
+            state.saved_registers.set(rd, value);
+            true
         }
-        Some(Instruction::Sw(i)) => {
-            let value = state.saved_registers.get(i.rs2()) as u32;
-            // println!("PLIC: Writing {:#x} to address {:#x}", value, guest_pa);
+        None => false,
+    }
+}
 
-            let mut clear_seip = false;
-            state.plic.write_u32(guest_pa, value, &mut clear_seip);
-            if clear_seip {
-                state.csrs.sip &= !0x200;
-            }
-            state.no_interrupt = false;
+/// Returns the destination register of the load `instruction` along with `device_value` sign or
+/// zero extended from the width of the load.
+fn mmio_load_result(instruction: u32, rv32: bool, device_value: u64) -> Option<(u32, u64)> {
+    Some(match trap::decode(instruction, rv32).ok()? {
+        Instruction::Lb(i) => (i.rd(), device_value as i8 as u64),
+        Instruction::Lbu(i) => (i.rd(), device_value as u8 as u64),
+        Instruction::Lh(i) => (i.rd(), device_value as i16 as u64),
+        Instruction::Lhu(i) => (i.rd(), device_value as u16 as u64),
+        Instruction::Lw(i) => (i.rd(), device_value as i32 as u64),
+        Instruction::Lwu(i) => (i.rd(), device_value as u32 as u64),
+        Instruction::Ld(i) => (i.rd(), device_value),
+        _ => return None,
+    })
+}
+
+/// Returns the value written by an emulated store, truncated to the width of the store, or None if
+/// `instruction` isn't a store.
+pub fn emulate_mmio_store(state: &Context, instruction: u32) -> Option<u64> {
+    let (rs2, mask) = match trap::decode(instruction, state.rv32).ok()? {
+        Instruction::Sb(i) => (i.rs2(), 0xff),
+        Instruction::Sh(i) => (i.rs2(), 0xffff),
+        Instruction::Sw(i) => (i.rs2(), 0xffffffff),
+        Instruction::Sd(i) => (i.rs2(), !0),
+        _ => return None,
+    };
+    Some(state.saved_registers.get(rs2) & mask)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum AtomicOp {
+    Lr,
+    Sc,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+impl AtomicOp {
+    /// Result of applying this AMO to `old` with operand `src`, for an access `width` bytes wide.
+    fn apply(self, width: u64, old: u64, src: u64) -> u64 {
+        let (old_signed, src_signed) = match width {
+            4 => (old as i32 as i64, src as i32 as i64),
+            _ => (old as i64, src as i64),
+        };
+        let (old_unsigned, src_unsigned) = match width {
+            4 => (old as u32 as u64, src as u32 as u64),
+            _ => (old, src),
+        };
+
+        match self {
+            AtomicOp::Swap => src,
+            AtomicOp::Add => old.wrapping_add(src),
+            AtomicOp::Xor => old ^ src,
+            AtomicOp::And => old & src,
+            AtomicOp::Or => old | src,
+            AtomicOp::Min => if old_signed <= src_signed { old } else { src },
+            AtomicOp::Max => if old_signed >= src_signed { old } else { src },
+            AtomicOp::Minu => if old_unsigned <= src_unsigned { old } else { src },
+            AtomicOp::Maxu => if old_unsigned >= src_unsigned { old } else { src },
+            AtomicOp::Lr | AtomicOp::Sc => unreachable!(),
         }
-        Some(instr) => {
-            println!("PLIC: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
+    }
+}
+
+/// Check the decoding of compressed accesses, the MMIO access widths and load extension, and the
+/// AMO arithmetic against known results, panicking on any mismatch.
+pub fn selftest() {
+    // c.lw a0, 4(a1); c.sw a0, 4(a1); c.ld a0, 8(a1); c.sd s1, 248(a5); c.lwsp a5, 252(sp);
+    // c.ldsp a0, 16(sp); c.swsp a0, 12(sp); c.sdsp ra, 504(sp)
+    for &(compressed, full) in &[(0x41c8, 0x0045a503), (0xc1c8, 0x00a5a223), (0x6588, 0x0085b503),
+                                 (0xffe4, 0x0e97bc23), (0x57fe, 0x0fc12783), (0x6542, 0x01013503),
+                                 (0xc62a, 0x00a12623), (0xff86, 0x1e113c23)] {
+        assert_eq!(trap::expand_compressed(compressed, false), full);
+    }
+    // c.addi a0, 1 isn't an access and is left alone.
+    assert_eq!(trap::expand_compressed(0x0505, false), 0x0505);
+    // On RV32 the c.ld, c.sd, c.ldsp and c.sdsp encodings above are c.flw, c.fsw, c.flwsp and
+    // c.fswsp, which aren't integer accesses, while c.lw and c.sw still expand.
+    for &compressed in &[0x6588, 0xffe4, 0x6542, 0xff86] {
+        assert_eq!(trap::expand_compressed(compressed, true), compressed);
+        assert_eq!(mmio_access_width(compressed, true), None);
+    }
+    assert_eq!(trap::expand_compressed(0x41c8, true), 0x0045a503);
+    assert_eq!(trap::expand_compressed(0xc1c8, true), 0x00a5a223);
+
+    // lb/lbu/lh/lw/lwu/ld a0, 0(a1) and sb/sh/sw/sd a0, 0(a1)
+    let (lb, lbu, lh, lw) = (0x00058503, 0x0005c503, 0x00059503, 0x0005a503);
+    let (lwu, ld) = (0x0005e503, 0x0005b503);
+    let (sb, sh, sw, sd) = (0x00a58023, 0x00a59023, 0x00a5a023, 0x00a5b023);
+    for &(instruction, width) in &[(lb, 1), (lbu, 1), (lh, 2), (lw, 4), (lwu, 4), (ld, 8),
+                                   (sb, 1), (sh, 2), (sw, 4), (sd, 8)] {
+        assert_eq!(mmio_access_width(instruction, false), Some(width));
+    }
+    assert_eq!(mmio_access_width(0x0505, false), None);
+    assert_eq!(mmio_load_result(lb, false, 0x80), Some((10, 0xffff_ffff_ffff_ff80)));
+    assert_eq!(mmio_load_result(lbu, false, 0x180), Some((10, 0x80)));
+    assert_eq!(mmio_load_result(lh, false, 0x8000), Some((10, 0xffff_ffff_ffff_8000)));
+    assert_eq!(mmio_load_result(lw, false, 0x8000_0000), Some((10, 0xffff_ffff_8000_0000)));
+    assert_eq!(mmio_load_result(lwu, false, 0x1_8000_0000), Some((10, 0x8000_0000)));
+    assert_eq!(mmio_load_result(ld, false, 1 << 63), Some((10, 1 << 63)));
+    assert_eq!(mmio_load_result(sb, false, 0), None);
+
+    // amoadd.d must carry out of the low word, while amoadd.w wraps within it.
+    assert_eq!(AtomicOp::Add.apply(8, 0xffff_ffff, 1), 0x1_0000_0000);
+    assert_eq!(AtomicOp::Add.apply(8, !0, 2), 1);
+    assert_eq!(AtomicOp::Add.apply(4, 0xffff_ffff, 1) as u32, 0);
+    assert_eq!(AtomicOp::Min.apply(4, 0xffff_ffff, 1), 0xffff_ffff);
+    assert_eq!(AtomicOp::Minu.apply(4, 0xffff_ffff, 1), 1);
+    assert_eq!(AtomicOp::Max.apply(8, 1 << 63, 1), 1);
+    assert_eq!(AtomicOp::Maxu.apply(8, 1 << 63, 1), 1 << 63);
+}
+
+/// Decode an AMO or LR/SC instruction into its operation, access width and operands.
+fn decode_atomic(instruction: u32, rv32: bool) -> Option<(AtomicOp, u64, RType)> {
+    Some(match trap::decode(instruction, rv32).ok()? {
+        Instruction::LrW(i) => (AtomicOp::Lr, 4, i),
+        Instruction::ScW(i) => (AtomicOp::Sc, 4, i),
+        Instruction::AmoswapW(i) => (AtomicOp::Swap, 4, i),
+        Instruction::AmoaddW(i) => (AtomicOp::Add, 4, i),
+        Instruction::AmoxorW(i) => (AtomicOp::Xor, 4, i),
+        Instruction::AmoandW(i) => (AtomicOp::And, 4, i),
+        Instruction::AmoorW(i) => (AtomicOp::Or, 4, i),
+        Instruction::AmominW(i) => (AtomicOp::Min, 4, i),
+        Instruction::AmomaxW(i) => (AtomicOp::Max, 4, i),
+        Instruction::AmominuW(i) => (AtomicOp::Minu, 4, i),
+        Instruction::AmomaxuW(i) => (AtomicOp::Maxu, 4, i),
+        Instruction::LrD(i) => (AtomicOp::Lr, 8, i),
+        Instruction::ScD(i) => (AtomicOp::Sc, 8, i),
+        Instruction::AmoswapD(i) => (AtomicOp::Swap, 8, i),
+        Instruction::AmoaddD(i) => (AtomicOp::Add, 8, i),
+        Instruction::AmoxorD(i) => (AtomicOp::Xor, 8, i),
+        Instruction::AmoandD(i) => (AtomicOp::And, 8, i),
+        Instruction::AmoorD(i) => (AtomicOp::Or, 8, i),
+        Instruction::AmominD(i) => (AtomicOp::Min, 8, i),
+        Instruction::AmomaxD(i) => (AtomicOp::Max, 8, i),
+        Instruction::AmominuD(i) => (AtomicOp::Minu, 8, i),
+        Instruction::AmomaxuD(i) => (AtomicOp::Maxu, 8, i),
+        _ => return None,
+    })
+}
+
+/// Width in bytes of the AMO or LR/SC `instruction`, or None if it isn't atomic.
+pub fn atomic_access_width(instruction: u32, rv32: bool) -> Option<u64> {
+    decode_atomic(instruction, rv32).map(|(_, width, _)| width)
+}
+
+/// Emulate an AMO or LR/SC `instruction` targeting `guest_pa`, which must be in guest memory. Since
+/// each guest has a single hart, nothing else can touch the memory while this runs; the aq/rl bits
+/// are treated as full fences. A misaligned address or one outside guest memory is reflected to the
+/// guest as a misaligned or access fault at the faulting virtual address. Returns false if
+/// `instruction` isn't atomic.
+pub fn emulate_atomic(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+    let width = match atomic_access_width(instruction, state.rv32) {
+        Some(width) => width,
+        None => return false,
+    };
+    if guest_pa % width != 0 || !state.guest_memory.in_region(guest_pa) {
+        // LR is a load, while SC and the AMOs report store/AMO exceptions.
+        let lr = match decode_atomic(instruction, state.rv32) {
+            Some((AtomicOp::Lr, _, _)) => true,
+            _ => false,
+        };
+        let cause = match (guest_pa % width != 0, lr) {
+            (true, true) => SCAUSE_LOAD_MISALIGNED,
+            (true, false) => SCAUSE_ATOMIC_MISALIGNED,
+            (false, true) => SCAUSE_LOAD_ACCESS_FAULT,
+            (false, false) => SCAUSE_STORE_ACCESS_FAULT,
+        };
+        trap::reflect_exception(state, cause, csrr!(sepc), csrr!(stval));
+        return true;
+    }
+
+    let index = guest_pa & !0x7;
+    let offset = (guest_pa % 8) as usize;
+    emulate_atomic_with(state, guest_pa, instruction, |state| {
+        let current = state.guest_memory[index].to_ne_bytes();
+        match width {
+            4 => NativeEndian::read_u32(&current[offset..]) as u64,
+            _ => u64::from_ne_bytes(current),
+        }
+    }, |state, new| {
+        let mut current = state.guest_memory[index].to_ne_bytes();
+        match width {
+            4 => NativeEndian::write_u32(&mut current[offset..], new as u32),
+            _ => current = new.to_ne_bytes(),
+        }
+        state.guest_memory[index] = u64::from_ne_bytes(current);
+    })
+}
+
+/// Emulate the AMO or LR/SC `instruction` on the value at `guest_pa`, which `read` loads and
+/// `write` stores, so that it also works on device registers. Values are passed zero extended from
+/// the width of the access. Returns false if `instruction` isn't atomic.
+fn emulate_atomic_with<R, W>(state: &mut Context, guest_pa: u64, instruction: u32, read: R,
+                             write: W) -> bool
+    where R: FnOnce(&mut Context) -> u64, W: FnOnce(&mut Context, u64)
+{
+    let (op, width, i) = match decode_atomic(instruction, state.rv32) {
+        Some(decoded) => decoded,
+        None => return false,
+    };
+
+    riscv::barrier();
+    let raw = read(state);
+    let old = match width {
+        4 => raw as i32 as i64 as u64,
+        _ => raw,
+    };
+    let src = state.rs2_value(&i);
+
+    let (result, new) = match op {
+        AtomicOp::Lr => {
+            state.reservation = Some(guest_pa);
+            (old, None)
+        }
+        AtomicOp::Sc => match state.reservation.take() {
+            Some(addr) if addr == guest_pa => (0, Some(src)),
+            _ => (1, None),
+        },
+        op => (old, Some(op.apply(width, old, src))),
+    };
+
+    if let Some(new) = new {
+        write(state, if width == 4 { new as u32 as u64 } else { new });
+    }
+    riscv::barrier();
+
+    state.set_rd(&i, result);
+    trap::skip_instruction(instruction);
+    true
+}
+
+fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+    // AMOs operate on the addressed byte register; the upper bytes read as zero and are dropped on
+    // writes, as they would be by the narrow register itself.
+    if emulate_atomic_with(state, guest_pa, instruction,
+                           |state| state.uart.read(&state.host_clint, guest_pa) as u64,
+                           |state, new| state.uart.write(&state.host_clint, guest_pa, new as u8)) {
+        return true;
+    }
+
+    match mmio_access_width(instruction, state.rv32) {
+        Some(1) => {}
+        Some(_) => {
+            println!("UART: Instruction {:#x} used to target addr {:#x} from pc {:#x}", instruction, guest_pa, csrr!(sepc));
+            loop {}
+        }
+        None => return false,
+    }
+
+    match emulate_mmio_store(state, instruction) {
+        Some(value) => state.uart.write(&state.host_clint, guest_pa, value as u8),
+        None => {
+            let value = state.uart.read(&state.host_clint, guest_pa) as u64;
+            emulate_mmio_load(state, instruction, value);
+        }
+    }
+    trap::skip_instruction(instruction);
+    true
+}
+
+fn plic_write(state: &mut Context, guest_pa: u64, value: u32) {
+    // println!("PLIC: Writing {:#x} to address {:#x}", value, guest_pa);
+    let mut clear_seip = false;
+    state.plic.write_u32(guest_pa, value, &mut clear_seip);
+    if clear_seip {
+        state.csrs.sip &= !0x200;
+    }
+    state.no_interrupt = false;
+}
+
+fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+    let rv32 = state.rv32;
+    let width = mmio_access_width(instruction, rv32)
+        .or_else(|| atomic_access_width(instruction, rv32));
+    match width {
+        Some(4) => {}
+        Some(_) => {
+            println!("PLIC: Instruction {:#x} used to target addr {:#x} from pc {:#x}", instruction, guest_pa, csrr!(sepc));
             loop {}
         }
-        _ => {
+        None => {
             println!("Unrecognized instruction targetting PLIC {:#x} at {:#x}!", instruction, csrr!(sepc));
             loop {}
         }
     }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+
+    if emulate_atomic_with(state, guest_pa, instruction,
+                           |state| state.plic.read_u32(guest_pa) as u64,
+                           |state, new| plic_write(state, guest_pa, new as u32)) {
+        return true;
+    }
+
+    match emulate_mmio_store(state, instruction) {
+        Some(value) => plic_write(state, guest_pa, value as u32),
+        None => {
+            let value = state.plic.read_u32(guest_pa) as u64;
+            // println!("PLIC: Read value {:#x} at address {:#x}", value, guest_pa);
+            emulate_mmio_load(state, instruction, value);
+        }
+    }
+    trap::skip_instruction(instruction);
+    true
+}
+
+/// Merge `value` into the `width` bytes of `register` starting at byte `offset`, given that the
+/// register currently holds `current`.
+fn clint_write(state: &mut Context, register: ClintRegister, current: u64, offset: u64, width: u64,
+               value: u64) {
+    let shift = offset * 8;
+    let mask = if width == 8 { !0 } else { 0xffffffffu64 << shift };
+    let new = (current & !mask) | ((value << shift) & mask);
+    match register {
+        ClintRegister::Msip => {
+            // The guest runs in S-mode, so machine software interrupts are delivered as
+            // supervisor software interrupts.
+            state.csrs.sip.set(IP_SSIP, new & 1 != 0);
+            state.no_interrupt = false;
+        }
+        ClintRegister::Mtimecmp => trap::set_guest_timer(state, new),
+        ClintRegister::Mtime => {}
+    }
+}
+
+/// Deliver a load or store/AMO access fault for `instruction` to the guest, at the virtual address
+/// that caused the current page fault. Used for accesses a device doesn't implement, such as an
+/// unsupported register or width.
+fn reflect_access_fault(state: &mut Context, instruction: u32) -> bool {
+    let cause = match AccessType::from_instruction(instruction, state.rv32) {
+        Some(AccessType::Read) => SCAUSE_LOAD_ACCESS_FAULT,
+        _ => SCAUSE_STORE_ACCESS_FAULT,
+    };
+    trap::reflect_exception(state, cause, csrr!(sepc), csrr!(stval));
+    true
+}
+
+fn handle_clint_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
+    let (register, offset) = match state.clint.as_ref().unwrap().register(guest_pa) {
+        Some(r) => r,
+        None => return reflect_access_fault(state, instruction),
+    };
+
+    let current = match register {
+        ClintRegister::Msip => (state.csrs.sip & IP_SSIP != 0) as u64,
+        ClintRegister::Mtimecmp => state.csrs.mtimecmp,
+        ClintRegister::Mtime => state.host_clint.get_mtime(),
+    };
+
+    let shift = offset * 8;
+    let rv32 = state.rv32;
+    let width = mmio_access_width(instruction, rv32)
+        .or_else(|| atomic_access_width(instruction, rv32));
+    let width = match width {
+        Some(4) if offset % 4 == 0 => 4,
+        Some(8) if offset == 0 && register.size() == 8 => 8,
+        Some(_) => return reflect_access_fault(state, instruction),
+        None => return false,
+    };
+
+    let field = if width == 8 { current } else { (current >> shift) as u32 as u64 };
+    if emulate_atomic_with(state, guest_pa, instruction,
+                           |_| field,
+                           |state, new| clint_write(state, register, current, offset, width, new)) {
+        return true;
+    }
+
+    match emulate_mmio_store(state, instruction) {
+        Some(value) => clint_write(state, register, current, offset, width, value),
+        None => { emulate_mmio_load(state, instruction, current >> shift); }
+    }
+
+    trap::skip_instruction(instruction);
     true
 }
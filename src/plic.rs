@@ -5,6 +5,9 @@ use crate::constants::MAX_GUEST_HARTS;
 /// have one M-mode context and one S-mode context.
 const MAX_CONTEXTS: usize = MAX_GUEST_HARTS * 2;
 
+/// Size of the PLIC's MMIO window.
+pub const PLIC_SIZE: u64 = 0x4000000;
+
 pub struct PlicState {
     base: u64,
     source_priority: [u32; 512],
@@ -15,9 +18,9 @@ pub struct PlicState {
 }
 
 impl PlicState {
-    pub const fn new() -> Self {
+    pub const fn new(base: u64) -> Self {
         Self {
-            base: 0x0c000000,
+            base,
             source_priority: [0; 512],
             pending: [0; 16],
             enable: [[0; 32]; MAX_CONTEXTS],
@@ -26,20 +29,34 @@ impl PlicState {
         }
     }
 
+    /// Return to the power-on state, with every source disabled and nothing pending.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.base);
+    }
+
+    /// Pending bits for every interrupt source, one bit per source.
+    pub fn pending_words(&self) -> [u32; 16] {
+        self.pending
+    }
+    pub fn set_pending_words(&mut self, pending: [u32; 16]) {
+        self.pending = pending;
+    }
+
+    /// Whether `addr` falls within the PLIC's MMIO window.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr < self.base + PLIC_SIZE
+    }
+
     pub fn read_u32(&mut self, addr: u64) -> u32 {
         let offset = addr.wrapping_sub(self.base);
-        if offset <= 0x800 {
+        if offset < 0x800 {
             self.source_priority[offset as usize >> 2]
-        } else if offset >= 0x1000 && offset <= 0x1014 {
-            self.pending[offset as usize >> 2]
+        } else if offset >= 0x1000 && offset < 0x1040 {
+            self.pending[(offset - 0x1000) as usize >> 2]
         } else if offset >= 0x2000 && offset < 0x2000 + 0x80 * MAX_CONTEXTS as u64 {
             let hart = (offset - 0x2000) / 0x80;
             let index = ((offset - 0x2000) & 0x7f) >> 2;
-            if index <= 32 {
-                self.enable[hart as usize][index as usize]
-            } else {
-                0
-            }
+            self.enable[hart as usize][index as usize]
         } else if offset >= 0x200000 && offset < 0x200000 + 0x1000 * MAX_CONTEXTS as u64 {
             let hart = ((offset - 0x200000) / 0x1000) as usize;
             let index = ((offset - 0x200000) & 0xfff) >> 2;
@@ -47,23 +64,7 @@ impl PlicState {
                 self.thresholds[hart]
             } else if index == 1 {
                 if self.claim_complete[hart] == 0 {
-                    let threshold = self.thresholds[hart];
-                    let mut max_priority = threshold;
-                    for i in 0..self.pending.len() {
-                        if self.pending[i] == 0 {
-                            continue;
-                        }
-
-                        for j in 0..32 {
-                            if self.pending[i] & (1 << j) != 0 {
-                                let interrupt = i*32 + j;
-                                if self.source_priority[interrupt] > max_priority {
-                                    max_priority = self.source_priority[interrupt];
-                                    self.claim_complete[hart] = interrupt as u32;
-                                }
-                            }
-                        }
-                    }
+                    self.claim_complete[hart] = self.highest_priority_pending(hart).unwrap_or(0);
                 }
                 self.set_pending(self.claim_complete[hart], false);
                 self.claim_complete[hart]
@@ -77,17 +78,14 @@ impl PlicState {
 
     pub fn write_u32(&mut self, addr: u64, value: u32, clear_seip: &mut bool) {
         let offset = addr.wrapping_sub(self.base);
-        if offset <= 0x800 {
+        if offset < 0x800 {
             self.source_priority[offset as usize >> 2] = value;
-        } else if offset >= 0x1000 && offset <= 0x1014 {
-            self.pending[offset as usize >> 2] = value;
+        } else if offset >= 0x1000 && offset < 0x1040 {
+            self.pending[(offset - 0x1000) as usize >> 2] = value;
         } else if offset >= 0x2000 && offset < 0x2000 + 0x80 * MAX_CONTEXTS as u64 {
             let hart = (offset - 0x2000) / 0x80;
             let index = ((offset - 0x2000) & 0x7f) >> 2;
-
-            if index <= 32 {
-                self.enable[hart as usize][index as usize] = value;
-            }
+            self.enable[hart as usize][index as usize] = value;
         } else if offset >= 0x200000 && offset < 0x200000 + 0x1000 * MAX_CONTEXTS as u64 {
             let hart = (offset - 0x200000) / 0x1000;
             let index = ((offset - 0x200000) & 0xfff) >> 2;
@@ -114,24 +112,33 @@ impl PlicState {
         }
     }
 
-    pub fn interrupt_pending(&self) -> bool {
-        const CONTEXT: usize = 1; // TODO: shouldn't be a constant
-
-        let threshold = self.thresholds[CONTEXT];
+    /// Returns the pending interrupt enabled for `context` with the highest priority above the
+    /// context's threshold. Ties go to the lowest numbered interrupt.
+    fn highest_priority_pending(&self, context: usize) -> Option<u32> {
+        let mut max_priority = self.thresholds[context];
+        let mut best = None;
         for i in 0..self.pending.len() {
-            if self.pending[i] == 0 {
+            let pending = self.pending[i] & self.enable[context][i];
+            if pending == 0 {
                 continue;
             }
 
             for j in 0..32 {
-                if self.pending[i] & (1 << j) != 0 {
-                    if self.source_priority[i*32 + j] > threshold {
-                        return true;
+                if pending & (1 << j) != 0 {
+                    let interrupt = i*32 + j;
+                    if self.source_priority[interrupt] > max_priority {
+                        max_priority = self.source_priority[interrupt];
+                        best = Some(interrupt as u32);
                     }
                 }
             }
         }
+        best
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        const CONTEXT: usize = 1; // TODO: shouldn't be a constant
 
-        false
+        self.highest_priority_pending(CONTEXT).is_some()
     }
 }
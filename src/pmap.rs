@@ -1,11 +1,15 @@
+use crate::audit::{AuditLog, AuditOp};
 use crate::fdt::MachineMeta;
-use crate::context::Context;
+use crate::context::{Context, PrivilegeMode};
 use crate::constants::SYMBOL_PA2VA_OFFSET;
-use crate::memory_region::{MemoryRegion, PageTableRegion};
-use crate::riscv;
+use crate::memory_region::{MemoryRegion, PageTableRegion, PteOutOfRegion};
+use crate::{riscv, trace, trap, virtio};
+use crate::riscv::bits::{SATP_MODE, STATUS_MXR, STATUS_SUM};
 use arr_macro::arr;
 use arrayvec::ArrayVec;
-use core::ptr;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv_decode::Instruction;
 use riscv_decode::types::RType;
 
 const PAGE_SIZE: u64 = 4096;
@@ -20,6 +24,11 @@ mod segment_layout {
     pub const STACK_SIZE: u64 = 2 << 20;
     pub const HEAP_OFFSET: u64 = STACK_OFFSET + STACK_SIZE;
     pub const HEAP_SIZE: u64 = 28 << 20;
+    /// The start of the heap holds the guest kernel image, and the rest is the hypervisor's own
+    /// allocator heap.
+    pub const KERNEL_IMAGE_SIZE: u64 = 26 << 20;
+    pub const ALLOC_HEAP_OFFSET: u64 = HEAP_OFFSET + KERNEL_IMAGE_SIZE;
+    pub const ALLOC_HEAP_SIZE: u64 = HEAP_SIZE - KERNEL_IMAGE_SIZE;
     pub const PT_REGION_OFFSET: u64 = HEAP_OFFSET + HEAP_SIZE;
     pub const PT_REGION_SIZE: u64 = 32 << 20;
     pub const VM_RESERVATION_SIZE: u64 = PT_REGION_OFFSET + PT_REGION_SIZE; // 64MB
@@ -48,10 +57,25 @@ pub use pte_flags::*;
 mod page_table_constants {
     pub const DIRECT_MAP_PT_INDEX: u64 = 0xf80;
     pub const DIRECT_MAP_OFFSET: u64 = DIRECT_MAP_PT_INDEX << 27 | ((!0) << 39);
-    pub const DIRECT_MAP_PAGES: u64 = 8; // Uses 1 GB pages
+    pub const DIRECT_MAP_PAGES: u64 = 8; // Uses 1 GB pages (boot page tables only)
+    /// Amount of physical memory covered by each root page table entry of the direct map.
+    pub const DIRECT_MAP_ENTRY_SIZE: u64 = 1 << 30;
+    /// Size of the pages the direct map is built from. Using 2MB pages costs a page table per root
+    /// entry, but works on hosts where 1GB pages aren't available or desirable.
+    #[cfg(not(feature = "direct_map_2mb_pages"))]
+    pub const DIRECT_MAP_PAGE_SIZE: u64 = 1 << 30;
+    #[cfg(feature = "direct_map_2mb_pages")]
+    pub const DIRECT_MAP_PAGE_SIZE: u64 = 2 << 20;
+    /// The direct map may use every root page table entry from `DIRECT_MAP_PT_INDEX` up to, but not
+    /// including, the last one (which maps the hypervisor itself).
+    pub const MAX_DIRECT_MAP_PAGES: u64 = 511 - DIRECT_MAP_PT_INDEX / 8;
 }
 pub use page_table_constants::*;
 
+/// Number of root page table entries (each spanning `DIRECT_MAP_ENTRY_SIZE`) covered by the direct
+/// map. Until `init` sizes it based on the amount of host memory this matches the boot page tables.
+static DIRECT_MAP_EXTENT: AtomicU64 = AtomicU64::new(DIRECT_MAP_PAGES);
+
 /// Make a minimal page table to boot into S mode. See [1] for FU540 errata related to mixing huge
 /// pages and PMP.
 ///
@@ -96,21 +120,108 @@ pub fn sa2pa(sa: u64) -> u64 {
     sa - SYMBOL_PA2VA_OFFSET
 }
 
+/// The shadow page tables. Which one is active is chosen by `active_root`.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum PageTableRoot {
+    /// Guest in user mode with paging enabled. Only guest PTEs with the U bit are shadowed.
     UVA,
+    /// Guest in supervisor mode with paging enabled and `sstatus.SUM` clear. Only guest PTEs
+    /// without the U bit are shadowed.
     KVA,
+    /// Guest in supervisor mode with paging enabled and `sstatus.SUM` set, so both user and
+    /// supervisor guest PTEs are shadowed.
     MVA,
+    /// Guest paging disabled (`satp.MODE` is Bare), in either privilege mode. Maps guest physical
+    /// addresses directly.
     MPA,
 }
 use PageTableRoot::*;
+impl PageTableRoot {
+    /// Every root, in index order.
+    pub const ALL: &'static [Self] = &[MPA, UVA, KVA, MVA];
+    /// The roots used while guest paging is enabled, which hold shadows of guest mappings.
+    pub const SHADOWS: &'static [Self] = &[UVA, KVA, MVA];
+
+    pub fn to_index(self) -> usize {
+        match self {
+            MPA => 0,
+            UVA => 1,
+            KVA => 2,
+            MVA => 3,
+        }
+    }
 
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).cloned()
+    }
+}
+
+/// Link stored in the first word of the last page on the free list. Free pages are always page
+/// aligned, so this can never be mistaken for one, and it has the valid bit clear so that it can be
+/// written with `set_invalid_pte`.
 const NULL_PAGE_PTR: u64 = 2;
 
+/// Level of the root of each Sv39 shadow page table, which has three levels of tables below and
+/// including the root.
+const ROOT_TABLE_LEVEL: u8 = 2;
+/// Level of the root of each Sv48 shadow page table, used while the guest itself uses Sv48.
+const SV48_ROOT_TABLE_LEVEL: u8 = 3;
+
+/// Counters tracking how guest TLB flushes are handled by the shadow page tables.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FlushStats {
+    /// Total number of flushes, including ones that were ignored.
+    pub total_flushes: u64,
+    /// Flushes that discarded every guest mapping (other than global ones, for ASID-scoped flushes).
+    pub full_flushes: u64,
+    /// Flushes that only targeted the mappings for a single address.
+    pub targeted_flushes: u64,
+    /// Flushes skipped because they were for an ASID other than the current one.
+    pub ignored_flushes: u64,
+    /// Number of valid shadow PTEs that have been cleared.
+    pub ptes_invalidated: u64,
+}
+
+/// The shadow page tables for a single hart.
+///
+/// Every hart runs its own guest and owns its own `PageTables` (held in the hart's `Context`, which
+/// lives in the per-hart data segment). The page table region, free list, and all four roots are
+/// thus private to the hart and are never accessed concurrently, so no locking is needed beyond
+/// that of `CONTEXT` itself. The only state shared between harts is what the roots point to: the
+/// hypervisor code and shared data segments, and the direct map of host physical memory. Those
+/// mappings are installed once by `init` and never modified afterwards.
 pub struct PageTables {
     region: PageTableRegion,
-    root_page_tables: [u64; 4],
-    free_list_head: u64,
+    root_page_tables: [u64; PageTableRoot::ALL.len()],
+    /// Roots used in place of those in `root_page_tables` (other than MPA) while `sv48` is set. The
+    /// last entry of each points to a table whose upper entries are copies of those of the Sv39
+    /// root, so that the direct map and hypervisor mappings are the same in both.
+    sv48_root_page_tables: [u64; PageTableRoot::ALL.len()],
+    /// Whether guest virtual addresses are currently shadowed with Sv48 page tables.
+    sv48: bool,
+    /// Whether the host supports Sv48. Set by `init_sv48_roots`.
+    host_sv48: bool,
+    /// First free page. Each free page holds the address of the next in its first word, or
+    /// `NULL_PAGE_PTR` if it is the last. Every page on the list is page aligned and inside `region`.
+    free_list_head: Option<u64>,
+    direct_map_pages: u64,
+
+    total_pages: u64,
+    free_pages: u64,
+    min_free_pages: u64,
+    total_allocations: u64,
+
+    flush_stats: FlushStats,
+    /// ASID of the guest address space the shadow mappings were created for. The shadow page tables
+    /// are not ASID tagged, so they never hold translations for any other address space.
+    asid: u64,
+
+    /// Whether TLB flushes are currently being deferred by `with_batch`.
+    batching: bool,
+    /// Whether a TLB flush was skipped during the current batch.
+    batch_needs_fence: bool,
+
+    audit_log: AuditLog,
 }
 impl PageTables {
     /// Create a set of page tables from a memory region.
@@ -118,22 +229,47 @@ impl PageTables {
     /// The `initrd_start` and `initrd_end` parameters are an unfortunate implementation detail: the
     /// bootloader might have placed the init RAM disk inside our page table region. If this
     /// happened, we must make sure not to mark those pages as free until we're done using it.
-    pub fn new(region: MemoryRegion, initrd_start: u64, initrd_end: u64) -> Self {
+    pub fn new(region: MemoryRegion, initrd_start: u64, initrd_end: u64, direct_map_pages: u64) -> Self {
         let start = region.base();
         let end = start + region.len();
         let region = PageTableRegion::new(region);
 
         let mut ret = Self {
             region,
-            root_page_tables: [0, 0, 0, 0],
-            free_list_head: NULL_PAGE_PTR,
+            root_page_tables: [0; PageTableRoot::ALL.len()],
+            sv48_root_page_tables: [0; PageTableRoot::ALL.len()],
+            sv48: false,
+            host_sv48: false,
+            free_list_head: None,
+            direct_map_pages,
+            total_pages: (end - start) / PAGE_SIZE,
+            free_pages: 0,
+            min_free_pages: u64::max_value(),
+            total_allocations: 0,
+            flush_stats: FlushStats::default(),
+            asid: 0,
+            batching: false,
+            batch_needs_fence: false,
+            audit_log: AuditLog::new(),
         };
 
+        // An initrd that overlaps the region must lie entirely within it, since otherwise part of
+        // the region would be shared with memory the page tables don't own.
+        assert!(initrd_start <= initrd_end, "initrd ends before it starts ({:#x}..{:#x})",
+                initrd_start, initrd_end);
+        let initrd_overlaps = initrd_start < initrd_end && initrd_start < end && initrd_end > start;
+        if initrd_overlaps {
+            assert!(initrd_start >= start && initrd_end <= end,
+                    "initrd {:#x}..{:#x} straddles the page table region {:#x}..{:#x}",
+                    initrd_start, initrd_end, start, end);
+        }
+
         // initialize free list
         assert_eq!(start % PAGE_SIZE, 0);
         let mut addr = start;
         while addr < end {
-            if addr + PAGE_SIZE <= initrd_start || addr >= initrd_end {
+            let in_initrd = initrd_overlaps && addr + PAGE_SIZE > initrd_start && addr < initrd_end;
+            if !in_initrd {
                 ret.free_page(addr);
             }
 
@@ -141,114 +277,775 @@ impl PageTables {
         }
 
         // initialize root page tables
-        for i in 0..4 {
-            ret.root_page_tables[i] = ret.alloc_page();
+        for &root in PageTableRoot::ALL {
+            ret.root_page_tables[root.to_index()] = ret.alloc_page().expect("Out of hypervisor memory for page tables");
         }
 
         ret
     }
 
+    /// Number of root page table entries spanned by the direct map of host physical memory.
+    pub fn direct_map_pages(&self) -> u64 {
+        self.direct_map_pages
+    }
+
+    /// Number of pages currently on the free list.
+    pub fn free_pages(&self) -> u64 {
+        self.free_pages
+    }
+
+    /// Number of pages not on the free list. This includes pages reserved for the init RAM disk.
+    pub fn used_pages(&self) -> u64 {
+        self.total_pages - self.free_pages
+    }
+
+    /// The smallest number of free pages there have ever been.
+    pub fn low_memory_watermark(&self) -> u64 {
+        self.min_free_pages
+    }
+
+    /// Total number of page allocations made since creation.
+    pub fn total_allocations(&self) -> u64 {
+        self.total_allocations
+    }
+
+    pub fn flush_stats(&self) -> FlushStats {
+        self.flush_stats
+    }
+
     pub fn root_pa(&self, root: PageTableRoot) -> u64 {
-        let i = match root {
-            MPA => 0,
-            UVA => 1,
-            KVA => 2,
-            MVA => 3,
-        };
-        self.root_page_tables[i]
+        self.root_page_tables[root.to_index()]
+    }
+
+    /// Recent changes to the shadow mappings. Always empty without the `shadow_audit` feature.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    /// Returns the table at the top of the shadow page table currently used for `root`, along with
+    /// its level.
+    pub fn shadow_root(&self, root: PageTableRoot) -> (u64, u8) {
+        if self.sv48 && root != MPA {
+            (self.sv48_root_page_tables[root.to_index()], SV48_ROOT_TABLE_LEVEL)
+        } else {
+            (self.root_pa(root), ROOT_TABLE_LEVEL)
+        }
+    }
+
+    /// Whether the host can run Sv48 page tables, and so shadow a guest using Sv48.
+    pub fn supports_sv48(&self) -> bool {
+        self.host_sv48
+    }
+
+    /// Switch between shadowing guest virtual addresses with Sv39 and Sv48 page tables, discarding
+    /// every guest mapping if that changes anything.
+    pub fn set_sv48(&mut self, sv48: bool) {
+        assert!(!sv48 || self.host_sv48, "Host doesn't support Sv48");
+        if sv48 != self.sv48 {
+            self.invalidate_all();
+            self.sv48 = sv48;
+        }
+    }
+
+    /// Build the Sv48 roots out of the Sv39 ones, which must already hold the hypervisor's own
+    /// mappings, then check whether the host supports Sv48 by briefly installing one. An unsupported
+    /// mode leaves satp unchanged.
+    unsafe fn init_sv48_roots(&mut self) {
+        for &root in PageTableRoot::SHADOWS {
+            let sv39_root = self.root_pa(root);
+            let upper = self.alloc_page().expect("Out of hypervisor memory for page tables");
+            for i in DIRECT_MAP_PT_INDEX / 8..512 {
+                let pte = self.region[sv39_root + i * 8];
+                self.region.set_pte_unchecked(upper + i * 8, pte);
+            }
+
+            let top = self.alloc_page().expect("Out of hypervisor memory for page tables");
+            self.region.set_nonleaf_pte(top + 511 * 8, (upper >> 2) | PTE_VALID);
+            self.sv48_root_page_tables[root.to_index()] = top;
+        }
+
+        let old_satp = csrr!(satp);
+        let probe = (9 << 60) | (self.sv48_root_page_tables[KVA.to_index()] >> 12);
+        csrw!(satp, probe);
+        self.host_sv48 = csrr!(satp) == probe;
+        csrw!(satp, old_satp);
+        riscv::sfence_vma();
+    }
+
+    /// Whether `va` can have a shadow mapping: it must be canonical for the current shadow mode and
+    /// below the direct map, which starts the hypervisor's own part of the address space.
+    fn is_shadowable(&self, va: u64) -> bool {
+        va < DIRECT_MAP_OFFSET && if self.sv48 { is_sv48(va) } else { is_sv39(va) }
+    }
+
+    /// Clear every guest mapping from the shadow page table for `root`, sparing global leaves if
+    /// `keep_global` is set.
+    fn clear_guest_mappings(&mut self, root: PageTableRoot, keep_global: bool) {
+        let mut ranges = ArrayVec::<[(u64, u8, u64); 2]>::new();
+        let (top, level) = self.shadow_root(root);
+        if level == SV48_ROOT_TABLE_LEVEL {
+            ranges.push((top, level, 511));
+            let upper = (self.region[top + 511 * 8] >> 10) << 12;
+            ranges.push((upper, ROOT_TABLE_LEVEL, DIRECT_MAP_PT_INDEX / 8));
+        } else {
+            ranges.push((top, level, DIRECT_MAP_PT_INDEX / 8));
+        }
+
+        for (pa, level, end) in ranges {
+            if keep_global {
+                self.clear_non_global_range(pa, level, 0, end);
+            } else {
+                self.clear_page_table_range(pa, level, 0, end);
+            }
+        }
     }
 
     pub fn install_root(&self, root: PageTableRoot) {
-        let new_satp = (8 << 60) | (self.root_pa(root) >> 12);
+        let (top, level) = self.shadow_root(root);
+        let mode = if level == SV48_ROOT_TABLE_LEVEL { 9 } else { 8 };
+        let new_satp = (mode << 60) | (top >> 12);
         if csrr!(satp) != new_satp {
             unsafe { csrw!(satp, new_satp) }
             riscv::sfence_vma();
         }
     }
 
-    pub fn rmw_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64) -> u64 {
-        if va >= DIRECT_MAP_OFFSET {
-            panic!("Guest attempted to access reserved virtual address: {:x}", va);
+    /// Run `f` with per-address TLB flushes suppressed, and then do a single global flush at the end
+    /// if any were needed. Taking a closure ensures that the guest cannot run until the batch is
+    /// complete.
+    pub fn with_batch<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+        assert!(!self.batching);
+        self.batching = true;
+        let t = f(self);
+        self.batching = false;
+
+        if self.batch_needs_fence {
+            self.batch_needs_fence = false;
+            riscv::sfence_vma();
+        }
+        t
+    }
+
+    fn sfence_vma_addr(&mut self, va: u64) {
+        if self.batching {
+            self.batch_needs_fence = true;
+        } else {
+            riscv::sfence_vma_addr(va);
+        }
+    }
+
+    /// Install `pte` as the leaf mapping `va` at the given level, returning the previous contents
+    /// of that PTE. Any page table previously hanging off of a superpage slot is freed.
+    ///
+    /// Fails if `va` can't be shadowed because it overlaps the hypervisor's direct map or isn't a
+    /// valid Sv39 (or with an Sv48 guest, Sv48) address, or if there wasn't enough memory to
+    /// allocate intermediate page tables.
+    pub fn rmw_mapping(&mut self, root: PageTableRoot, va: u64, pte: u64, level: PageTableLevel)
+                       -> Result<u64, MappingError> {
+        if !self.is_shadowable(va) {
+            return Err(MappingError::ReservedAddress);
         }
+        assert_eq!(va % level.page_size(), 0);
 
-        let pte_addr = self.pte_for_addr(root, va);
+        let pte_addr = self.pte_for_addr(root, va, level).ok_or(MappingError::OutOfMemory)?;
         let old = self.region[pte_addr];
+        if old & PTE_RWXV == PTE_VALID {
+            let page = (old >> 10) << 12;
+            self.clear_page_table(page, level.table_level() - 1);
+            self.free_page(page);
+            self.sfence_vma_addr(va);
+        }
         self.region.set_leaf_pte(pte_addr, pte);
-        old
+        self.audit_log.record(AuditOp::Map, root, va, (pte >> 10) << 12, pte & 0x3ff);
+        Ok(old)
     }
 
-    // Returns the physical address of the pte for a given virtual address.
-    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64) -> u64 {
-        // These ranges use huge pages...
-        assert!(va < DIRECT_MAP_OFFSET);
-        assert!(is_sv39(va));
+    // Returns the physical address of the pte for a given virtual address at the given level,
+    // allocating intermediate page tables as needed. Callers must have already rejected addresses
+    // that can't be shadowed.
+    fn pte_for_addr(&mut self, root: PageTableRoot, va: u64, level: PageTableLevel) -> Option<u64> {
         assert!(root != PageTableRoot::MPA);
 
-        let mut page_table = self.root_pa(root);
-        for level in 0..2 {
-            let pte_index = (va >> (30 - 9 * level)) & 0x1ff;
+        // These ranges use huge pages...
+        assert!(self.is_shadowable(va));
+        assert!(level <= PageTableLevel::Level1GB && level != PageTableLevel::Level4MB);
+
+        let (mut page_table, top) = self.shadow_root(root);
+        for table_level in (level.table_level() + 1..=top).rev() {
+            let pte_index = (va >> (12 + 9 * table_level)) & 0x1ff;
             let pte_addr = page_table + pte_index * 8;
             let pte = self.region[pte_addr];
 
-            if pte & PTE_VALID != 0 {
-                assert_eq!(pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE), 0);
+            if pte & PTE_RWXV == PTE_VALID {
                 page_table = (pte >> 10) << 12;
             } else {
-                let page = self.alloc_page();
+                // Any superpage mapping here is simply discarded. The guest will fault on the rest
+                // of the range and we'll fill it back in at a smaller page size.
+                let page = self.alloc_page()?;
                 self.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
                 page_table = page;
+                if pte & PTE_VALID != 0 {
+                    self.sfence_vma_addr(va);
+                }
+            }
+        }
+        Some(page_table + ((va >> (12 + 9 * level.table_level())) & 0x1ff) * 8)
+    }
+
+    /// Returns the physical address of the MPA pte for `guest_pa` at the given level, allocating
+    /// intermediate page tables as needed. Unlike the shadow roots, MPA mappings are never refilled
+    /// on demand (unless guest memory is mapped lazily), so any superpage in the way is split into
+    /// equivalent smaller mappings rather than discarded.
+    pub fn mpa_pte_for_addr(&mut self, guest_pa: u64, level: PageTableLevel) -> Option<u64> {
+        // The upper half of the MPA root holds the direct map and hypervisor mappings.
+        assert!(guest_pa < 1 << 38, "Guest physical address {:#x} out of range", guest_pa);
+
+        let depth = match level {
+            PageTableLevel::Level1GB => 0,
+            PageTableLevel::Level2MB => 1,
+            PageTableLevel::Level4KB => 2,
+            PageTableLevel::Level4MB | PageTableLevel::Level512GB => unreachable!(),
+        };
+
+        let mut page_table = self.root_pa(MPA);
+        for level in 0..depth {
+            let shift = 30 - 9 * level;
+            let pte_addr = page_table + ((guest_pa >> shift) & 0x1ff) * 8;
+            let pte = self.region[pte_addr];
+
+            if pte & PTE_RWXV == PTE_VALID {
+                page_table = (pte >> 10) << 12;
+                continue;
+            }
+
+            let page = self.alloc_page()?;
+            if pte & PTE_VALID != 0 {
+                let child_size = 1 << (shift - 9);
+                let base = (pte >> 10) << 12;
+                for i in 0..512 {
+                    self.region.set_leaf_pte(page + i * 8, ((base + i * child_size) >> 2) | (pte & 0x3ff));
+                }
+            }
+            self.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
+            page_table = page;
+            if pte & PTE_VALID != 0 {
+                self.sfence_vma_addr(guest_pa);
+            }
+        }
+        Some(page_table + ((guest_pa >> (30 - 9 * depth)) & 0x1ff) * 8)
+    }
+
+    /// Map the guest physical page at `guest_pa` to `host_pa` in MPA, replacing whatever was mapped
+    /// there before. Returns None if there wasn't enough memory for intermediate page tables.
+    pub fn mpa_set_mapping(&mut self, guest_pa: u64, host_pa: u64, level: PageTableLevel) -> Option<()> {
+        assert_eq!(guest_pa % level.page_size(), 0);
+        assert_eq!(host_pa % level.page_size(), 0);
+
+        let pte_addr = self.mpa_pte_for_addr(guest_pa, level)?;
+        self.mpa_clear_pte(pte_addr, level);
+        let pte = (host_pa >> 2) | PTE_AD | PTE_USER | PTE_RWXV;
+        self.region.set_leaf_pte(pte_addr, pte);
+        self.audit_log.record(AuditOp::Map, MPA, guest_pa, host_pa, pte & 0x3ff);
+        self.sfence_vma_addr(guest_pa);
+        Some(())
+    }
+
+    /// Remove write permission from the MPA mappings of `[guest_pa, guest_pa+len)`, splitting any
+    /// superpage that covers part of the range. Pages that aren't mapped yet are skipped. Returns
+    /// None if there wasn't enough memory to split a superpage.
+    pub fn mpa_write_protect(&mut self, guest_pa: u64, len: u64) -> Option<()> {
+        assert_eq!(guest_pa % PAGE_SIZE, 0);
+        assert_eq!(len % PAGE_SIZE, 0);
+
+        for page in (guest_pa..guest_pa + len).step_by(PAGE_SIZE as usize) {
+            if self.mpa_leaf_level(page).is_err() {
+                continue;
+            }
+            let pte_addr = self.mpa_pte_for_addr(page, PageTableLevel::Level4KB)?;
+            let pte = self.region[pte_addr] & !PTE_WRITE;
+            self.region.set_leaf_pte(pte_addr, pte);
+            self.audit_log.record(AuditOp::Map, MPA, page, (pte >> 10) << 12, pte & 0x3ff);
+            self.sfence_vma_addr(page);
+        }
+        Some(())
+    }
+
+    /// Returns the level of the MPA leaf mapping `guest_pa`. If nothing maps it, returns the level of
+    /// the invalid entry instead, in which case nothing in the surrounding page of that size is mapped.
+    pub fn mpa_leaf_level(&self, guest_pa: u64) -> Result<PageTableLevel, PageTableLevel> {
+        let (mut page_table, top) = self.shadow_root(MPA);
+        for table_level in (0..=top).rev() {
+            let level = PageTableLevel::from_table_level(table_level);
+            let pte_index = (guest_pa >> level.page_size().trailing_zeros()) & 0x1ff;
+            let pte = self.region[page_table + pte_index * 8];
+
+            if pte & PTE_VALID == 0 {
+                return Err(level);
+            } else if pte & PTE_RWXV != PTE_VALID {
+                return Ok(level);
+            }
+            page_table = (pte >> 10) << 12;
+        }
+        Err(PageTableLevel::Level4KB)
+    }
+
+    /// Remove the MPA mapping for the page at `guest_pa`, splitting any superpage that covers it.
+    /// Returns None if there wasn't enough memory to split a superpage.
+    pub fn mpa_unmap(&mut self, guest_pa: u64, level: PageTableLevel) -> Option<()> {
+        assert_eq!(guest_pa % level.page_size(), 0);
+
+        let pte_addr = self.mpa_pte_for_addr(guest_pa, level)?;
+        self.mpa_clear_pte(pte_addr, level);
+        self.audit_log.record(AuditOp::Unmap, MPA, guest_pa, 0, 0);
+        self.sfence_vma_addr(guest_pa);
+        Some(())
+    }
+
+    /// Invalidate an MPA pte at `level`, freeing the page table it points to if it isn't a leaf.
+    fn mpa_clear_pte(&mut self, pte_addr: u64, level: PageTableLevel) {
+        let pte = self.region[pte_addr];
+        if pte & PTE_RWXV == PTE_VALID {
+            let page = (pte >> 10) << 12;
+            self.clear_page_table(page, level.table_level() - 1);
+            self.free_page(page);
+        }
+        self.region.set_invalid_pte(pte_addr, 0);
+    }
+
+    /// Remove the mapping for `va` from the given root. Any intermediate page tables left without
+    /// valid entries are returned to the free list.
+    pub fn unmap(&mut self, root: PageTableRoot, va: u64) {
+        assert!(root != PageTableRoot::MPA);
+        if !self.is_shadowable(va) {
+            return;
+        }
+
+        let mut path = ArrayVec::<[u64; 4]>::new();
+        let (mut page_table, top) = self.shadow_root(root);
+        for table_level in (0..=top).rev() {
+            let pte_addr = page_table + ((va >> (12 + 9 * table_level)) & 0x1ff) * 8;
+            let pte = self.region[pte_addr];
+            if pte & PTE_VALID == 0 {
+                return;
+            }
+
+            path.push(pte_addr);
+            if pte & PTE_RWXV != PTE_VALID {
+                break;
+            }
+            page_table = (pte >> 10) << 12;
+        }
+
+        let leaf = path.pop().unwrap();
+        self.region.set_invalid_pte(leaf, 0);
+        self.audit_log.record(AuditOp::Unmap, root, va, 0, 0);
+
+        // Walk back up towards the root (which is never freed) releasing empty page tables. The table
+        // under the last entry of an Sv48 root always holds the hypervisor's mappings, so it's never
+        // empty.
+        let mut page_table = leaf & !(PAGE_SIZE - 1);
+        while let Some(parent) = path.pop() {
+            if (0..512).any(|i| self.region[page_table + i * 8] & PTE_VALID != 0) {
+                break;
+            }
+
+            self.region.set_invalid_pte(parent, 0);
+            self.free_page(page_table);
+            page_table = parent & !(PAGE_SIZE - 1);
+        }
+
+        self.sfence_vma_addr(va);
+    }
+
+    /// Returns the physical address and level of the leaf PTE mapping `va`, or None if `va` is not
+    /// mapped. Unlike `pte_for_addr` this never allocates page tables.
+    fn find_leaf_pte(&self, root: PageTableRoot, va: u64) -> Option<(u64, PageTableLevel)> {
+        if !self.is_shadowable(va) {
+            return None;
+        }
+
+        let (mut page_table, top) = self.shadow_root(root);
+        for table_level in (0..=top).rev() {
+            let level = PageTableLevel::from_table_level(table_level);
+            let pte_index = (va >> level.page_size().trailing_zeros()) & 0x1ff;
+            let pte_addr = page_table + pte_index * 8;
+            let pte = self.region[pte_addr];
+
+            if pte & PTE_VALID == 0 {
+                return None;
+            } else if pte & PTE_RWXV != PTE_VALID {
+                return Some((pte_addr, level));
+            }
+            page_table = (pte >> 10) << 12;
+        }
+        None
+    }
+
+    /// Remove every shadow mapping of guest virtual addresses and flush the TLB. Unlike a guest
+    /// `sfence.vma` this can be used at any point, including during teardown or reconfiguration.
+    pub fn invalidate_all(&mut self) {
+        self.flush_stats.total_flushes += 1;
+        self.flush_stats.full_flushes += 1;
+        for &root in PageTableRoot::SHADOWS {
+            self.clear_guest_mappings(root, false);
+            self.audit_log.record(AuditOp::Flush, root, 0, 0, 0);
+        }
+
+        riscv::sfence_vma();
+    }
+
+    /// Remove every shadow mapping belonging to `asid`. Returns whether anything was invalidated,
+    /// which is only the case if `asid` is the one the shadow page tables currently hold.
+    ///
+    /// Shadow leaves copy the guest's `PTE_GLOBAL` bit, and like an ASID-scoped `sfence.vma` this
+    /// leaves global mappings in place.
+    pub fn invalidate_asid(&mut self, asid: u64) -> bool {
+        self.flush_stats.total_flushes += 1;
+        if asid != self.asid {
+            self.flush_stats.ignored_flushes += 1;
+            return false;
+        }
+
+        self.flush_stats.full_flushes += 1;
+        for &root in PageTableRoot::SHADOWS {
+            self.clear_guest_mappings(root, true);
+            self.audit_log.record(AuditOp::Flush, root, 0, 0, 0);
+        }
+
+        riscv::sfence_vma();
+        true
+    }
+
+    /// Remove the shadow mappings of the guest page containing `va`, as a guest `sfence.vma` of
+    /// that address does, sparing global leaves if `keep_global` is set.
+    fn flush_guest_page(&mut self, va: u64, keep_global: bool) {
+        self.flush_stats.total_flushes += 1;
+        self.flush_stats.targeted_flushes += 1;
+        if va >= DIRECT_MAP_OFFSET {
+            return;
+        }
+
+        // Only the guest page containing va is invalidated. If it was shadowed by several smaller
+        // pages the TLB could hold any of them, so a global fence is needed instead.
+        let mut needs_global_fence = false;
+        for &root in PageTableRoot::SHADOWS {
+            if let Some((pte_addr, level)) = self.find_leaf_pte(root, va) {
+                if keep_global && self.region[pte_addr] & PTE_GLOBAL != 0 {
+                    continue;
+                }
+                // The reserved bits of the shadow PTE record the size of the guest mapping, which
+                // may be larger than the page size used for the shadow mapping.
+                let guest_level = match (self.region[pte_addr] >> 8) & 0x3 {
+                    0 => PageTableLevel::Level4KB,
+                    1 => PageTableLevel::Level2MB,
+                    2 => PageTableLevel::Level1GB,
+                    _ => PageTableLevel::Level512GB,
+                };
+                needs_global_fence |= guest_level != level;
+                self.clear_guest_page(root, va, guest_level);
+            }
+        }
+        if needs_global_fence {
+            riscv::sfence_vma();
+        } else {
+            riscv::sfence_vma_addr(va);
+        }
+    }
+
+    /// Switch the shadow page tables to a new guest address space, discarding all old mappings.
+    pub fn set_asid(&mut self, asid: u64) {
+        self.asid = asid;
+        self.invalidate_all();
+    }
+
+    pub fn asid(&self) -> u64 {
+        self.asid
+    }
+
+    /// Invalidate the shadow mappings for the guest page of size `level` containing `va`. This
+    /// covers both a shadow leaf of the same size and any smaller leaves that were created for it.
+    fn clear_guest_page(&mut self, root: PageTableRoot, va: u64, level: PageTableLevel) {
+        self.audit_log.record(AuditOp::Unmap, root, va, 0, 0);
+        let (mut page_table, top) = self.shadow_root(root);
+        if level == PageTableLevel::Level512GB && top == ROOT_TABLE_LEVEL {
+            self.clear_guest_mappings(root, false);
+            return;
+        }
+
+        for table_level in (0..=top).rev() {
+            let pte_index = (va >> (12 + 9 * table_level)) & 0x1ff;
+            let pte = self.region[page_table + pte_index * 8];
+            if table_level == SV48_ROOT_TABLE_LEVEL && pte_index == 511 {
+                // This entry also holds the hypervisor's mappings, so only its guest part is cleared.
+                page_table = (pte >> 10) << 12;
+                if level == PageTableLevel::Level512GB {
+                    self.clear_page_table_range(page_table, ROOT_TABLE_LEVEL, 0, DIRECT_MAP_PT_INDEX/8);
+                    return;
+                }
+                continue;
             }
+            if table_level == level.table_level() || pte & PTE_RWXV != PTE_VALID {
+                self.clear_page_table_range(page_table, table_level, pte_index, pte_index + 1);
+                return;
+            }
+            page_table = (pte >> 10) << 12;
         }
-        page_table + ((va >> 12) & 0x1ff) * 8
     }
 
-    pub fn clear_page_table(&mut self, pa: u64) {
-        self.clear_page_table_range(pa, 0, 512);
+    /// Clear the page table at `pa`, which sits at `level` of the tree (`ROOT_TABLE_LEVEL` or
+    /// `SV48_ROOT_TABLE_LEVEL` for a root, zero for a table of 4KB leaves).
+    pub fn clear_page_table(&mut self, pa: u64, level: u8) {
+        self.clear_page_table_range(pa, level, 0, 512);
     }
-    pub fn clear_page_table_range(&mut self, pa: u64, start_index: u64, end_index: u64) {
+    pub fn clear_page_table_range(&mut self, pa: u64, level: u8, start_index: u64, end_index: u64) {
         assert!(start_index <= end_index);
         assert!(end_index <= 512);
+        assert!(level <= SV48_ROOT_TABLE_LEVEL);
 
         for i in start_index..end_index {
+            // Leaf PTEs (including superpages at higher levels) only point into guest memory, so
+            // only non-leaf entries have pages that need to be freed.
             let pte = self.region[pa + i * 8];
+            if pte & PTE_VALID != 0 {
+                self.flush_stats.ptes_invalidated += 1;
+            }
             if pte & PTE_RWXV == PTE_VALID {
+                // The recursion is bounded by the depth of the tree, so a corrupt table can't send
+                // it off following pointers indefinitely.
+                assert!(level > 0, "Shadow page table {:#x} has non-leaf entry {:#x} at the lowest level",
+                        pa, pte);
                 let page = (pte >> 10) << 12;
-                self.clear_page_table(page);
+                self.clear_page_table(page, level - 1);
                 self.free_page(page);
             }
             self.region.set_invalid_pte(pa + i * 8, 0);
         }
     }
 
-    fn alloc_page(&mut self) -> u64 {
-        if self.free_list_head == NULL_PAGE_PTR {
-            panic!("Out of hypervisor memory for page tables");
+    /// Like `clear_page_table_range`, but keep global leaf mappings. Page tables left without any
+    /// valid entries are freed. Returns whether anything in the range is still mapped.
+    fn clear_non_global_range(&mut self, pa: u64, level: u8, start_index: u64, end_index: u64) -> bool {
+        assert!(start_index <= end_index);
+        assert!(end_index <= 512);
+        assert!(level <= SV48_ROOT_TABLE_LEVEL);
+
+        let mut remaining = false;
+        for i in start_index..end_index {
+            let pte = self.region[pa + i * 8];
+            if pte & PTE_VALID == 0 {
+                continue;
+            } else if pte & PTE_RWXV == PTE_VALID {
+                assert!(level > 0, "Shadow page table {:#x} has non-leaf entry {:#x} at the lowest level",
+                        pa, pte);
+                let page = (pte >> 10) << 12;
+                if self.clear_non_global_range(page, level - 1, 0, 512) {
+                    remaining = true;
+                    continue;
+                }
+                self.free_page(page);
+            } else if pte & PTE_GLOBAL != 0 {
+                remaining = true;
+                continue;
+            }
+
+            self.flush_stats.ptes_invalidated += 1;
+            self.region.set_invalid_pte(pa + i * 8, 0);
         }
+        remaining
+    }
 
-        let free = self.free_list_head;
-        self.free_list_head = self.region[free];
+    /// Allocate a zeroed page, or return None if no free pages remain.
+    #[inline]
+    fn alloc_page(&mut self) -> Option<u64> {
+        let free = self.free_list_head?;
+        let next = self.region[free];
+        self.free_list_head = if next == NULL_PAGE_PTR {
+            None
+        } else {
+            // A bad link means something wrote to a page after it was freed, so stop here rather
+            // than handing out arbitrary memory as page tables.
+            assert!(self.is_page_in_region(next), "Corrupt page table free list: page {:#x} links to {:#x}",
+                    free, next);
+            Some(next)
+        };
+        self.free_pages -= 1;
+        self.min_free_pages = self.min_free_pages.min(self.free_pages);
+        self.total_allocations += 1;
 
+        // Pages are zeroed when freed, so unless that's disabled only the free list link remains.
+        let dirty_bytes = if cfg!(feature = "skip_zero_on_free") { PAGE_SIZE } else { 8 };
         let mut addr = free;
-        while addr < free + PAGE_SIZE {
+        while addr < free + dirty_bytes {
             self.region.set_invalid_pte(addr, 0);
             addr += 8;
         }
 
-        free
+        Some(free)
     }
 
     fn free_page(&mut self, page: u64) {
-        self.region.set_invalid_pte(page, self.free_list_head);
-        self.free_list_head = page;
+        assert!(self.is_page_in_region(page), "Freeing page {:#x} not owned by the page tables", page);
+        if !cfg!(feature = "skip_zero_on_free") {
+            let mut addr = page + 8;
+            while addr < page + PAGE_SIZE {
+                self.region.set_invalid_pte(addr, 0);
+                addr += 8;
+            }
+        }
+        self.region.set_invalid_pte(page, self.free_list_head.unwrap_or(NULL_PAGE_PTR));
+        self.free_list_head = Some(page);
+        self.free_pages += 1;
+    }
+
+    fn is_page_in_region(&self, page: u64) -> bool {
+        page % PAGE_SIZE == 0 && self.region.contains_pte(page)
     }
 }
 
 pub fn pa2va(pa: u64) -> u64 { pa + DIRECT_MAP_OFFSET }
 pub fn va2pa(va: u64) -> u64 {
      // Must be in HPA region.
-    assert!(va >= DIRECT_MAP_OFFSET);
-    assert!(va < DIRECT_MAP_OFFSET + (DIRECT_MAP_PAGES<<30));
-    va - DIRECT_MAP_OFFSET
+    try_va2pa(va).expect("va2pa: address outside the direct map")
+}
+/// Like `va2pa` but returns None if `va` is outside the direct map.
+pub fn try_va2pa(va: u64) -> Option<u64> {
+    let extent = DIRECT_MAP_EXTENT.load(Ordering::Relaxed) * DIRECT_MAP_ENTRY_SIZE;
+    if va >= DIRECT_MAP_OFFSET && va < DIRECT_MAP_OFFSET + extent {
+        Some(va - DIRECT_MAP_OFFSET)
+    } else {
+        None
+    }
+}
+
+/// Check the direct map conversions, the address classification helpers, the guest permission
+/// checks, the page table walkers and the bounds checks on writes to `page_tables`, panicking on
+/// any mismatch. Must be called after the extent of the direct map has been set.
+pub fn selftest(page_tables: &mut PageTables) {
+    let extent = DIRECT_MAP_EXTENT.load(Ordering::Relaxed) * DIRECT_MAP_ENTRY_SIZE;
+    let mut entry = 0;
+    while entry < extent {
+        for &offset in &[0, PAGE_SIZE, HPAGE_SIZE - 8, DIRECT_MAP_ENTRY_SIZE - 1] {
+            assert_eq!(va2pa(pa2va(entry + offset)), entry + offset);
+        }
+        entry += DIRECT_MAP_ENTRY_SIZE;
+    }
+    assert_eq!(try_va2pa(DIRECT_MAP_OFFSET - 1), None);
+    assert_eq!(try_va2pa(DIRECT_MAP_OFFSET + extent - 1), Some(extent - 1));
+    assert_eq!(try_va2pa(DIRECT_MAP_OFFSET + extent), None);
+
+    assert!(is_sv39(0x3f_ffff_ffff));
+    assert!(!is_sv39(0x40_0000_0000));
+    assert!(!is_sv39(0xffff_ffbf_ffff_ffff));
+    assert!(is_sv39(0xffff_ffc0_0000_0000));
+    assert!(is_sv48(0x7fff_ffff_ffff));
+    assert!(!is_sv48(0x8000_0000_0000));
+    assert!(!is_sv48(0xffff_7fff_ffff_ffff));
+    assert!(is_sv48(0xffff_8000_0000_0000));
+    assert!(is_sv32(0xffff_ffff));
+    assert!(!is_sv32(0x1_0000_0000));
+
+    assert_eq!(satp_to_rv32(satp_from_rv32(0x8123_4567)), 0x8123_4567);
+
+    // Supervisor access to user pages needs SUM and is never allowed for fetches; MXR makes
+    // execute-only pages readable.
+    let (r, x, u) = (PTE_READ, PTE_EXECUTE, PTE_USER);
+    assert!(pte_permits(r | u, AccessType::Read, true, 0));
+    assert!(!pte_permits(r, AccessType::Read, true, STATUS_SUM));
+    assert!(!pte_permits(r | u, AccessType::Read, false, 0));
+    assert!(pte_permits(r | u, AccessType::Read, false, STATUS_SUM));
+    assert!(!pte_permits(x | u, AccessType::Execute, false, STATUS_SUM));
+    assert!(!pte_permits(r | u, AccessType::Write, false, STATUS_SUM));
+    assert!(!pte_permits(x, AccessType::Read, false, 0));
+    assert!(pte_permits(x, AccessType::Read, false, STATUS_MXR));
+    assert!(!pte_permits(x, AccessType::Write, false, STATUS_MXR));
+
+    // A root at 0x1000 points twice to a table at 0x2000, which points back at both tables and at a
+    // table outside the readable region. The walk must report the cycles rather than follow them,
+    // but still descend into the shared table a second time.
+    let table = |pa: u64| ((pa >> 12) << 10) | PTE_VALID;
+    let read_pte = |addr: u64| match addr {
+        0x1000 | 0x1008 => Some(table(0x2000)),
+        0x2000 => Some(table(0x1000)),
+        0x2008 => Some(table(0x2000)),
+        0x2010 => Some(table(0x9000)),
+        0x1000..=0x2fff => Some(0),
+        _ => None,
+    };
+    let (mut entries, mut cycles, mut out_of_region) = (0, 0, 0);
+    collect_page_table(&read_pte, 0x1000, 2, 0, &mut |result| match result {
+        Ok(_) => entries += 1,
+        Err(PageTableError::Cycle { .. }) => cycles += 1,
+        Err(PageTableError::OutOfRegion { pa, .. }) => {
+            assert_eq!(pa, 0x9000);
+            out_of_region += 1;
+        }
+        Err(PageTableError::TooDeep { .. }) => panic!("page table walk went too deep"),
+    });
+    assert_eq!((entries, cycles, out_of_region), (8, 4, 2));
+
+    // Walks fail rather than read a page table that `read_pte` refuses, like one placed on the
+    // UART, and superpages must be aligned to their size.
+    let leaf = |pa: u64| ((pa >> 12) << 10) | PTE_READ | PTE_WRITE | PTE_VALID;
+    let read_pte = |addr: u64| match addr {
+        0x1000 => Some(table(0x2000)),
+        0x1008 => Some(table(0x1000_0000)),
+        0x2008 => Some(leaf(0x8020_0000)),
+        0x2010 => Some(leaf(0x8020_1000)),
+        0x1000..=0x2fff => Some(0),
+        _ => None,
+    };
+    let walk = walk_page_table(0x1000, 0x20_1234, SatpMode::Sv39, read_pte);
+    assert_eq!(walk.map(|w| w.pa), Some(0x8020_1234));
+    assert!(walk_page_table(0x1000, 0x4000_0000, SatpMode::Sv39, read_pte).is_none());
+    assert!(walk_page_table(0x1000, 0x40_0000, SatpMode::Sv39, read_pte).is_none());
+    assert!(walk_page_table(0x1000_0000, 0, SatpMode::Sv39, read_pte).is_none());
+
+    // PTE writes just past either end of the page table region, or not aligned to a PTE, are
+    // refused with the address that was rejected.
+    let region = &mut page_tables.region;
+    let (start, end) = (region.base(), region.end_pa());
+    for &pte_address in &[start - 8, end, start + 4] {
+        assert_eq!(region.try_set_invalid_pte(pte_address, 0), Err(PteOutOfRegion { pte_address }));
+    }
+
+    // Shadow leaves in UVA, which isn't installed yet, pointing just past the page table region.
+    // Flushing the current ASID must spare a global leaf but remove its non-global neighbour.
+    let free_pages = page_tables.free_pages();
+    let shadow_pa = (end + HPAGE_SIZE - 1) & !(HPAGE_SIZE - 1);
+    let shadow_leaf = |pa: u64, flags: u64| ((pa >> 12) << 10) | flags | PTE_AD | PTE_RWV;
+    let level = PageTableLevel::Level4KB;
+    page_tables.rmw_mapping(UVA, 0x1000, shadow_leaf(shadow_pa, PTE_GLOBAL), level).unwrap();
+    page_tables.rmw_mapping(UVA, 0x2000, shadow_leaf(shadow_pa + PAGE_SIZE, 0), level).unwrap();
+    assert!(page_tables.invalidate_asid(page_tables.asid()));
+    assert_eq!(page_tables.find_leaf_pte(UVA, 0x1000).map(|(_, level)| level), Some(level));
+    assert!(page_tables.find_leaf_pte(UVA, 0x2000).is_none());
+
+    page_tables.invalidate_all();
+    assert!(page_tables.find_leaf_pte(UVA, 0x1000).is_none());
+
+    // A targeted flush removes just the guest page containing the address: a 4KB leaf, a 2MB
+    // shadow superpage, or every 4KB shadow leaf of a 2MB guest page (whose size is recorded in
+    // the reserved bits). Neighbouring pages stay mapped.
+    let guest_2mb = 1 << 8;
+    let level_2mb = PageTableLevel::Level2MB;
+    page_tables.rmw_mapping(UVA, 0x1000, shadow_leaf(shadow_pa, 0), level).unwrap();
+    page_tables.rmw_mapping(UVA, 0x2000, shadow_leaf(shadow_pa, 0), level).unwrap();
+    page_tables.rmw_mapping(UVA, 0x20_0000, shadow_leaf(shadow_pa, guest_2mb), level_2mb).unwrap();
+    page_tables.rmw_mapping(UVA, 0x40_0000, shadow_leaf(shadow_pa, guest_2mb), level_2mb).unwrap();
+    page_tables.rmw_mapping(UVA, 0x60_0000, shadow_leaf(shadow_pa, guest_2mb), level).unwrap();
+    page_tables.rmw_mapping(UVA, 0x60_1000, shadow_leaf(shadow_pa, guest_2mb), level).unwrap();
+    page_tables.rmw_mapping(UVA, 0x80_0000, shadow_leaf(shadow_pa, 0), level).unwrap();
+    for &va in &[0x1000, 0x20_1000, 0x60_0000] {
+        page_tables.flush_guest_page(va, false);
+    }
+    for &(va, mapped) in &[(0x1000, false), (0x2000, true), (0x20_0000, false), (0x40_0000, true),
+                           (0x60_0000, false), (0x60_1000, false), (0x80_0000, true)] {
+        assert_eq!(page_tables.find_leaf_pte(UVA, va).is_some(), mapped);
+    }
+
+    page_tables.invalidate_all();
+    assert_eq!(page_tables.free_pages(), free_pages);
+    page_tables.flush_stats = FlushStats::default();
 }
 
 pub struct Pte {
@@ -257,24 +1054,39 @@ pub struct Pte {
     pub level: PageTableLevel,
 }
 pub struct PageTableWalk {
-    pub path: ArrayVec<[Pte; 3]>,
+    pub path: ArrayVec<[Pte; 4]>,
     pub pa: u64,
 }
-pub fn walk_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, read_pte: R) -> Option<PageTableWalk> {
-    if !is_sv39(va) || root % PAGE_SIZE != 0 {
+/// Walk the page table rooted at `root` to translate `va`. Every PTE is loaded through `read_pte`,
+/// which must return None for any address that isn't backed by page table memory so that device
+/// registers are never interpreted as PTEs. For guest page tables `MemoryRegion::get` does this,
+/// since a guest can point satp or a non-leaf PTE anywhere, including at MMIO windows like the
+/// UART.
+pub fn walk_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, mode: SatpMode, read_pte: R) -> Option<PageTableWalk> {
+    if mode == SatpMode::Sv32 {
+        return walk_sv32_page_table(root, va, read_pte);
+    }
+
+    let levels = match mode {
+        SatpMode::Sv39 if is_sv39(va) => 3,
+        SatpMode::Sv48 if is_sv48(va) => 4,
+        _ => return None,
+    };
+    if root % PAGE_SIZE != 0 {
         return None;
     }
 
     let mut path = ArrayVec::new();
     let mut page_table = root;
-    for level in 0..3 {
-        let pte_index = (va >> (30 - 9 * level)) & 0x1ff;
+    for level in (0..levels).rev() {
+        let pte_index = (va >> (12 + 9 * level)) & 0x1ff;
         let pte_addr = page_table + pte_index * 8;
         let pte = read_pte(pte_addr)?;
         let level = match level {
-            0 => PageTableLevel::Level1GB,
+            0 => PageTableLevel::Level4KB,
             1 => PageTableLevel::Level2MB,
-            2 => PageTableLevel::Level4KB,
+            2 => PageTableLevel::Level1GB,
+            3 => PageTableLevel::Level512GB,
             _ => unreachable!(),
         };
 
@@ -283,12 +1095,13 @@ pub fn walk_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, read_pte:
         if pte & PTE_VALID == 0 || ((pte & PTE_WRITE) != 0 && (pte & PTE_READ) == 0) {
             return None;
         } else if pte & (PTE_READ | PTE_EXECUTE) != 0 {
-            let pa = match level {
-                PageTableLevel::Level4KB => ((pte >> 10) << 12) | (va & 0xfff),
-                PageTableLevel::Level2MB => ((pte >> 19) << 21) | (va & 0x1fffff),
-                PageTableLevel::Level1GB => ((pte >> 28) << 30) | (va & 0x3fffffff),
-            };
-            return Some(PageTableWalk{path, pa});
+            // Superpages must be aligned to their size or else the guest gets a page fault.
+            let offset_mask = level.page_size() - 1;
+            let page_pa = (pte >> 10) << 12;
+            if page_pa & offset_mask != 0 {
+                return None;
+            }
+            return Some(PageTableWalk{path, pa: page_pa | (va & offset_mask)});
         } else {
             page_table = (pte >> 10) << 12;
         }
@@ -296,29 +1109,156 @@ pub fn walk_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, read_pte:
     return None;
 }
 
+/// Two level walk of an Sv32 page table. PTEs are only 4 bytes, so each is read out of the aligned
+/// 8 byte word containing it. Physical addresses are 34 bits wide.
+fn walk_sv32_page_table<R: Fn(u64) -> Option<u64>>(root: u64, va: u64, read_pte: R) -> Option<PageTableWalk> {
+    if !is_sv32(va) || root % PAGE_SIZE != 0 {
+        return None;
+    }
+
+    let mut path = ArrayVec::new();
+    let mut page_table = root;
+    for &level in &[PageTableLevel::Level4MB, PageTableLevel::Level4KB] {
+        let shift = if level == PageTableLevel::Level4MB { 22 } else { 12 };
+        let pte_addr = page_table + ((va >> shift) & 0x3ff) * 4;
+        let pte = (read_pte(pte_addr & !0x7)? >> ((pte_addr & 0x4) * 8)) & 0xffff_ffff;
+
+        path.push(Pte {addr: pte_addr, value: pte, level});
+
+        if pte & PTE_VALID == 0 || ((pte & PTE_WRITE) != 0 && (pte & PTE_READ) == 0) {
+            return None;
+        } else if pte & (PTE_READ | PTE_EXECUTE) != 0 {
+            let offset_mask = level.page_size() - 1;
+            let page_pa = (pte >> 10) << 12;
+            if page_pa & offset_mask != 0 {
+                return None;
+            }
+            return Some(PageTableWalk{path, pa: page_pa | (va & offset_mask)});
+        } else {
+            page_table = (pte >> 10) << 12;
+        }
+    }
+    None
+}
+
+/// Returns whether va is a zero extended 32 bit address
+pub fn is_sv32(va: u64) -> bool {
+    va >> 32 == 0
+}
+
 /// Returns whether va is a sign extended 39 bit address
 pub fn is_sv39(va: u64) -> bool {
     let shifted = va >> 38;
     shifted == 0 || shifted == 0x3ffffff
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum PageTableLevel {
+/// Returns whether va is a sign extended 48 bit address
+pub fn is_sv48(va: u64) -> bool {
+    let shifted = va >> 47;
+    shifted == 0 || shifted == 0x1ffff
+}
+
+/// MODE value used to record Sv32 in the RV64 layout of `satp` kept for RV32 guests. The value is
+/// reserved in RV64, so a guest can never select it itself.
+pub const SATP_MODE_SV32: u64 = 1;
+
+/// Guest address translation modes that can be selected through the MODE field of `satp`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SatpMode {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+}
+impl SatpMode {
+    /// Decode the MODE field of `satp`. Returns None for reserved or unsupported modes. The satp of
+    /// an RV32 guest is always stored in the RV64 layout, see `satp_from_rv32`.
+    pub fn from_satp(satp: u64) -> Option<Self> {
+        match (satp & riscv::bits::SATP_MODE) >> 60 {
+            0 => Some(SatpMode::Bare),
+            SATP_MODE_SV32 => Some(SatpMode::Sv32),
+            8 => Some(SatpMode::Sv39),
+            9 => Some(SatpMode::Sv48),
+            _ => None,
+        }
+    }
+}
+
+/// Convert an RV32 `satp` value (MODE in bit 31, 9 bit ASID, 22 bit PPN) into the RV64 layout used
+/// internally.
+pub fn satp_from_rv32(satp: u64) -> u64 {
+    let mode = if satp & (1 << 31) != 0 { SATP_MODE_SV32 } else { 0 };
+    (mode << 60) | (((satp >> 22) & 0x1ff) << 44) | (satp & 0x3f_ffff)
+}
+
+/// Inverse of `satp_from_rv32`.
+pub fn satp_to_rv32(satp: u64) -> u64 {
+    let mode = if (satp & riscv::bits::SATP_MODE) >> 60 == SATP_MODE_SV32 { 1 << 31 } else { 0 };
+    mode | (((satp >> 44) & 0x1ff) << 22) | (satp & 0x3f_ffff)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageTableLevel {
     Level4KB,
     Level2MB,
+    /// Sv32 megapage. Never used by the shadow page tables.
+    Level4MB,
     Level1GB,
+    Level512GB,
+}
+impl PageTableLevel {
+    /// Depth of the Sv39 or Sv48 page table holding PTEs at this level, counting up from zero for
+    /// the last level tables.
+    fn table_level(&self) -> u8 {
+        match *self {
+            PageTableLevel::Level4KB => 0,
+            PageTableLevel::Level2MB => 1,
+            PageTableLevel::Level1GB => 2,
+            PageTableLevel::Level512GB => 3,
+            PageTableLevel::Level4MB => unreachable!("not an Sv39 or Sv48 page table level"),
+        }
+    }
+
+    /// Inverse of `table_level`.
+    fn from_table_level(table_level: u8) -> Self {
+        match table_level {
+            0 => PageTableLevel::Level4KB,
+            1 => PageTableLevel::Level2MB,
+            2 => PageTableLevel::Level1GB,
+            3 => PageTableLevel::Level512GB,
+            _ => unreachable!("no page table level {}", table_level),
+        }
+    }
+
+    /// Number of bytes mapped by a leaf PTE at this level.
+    pub fn page_size(&self) -> u64 {
+        match *self {
+            PageTableLevel::Level4KB => 1 << 12,
+            PageTableLevel::Level2MB => 1 << 21,
+            PageTableLevel::Level4MB => 1 << 22,
+            PageTableLevel::Level1GB => 1 << 30,
+            PageTableLevel::Level512GB => 1 << 39,
+        }
+    }
 }
 
 pub struct AddressTranslation {
     pub pte_value: u64,
     pub pte_addr: u64,
     pub guest_pa: u64,
+    /// Level of the leaf PTE, which determines the size of the guest page it maps.
     pub level: PageTableLevel,
 }
+impl AddressTranslation {
+    /// Size in bytes of the guest page (4KB, 2MB/4MB or 1GB) containing the translated address.
+    pub fn page_size(&self) -> u64 {
+        self.level.page_size()
+    }
+}
 
-pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64, addr: u64)
+pub fn translate_guest_address(guest_memory: &MemoryRegion, mode: SatpMode, root_page_table: u64, addr: u64)
                                -> Option<AddressTranslation> {
-    walk_page_table(root_page_table, addr, |pa| guest_memory.get(pa)).map(|t| {
+    walk_page_table(root_page_table, addr, mode, |pa| guest_memory.get(pa)).map(|t| {
         AddressTranslation {
             pte_value: t.path[t.path.len() - 1].value,
             pte_addr: t.path[t.path.len() - 1].addr,
@@ -327,43 +1267,526 @@ pub fn translate_guest_address(guest_memory: &MemoryRegion, root_page_table: u64
         }
     })
 }
+
+/// Translate the guest virtual address `va` using the guest page table selected by `satp`. Returns
+/// the guest physical address along with the flags and level of the leaf PTE, or None if `va` isn't
+/// mapped. With paging disabled `va` is returned unchanged, with no flags.
+pub fn guest_va_to_pa(guest_memory: &MemoryRegion, satp: u64, va: u64) -> Option<(u64, u64, PageTableLevel)> {
+    let mode = SatpMode::from_satp(satp)?;
+    if mode == SatpMode::Bare {
+        return Some((va, 0, PageTableLevel::Level4KB));
+    }
+
+    let root = (satp & riscv::bits::SATP_PPN) << 12;
+    let translation = translate_guest_address(guest_memory, mode, root, va)?;
+    Some((translation.guest_pa, translation.pte_value & 0x3ff, translation.level))
+}
+
+const TRANSLATION_CACHE_ENTRIES: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+struct CachedTranslation {
+    satp: u64,
+    va_page: u64,
+    pa_page: u64,
+    flags: u64,
+}
+
+/// A tiny direct mapped cache of the guest translations made by the hypervisor on the guest's
+/// behalf. Like a TLB, entries are keyed on `satp` (and thus the ASID) and only removed by
+/// `sfence.vma` or writes to `satp`, so changes to guest page tables may not be seen until then.
+pub struct TranslationCache {
+    entries: [Option<CachedTranslation>; TRANSLATION_CACHE_ENTRIES],
+    hits: u64,
+    misses: u64,
+}
+
+impl TranslationCache {
+    pub fn new() -> Self {
+        Self { entries: [None; TRANSLATION_CACHE_ENTRIES], hits: 0, misses: 0 }
+    }
+
+    /// Same as `guest_va_to_pa` but consults the cache first. The level of the mapping isn't
+    /// recorded, so only the guest physical address and PTE flags are returned.
+    pub fn translate(&mut self, guest_memory: &MemoryRegion, satp: u64, va: u64) -> Option<(u64, u64)> {
+        if SatpMode::from_satp(satp)? == SatpMode::Bare {
+            return Some((va, 0));
+        }
+
+        let va_page = va & !(PAGE_SIZE - 1);
+        let index = (va_page / PAGE_SIZE) as usize % TRANSLATION_CACHE_ENTRIES;
+        if let Some(entry) = self.entries[index] {
+            if entry.satp == satp && entry.va_page == va_page {
+                self.hits += 1;
+                return Some((entry.pa_page | (va & (PAGE_SIZE - 1)), entry.flags));
+            }
+        }
+
+        self.misses += 1;
+        let (pa, flags, _) = guest_va_to_pa(guest_memory, satp, va)?;
+        self.entries[index] = Some(CachedTranslation { satp, va_page, pa_page: pa & !(PAGE_SIZE - 1), flags });
+        Some((pa, flags))
+    }
+
+    /// Drop cached translations with the semantics of `sfence.vma`: restricted to the page
+    /// containing `va` if given, and to non-global mappings in address space `asid` if given.
+    pub fn invalidate(&mut self, va: Option<u64>, asid: Option<u64>) {
+        for entry in self.entries.iter_mut() {
+            let matches = match *entry {
+                Some(e) => va.map(|va| va & !(PAGE_SIZE - 1) == e.va_page).unwrap_or(true)
+                    && asid.map(|asid| {
+                        e.flags & PTE_GLOBAL == 0 && (e.satp & riscv::bits::SATP_ASID) >> 44 == asid
+                    }).unwrap_or(true),
+                None => false,
+            };
+            if matches {
+                *entry = None;
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+/// Print the result of `guest_va_to_pa`, including the size of the page mapping `va`.
+pub fn print_guest_va_translation(guest_memory: &MemoryRegion, satp: u64, va: u64) {
+    match guest_va_to_pa(guest_memory, satp, va) {
+        Some((pa, flags, level)) => {
+            let size = match level {
+                PageTableLevel::Level4KB => "4K",
+                PageTableLevel::Level2MB => "2M",
+                PageTableLevel::Level4MB => "4M",
+                PageTableLevel::Level1GB => "1G",
+                PageTableLevel::Level512GB => "512G",
+            };
+            println!("{:#x} -> {:#x} ({} page, flags={:#x})", va, pa, size, flags);
+        }
+        None => println!("{:#x} -> unmapped", va),
+    }
+}
+
+/// The kind of memory access that triggered a guest address translation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+impl AccessType {
+    /// Returns the PTE permission bit that must be set for this access to succeed.
+    pub fn pte_bit(&self) -> u64 {
+        match *self {
+            AccessType::Read => PTE_READ,
+            AccessType::Write => PTE_WRITE,
+            AccessType::Execute => PTE_EXECUTE,
+        }
+    }
+
+    /// Returns the scause value for a page fault caused by this kind of access.
+    pub fn page_fault_cause(&self) -> u64 {
+        match *self {
+            AccessType::Read => riscv::bits::SCAUSE_LOAD_PAGE_FAULT,
+            AccessType::Write => riscv::bits::SCAUSE_STORE_PAGE_FAULT,
+            AccessType::Execute => riscv::bits::SCAUSE_INSN_PAGE_FAULT,
+        }
+    }
+
+    /// Returns the kind of access that caused a page fault with the given scause value.
+    pub fn from_page_fault_cause(cause: u64) -> Option<Self> {
+        match cause {
+            riscv::bits::SCAUSE_LOAD_PAGE_FAULT => Some(AccessType::Read),
+            riscv::bits::SCAUSE_STORE_PAGE_FAULT => Some(AccessType::Write),
+            riscv::bits::SCAUSE_INSN_PAGE_FAULT => Some(AccessType::Execute),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of access made by a load, store or atomic `instruction`. LR only reads,
+    /// while SC and every AMO count as writes.
+    pub fn from_instruction(instruction: u32, rv32: bool) -> Option<Self> {
+        match trap::decode(instruction, rv32).ok()? {
+            Instruction::Lb(_) | Instruction::Lh(_) | Instruction::Lw(_) | Instruction::Ld(_) |
+            Instruction::Lbu(_) | Instruction::Lhu(_) | Instruction::Lwu(_) |
+            Instruction::LrW(_) | Instruction::LrD(_) => Some(AccessType::Read),
+
+            Instruction::Sb(_) | Instruction::Sh(_) | Instruction::Sw(_) | Instruction::Sd(_) |
+            Instruction::ScW(_) | Instruction::ScD(_) |
+            Instruction::AmoswapW(_) | Instruction::AmoaddW(_) | Instruction::AmoxorW(_) |
+            Instruction::AmoandW(_) | Instruction::AmoorW(_) | Instruction::AmominW(_) |
+            Instruction::AmomaxW(_) | Instruction::AmominuW(_) | Instruction::AmomaxuW(_) |
+            Instruction::AmoswapD(_) | Instruction::AmoaddD(_) | Instruction::AmoxorD(_) |
+            Instruction::AmoandD(_) | Instruction::AmoorD(_) | Instruction::AmominD(_) |
+            Instruction::AmomaxD(_) | Instruction::AmominuD(_) | Instruction::AmomaxuD(_) => Some(AccessType::Write),
+
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of access that caused a page fault, using the faulting instruction (if
+    /// known) to resolve loads that are really part of a read-modify-write like an AMO.
+    pub fn for_page_fault(cause: u64, instruction: Option<u32>, rv32: bool) -> Option<Self> {
+        let access = Self::from_page_fault_cause(cause)?;
+        let instruction_access = instruction.and_then(|i| Self::from_instruction(i, rv32));
+        if access == AccessType::Read && instruction_access == Some(AccessType::Write) {
+            return Some(AccessType::Write);
+        }
+        Some(access)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TranslationError {
+    /// The walk did not reach a valid leaf PTE.
+    NotPresent,
+    /// A leaf PTE was found but it does not grant the requested access.
+    PermissionDenied,
+    /// The leaf PTE was modified concurrently while it was being updated. The access should be
+    /// retried.
+    Retry,
+}
+
+/// Like `translate_guest_address` but additionally checks that the leaf PTE permits `access` from
+/// the given privilege level, applying the SUM and MXR bits of the guest's `sstatus`. Supervisor
+/// accesses to user pages are only allowed if SUM is set, and are never allowed for instruction
+/// fetches. With MXR set, loads from executable pages are allowed even if they aren't readable.
+pub fn translate_guest_address_checked(guest_memory: &MemoryRegion, mode: SatpMode, root_page_table: u64,
+                                       addr: u64, access: AccessType, user_mode: bool, sstatus: u64)
+                                       -> Result<AddressTranslation, TranslationError> {
+    let translation = translate_guest_address(guest_memory, mode, root_page_table, addr)
+        .ok_or(TranslationError::NotPresent)?;
+
+    if !pte_permits(translation.pte_value, access, user_mode, sstatus) {
+        return Err(TranslationError::PermissionDenied);
+    }
+
+    Ok(translation)
+}
+
+/// Whether a leaf PTE with flags `pte` permits `access` from the given privilege level, under the
+/// SUM and MXR bits of the guest's `sstatus`.
+fn pte_permits(pte: u64, access: AccessType, user_mode: bool, sstatus: u64) -> bool {
+    let sum = sstatus & STATUS_SUM != 0;
+    let mxr = sstatus & STATUS_MXR != 0;
+
+    let mut permitted = pte & access.pte_bit() != 0;
+    if access == AccessType::Read && mxr {
+        permitted |= pte & PTE_EXECUTE != 0;
+    }
+    if !permitted {
+        return false;
+    }
+
+    let user_page = pte & PTE_USER != 0;
+    if user_mode {
+        user_page
+    } else {
+        !user_page || (sum && access != AccessType::Execute)
+    }
+}
+
+/// Like `translate_guest_address_checked` but also sets the accessed bit (and the dirty bit for
+/// writes) in the leaf PTE, as the hardware page table walker would. The update is done with a
+/// compare-and-swap so a concurrent modification of the PTE results in `TranslationError::Retry`.
+pub fn translate_guest_address_and_set_ad(guest_memory: &mut MemoryRegion, mode: SatpMode, root_page_table: u64,
+                                          addr: u64, access: AccessType, user_mode: bool, sstatus: u64)
+                                          -> Result<AddressTranslation, TranslationError> {
+    let mut translation = translate_guest_address_checked(guest_memory, mode, root_page_table, addr,
+                                                          access, user_mode, sstatus)?;
+
+    let new_pte = match access {
+        AccessType::Write => translation.pte_value | PTE_ACCESSED | PTE_DIRTY,
+        AccessType::Read | AccessType::Execute => translation.pte_value | PTE_ACCESSED,
+    };
+
+    if new_pte != translation.pte_value {
+        if mode == SatpMode::Sv32 {
+            // Sv32 PTEs are only 4 bytes, so swap the aligned word containing the PTE.
+            let word_addr = translation.pte_addr & !0x7;
+            let shift = (translation.pte_addr & 0x4) * 8;
+            let word = guest_memory.get(word_addr).ok_or(TranslationError::Retry)?;
+            let current = (word & !(0xffff_ffff << shift)) | (translation.pte_value << shift);
+            let new = (word & !(0xffff_ffff << shift)) | (new_pte << shift);
+            guest_memory.compare_exchange(word_addr, current, new).map_err(|_| TranslationError::Retry)?;
+        } else {
+            guest_memory.compare_exchange(translation.pte_addr, translation.pte_value, new_pte)
+                .map_err(|_| TranslationError::Retry)?;
+        }
+        translation.pte_value = new_pte;
+    }
+
+    Ok(translation)
+}
+
 pub fn translate_host_address(addr: u64) -> Option<PageTableWalk> {
     // The currently installed page table should always have all of its pages mapped in the direct
     // map region, thus deferencing pointers during a page table walk should always be safe.
     let root_page_table = (csrr!(satp) & riscv::bits::SATP_PPN) << 12;
-    walk_page_table(root_page_table, addr, |pa| Some(unsafe { *(pa2va(pa) as *const u64) }))
+    walk_page_table(root_page_table, addr, SatpMode::Sv39, |pa| Some(unsafe { *(pa2va(pa) as *const u64) }))
+}
+
+pub const MAX_GUEST_MEMORY_BANKS: usize = crate::fdt::MAX_MEMORY_BANKS;
+
+/// A contiguous range of guest physical memory, backed by host physical memory starting
+/// `host_shift` bytes higher.
+#[derive(Copy, Clone, Debug)]
+pub struct GuestMemoryBank {
+    pub guest_pa: u64,
+    pub size: u64,
+    pub host_shift: u64,
+}
+
+/// Map the page of `bank` containing `guest_pa` into MPA. A 2MB page is used if `allow_hpage` is set
+/// and the entire 2MB region is inside the bank and suitably aligned in host memory, and a 4KB page
+/// otherwise. Returns the address and size of the page mapped, or None if out of memory for page
+/// tables.
+fn map_guest_memory_page(shadow_page_tables: &mut PageTables, bank: &GuestMemoryBank, guest_pa: u64,
+                         allow_hpage: bool) -> Option<(u64, PageTableLevel)> {
+    let end = bank.guest_pa + bank.size;
+    assert!(guest_pa >= bank.guest_pa && guest_pa < end);
+
+    let hpage = guest_pa & !(HPAGE_SIZE - 1);
+    let (va, level) = if allow_hpage && hpage >= bank.guest_pa && end - hpage >= HPAGE_SIZE
+        && (hpage + bank.host_shift) % HPAGE_SIZE == 0
+    {
+        (hpage, PageTableLevel::Level2MB)
+    } else {
+        (guest_pa & !(PAGE_SIZE - 1), PageTableLevel::Level4KB)
+    };
+
+    shadow_page_tables.mpa_set_mapping(va, va + bank.host_shift, level)?;
+    Some((va, level))
+}
+
+/// Map a bank of guest physical memory into the MPA page table. 2MB pages are used wherever
+/// possible, with 4KB pages covering any unaligned head or tail of the bank.
+fn map_guest_memory_bank(shadow_page_tables: &mut PageTables, bank: &GuestMemoryBank) {
+    assert_eq!(bank.guest_pa % PAGE_SIZE, 0);
+    assert_eq!(bank.size % PAGE_SIZE, 0);
+
+    let end = bank.guest_pa + bank.size;
+    let mut va = bank.guest_pa;
+    while va < end {
+        let (page, level) = map_guest_memory_page(shadow_page_tables, bank, va, true)
+            .expect("Out of hypervisor memory for page tables");
+        va = page + level.page_size();
+    }
 }
 
-pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &MachineMeta) -> (PageTables, MemoryRegion, u64) {
+/// Handle a fault while guest paging is disabled. When guest memory is mapped lazily, this installs
+/// the MPA mapping covering `guest_pa` on first access. Returns whether a mapping was added.
+pub fn handle_mpa_fault(state: &mut Context, guest_pa: u64) -> bool {
+    if !cfg!(feature = "lazy_guest_memory") || !state.guest_memory.in_region(guest_pa)
+        || virtio::is_reclaimed(state, guest_pa & !(PAGE_SIZE - 1), PAGE_SIZE)
+    {
+        return false;
+    }
+
+    // The device tree is mapped read-only, so a fault on it that is already mapped is a write.
+    let page = guest_pa & !(PAGE_SIZE - 1);
+    let dtb_page = state.boot.overlaps_dtb(page, PAGE_SIZE);
+    if dtb_page && state.shadow_page_tables.mpa_leaf_level(page).is_ok() {
+        return false;
+    }
+
+    let bank = match state.memory_banks.iter().find(|b| guest_pa >= b.guest_pa && guest_pa - b.guest_pa < b.size) {
+        Some(&bank) => bank,
+        None => return false,
+    };
+    // Pages reclaimed by a balloon must stay unmapped, and the device tree must be mapped with its
+    // own permissions, so avoid superpages that contain either.
+    let hpage = guest_pa & !(HPAGE_SIZE - 1);
+    let allow_hpage = !virtio::is_reclaimed(state, hpage, HPAGE_SIZE) && !state.boot.overlaps_dtb(hpage, HPAGE_SIZE);
+    if map_guest_memory_page(&mut state.shadow_page_tables, &bank, guest_pa, allow_hpage).is_none() {
+        // MPA isn't cleared by flushing, so mappings already made by this function are kept.
+        flush_shadow_page_table(&mut state.shadow_page_tables);
+        map_guest_memory_page(&mut state.shadow_page_tables, &bank, guest_pa, allow_hpage)
+            .expect("Out of hypervisor memory for page tables");
+    }
+    if dtb_page {
+        state.shadow_page_tables.mpa_write_protect(page, PAGE_SIZE).expect("4KB mapping needs no split");
+    }
+    riscv::sfence_vma_addr(guest_pa);
+    true
+}
+
+/// Smallest amount of memory a guest can be given.
+const MIN_GUEST_MEMORY: u64 = 64 * 1024 * 1024;
+
+/// Reasons a shadow mapping can't be installed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MappingError {
+    /// The virtual address is used by the hypervisor itself (or is outside of Sv39) and so can't
+    /// be mapped for the guest.
+    ReservedAddress,
+    /// No free pages were left for intermediate page tables.
+    OutOfMemory,
+}
+
+/// Reasons `init` can refuse to set up a guest's memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuestMemoryError {
+    /// The hart segment doesn't leave room for any guest memory before the end of host RAM.
+    SegmentOutsideHostMemory,
+    /// Less than the minimum amount of guest memory is available within the limit.
+    TooSmall,
+    /// The host shift of the given bank isn't a multiple of 2MB.
+    MisalignedShift { bank: usize },
+    /// The given bank would be backed by host memory outside of the range set aside for the guest.
+    BankOutsideHostMemory { bank: usize },
+    /// The given banks would be backed by the same host memory.
+    BanksOverlap { first: usize, second: usize },
+}
+
+/// Address in the hart segment at `hart_base_pa` of its guest's copy of the host's disk image. The
+/// copy sits at the end of the segment (or of host memory), starting on a 2MB boundary, and guest
+/// memory stops where it starts. Without a disk image this is just the end of the segment.
+pub fn disk_image_pa(hart_base_pa: u64, machine: &MachineMeta) -> u64 {
+    let host_memory_end = machine.physical_memory_offset + machine.physical_memory_size;
+    let segment_end = (hart_base_pa + HART_SEGMENT_SIZE).min(host_memory_end);
+    match machine.disk_end - machine.disk_start {
+        0 => segment_end,
+        disk_size => segment_end.saturating_sub(disk_size) & !(HPAGE_SIZE - 1),
+    }
+}
+
+/// Lay out guest memory like the host's banks, keeping the parts of them that fall within `size`
+/// bytes of the first. Every bank is backed at the same `host_shift`, so host memory behind gaps
+/// between banks is left unused.
+pub fn guest_memory_banks(machine: &MachineMeta, size: u64, host_shift: u64)
+                          -> ArrayVec<[GuestMemoryBank; MAX_GUEST_MEMORY_BANKS]> {
+    let start = machine.physical_memory_offset;
+    let end = start + size;
+    if machine.memory_banks.is_empty() {
+        return [GuestMemoryBank { guest_pa: start, size, host_shift }].iter().cloned().collect();
+    }
+
+    machine.memory_banks.iter()
+        .filter(|&&(base, _)| base >= start && base < end)
+        .map(|&(base, bank_size)| GuestMemoryBank {
+            guest_pa: base,
+            size: bank_size.min(end - base) & !(PAGE_SIZE - 1),
+            host_shift,
+        })
+        .collect()
+}
+
+/// Check that each bank is backed by its own part of the host range `[host_start, host_end)`, so
+/// that no guest memory aliases hypervisor memory or another bank. Shifts must be multiples of 2MB
+/// so that banks can be mapped with superpages.
+pub fn validate_guest_memory_banks(banks: &[GuestMemoryBank], host_start: u64, host_end: u64)
+                                   -> Result<(), GuestMemoryError> {
+    let host_range = |bank: &GuestMemoryBank| {
+        let start = bank.guest_pa.checked_add(bank.host_shift)?;
+        Some((start, start.checked_add(bank.size)?))
+    };
+
+    for (i, bank) in banks.iter().enumerate() {
+        if bank.host_shift % HPAGE_SIZE != 0 {
+            return Err(GuestMemoryError::MisalignedShift { bank: i });
+        }
+        match host_range(bank) {
+            Some((start, end)) if start >= host_start && end <= host_end => {}
+            _ => return Err(GuestMemoryError::BankOutsideHostMemory { bank: i }),
+        }
+    }
+
+    for (i, a) in banks.iter().enumerate() {
+        for (j, b) in banks.iter().enumerate().skip(i + 1) {
+            let ((a_start, a_end), (b_start, b_end)) = (host_range(a).unwrap(), host_range(b).unwrap());
+            if a_start < b_end && b_start < a_end {
+                return Err(GuestMemoryError::BanksOverlap { first: i, second: j });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the direct map entry of the root page table at `root_va` that covers `pa`, using pages of
+/// `DIRECT_MAP_PAGE_SIZE`. Page tables are filled in directly since the direct map covers the page
+/// table region itself.
+unsafe fn map_direct_map_entry(shadow_page_tables: &mut PageTables, root_va: u64, pa: u64) {
+    let pa = pa & !(DIRECT_MAP_ENTRY_SIZE - 1);
+    let root_pte = (root_va + DIRECT_MAP_PT_INDEX + (pa / DIRECT_MAP_ENTRY_SIZE) * 8) as *mut u64;
+
+    if DIRECT_MAP_PAGE_SIZE == DIRECT_MAP_ENTRY_SIZE {
+        *root_pte = (pa >> 2) | PTE_AD | PTE_RWV;
+        return;
+    }
+
+    assert_eq!(DIRECT_MAP_PAGE_SIZE, HPAGE_SIZE, "Unsupported direct map page size");
+    let page = shadow_page_tables.alloc_page().expect("Out of hypervisor memory for page tables");
+    for i in 0..(DIRECT_MAP_ENTRY_SIZE / DIRECT_MAP_PAGE_SIZE) {
+        shadow_page_tables.region.set_pte_unchecked(
+            page + i * 8, ((pa + i * DIRECT_MAP_PAGE_SIZE) >> 2) | PTE_AD | PTE_RWV);
+    }
+    *root_pte = (page >> 2) | PTE_VALID;
+}
+
+/// Set up the memory for the guest running in the hart segment at `hart_base_pa`. The guest gets
+/// the rest of its hart segment after the hypervisor's reservation, clamped to both the end of host
+/// RAM and `max_guest_memory`. Returns the shift of the first bank, which backs `guest_memory`.
+pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &MachineMeta, max_guest_memory: u64)
+                   -> Result<(PageTables, MemoryRegion, u64), GuestMemoryError> {
     assert_eq!(hart_base_pa % HART_SEGMENT_SIZE, 0);
 
+    let guest_memory_end = disk_image_pa(hart_base_pa, machine);
+    let available = guest_memory_end.checked_sub(hart_base_pa + VM_RESERVATION_SIZE)
+        .ok_or(GuestMemoryError::SegmentOutsideHostMemory)?;
+
     let gpm_offset = machine.physical_memory_offset;
-    let gpm_size = HART_SEGMENT_SIZE.checked_sub(VM_RESERVATION_SIZE).unwrap();
+    let gpm_size = available.min(max_guest_memory) & !(PAGE_SIZE - 1);
     let guest_shift = VM_RESERVATION_SIZE + hart_base_pa.checked_sub(machine.physical_memory_offset).unwrap();
-    assert_eq!(gpm_offset, 0x80000000);
-    assert!(gpm_size > 64 * 1024 * 1024);
+    if gpm_size < MIN_GUEST_MEMORY {
+        return Err(GuestMemoryError::TooSmall);
+    }
+
+    let banks = guest_memory_banks(machine, gpm_size, guest_shift);
+    validate_guest_memory_banks(&banks, hart_base_pa + VM_RESERVATION_SIZE, guest_memory_end)?;
 
-    // Create guest memory region
-    let guest_memory = MemoryRegion::with_base_address(pa2va(gpm_offset + guest_shift), machine.physical_memory_offset, gpm_size);
+    // Size the direct map to cover all of host physical memory, rounded up to a whole root entry.
+    assert_eq!(DIRECT_MAP_ENTRY_SIZE % DIRECT_MAP_PAGE_SIZE, 0);
+    assert_eq!(HART_SEGMENT_SIZE % DIRECT_MAP_PAGE_SIZE, 0);
+    assert_eq!(machine.physical_memory_offset % DIRECT_MAP_PAGE_SIZE, 0,
+               "Direct map page size doesn't divide the start of physical memory");
+    let host_memory_end = machine.physical_memory_offset + machine.physical_memory_size;
+    let direct_map_pages = (host_memory_end + DIRECT_MAP_ENTRY_SIZE - 1) / DIRECT_MAP_ENTRY_SIZE;
+    assert!(direct_map_pages <= MAX_DIRECT_MAP_PAGES, "Host physical memory too large for direct map");
+    assert!(hart_base_pa / DIRECT_MAP_ENTRY_SIZE < direct_map_pages);
+    DIRECT_MAP_EXTENT.store(direct_map_pages, Ordering::Relaxed);
+
+    // Create guest memory region, which spans every bank along with any gaps between them.
+    let guest_memory = MemoryRegion::with_base_address(pa2va(gpm_offset + guest_shift), gpm_offset, gpm_size);
 
     // Create shadow page tables
     let memory_region = MemoryRegion::new(pa2va(hart_base_pa + PT_REGION_OFFSET), PT_REGION_SIZE);
-    let mut shadow_page_tables = PageTables::new(memory_region, machine.initrd_start, machine.initrd_end);
+    let mut shadow_page_tables = PageTables::new(memory_region, machine.initrd_start, machine.initrd_end,
+                                                 direct_map_pages);
 
     let sshift = shared_segments_shift >> 2;
 
     // Initialize shadow page tables
-    for &root in &[MPA, UVA, KVA, MVA] {
+    for &root in PageTableRoot::ALL {
         let va = pa2va(shadow_page_tables.root_pa(root));
         ptr::write_bytes(va as *mut u8, 0, PAGE_SIZE as usize);
 
-        *((va + DIRECT_MAP_PT_INDEX + 0 * 8) as *mut u64) = (0 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + 1 * 8) as *mut u64) = (1 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + (hart_base_pa >> 30) * 8) as *mut u64) = (hart_base_pa >> 2) | PTE_AD | PTE_RWV;
+        // Direct map of everything below physical memory (devices) and of this hart's own segment.
+        // Other harts' segments fall within the extent of the direct map but are left unmapped.
+        let mut pa = 0;
+        while pa < machine.physical_memory_offset {
+            map_direct_map_entry(&mut shadow_page_tables, va, pa);
+            pa += DIRECT_MAP_ENTRY_SIZE;
+        }
+        map_direct_map_entry(&mut shadow_page_tables, va, hart_base_pa);
 
         // Hypervisor code + data
         let hp = 2 << 18;
-        let page = shadow_page_tables.alloc_page();
+        let page = shadow_page_tables.alloc_page().expect("Out of hypervisor memory for page tables");
         *((va + 0xff8) as *mut u64) = (page >> 2) | PTE_VALID;
         shadow_page_tables.region.set_pte_unchecked(
             page, (0x20000000+sshift) | PTE_AD | PTE_RXV);       // Code + read only data
@@ -374,49 +1797,127 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
         shadow_page_tables.region.set_pte_unchecked(
             page+32, ((hart_base_pa>>2)+hp) | PTE_AD | PTE_RWV); // Stack
     }
+    shadow_page_tables.init_sv48_roots();
     shadow_page_tables.install_root(MPA);
+    if cfg!(debug_assertions) {
+        selftest(&mut shadow_page_tables);
+    }
 
-    // Map guest physical memory
-    assert_eq!(gpm_size % HPAGE_SIZE, 0);
-    let root_pa = shadow_page_tables.root_pa(MPA);
-    let npages = gpm_size / HPAGE_SIZE;
-    for p in 0..npages  {
-        let va = gpm_offset + p * HPAGE_SIZE;
-        let pa = va + guest_shift;
-
-        let pte_index = va >> 30;
-        let pte_addr = root_pa + pte_index * 8;
-        let pte = shadow_page_tables.region[pte_addr];
-        let page_table = if pte & PTE_VALID != 0 {
-            assert_eq!(pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE), 0);
-            (pte >> 10) << 12
-        } else {
-            let page = shadow_page_tables.alloc_page();
-            shadow_page_tables.region.set_nonleaf_pte(pte_addr, (page >> 2) | PTE_VALID);
-            page
+    // Map guest physical memory, unless it'll be done on demand by `handle_mpa_fault`.
+    if !cfg!(feature = "lazy_guest_memory") {
+        shadow_page_tables.with_batch(|shadow_page_tables| {
+            for bank in &banks {
+                map_guest_memory_bank(shadow_page_tables, bank);
+            }
+        });
+    }
+
+    Ok((shadow_page_tables, guest_memory, guest_shift))
+}
+
+/// A single non-zero entry found while walking a page table.
+#[derive(Copy, Clone, Debug)]
+pub struct PageTableEntry {
+    /// First virtual address covered by the entry.
+    pub va: u64,
+    /// Physical address of the page (or next level page table) the entry points to.
+    pub pa: u64,
+    /// Level of the table holding the entry, with 0 being the last level.
+    pub level: u8,
+    /// Physical address of the entry itself.
+    pub pte_addr: u64,
+    /// Low flag bits of the entry.
+    pub flags: u64,
+    /// Raw value of the entry.
+    pub pte: u64,
+}
+impl PageTableEntry {
+    pub fn is_valid(&self) -> bool { self.flags & PTE_VALID != 0 }
+    pub fn is_leaf(&self) -> bool { self.is_valid() && self.flags & PTE_RWXV != PTE_VALID }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum PageTableError {
+    /// A page table pointed to by `va` lies outside of the readable region.
+    OutOfRegion { va: u64, pa: u64 },
+    /// A last level page table contained a pointer to another page table.
+    TooDeep { va: u64, pa: u64 },
+    /// A non-leaf entry pointed back at a page table that was already being walked.
+    Cycle { va: u64, pa: u64 },
+}
+
+/// Walk the page table at `pt`, passing every non-zero entry (or a description of why part of the
+/// table couldn't be walked) to `visit`. Entries are reported before the contents of the tables
+/// they point to. `read_pte` returns None for addresses outside the region holding the tables.
+///
+/// The tables may be guest controlled, so a non-leaf entry pointing back at one of the tables
+/// currently being walked is reported as a cycle rather than followed.
+pub fn collect_page_table<R, F>(read_pte: &R, pt: u64, level: u8, base: u64, visit: &mut F) where
+    R: Fn(u64) -> Option<u64>,
+    F: FnMut(Result<PageTableEntry, PageTableError>),
+{
+    let mut ancestors = ArrayVec::<[u64; 4]>::new();
+    collect_page_table_inner(read_pte, pt, level, base, &mut ancestors, visit);
+}
+
+fn collect_page_table_inner<R, F>(read_pte: &R, pt: u64, level: u8, base: u64,
+                                  ancestors: &mut ArrayVec<[u64; 4]>, visit: &mut F) where
+    R: Fn(u64) -> Option<u64>,
+    F: FnMut(Result<PageTableEntry, PageTableError>),
+{
+    if ancestors.try_push(pt).is_err() {
+        visit(Err(PageTableError::TooDeep { va: base, pa: pt }));
+        return;
+    }
+
+    for i in 0..512 {
+        let pte_addr = pt + i * 8;
+        let pte = match read_pte(pte_addr) {
+            Some(pte) => pte,
+            None => {
+                visit(Err(PageTableError::OutOfRegion { va: base, pa: pt }));
+                break;
+            }
         };
-        shadow_page_tables.region.set_leaf_pte(page_table + ((va >> 21) & 0x1ff) * 8,
-                                               (pa >> 2) | PTE_AD | PTE_USER | PTE_RWXV);
+        if pte == 0 {
+            continue;
+        }
+
+        let entry = PageTableEntry {
+            va: base + (i << (12 + level as u64 * 9)),
+            pa: (pte >> 10) << 12,
+            level,
+            pte_addr,
+            flags: pte & 0x3ff,
+            pte,
+        };
+        visit(Ok(entry));
+
+        if entry.is_valid() && !entry.is_leaf() {
+            if level == 0 {
+                visit(Err(PageTableError::TooDeep { va: entry.va, pa: entry.pa }));
+            } else if ancestors.contains(&entry.pa) {
+                visit(Err(PageTableError::Cycle { va: entry.va, pa: entry.pa }));
+            } else {
+                collect_page_table_inner(read_pte, entry.pa, level - 1, entry.va, ancestors, visit);
+            }
+        }
     }
 
-    (shadow_page_tables, guest_memory, guest_shift)
+    ancestors.pop();
 }
 
 #[allow(unused)]
 pub fn print_page_table(page_table_region: &PageTableRegion, pt: u64, level: u8) {
-    for i in 0..512 {
-        let pte = page_table_region[pt + i*8];
-        if pte & PTE_VALID != 0 {
-            for _ in 0..(3 - level) {
+    collect_page_table(&|pa| Some(page_table_region[pa]), pt, level, 0, &mut |entry| match entry {
+        Ok(entry) => if entry.is_valid() {
+            for _ in 0..(3 - entry.level) {
                 print!("  ");
             }
-            println!("{:#x}: {:#x}", i * 8, pte);
+            println!("{:#x}: {:#x}", entry.pte_addr & 0xfff, entry.pte);
         }
-        if pte & PTE_RWXV == PTE_VALID {
-            assert!(level != 0);
-            print_page_table(page_table_region, (pte >> 10) << 12, level - 1);
-        }
-    }
+        Err(e) => println!("{:?}", e),
+    });
 }
 
 #[allow(unused)]
@@ -426,75 +1927,482 @@ pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, b
         return;
     }
 
-    for i in 0..512 {
-        let addr = base + (i << (12 + level * 9));
-        let pte = guest_memory[pt + i*8];
-        if pte == 0 {
-            continue;
+    collect_page_table(&|pa| guest_memory.get(pa), pt, level, base, &mut |entry| match entry {
+        Ok(entry) => {
+            for _ in 0..(2 - entry.level) {
+                print!("__ ");
+            }
+
+            if entry.is_leaf() {
+                println!("{:#x} -> {:#x}", entry.va, entry.pa);
+            } else if entry.is_valid() {
+                println!("{:#x}: {:#x}", entry.va, entry.pte);
+            } else {
+                println!("{:#x}: {:#x} (not valid)", entry.va, entry.pte);
+            }
         }
+        Err(PageTableError::OutOfRegion { va, pa }) => println!("{:#x}: {:#x} (bad ppn)", va, pa),
+        Err(PageTableError::TooDeep { va, pa }) => println!("{:#x}: {:#x} (too deep)", va, pa),
+        Err(PageTableError::Cycle { va, pa }) => println!("{:#x}: {:#x} (cycle detected)", va, pa),
+    });
+}
+
+/// A shadow leaf mapping that doesn't follow from the guest's page tables.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Guest virtual address mapped by the shadow PTE.
+    pub va: u64,
+    /// Host physical address and flags of the shadow PTE.
+    pub shadow_pa: u64,
+    pub shadow_flags: u64,
+    /// An access the shadow PTE allows.
+    pub access: AccessType,
+    /// Host physical address the guest's page tables map `va` to for `access`, or None if they
+    /// don't permit it at all.
+    pub expected_pa: Option<u64>,
+}
+
+/// Compare the leaves of the shadow page table `root` against the guest page table selected by
+/// `satp`, calling `visit` for each access a shadow PTE allows that the guest's PTE doesn't, or for
+/// which the shadow maps somewhere other than the memory backing the guest's translation. Shadows
+/// are visited in address order and each shadow PTE reports read, write and execute in turn.
+pub fn diff_shadow_vs_guest<F: FnMut(Divergence)>(state: &Context, root: PageTableRoot, satp: u64, visit: &mut F) {
+    assert!(root != MPA);
+    let mode = match SatpMode::from_satp(satp) {
+        Some(SatpMode::Bare) | None => return,
+        Some(mode) => mode,
+    };
+    let guest_root = (satp & riscv::bits::SATP_PPN) << 12;
+    let user_mode = root == UVA;
+    let sstatus = match root {
+        MVA => state.csrs.sstatus | STATUS_SUM,
+        _ => state.csrs.sstatus & !STATUS_SUM,
+    };
 
-        for _ in 0..(2 - level) {
-            print!("__ ");
+    let region = &state.shadow_page_tables.region;
+    let read_pte = |pa| if region.contains_pte(pa) { Some(region[pa]) } else { None };
+    let (top, level) = state.shadow_page_tables.shadow_root(root);
+    let va_bits = 12 + 9 * (level as u64 + 1);
+    collect_page_table(&read_pte, top, level, 0, &mut |entry| {
+        let entry = match entry {
+            Ok(entry) if entry.is_leaf() => entry,
+            _ => return,
+        };
+        // Sign extend the Sv39 or Sv48 address, then skip the hypervisor's own mappings.
+        let va = if entry.va & (1 << (va_bits - 1)) != 0 { entry.va | !((1 << va_bits) - 1) } else { entry.va };
+        if va >= DIRECT_MAP_OFFSET {
+            return;
         }
 
-        if pte & PTE_RWXV == PTE_VALID {
-            assert!(level != 0);
-            let child = (pte >> 10) << 12;
-            if !guest_memory.in_region(child) {
-                println!("{:#x}: {:#x} (bad ppn)", addr, pte);
-            } else {
-                println!("{:#x}: {:#x}", addr, pte);
-                print_guest_page_table(guest_memory, child, level - 1, addr);
-                //break;
+        for &access in &[AccessType::Read, AccessType::Write, AccessType::Execute] {
+            if entry.flags & access.pte_bit() == 0 {
+                continue;
+            }
+            let expected_pa = translate_guest_address_checked(&state.guest_memory, mode, guest_root, va,
+                                                              access, user_mode, sstatus)
+                .ok()
+                .map(|t| if state.guest_memory.in_region(t.guest_pa) {
+                    t.guest_pa + state.guest_shift
+                } else {
+                    t.guest_pa
+                });
+            if expected_pa != Some(entry.pa) {
+                visit(Divergence { va, shadow_pa: entry.pa, shadow_flags: entry.flags, access, expected_pa });
             }
-        } else if pte & PTE_VALID != 0 {
-            println!("{:#x} -> {:#x}", addr, (pte >> 10) << 12);
-        } else if pte != 0 {
-            println!("{:#x}: {:#x} (not valid)", addr, pte);
         }
+    });
+}
+
+/// Print the guest's current page table, then the shadow page table `root` derived from it, then
+/// any places where they disagree.
+#[allow(unused)]
+pub fn print_shadow_and_guest_tables(state: &Context, root: PageTableRoot) {
+    let satp = state.csrs.satp;
+    println!("Guest page table (satp = {:#x}):", satp);
+    print_guest_page_table(&state.guest_memory, (satp & riscv::bits::SATP_PPN) << 12, 2, 0);
+    println!("Shadow page table {:?}:", root);
+    let (top, level) = state.shadow_page_tables.shadow_root(root);
+    print_page_table(&state.shadow_page_tables.region, top, level);
+    diff_shadow_vs_guest(state, root, satp, &mut |d| {
+        println!("{:#x} -> {:#x} [{:#x}] allows {:?} but guest maps it to {:x?}",
+                 d.va, d.shadow_pa, d.shadow_flags, d.access, d.expected_pa);
+    });
+}
+
+/// Maximum number of guest page table pages that can be write protected at once.
+pub const MAX_PROTECTED_PAGE_TABLES: usize = 256;
+
+/// A guest page table page that is write protected in the shadow page tables, so that the shadow
+/// mappings derived from it can be updated as soon as it changes.
+#[derive(Copy, Clone, Debug)]
+pub struct ProtectedPageTable {
+    /// Guest physical address of the page table.
+    pub guest_pa: u64,
+    /// First virtual address translated by the page table, truncated to `va_bits` bits.
+    pub va_base: u64,
+    /// Number of bytes of address space translated by each entry.
+    pub entry_size: u64,
+    /// Width of a virtual address in the guest's translation mode.
+    pub va_bits: u32,
+}
+impl ProtectedPageTable {
+    /// Returns the virtual address translated by the entry at `pte_addr`.
+    pub fn va_for_pte(&self, pte_addr: u64) -> u64 {
+        // Sv32 addresses are zero extended and have 4 byte PTEs.
+        if self.va_bits == 32 {
+            return self.va_base + ((pte_addr & 0xfff) / 4) * self.entry_size;
+        }
+
+        let va = self.va_base + ((pte_addr & 0xfff) / 8) * self.entry_size;
+        let shift = 64 - self.va_bits;
+        (((va << shift) as i64) >> shift) as u64
     }
 }
 
-pub fn flush_shadow_page_table(shadow_page_tables: &mut PageTables) {
-    for &root in &[UVA, KVA, MVA] {
-        shadow_page_tables.clear_page_table_range(shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8);
+/// Returns the shadow page table that matches the guest's current privilege level, `satp` and
+/// `sstatus.SUM`. It must be installed before returning to the guest.
+pub fn active_root(state: &Context) -> PageTableRoot {
+    if (state.csrs.satp & SATP_MODE) == 0 {
+        MPA
+    } else if state.current_mode() == PrivilegeMode::User {
+        UVA
+    } else if state.csrs.sstatus & STATUS_SUM == 0 {
+        KVA
+    } else {
+        MVA
     }
+}
 
-    riscv::sfence_vma();
+pub fn flush_shadow_page_table(shadow_page_tables: &mut PageTables) {
+    shadow_page_tables.invalidate_all();
+}
+
+/// Stop write protecting the guest's page tables. Only valid right after the shadow page tables
+/// have been flushed, since writes to the tables will no longer remove mappings derived from them.
+pub fn forget_protected_page_tables(state: &mut Context) {
+    state.protected_page_tables.clear();
 }
 
 #[inline]
 pub fn handle_sfence_vma(state: &mut Context, instruction: RType) {
-    if instruction.rs1() == 0 {
-        flush_shadow_page_table(&mut state.shadow_page_tables);
+    let fence_va = Some(instruction.rs1()).filter(|&r| r != 0).map(|r| state.saved_registers.get(r));
+    let fence_asid = Some(instruction.rs2()).filter(|&r| r != 0)
+        .map(|r| state.saved_registers.get(r) & (riscv::bits::SATP_ASID >> 44));
+    sfence_vma(state, fence_va, fence_asid);
+}
+
+/// Perform a guest `sfence.vma` for the page containing `fence_va` (or every address if None) in
+/// `fence_asid` (or every address space if None).
+pub fn sfence_vma(state: &mut Context, fence_va: Option<u64>, fence_asid: Option<u64>) {
+    state.translation_cache.invalidate(fence_va, fence_asid);
+
+    // The shadow page tables only ever contain translations for the current ASID, so fences
+    // targeting any other ASID can be ignored. Fences for the current ASID spare global mappings.
+    if let Some(asid) = fence_asid {
+        if asid != state.shadow_page_tables.asid() || fence_va.is_none() {
+            state.shadow_page_tables.invalidate_asid(asid);
+            return;
+        }
+    }
+
+    if let Some(va) = fence_va {
+        state.shadow_page_tables.flush_guest_page(va, fence_asid.is_some());
     } else {
-        let va = state.saved_registers.get(instruction.rs1());
-        if va < DIRECT_MAP_OFFSET {
-            for &root in &[UVA, KVA, MVA] {
-                let pte_addr = state.shadow_page_tables.pte_for_addr(root, va);
-
-                match (state.shadow_page_tables.region[pte_addr] >> 8) & 0x3 {
-                    0 => state.shadow_page_tables.region.set_invalid_pte(pte_addr, 0),
-                    1 => for i in 0..512 {
-                        state.shadow_page_tables.region.set_invalid_pte(
-                            (pte_addr & !(PAGE_SIZE - 1)) + i * 8, 0)
-                    }
-                    _ => state.shadow_page_tables.clear_page_table_range(
-                        state.shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8),
-                }
-            }
-            riscv::sfence_vma_addr(va);
+        state.shadow_page_tables.invalidate_all();
+        forget_protected_page_tables(state);
+    }
+}
+
+#[allow(unused)]
+pub fn dump_pmap_stats(state: &Context) {
+    let shadow_page_tables = &state.shadow_page_tables;
+    let stats = shadow_page_tables.flush_stats();
+    println!("Shadow page table flushes: {} total, {} full, {} targeted, {} ignored",
+             stats.total_flushes, stats.full_flushes, stats.targeted_flushes, stats.ignored_flushes);
+    println!("Shadow PTEs invalidated: {}", stats.ptes_invalidated);
+    println!("Page table pages: {} used, {} free, {} low watermark, {} allocations",
+             shadow_page_tables.used_pages(), shadow_page_tables.free_pages(),
+             shadow_page_tables.low_memory_watermark(), shadow_page_tables.total_allocations());
+    println!("Guest accesses: {} walked, {} direct",
+             GUEST_ACCESS_WALKS.load(Ordering::Relaxed), GUEST_ACCESS_DIRECT.load(Ordering::Relaxed));
+    println!("Translation cache: {} hits, {} misses",
+             state.translation_cache.hits(), state.translation_cache.misses());
+}
+
+/// Reasons that an access to guest memory through the guest's page tables can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuestAccessError {
+    /// The guest virtual address could not be translated.
+    PageFault,
+    /// The address translated to a guest physical address that isn't backed by guest memory.
+    AccessFault,
+    /// The address was not naturally aligned for the access size.
+    Misaligned,
+}
+impl GuestAccessError {
+    /// Returns the scause value the guest should see for this error.
+    pub fn cause(&self, access: AccessType) -> u64 {
+        use riscv::bits::*;
+        match (*self, access) {
+            (GuestAccessError::PageFault, _) => access.page_fault_cause(),
+            (GuestAccessError::AccessFault, AccessType::Read) => SCAUSE_LOAD_ACCESS_FAULT,
+            (GuestAccessError::AccessFault, AccessType::Write) => SCAUSE_STORE_ACCESS_FAULT,
+            (GuestAccessError::AccessFault, AccessType::Execute) => SCAUSE_INSN_ACCESS_FAULT,
+            (GuestAccessError::Misaligned, AccessType::Read) => SCAUSE_LOAD_MISALIGNED,
+            (GuestAccessError::Misaligned, AccessType::Write) => SCAUSE_ATOMIC_MISALIGNED,
+            (GuestAccessError::Misaligned, AccessType::Execute) => SCAUSE_INSN_MISALIGNED,
         }
     }
 }
 
-pub fn read64(guest_memory: &MemoryRegion, page_table_ppn: u64, guest_va: u64) -> Option<u64> {
+/// Number of `read64`/`write64` accesses that required a walk of the guest page tables, and the
+/// number that were made directly because guest translation was disabled. Kept in the data
+/// segment, so each hart counts its own accesses.
+static GUEST_ACCESS_WALKS: AtomicU64 = AtomicU64::new(0);
+static GUEST_ACCESS_DIRECT: AtomicU64 = AtomicU64::new(0);
+
+/// Load the word at guest virtual address `guest_va`, translating through the guest page table
+/// selected by `satp`. Translations are looked up in `cache` first.
+pub fn try_read64(guest_memory: &MemoryRegion, cache: &mut TranslationCache, satp: u64, guest_va: u64)
+                  -> Result<u64, GuestAccessError> {
+    if guest_va % 8 != 0 {
+        return Err(GuestAccessError::Misaligned);
+    }
+
+    // Without guest translation, virtual addresses are already guest physical addresses and there
+    // are no permissions to check, so guest memory can be read directly.
+    if SatpMode::from_satp(satp) == Some(SatpMode::Bare) {
+        GUEST_ACCESS_DIRECT.fetch_add(1, Ordering::Relaxed);
+        let value = guest_memory.get(guest_va).ok_or(GuestAccessError::AccessFault)?;
+        trace::record(AccessType::Read, guest_va, guest_va, 8, value, true);
+        return Ok(value);
+    }
+
+    GUEST_ACCESS_WALKS.fetch_add(1, Ordering::Relaxed);
+    let (guest_pa, _) = cache.translate(guest_memory, satp, guest_va).ok_or(GuestAccessError::PageFault)?;
+    let value = guest_memory.get(guest_pa).ok_or(GuestAccessError::AccessFault)?;
+    trace::record(AccessType::Read, guest_va, guest_pa, 8, value, false);
+    Ok(value)
+}
+
+/// Fetch the instruction at guest virtual address `pc` as the guest would, translating through the
+/// guest page table selected by `satp` and requiring execute permission (and, for `user_mode`, the U
+/// bit). Compressed instructions are returned in their 16-bit form. Each 16-bit parcel is translated
+/// separately, so an instruction may straddle two differently mapped pages. On failure, returns the
+/// error along with the address of the parcel that couldn't be fetched, which is the `stval` the
+/// guest should see.
+pub fn fetch_guest_instruction(guest_memory: &MemoryRegion, satp: u64, pc: u64, user_mode: bool)
+                               -> Result<u32, (GuestAccessError, u64)> {
+    let mode = SatpMode::from_satp(satp).ok_or((GuestAccessError::AccessFault, pc))?;
+    let root = (satp & riscv::bits::SATP_PPN) << 12;
+    let fetch_parcel = |va: u64| -> Result<u16, GuestAccessError> {
+        if va % 2 != 0 {
+            return Err(GuestAccessError::Misaligned);
+        }
+        let guest_pa = if mode == SatpMode::Bare {
+            va
+        } else {
+            let page_translation = translate_guest_address_checked(guest_memory, mode, root, va & !0xfff,
+                                                                   AccessType::Execute, user_mode, 0)
+                .map_err(|_| GuestAccessError::PageFault)?;
+            (page_translation.guest_pa & !0xfff) | (va & 0xfff)
+        };
+        guest_memory.get_u16(guest_pa).ok_or(GuestAccessError::AccessFault)
+    };
+
+    let low = fetch_parcel(pc).map_err(|e| (e, pc))?;
+    match riscv_decode::instruction_length(low) {
+        4 => {
+            let high_va = pc.wrapping_add(2);
+            let high = fetch_parcel(high_va).map_err(|e| (e, high_va))?;
+            Ok(low as u32 | (high as u32) << 16)
+        }
+        // Longer instructions aren't supported, so only the first parcel is returned and the
+        // instruction will fail to decode.
+        _ => Ok(low as u32),
+    }
+}
+
+pub fn read64(guest_memory: &MemoryRegion, cache: &mut TranslationCache, satp: u64, guest_va: u64) -> Option<u64> {
+    try_read64(guest_memory, cache, satp, guest_va).ok()
+}
+
+/// Store `value` to the guest virtual address `guest_va`. The access is permission checked as if
+/// it were made by the guest supervisor with SUM set, and marks the guest PTE as dirty.
+pub fn write64(guest_memory: &mut MemoryRegion, mode: SatpMode, page_table_ppn: u64, guest_va: u64, value: u64)
+               -> Result<(), GuestAccessError> {
+    if guest_va % 8 != 0 {
+        return Err(GuestAccessError::Misaligned);
+    }
+
+    if mode == SatpMode::Bare {
+        GUEST_ACCESS_DIRECT.fetch_add(1, Ordering::Relaxed);
+        if !guest_memory.in_region(guest_va) {
+            return Err(GuestAccessError::AccessFault);
+        }
+        guest_memory[guest_va] = value;
+        trace::record(AccessType::Write, guest_va, guest_va, 8, value, true);
+        return Ok(());
+    }
+
+    GUEST_ACCESS_WALKS.fetch_add(1, Ordering::Relaxed);
     let guest_page = guest_va & !0xfff;
-    if let Some(page_translation) = translate_guest_address(guest_memory, page_table_ppn << 12, guest_page) {
-        // assert!(!virtio::is_queue_access(state, page_translation.guest_pa));
-        let guest_pa = (page_translation.guest_pa & !0xfff) | (guest_va & 0xfff);
-        return guest_memory.get(guest_pa);
+    let page_translation = loop {
+        match translate_guest_address_and_set_ad(guest_memory, mode, page_table_ppn << 12, guest_page,
+                                                 AccessType::Write, false, STATUS_SUM) {
+            Ok(translation) => break translation,
+            Err(TranslationError::Retry) => continue,
+            Err(_) => return Err(GuestAccessError::PageFault),
+        }
+    };
+
+    let guest_pa = (page_translation.guest_pa & !0xfff) | (guest_va & 0xfff);
+    if !guest_memory.in_region(guest_pa) {
+        return Err(GuestAccessError::AccessFault);
     }
+    guest_memory[guest_pa] = value;
+    trace::record(AccessType::Write, guest_va, guest_pa, 8, value, false);
+    Ok(())
+}
 
-    None
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain old data that can be copied to and from guest memory as little-endian bytes. The trait is
+/// sealed and only implemented for primitive integers, which have no padding or pointers and for
+/// which every bit pattern is valid.
+pub trait FromBytes: sealed::Sealed + Copy {
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn to_le_slice(self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_from_bytes {
+    ($($t:ty),*) => {$(
+        impl sealed::Sealed for $t {}
+        impl FromBytes for $t {
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                let mut buf = [0u8; mem::size_of::<$t>()];
+                buf.copy_from_slice(bytes);
+                <$t>::from_le_bytes(buf)
+            }
+            fn to_le_slice(self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*}
+}
+impl_from_bytes!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Size of the largest `FromBytes` type.
+const MAX_FROM_BYTES_SIZE: usize = 8;
+
+/// Translate `va` for a store, marking the guest PTE as accessed and dirty.
+fn guest_va_to_pa_for_write(guest_memory: &mut MemoryRegion, satp: u64, va: u64) -> Option<u64> {
+    let mode = SatpMode::from_satp(satp)?;
+    if mode == SatpMode::Bare {
+        return Some(va);
+    }
+
+    let root = (satp & riscv::bits::SATP_PPN) << 12;
+    loop {
+        match translate_guest_address_and_set_ad(guest_memory, mode, root, va & !0xfff,
+                                                 AccessType::Write, false, STATUS_SUM) {
+            Ok(translation) => return Some((translation.guest_pa & !0xfff) | (va & 0xfff)),
+            Err(TranslationError::Retry) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Split the `len` bytes at guest virtual address `va` into pieces that don't cross a page
+/// boundary, calling `f` with the offset and length of each.
+fn for_each_page_piece<F: FnMut(u64, usize, usize) -> Option<()>>(va: u64, len: usize, mut f: F) -> Option<()> {
+    let mut offset = 0;
+    while offset < len {
+        let piece_va = va.checked_add(offset as u64)?;
+        let piece_len = (len - offset).min((PAGE_SIZE - piece_va % PAGE_SIZE) as usize);
+        f(piece_va, offset, piece_len)?;
+        offset += piece_len;
+    }
+    Some(())
+}
+
+/// Read a little-endian `T` from guest virtual address `va`, translating through the guest page
+/// table selected by `satp`. Each page is translated separately, so the value may straddle two
+/// differently mapped pages. Returns None if any byte isn't mapped or isn't in guest memory.
+pub fn read_guest<T: FromBytes>(guest_memory: &MemoryRegion, satp: u64, va: u64) -> Option<T> {
+    let mut buf = [0u8; MAX_FROM_BYTES_SIZE];
+    let bytes = &mut buf[..mem::size_of::<T>()];
+    for_each_page_piece(va, bytes.len(), |piece_va, offset, len| {
+        let (guest_pa, _, _) = guest_va_to_pa(guest_memory, satp, piece_va)?;
+        guest_memory.copy_to_slice(guest_pa, &mut bytes[offset..][..len]).ok()
+    })?;
+    Some(T::from_le_slice(bytes))
+}
+
+/// Write `value` to guest virtual address `va` in little-endian byte order, marking the guest PTEs
+/// dirty. Nothing is written unless every byte is mapped and in guest memory.
+pub fn write_guest<T: FromBytes>(guest_memory: &mut MemoryRegion, satp: u64, va: u64, value: T) -> Option<()> {
+    let mut buf = [0u8; MAX_FROM_BYTES_SIZE];
+    let bytes = &mut buf[..mem::size_of::<T>()];
+    value.to_le_slice(bytes);
+
+    let mut pieces = [(0, 0, 0); 2];
+    let mut count = 0;
+    for_each_page_piece(va, bytes.len(), |piece_va, offset, len| {
+        let guest_pa = guest_va_to_pa_for_write(guest_memory, satp, piece_va)?;
+        if !guest_memory.in_region(guest_pa) || !guest_memory.in_region(guest_pa + len as u64 - 1) {
+            return None;
+        }
+        pieces[count] = (guest_pa, offset, len);
+        count += 1;
+        Some(())
+    })?;
+
+    for &(guest_pa, offset, len) in &pieces[..count] {
+        guest_memory.copy_from_slice(guest_pa, &bytes[offset..][..len]).unwrap();
+    }
+    Some(())
+}
+
+/// The first address of a guest buffer that can't be accessed, and why.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FaultInfo {
+    pub error: GuestAccessError,
+    /// Guest virtual address of the first inaccessible byte, which is the `stval` the guest should
+    /// see if the fault is reflected to it.
+    pub va: u64,
+}
+
+/// Check that every byte of the `len` bytes at guest virtual address `va` can be accessed with
+/// `access`, as the guest supervisor with SUM set, and is backed by guest memory. Pages are checked
+/// in order and the first one that fails is reported, so a caller that validates a buffer up front
+/// never ends up performing only part of an operation. Nothing in guest memory is modified, so the
+/// accessed and dirty bits are left for the access itself to set.
+pub fn validate_guest_range(guest_memory: &MemoryRegion, satp: u64, va: u64, len: u64, access: AccessType)
+                            -> Result<(), FaultInfo> {
+    let mode = SatpMode::from_satp(satp)
+        .ok_or(FaultInfo { error: GuestAccessError::AccessFault, va })?;
+    let root = (satp & riscv::bits::SATP_PPN) << 12;
+    let end = va.checked_add(len)
+        .ok_or(FaultInfo { error: GuestAccessError::PageFault, va })?;
+
+    let mut piece_va = va;
+    while piece_va < end {
+        let piece_end = ((piece_va & !0xfff).saturating_add(PAGE_SIZE)).min(end);
+        let guest_pa = if mode == SatpMode::Bare {
+            piece_va
+        } else {
+            let page_translation = translate_guest_address_checked(guest_memory, mode, root, piece_va & !0xfff,
+                                                                   access, false, STATUS_SUM)
+                .map_err(|_| FaultInfo { error: GuestAccessError::PageFault, va: piece_va })?;
+            (page_translation.guest_pa & !0xfff) | (piece_va & 0xfff)
+        };
+        if !guest_memory.in_region(guest_pa) || !guest_memory.in_region(guest_pa + (piece_end - piece_va) - 1) {
+            return Err(FaultInfo { error: GuestAccessError::AccessFault, va: piece_va });
+        }
+        piece_va = piece_end;
+    }
+    Ok(())
 }
@@ -1,3 +1,4 @@
+use arrayvec::ArrayVec;
 use core::{fmt, ptr};
 use spin::MutexGuard;
 use crate::statics::SHARED_STATICS;
@@ -100,6 +101,45 @@ impl UartWriter {
         self.inner.getchar(pmap::pa2va(self.pa))
     }
 
+    /// Wait until everything written so far has left the UART's transmitter.
+    pub fn flush(&mut self) {
+        let base_address = pmap::pa2va(self.pa);
+        unsafe {
+            match self.inner {
+                UartWriterInner::Ns16550a { initialized: false } => {}
+                UartWriterInner::Ns16550a { initialized: true } => {
+                    // Line status register: transmitter empty.
+                    while ptr::read_volatile((base_address as *mut u8).offset(5)) & 0x40 == 0 {}
+                }
+                UartWriterInner::SiFive => {
+                    // The transmit FIFO's level can't be read, so only wait until it isn't full.
+                    while ptr::read_volatile(base_address as *mut u32) & 0x80000000 != 0 {}
+                }
+            }
+        }
+    }
+
+    /// Have the UART raise an interrupt whenever it receives data.
+    pub fn enable_rx_interrupt(&mut self) {
+        let base_address = pmap::pa2va(self.pa);
+        unsafe {
+            match self.inner {
+                UartWriterInner::Ns16550a { ref mut initialized } => {
+                    if !*initialized {
+                        UartWriterInner::initialize_ns16550a(base_address as *mut u8);
+                        *initialized = true;
+                    }
+                    // Interrupt enable register: received data available.
+                    ptr::write_volatile((base_address as *mut u8).offset(1), 0x01);
+                }
+                UartWriterInner::SiFive => {
+                    // Interrupt enable register: receive watermark.
+                    ptr::write_volatile((base_address as *mut u32).offset(4), 0x2);
+                }
+            }
+        }
+    }
+
     pub unsafe fn init(&mut self, address: u64, ty: UartType) {
         if let UartWriterInner::Ns16550a { initialized: true } = self.inner {
             assert_eq!(self.pa, address);
@@ -166,6 +206,51 @@ pub fn guest_println(guestid: u64, line: &[u8]) {
     writer.write_str("\n").unwrap();
 }
 
+/// Output from a guest console. When multiple guests are running, their output is buffered a line
+/// at a time and tagged with the guest's ID.
+pub struct GuestOutput {
+    guestid: Option<u64>,
+    line_buffer: ArrayVec<[u8; 256]>,
+}
+
+impl GuestOutput {
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            guestid,
+            line_buffer: ArrayVec::new(),
+        }
+    }
+
+    pub fn output_byte(&mut self, value: u8) {
+        if let Some(guestid) = self.guestid {
+            let len = self.line_buffer.len();
+            if len > 0 && self.line_buffer[len - 1] == '\r' as u8 && value != '\n' as u8 {
+                guest_println(guestid, &self.line_buffer);
+                self.line_buffer.clear();
+            }
+            if value == '\n' as u8 || self.line_buffer.is_full() {
+                guest_println(guestid, &self.line_buffer);
+                self.line_buffer.clear();
+            } else {
+                self.line_buffer.push(value);
+            }
+        } else {
+            SHARED_STATICS.uart_writer.lock().putchar(value);
+        }
+    }
+
+    /// Print any partial line that is still being buffered and wait for it to be transmitted.
+    pub fn flush(&mut self) {
+        if let Some(guestid) = self.guestid {
+            if !self.line_buffer.is_empty() {
+                guest_println(guestid, &self.line_buffer);
+                self.line_buffer.clear();
+            }
+        }
+        SHARED_STATICS.uart_writer.lock().flush();
+    }
+}
+
 pub fn mwriter<'a>() -> Option<MutexGuard<'a, UartWriter>> {
     SHARED_STATICS.uart_writer.try_lock()
 }
@@ -0,0 +1,127 @@
+//! Streaming dumps of guest physical memory.
+//!
+//! A dump is made in chunks so that it can be interleaved with running the guest: each call to
+//! `RamDump::step` copies out at most a fixed number of bytes and then returns. Only memory that is
+//! currently backed in MPA is written, so pages that were never touched (with `lazy_guest_memory`)
+//! or that have been reclaimed by the balloon are skipped.
+
+use core::fmt::Write;
+use crate::context::Context;
+use crate::statics::SHARED_STATICS;
+
+/// Granularity at which backed memory is recorded.
+const REGION_SIZE: u64 = 2 << 20;
+/// Enough regions to cover a 1GB hart segment.
+const MAX_REGIONS: usize = 512;
+
+/// Destination for the contents of a dump.
+pub trait DumpSink {
+    /// Called once before any data with the guest physical range being dumped.
+    fn begin(&mut self, base: u64, len: u64);
+    /// Called with the contents of guest memory starting at `guest_pa`.
+    fn write(&mut self, guest_pa: u64, data: &[u8]);
+    /// Called once the dump is complete, with one bit per 2MB region of guest memory that was at
+    /// least partially backed.
+    fn finish(&mut self, backed_regions: &[u64]);
+}
+
+/// Writes dumps to the host UART as lines of text: a `B <base> <len>` header, then one
+/// `D <guest_pa> <hex bytes>` line per 32 bytes of data, and finally a `E <bitmap>` trailer.
+pub struct UartDumpSink;
+
+impl DumpSink for UartDumpSink {
+    fn begin(&mut self, base: u64, len: u64) {
+        let _ = writeln!(SHARED_STATICS.uart_writer.lock(), "B {:x} {:x}", base, len);
+    }
+
+    fn write(&mut self, guest_pa: u64, data: &[u8]) {
+        let mut writer = SHARED_STATICS.uart_writer.lock();
+        for (i, line) in data.chunks(32).enumerate() {
+            let _ = write!(writer, "D {:x} ", guest_pa + i as u64 * 32);
+            for byte in line {
+                let _ = write!(writer, "{:02x}", byte);
+            }
+            let _ = writeln!(writer);
+        }
+    }
+
+    fn finish(&mut self, backed_regions: &[u64]) {
+        let mut writer = SHARED_STATICS.uart_writer.lock();
+        let _ = write!(writer, "E ");
+        for word in backed_regions {
+            let _ = write!(writer, "{:016x}", word);
+        }
+        let _ = writeln!(writer);
+    }
+}
+
+/// Progress of a dump of guest memory.
+pub struct RamDump {
+    next_pa: u64,
+    end: u64,
+    started: bool,
+    backed_regions: [u64; MAX_REGIONS / 64],
+}
+
+impl RamDump {
+    /// Prepare to dump the first `gpm_size` bytes of the guest's memory.
+    pub fn new(state: &Context, gpm_size: u64) -> Self {
+        let base = state.guest_memory.base();
+        Self {
+            next_pa: base,
+            end: base + gpm_size.min(state.guest_memory.len()).min(REGION_SIZE * MAX_REGIONS as u64),
+            started: false,
+            backed_regions: [0; MAX_REGIONS / 64],
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_pa >= self.end
+    }
+
+    /// Bitmap of the 2MB regions found to be backed so far.
+    pub fn backed_regions(&self) -> &[u64] {
+        &self.backed_regions
+    }
+
+    /// Write out up to `max_bytes` more of guest memory. Returns whether the dump is complete.
+    pub fn step<S: DumpSink>(&mut self, state: &Context, sink: &mut S, max_bytes: u64) -> bool {
+        let base = state.guest_memory.base();
+        let was_done = self.started && self.is_done();
+        if !self.started {
+            self.started = true;
+            sink.begin(base, self.end - base);
+        }
+
+        let mut remaining = max_bytes;
+        while remaining > 0 && !self.is_done() {
+            let level = state.shadow_page_tables.mpa_leaf_level(self.next_pa);
+            let page_size = level.unwrap_or_else(|hole| hole).page_size();
+            let page_end = ((self.next_pa & !(page_size - 1)) + page_size).min(self.end);
+
+            if level.is_err() {
+                // Unbacked, so skip the whole hole at once. Each lookup still costs a little, so
+                // charge it against the budget as if it were a line of output.
+                self.next_pa = page_end;
+                remaining = remaining.saturating_sub(32);
+                continue;
+            }
+
+            let region = ((self.next_pa - base) / REGION_SIZE) as usize;
+            self.backed_regions[region / 64] |= 1 << (region % 64);
+
+            let mut buffer = [0u8; 256];
+            let len = (page_end - self.next_pa).min(remaining).min(buffer.len() as u64) as usize;
+            if state.guest_memory.copy_to_slice(self.next_pa, &mut buffer[..len]).is_ok() {
+                sink.write(self.next_pa, &buffer[..len]);
+            }
+            self.next_pa += len as u64;
+            remaining -= len as u64;
+        }
+
+        if self.is_done() && !was_done {
+            sink.finish(&self.backed_regions);
+        }
+        self.is_done()
+    }
+}
@@ -10,6 +10,8 @@ pub const STATUS_FS: u64 = 3 << 13;
 pub const STATUS_XS: u64 = 3 << 15;
 pub const STATUS_SUM: u64 = 1 << 18;
 pub const STATUS_MXR: u64 = 1 << 19;
+pub const STATUS_UXL: u64 = 3 << 32;
+pub const STATUS_UXL_32: u64 = 1 << 32;
 pub const STATUS_SD: u64 = 1 << 63;
 
 pub const STATUS_MPP_M: u64 = 3 << 11;
@@ -44,6 +46,7 @@ pub const SCAUSE_INSN_MISALIGNED: u64 = 0;
 pub const SCAUSE_INSN_ACCESS_FAULT: u64 = 1;
 pub const SCAUSE_ILLEGAL_INSN: u64 = 2;
 pub const SCAUSE_BREAKPOINT: u64 = 3;
+pub const SCAUSE_LOAD_MISALIGNED: u64 = 4;
 pub const SCAUSE_LOAD_ACCESS_FAULT: u64 = 5;
 pub const SCAUSE_ATOMIC_MISALIGNED: u64 = 6;
 pub const SCAUSE_STORE_ACCESS_FAULT: u64 = 7;
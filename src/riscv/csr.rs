@@ -12,6 +12,7 @@ pub const uip: u64 = 0x044;
 pub const fflags: u64 = 0x001;
 pub const frm: u64 = 0x002;
 pub const fcsr: u64 = 0x003;
+pub const seed: u64 = 0x015;
 pub const cycle: u64 = 0xc00;
 pub const time: u64 = 0xc01;
 pub const instret: u64 = 0xc02;
@@ -1,5 +1,4 @@
-
-use crate::riscv::bits::STATUS_FS;
+use crate::riscv::bits::{STATUS_FS, STATUS_MXR};
 
 /// atomic read from CSR
 #[macro_export]
@@ -110,3 +109,7 @@ pub fn clear_sip(mask: u64) {
 pub fn set_sstatus_fs(new: u64) {
     unsafe { csrw!(sstatus, (new & STATUS_FS) | (csrr!(sstatus) & !STATUS_FS)) }
 }
+
+pub fn set_sstatus_mxr(new: u64) {
+    unsafe { csrw!(sstatus, (new & STATUS_MXR) | (csrr!(sstatus) & !STATUS_MXR)) }
+}
@@ -0,0 +1,244 @@
+//! Emulation of the SBI calls a guest kernel makes into its supervisor execution environment.
+//!
+//! The extension id is passed in a7 and, for v0.2 extensions, the function id in a6. Calls from the
+//! legacy (v0.1) extensions return a single value in a0, while v0.2 calls return an error code in a0
+//! and a value in a1.
+//!
+//! References:
+//!
+//! https://github.com/riscv/riscv-sbi-doc/blob/master/riscv-sbi.adoc
+
+use crate::context::Context;
+use crate::riscv::bits::SATP_ASID;
+use crate::trap::InterruptCause;
+use crate::{input, pmap, riscv, trap, virtio};
+
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const SBI_LEGACY_SET_TIMER: u64 = 0;
+const SBI_LEGACY_CONSOLE_PUTCHAR: u64 = 1;
+const SBI_LEGACY_CONSOLE_GETCHAR: u64 = 2;
+const SBI_LEGACY_REMOTE_FENCE_I: u64 = 5;
+const SBI_LEGACY_REMOTE_SFENCE_VMA: u64 = 6;
+const SBI_LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 7;
+const SBI_LEGACY_SHUTDOWN: u64 = 8;
+
+const SBI_EXT_BASE: u64 = 0x10;
+const SBI_EXT_TIME: u64 = 0x54494D45;
+const SBI_EXT_IPI: u64 = 0x735049;
+const SBI_EXT_RFENCE: u64 = 0x52464E43;
+const SBI_EXT_HSM: u64 = 0x48534D;
+const SBI_EXT_SRST: u64 = 0x53525354;
+
+/// Reset types for the SRST extension's `system_reset`.
+const SBI_SRST_TYPE_SHUTDOWN: u64 = 0;
+const SBI_SRST_TYPE_COLD_REBOOT: u64 = 1;
+const SBI_SRST_TYPE_WARM_REBOOT: u64 = 2;
+
+/// Values returned by the HSM extension's `hart_get_status`.
+const SBI_HSM_STATUS_STARTED: u64 = 0;
+
+/// Number of harts in each guest. Guest hart 0 is run by the host hart handling its ecalls.
+const GUEST_HARTS: u64 = 1;
+
+/// Value of `hart_mask_base` that selects every hart regardless of `hart_mask`.
+const HART_MASK_BASE_ALL: u64 = !0;
+
+/// Remote fences covering more pages than this flush the whole address space instead.
+const MAX_TARGETED_FENCE_PAGES: u64 = 16;
+
+/// Version of the SBI specification implemented, encoded as major << 24 | minor.
+const SBI_SPEC_VERSION: u64 = 2;
+/// Implementation id reported to the guest. Ids 0 through 4 are assigned to other implementations.
+const SBI_IMPL_ID: u64 = 0x5256;
+
+/// RVirt specific SBI call (from the firmware specific extension space) which flushes all guest
+/// console output to the host UART before returning 0 in a0. Guests can use it before crashing or
+/// shutting down to make sure no log output is lost. Block device writes are always committed by the
+/// time the guest sees them complete, so they need no flushing.
+pub const SBI_RVIRT_FLUSH: u64 = 0x0A00_0000;
+
+/// Handle an ecall made by the guest kernel. Returns whether the caller should advance sepc past the
+/// ecall, which isn't the case if the guest was rebooted.
+pub fn handle_ecall(state: &mut Context) -> bool {
+    let extension = state.saved_registers.get(17);
+    if extension == SBI_EXT_SRST && state.saved_registers.get(16) == 0 {
+        let reset_type = state.saved_registers.get(10);
+        match reset_type {
+            SBI_SRST_TYPE_SHUTDOWN => shutdown(state),
+            SBI_SRST_TYPE_COLD_REBOOT | SBI_SRST_TYPE_WARM_REBOOT => {
+                println!("Guest requested reboot (reason {})", state.saved_registers.get(11));
+                state.reset();
+                return false;
+            }
+            _ => {
+                state.saved_registers.set(10, SBI_ERR_INVALID_PARAM as u64);
+                state.saved_registers.set(11, 0);
+                return true;
+            }
+        }
+    }
+
+    match extension {
+        SBI_EXT_BASE | SBI_EXT_TIME | SBI_EXT_IPI | SBI_EXT_RFENCE | SBI_EXT_HSM | SBI_EXT_SRST => {
+            let function = state.saved_registers.get(16);
+            let (error, value) = match handle_call(state, extension, function) {
+                Ok(value) => (SBI_SUCCESS, value),
+                Err(error) => (error, 0),
+            };
+            state.saved_registers.set(10, error as u64);
+            state.saved_registers.set(11, value);
+        }
+        _ => {
+            let ret = handle_legacy_call(state, extension);
+            state.saved_registers.set(10, ret as u64);
+        }
+    }
+    true
+}
+
+/// Stop the guest for good. If the platform has a test finisher then the whole machine is powered
+/// off, otherwise the hart just idles.
+fn shutdown(state: &mut Context) -> ! {
+    state.uart.output.flush();
+    virtio::flush_devices(state);
+    if let Some(ref mut finisher) = state.test_finisher {
+        finisher.pass();
+    }
+    loop {
+        riscv::wfi();
+    }
+}
+
+/// Handle a call to one of the v0.2 extensions, returning either the value or an error code.
+fn handle_call(state: &mut Context, extension: u64, function: u64) -> Result<u64, i64> {
+    let a0 = state.saved_registers.get(10);
+    let a1 = state.saved_registers.get(11);
+    let a2 = state.saved_registers.get(12);
+    let a3 = state.saved_registers.get(13);
+    let a4 = state.saved_registers.get(14);
+    match (extension, function) {
+        (SBI_EXT_BASE, 0) => Ok(SBI_SPEC_VERSION),
+        (SBI_EXT_BASE, 1) => Ok(SBI_IMPL_ID),
+        (SBI_EXT_BASE, 2) => Ok(0),
+        (SBI_EXT_BASE, 3) => Ok(match a0 {
+            SBI_EXT_BASE | SBI_EXT_TIME | SBI_EXT_IPI | SBI_EXT_RFENCE | SBI_EXT_HSM | SBI_EXT_SRST => 1,
+            SBI_RVIRT_FLUSH => 1,
+            SBI_LEGACY_SET_TIMER | SBI_LEGACY_CONSOLE_PUTCHAR | SBI_LEGACY_CONSOLE_GETCHAR => 1,
+            SBI_LEGACY_REMOTE_FENCE_I | SBI_LEGACY_REMOTE_SFENCE_VMA => 1,
+            SBI_LEGACY_REMOTE_SFENCE_VMA_ASID | SBI_LEGACY_SHUTDOWN => 1,
+            _ => 0,
+        }),
+        // The machine id CSRs can't be read from S-mode, so report them as unimplemented.
+        (SBI_EXT_BASE, 4..=6) => Ok(0),
+        (SBI_EXT_TIME, 0) => {
+            trap::set_guest_timer(state, a0);
+            Ok(0)
+        }
+        (SBI_EXT_IPI, 0) => {
+            if decode_hart_mask(a0, a1)? & 1 != 0 {
+                let hartid = state.hartid;
+                trap::inject_interrupt(state, hartid, InterruptCause::Software);
+            }
+            Ok(0)
+        }
+        (SBI_EXT_RFENCE, 0) => {
+            if decode_hart_mask(a0, a1)? & 1 != 0 {
+                riscv::fence_i();
+            }
+            Ok(0)
+        }
+        (SBI_EXT_RFENCE, 1) => {
+            if decode_hart_mask(a0, a1)? & 1 != 0 {
+                fence_range(state, a2, a3, None);
+            }
+            Ok(0)
+        }
+        (SBI_EXT_RFENCE, 2) => {
+            if decode_hart_mask(a0, a1)? & 1 != 0 {
+                fence_range(state, a2, a3, Some(a4 & (SATP_ASID >> 44)));
+            }
+            Ok(0)
+        }
+        // Guests only have a single hart, which is necessarily running when it makes this call. So
+        // there is never a stopped hart to start, and stopping the hart ends the guest.
+        (SBI_EXT_HSM, 0) if a0 < GUEST_HARTS => Err(SBI_ERR_ALREADY_AVAILABLE),
+        (SBI_EXT_HSM, 1) => {
+            println!("Guest stopped its last hart");
+            loop {
+                riscv::wfi();
+            }
+        }
+        (SBI_EXT_HSM, 2) if a0 < GUEST_HARTS => Ok(SBI_HSM_STATUS_STARTED),
+        (SBI_EXT_HSM, 0) | (SBI_EXT_HSM, 2) => Err(SBI_ERR_INVALID_PARAM),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Handle a call to one of the legacy extensions, returning the value to place in a0.
+fn handle_legacy_call(state: &mut Context, extension: u64) -> i64 {
+    let a0 = state.saved_registers.get(10);
+    match extension {
+        SBI_LEGACY_SET_TIMER => {
+            trap::set_guest_timer(state, a0);
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_CONSOLE_PUTCHAR => {
+            state.uart.output_byte(a0 as u8);
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_CONSOLE_GETCHAR => input::getchar().map(|ch| ch as i64).unwrap_or(-1),
+        SBI_LEGACY_REMOTE_FENCE_I => {
+            riscv::fence_i();
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_REMOTE_SFENCE_VMA | SBI_LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
+            // a result, this function ignores the arguments and just does a global fence. This
+            // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
+            pmap::sfence_vma(state, None, None);
+            SBI_SUCCESS
+        }
+        SBI_LEGACY_SHUTDOWN => shutdown(state),
+        SBI_RVIRT_FLUSH => {
+            state.uart.output.flush();
+            virtio::flush_devices(state);
+            SBI_SUCCESS
+        }
+        _ => SBI_ERR_NOT_SUPPORTED,
+    }
+}
+
+/// Convert an SBI v0.2 `hart_mask` and `hart_mask_base` into a bitmask of guest harts. Selecting a
+/// hart that doesn't exist is an error.
+fn decode_hart_mask(hart_mask: u64, hart_mask_base: u64) -> Result<u64, i64> {
+    if hart_mask_base == HART_MASK_BASE_ALL {
+        return Ok((1 << GUEST_HARTS) - 1);
+    }
+    if hart_mask == 0 {
+        return Ok(0);
+    }
+
+    let highest = hart_mask_base.checked_add(63 - hart_mask.leading_zeros() as u64);
+    match highest {
+        Some(highest) if highest < GUEST_HARTS => Ok(hart_mask << hart_mask_base),
+        _ => Err(SBI_ERR_INVALID_PARAM),
+    }
+}
+
+/// Fence the guest pages overlapping `[start, start+size)`. Per the SBI specification, a `start`
+/// and `size` of zero or a `size` of all ones means the entire address space.
+fn fence_range(state: &mut Context, start: u64, size: u64, asid: Option<u64>) {
+    let end = start.checked_add(size).filter(|_| size != 0);
+    match end {
+        Some(end) if size <= MAX_TARGETED_FENCE_PAGES * 4096 => {
+            for page in ((start & !0xfff)..end).step_by(4096) {
+                pmap::sfence_vma(state, Some(page), asid);
+            }
+        }
+        _ => pmap::sfence_vma(state, None, asid),
+    }
+}
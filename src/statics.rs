@@ -1,5 +1,5 @@
 use arr_macro::arr;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64};
 use spin::Mutex;
 use crate::constants::*;
 use crate::print::{self, UartWriter};
@@ -22,6 +22,8 @@ pub enum IpiReason {
 pub struct Shared {
     pub boot_page_tables: [[u64; 1024]; MAX_HOST_HARTS],
     pub ipi_reason_array: [Mutex<Option<IpiReason>>; MAX_HOST_HARTS],
+    /// Guest `sip` bits injected into each hart's guest by other harts and not yet collected.
+    pub injected_interrupts: [AtomicU64; MAX_HOST_HARTS],
     pub uart_writer: Mutex<UartWriter>,
     pub hart_lottery: AtomicBool,
 }
@@ -62,6 +64,7 @@ const fn make_boot_page_tables_array() -> [[u64; 1024]; MAX_HOST_HARTS] {
 pub static __SHARED_STATICS_IMPL: Shared = Shared {
     boot_page_tables: make_boot_page_tables_array(),
     ipi_reason_array: arr![Mutex::new(None); 16],
+    injected_interrupts: arr![AtomicU64::new(0); 16],
     // see also: print::early_guess_uart
     uart_writer: Mutex::new(UartWriter {
         pa: 0x10000000,
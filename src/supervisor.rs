@@ -11,15 +11,24 @@
 #![feature(try_blocks)]
 
 use rvirt::*;
+use rvirt::memory_region::MemoryRegion;
 
 // mandatory rust environment setup
 #[lang = "eh_personality"] extern fn eh_personality() {}
-#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! { println!("{}", info); loop {}}
+#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! {
+    println!("{}", info);
+    unsafe { backtrace::dump_guest_state_on_panic() };
+    loop {}
+}
 #[start] fn start(_argc: isize, _argv: *const *const u8) -> isize {0}
 #[no_mangle] fn abort() -> ! { println!("Abort!"); loop {}}
 
 static GUEST_DTB: &'static [u8] = include_bytes!("guest.dtb");
 
+/// Upper bound on the memory given to each guest. Guests otherwise get whatever remains of their
+/// hart segment, which is also clamped to the end of host RAM.
+const MAX_GUEST_MEMORY: u64 = 960 << 20;
+
 #[link_section = ".initrd"]
 #[cfg(feature = "embed_guest_kernel")]
 static GUEST_KERNEL: [u8; include_bytes!(env!("RVIRT_GUEST_KERNEL")).len()] =
@@ -68,6 +77,7 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
     // successfully printing output.
     assert!(machine.initrd_end <= machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE);
     assert!(machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE);
+    assert!(machine.disk_end <= machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE);
     assert!(machine.harts.iter().any(|h| h.hartid == hartid));
     if !cfg!(feature = "embed_guest_kernel") && machine.initrd_end == 0 {
         println!("WARN: No guest kernel provided. Make sure to pass one with `-initrd or compile with --features embed_guest_kernel`");
@@ -104,6 +114,11 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
                 irq_mask |= 1u32 << irq;
             }
         }
+        if let (1, Some(irq)) = (guestid, machine.uart_irq) {
+            assert!(irq < 32);
+            irq_mask |= 1u32 << irq;
+            SHARED_STATICS.uart_writer.lock().enable_rx_interrupt();
+        }
 
         *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
         *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
@@ -117,6 +132,12 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
         core::ptr::copy(pa2va(device_tree_blob) as *const u8,
                         pa2va(hart_base_pa + 4096*2) as *mut u8,
                         fdt.total_size() as usize);
+        let kernel_size = if machine.initrd_start == machine.initrd_end {
+            GUEST_KERNEL.len() as u64
+        } else {
+            machine.initrd_end - machine.initrd_start
+        };
+        assert!(kernel_size <= pmap::KERNEL_IMAGE_SIZE, "Guest kernel too large ({} bytes)", kernel_size);
         if machine.initrd_start == machine.initrd_end {
             core::ptr::copy(&GUEST_KERNEL as *const _ as *const u8,
                             pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
@@ -126,6 +147,12 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
                             pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
                             (machine.initrd_end - machine.initrd_start) as usize);
         }
+        // Every guest gets its own copy of the disk image, so writes by one aren't seen by others.
+        if machine.disk_end > machine.disk_start {
+            core::ptr::copy(pa2va(machine.disk_start) as *const u8,
+                            pa2va(pmap::disk_image_pa(hart_base_pa, &machine)) as *mut u8,
+                            (machine.disk_end - machine.disk_start) as usize);
+        }
 
         let reason = IpiReason::TriggerHartEntry {
             a0: hart.hartid,
@@ -193,31 +220,83 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
     let machine = fdt.parse();
 
     // Initialize memory subsystem.
-    let (shadow_page_tables, guest_memory, guest_shift) =
-        pmap::init(hart_base_pa, shared_segments_shift, &machine);
+    let (mut shadow_page_tables, mut guest_memory, guest_shift) =
+        match pmap::init(hart_base_pa, shared_segments_shift, &machine, MAX_GUEST_MEMORY) {
+            Ok(result) => result,
+            Err(e) => panic!("Unable to set up guest memory: {:?}", e),
+        };
+    heap::init(pa2va(hart_base_pa + pmap::ALLOC_HEAP_OFFSET), pmap::ALLOC_HEAP_SIZE);
+    if cfg!(debug_assertions) {
+        csr::selftest();
+        pfault::selftest();
+        trap::selftest(&mut guest_memory);
+        drivers::queue::selftest(&mut guest_memory);
+    }
 
     // Load guest binary
-    let (entry, max_addr) = sum::access_user_memory(||{
-        elf::load_elf(pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *const u8,
-                      machine.physical_memory_offset as *mut u8)
-    });
+    let kernel = pa2va(hart_base_pa + pmap::HEAP_OFFSET);
+    let (entry, max_addr) = elf::load_elf(kernel as *const u8, &mut guest_memory);
     let guest_dtb = (max_addr | 0x1fffff) + 1;
     csrw!(sepc, entry);
 
-    // Load guest FDT.
-    let guest_machine = sum::access_user_memory(||{
-        core::ptr::copy(GUEST_DTB.as_ptr(),
-                        guest_dtb as *mut u8,
-                        GUEST_DTB.len());
+    // Load guest FDT. The built-in tree supplies the layout of the guest's devices, which is used to
+    // generate the tree the guest actually sees.
+    guest_memory.copy_from_slice(guest_dtb, GUEST_DTB).expect("Guest device tree doesn't fit in guest memory");
+    let mut guest_machine = sum::access_user_memory(||{
         let mut guest_fdt = Fdt::new(guest_dtb);
         guest_fdt.initialize_guest(guest_memory.len(), &machine.bootargs);
         guest_fdt.parse()
     });
+    guest_machine.physical_memory_offset = guest_memory.base();
+    guest_machine.physical_memory_size = guest_memory.len();
+    guest_machine.memory_banks = pmap::guest_memory_banks(&machine, guest_memory.len(), guest_shift).iter()
+        .map(|bank| (bank.guest_pa, bank.size))
+        .collect();
+    guest_machine.bootargs = machine.bootargs.clone();
+    guest_machine.timebase_frequency = machine.timebase_frequency;
+    if elf::is_elf32(kernel as *const u8) {
+        guest_machine.harts.iter_mut().for_each(fdt::Hart::set_rv32);
+    }
+    let disk = if machine.disk_end > machine.disk_start {
+        Some(MemoryRegion::with_base_address(pa2va(pmap::disk_image_pa(hart_base_pa, &machine)), 0,
+                                             machine.disk_end - machine.disk_start))
+    } else {
+        None
+    };
+    for i in 0..virtio::emulated_devices(disk.is_some()).len() {
+        let slot = virtio::FIRST_EMULATED_SLOT + i;
+        guest_machine.virtio.push(fdt::Device {
+            base_address: virtio::slot_address(slot),
+            size: 0x1000,
+            irq: slot as u64 + 1,
+        });
+    }
+
+    let mut guest_fdt = [0u8; context::GUEST_FDT_SIZE];
+    let dtb_region = (guest_dtb, context::GUEST_DTB_REGION_SIZE);
+    let guest_fdt_size = build_guest_fdt(&guest_machine, &[dtb_region], &mut guest_fdt);
+    guest_memory.copy_from_slice(guest_dtb, &guest_fdt[..guest_fdt_size])
+        .expect("Guest device tree doesn't fit in guest memory");
+    assert!(guest_memory.in_region(guest_dtb + context::GUEST_DTB_REGION_SIZE - 1),
+            "Guest device tree region doesn't fit in guest memory");
+    shadow_page_tables.mpa_write_protect(guest_dtb, context::GUEST_DTB_REGION_SIZE)
+        .expect("Out of hypervisor memory for page tables");
 
     // Initialize context
-    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_shift, hartid, guestid);
+    let boot = context::BootImage {
+        kernel,
+        guest_dtb,
+        fdt: guest_fdt,
+        fdt_len: guest_fdt_size,
+    };
+    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_shift, hartid, guestid,
+                        boot, disk);
+    if cfg!(debug_assertions) {
+        trap::sret_selftest();
+    }
 
-    // Jump into the guest kernel.
+    // Jump into the guest kernel, passing the hart id the guest boots on (always 0) and the address
+    // of its device tree.
     asm!("mv a1, $0 // dtb = guest_dtb
 
           li ra, 0
@@ -0,0 +1,79 @@
+//! Optional tracing of the guest memory accesses made or emulated by the hypervisor.
+//!
+//! With the `trace_guest_accesses` feature, every access through `pmap::read64`/`pmap::write64` and
+//! every emulated MMIO access is passed to the hook installed with `set_hook`. Accesses that take
+//! the direct path because guest paging is disabled are only reported after
+//! `set_trace_direct(true)`. Without the feature nothing is recorded and the calls compile away.
+//!
+//! The hook and flag live in the data segment, so each hart traces its own guest.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv_decode::Instruction;
+use spin::Mutex;
+use crate::context::Context;
+use crate::{pfault, trap};
+use crate::pmap::AccessType;
+
+/// A single guest memory access.
+#[derive(Copy, Clone, Debug)]
+pub struct TraceEvent {
+    pub access: AccessType,
+    pub guest_va: u64,
+    pub guest_pa: u64,
+    pub size: u8,
+    /// Value read or written, zero extended.
+    pub value: u64,
+}
+
+static HOOK: Mutex<Option<fn(&TraceEvent)>> = Mutex::new(None);
+static TRACE_DIRECT: AtomicBool = AtomicBool::new(false);
+
+/// Install `hook` to be called for each traced access, or remove the current one.
+pub fn set_hook(hook: Option<fn(&TraceEvent)>) {
+    *HOOK.lock() = hook;
+}
+
+/// Whether accesses made without walking guest page tables should also be reported.
+pub fn set_trace_direct(enabled: bool) {
+    TRACE_DIRECT.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn record(access: AccessType, guest_va: u64, guest_pa: u64, size: u8, value: u64, direct: bool) {
+    if !cfg!(feature = "trace_guest_accesses") || (direct && !TRACE_DIRECT.load(Ordering::Relaxed)) {
+        return;
+    }
+
+    // Release the lock before calling the hook, in case it makes traced accesses itself.
+    let hook = *HOOK.lock();
+    if let Some(hook) = hook {
+        hook(&TraceEvent { access, guest_va, guest_pa, size, value });
+    }
+}
+
+/// Record an emulated MMIO load or store made by `instruction`. Must be called after the access
+/// has been emulated, so that a load's destination register holds the value read.
+pub fn record_mmio(state: &Context, guest_va: u64, guest_pa: u64, instruction: u32) {
+    if !cfg!(feature = "trace_guest_accesses") {
+        return;
+    }
+
+    let size = match pfault::mmio_access_width(instruction, state.rv32) {
+        Some(size) => size as u8,
+        None => return,
+    };
+    let (access, value) = match pfault::emulate_mmio_store(state, instruction) {
+        Some(value) => (AccessType::Write, value),
+        None => {
+            let rd = match trap::decode(instruction, state.rv32).ok() {
+                Some(Instruction::Lb(i)) | Some(Instruction::Lbu(i)) | Some(Instruction::Lh(i)) |
+                Some(Instruction::Lhu(i)) | Some(Instruction::Lw(i)) | Some(Instruction::Lwu(i)) |
+                Some(Instruction::Ld(i)) => i.rd(),
+                _ => return,
+            };
+            let mask = if size == 8 { !0 } else { (1 << (size as u64 * 8)) - 1 };
+            (AccessType::Read, state.get_reg(rd) & mask)
+        }
+    };
+    record(access, guest_va, guest_pa, size, value, false);
+}
@@ -1,7 +1,12 @@
-use riscv_decode::Instruction;
-use crate::context::{Context, CONTEXT, IrqMapping};
+use riscv_decode::{DecodingError, Instruction};
+use crate::context::{Context, CONTEXT, IrqMapping, PrivilegeMode};
+use crate::csr::{self, CsrOp};
+use crate::memory_region::MemoryRegion;
+use crate::pmap::{AccessType, GuestAccessError, PTE_ACCESSED, PTE_RXV, PTE_VALID};
 use crate::riscv::bits::*;
-use crate::{pfault, pmap, riscv, sum, virtio};
+use crate::statics::SHARED_STATICS;
+use crate::{gdb, input, pfault, pmap, riscv, sbi, sum, virtio};
+use core::sync::atomic::Ordering;
 
 pub trait U64Bits {
     fn get(&self, mask: Self) -> bool;
@@ -131,17 +136,23 @@ pub fn strap() {
 
     let mut state = CONTEXT.lock();
     let mut state = (&mut *state).as_mut().unwrap();
+    collect_injected_interrupts(&mut state);
 
     // For the processor to have generated a load/store page fault or an illegal instruction fault,
     // the processor must have been able to load the relevant instruction (or else an access fault
     // or instruction page fault would have been triggered). Thus, it is safe to access memory
-    // pointed to by `sepc`.
+    // pointed to by `sepc`, except for the second half of an instruction crossing into another page.
     let instruction = match cause {
         SCAUSE_LOAD_PAGE_FAULT |
         SCAUSE_STORE_PAGE_FAULT |
-        SCAUSE_ILLEGAL_INSN => unsafe {
-            Some(load_instruction_at_address(&mut state, csrr!(sepc)))
-        }
+        SCAUSE_ILLEGAL_INSN => match unsafe { load_instruction_at_address(&mut state, csrr!(sepc)) } {
+            Ok(instruction) => Some(instruction),
+            Err((error, va)) => {
+                reflect_exception(&mut state, error.cause(AccessType::Execute), csrr!(sepc), va);
+                state.shadow_page_tables.install_root(pmap::active_root(state));
+                return;
+            }
+        },
         _ => None,
     };
 
@@ -153,135 +164,111 @@ pub fn strap() {
         if pfault::handle_page_fault(&mut state, cause, instruction.map(|i|i.0)) {
             maybe_forward_interrupt(&mut state, pc);
         } else {
-            forward_exception(&mut state, cause, pc);
+            let instruction = instruction.map(|i|i.0);
+            let access = AccessType::for_page_fault(cause, instruction, state.rv32).unwrap();
+            reflect_page_fault(&mut state, csrr!(stval), access);
         }
-    } else if cause == SCAUSE_ILLEGAL_INSN && state.smode {
+    } else if cause == SCAUSE_ILLEGAL_INSN && state.current_mode() == PrivilegeMode::Supervisor {
         let pc = csrr!(sepc);
         let (instruction, len) = instruction.unwrap();
         let mut advance_pc = true;
+        let mut illegal = false;
         match riscv_decode::decode(instruction).ok() {
             Some(Instruction::Sret) => {
-                if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
-                    state.no_interrupt = false;
-                }
-                state.csrs.pop_sie();
-                state.smode = state.csrs.sstatus.get(STATUS_SPP);
-                state.csrs.sstatus.set(STATUS_SPP, false);
-                riscv::set_sepc(state.csrs.sepc);
+                emulate_sret(&mut state);
+                advance_pc = false;
+            }
+            // See `csr::M_MODE_CSRS` for how the guest's idea of machine mode maps onto supervisor
+            // mode.
+            Some(Instruction::Mret) if cfg!(feature = "mret_compat") => {
+                emulate_sret(&mut state);
                 advance_pc = false;
-
-                if !state.smode {
-                    state.no_interrupt = false;
-                }
             }
             Some(Instruction::SfenceVma(rtype)) => pmap::handle_sfence_vma(&mut state, rtype),
-            Some(Instruction::Csrrw(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let value = state.saved_registers.get(i.rs1());
-                state.set_csr(i.csr(), value);
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrw(i)) => {
+                let value = state.rs1_value(&i);
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Write(value));
             }
-            Some(Instruction::Csrrs(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = state.saved_registers.get(i.rs1());
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev | mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrs(i)) => {
+                let mask = state.rs1_value(&i);
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Set(mask));
             }
-            Some(Instruction::Csrrc(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = state.saved_registers.get(i.rs1());
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev & !mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrc(i)) => {
+                let mask = state.rs1_value(&i);
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Clear(mask));
             }
-            Some(Instruction::Csrrwi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                state.set_csr(i.csr(), i.zimm() as u64);
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrwi(i)) => {
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Write(i.zimm() as u64));
             }
-            Some(Instruction::Csrrsi(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = i.zimm() as u64;
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev | mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrsi(i)) => {
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Set(i.zimm() as u64));
             }
-            Some(Instruction::Csrrci(i)) => if let Some(prev) = state.get_csr(i.csr()) {
-                let mask = i.zimm() as u64;
-                if mask != 0 {
-                    state.set_csr(i.csr(), prev & !mask);
-                }
-                state.saved_registers.set(i.rd(), prev);
+            Some(Instruction::Csrrci(i)) => {
+                illegal = !csr::emulate(&mut state, i.csr(), i.rd(), CsrOp::Clear(i.zimm() as u64));
             }
-            Some(Instruction::Wfi) => {}
+            Some(Instruction::Wfi) => wait_for_interrupt(&mut state),
             Some(decoded) => {
                 println!("Unrecognized instruction! {:?} @ pc={:#x}", decoded, pc);
-                forward_exception(&mut state, cause, pc);
-                advance_pc = false;
+                illegal = true;
             }
             None => {
                 println!("Unrecognized instruction {:#x} @ pc={:#x}", instruction, pc);
-                forward_exception(&mut state, cause, pc);
-                advance_pc = false;
+                illegal = true;
             }
         }
 
-        if advance_pc {
+        if illegal {
+            reflect_illegal_instruction(&mut state, instruction);
+        } else if advance_pc {
             riscv::set_sepc(pc + len);
         }
         maybe_forward_interrupt(&mut state, csrr!(sepc));
-    } else if cause == SCAUSE_ENV_CALL && state.smode {
-        match state.saved_registers.get(17) {
-            0 => {
-                state.csrs.sip.set(IP_STIP, false);
-                state.csrs.mtimecmp = state.saved_registers.get(10);
-                riscv::sbi::set_timer(state.csrs.mtimecmp);
-            }
-            1 => {
-                let value = state.saved_registers.get(10) as u8;
-                state.uart.output_byte(value)
-            }
-            5 => riscv::fence_i(),
-            6 | 7 => {
-                // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
-                // a result, this function ignores the arguments and just does a global fence. This
-                // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
-                pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
-            }
-            8 => {
-                if let Some(ref mut finisher) = state.test_finisher {
-                    finisher.pass();
-                }
-                loop {}
-            }
-            i => {
-                println!("Got ecall from guest function={}!", i);
-                loop {}
-            }
+    } else if cause == SCAUSE_ILLEGAL_INSN && state.current_mode() == PrivilegeMode::User
+        && csr::emulate_user_counter_read(&mut state, instruction.unwrap().0)
+    {
+        riscv::set_sepc(csrr!(sepc) + instruction.unwrap().1);
+    } else if cause == SCAUSE_BREAKPOINT && gdb::handle_breakpoint(&mut state) {
+        // Stopped at a breakpoint set by GDB or a completed single step, and now resumed.
+    } else if cause == SCAUSE_ENV_CALL && state.current_mode() == PrivilegeMode::Supervisor {
+        if sbi::handle_ecall(&mut state) {
+            riscv::set_sepc(csrr!(sepc) + 4);
         }
-        riscv::set_sepc(csrr!(sepc) + 4);
     } else {
         if cause != SCAUSE_ENV_CALL { // no need to print anything for guest syscalls...
-            println!("Forward exception (cause = {}, smode={})!", cause, state.smode);
+            println!("Forward exception (cause = {}, mode={:?})!", cause, state.current_mode());
+        }
+        match instruction {
+            Some((instruction, _)) if cause == SCAUSE_ILLEGAL_INSN => {
+                reflect_illegal_instruction(&mut state, instruction)
+            }
+            _ => forward_exception(&mut state, cause, csrr!(sepc)),
         }
-        forward_exception(&mut state, cause, csrr!(sepc));
     }
 
-    state.shadow_page_tables.install_root(state.shadow());
+    if cfg!(feature = "gdb_stub") {
+        gdb::poll(&mut state);
+    }
+    gdb::update_single_step(&mut state);
+
+    state.shadow_page_tables.install_root(pmap::active_root(state));
 }
 
 fn handle_interrupt(state: &mut Context, cause: u64) {
     let interrupt = cause & 0xff;
     match interrupt {
         0x1 => {
-            // Software interrupt
-            unreachable!();
+            // Software interrupt: another hart injected an interrupt into our guest, which is picked
+            // up by `collect_injected_interrupts`.
+            riscv::sbi::clear_ipi();
+            collect_injected_interrupts(state);
         }
         0x5 => {
             // Timer interrupt
             let time = state.host_clint.get_mtime();
             let mut next = time + 1_000_000;
 
-            crate::context::Uart::timer(state, time);
+            crate::uart_device::Uart::timer(state, time);
+            virtio::poll_devices(state);
             if state.csrs.mtimecmp <= time {
                 state.csrs.sip |= IP_STIP;
                 state.no_interrupt = false;
@@ -304,6 +291,9 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
                         virtio::Device::Passthrough { .. } => true,
                         virtio::Device::Unmapped => false,
                         virtio::Device::Macb(ref mut macb) => macb.interrupt(&mut state.guest_memory),
+                        virtio::Device::Block { .. } | virtio::Device::Console { .. } |
+                        virtio::Device::Balloon { .. } | virtio::Device::Net { .. } |
+                        virtio::Device::Rng { .. } => false,
                     };
 
                     if forward {
@@ -318,6 +308,12 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
                         }
                     }
                 }
+                IrqMapping::HostUart => {
+                    input::receive();
+                    let time = state.host_clint.get_mtime();
+                    crate::uart_device::Uart::timer(state, time);
+                    virtio::poll_devices(state);
+                }
                 IrqMapping::Ignored => {}
             }
 
@@ -329,6 +325,215 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
     }
 }
 
+/// Return from the guest's trap handler: restore the privilege level from SPP and SIE from SPIE, and
+/// resume at the guest's `sepc`. Any interrupt this makes deliverable is forwarded by the caller's
+/// `maybe_forward_interrupt` before the guest executes another instruction.
+fn emulate_sret(state: &mut Context) {
+    if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
+        state.no_interrupt = false;
+    }
+    state.csrs.pop_sie();
+    state.guest_mode = PrivilegeMode::from_spp(state.csrs.sstatus.get(STATUS_SPP));
+    state.csrs.sstatus.set(STATUS_SPP, false);
+    riscv::set_sepc(state.csrs.sepc);
+
+    if state.current_mode() == PrivilegeMode::User {
+        // Interrupts are always enabled in user mode.
+        state.no_interrupt = false;
+    }
+
+    // The privilege level selects the shadow page table (UVA for user mode, KVA or MVA for
+    // supervisor mode).
+    state.shadow_page_tables.install_root(pmap::active_root(state));
+}
+
+/// Check `emulate_sret` against this hart's guest: the mode comes from SPP, SIE from SPIE, the
+/// guest resumes at its `sepc`, and the shadow root for the new mode is installed. Must be called
+/// after `context::initialize` and before the guest starts, and puts back the state it changes.
+pub fn sret_selftest() {
+    let mut state = CONTEXT.lock();
+    let state = (&mut *state).as_mut().unwrap();
+    let saved = (state.csrs.sstatus, state.csrs.sepc, state.csrs.satp, state.guest_mode,
+                 state.no_interrupt);
+    let saved_sepc = csrr!(sepc);
+
+    // Guest paging is enabled so that the mode selects between the UVA and KVA roots.
+    state.csrs.satp = 8 << 60;
+    for &(spp, spie) in &[(false, true), (true, false), (true, true)] {
+        state.csrs.sstatus = 0;
+        state.csrs.sstatus.set(STATUS_SPP, spp);
+        state.csrs.sstatus.set(STATUS_SPIE, spie);
+        state.csrs.sepc = if spp { 0x8020_0000 } else { 0x1_0000 };
+        state.no_interrupt = true;
+        emulate_sret(state);
+
+        assert_eq!(state.guest_mode, PrivilegeMode::from_spp(spp));
+        assert_eq!(state.csrs.sstatus & (STATUS_SIE | STATUS_SPIE | STATUS_SPP),
+                   STATUS_SPIE | if spie { STATUS_SIE } else { 0 });
+        assert_eq!(csrr!(sepc), state.csrs.sepc);
+        let root = if spp { pmap::PageTableRoot::KVA } else { pmap::PageTableRoot::UVA };
+        assert_eq!(csrr!(satp) & SATP_PPN, state.shadow_page_tables.shadow_root(root).0 >> 12);
+        // Returning to user mode or re-enabling SIE may make an interrupt deliverable.
+        assert_eq!(state.no_interrupt, spp && !spie);
+    }
+
+    let (sstatus, sepc, satp, guest_mode, no_interrupt) = saved;
+    state.csrs.sstatus = sstatus;
+    state.csrs.sepc = sepc;
+    state.csrs.satp = satp;
+    state.guest_mode = guest_mode;
+    state.no_interrupt = no_interrupt;
+    riscv::set_sepc(saved_sepc);
+    state.shadow_page_tables.install_root(pmap::active_root(state));
+}
+
+/// Set the time at which the guest's timer interrupt fires, clearing any timer interrupt that is
+/// already pending. Used both for the SBI timer calls and for writes to the emulated CLINT. Expiry is
+/// noticed by the host timer interrupt handler, which also wakes a guest parked in `wfi`.
+pub fn set_guest_timer(state: &mut Context, stime_value: u64) {
+    state.csrs.sip.set(IP_STIP, false);
+    state.csrs.mtimecmp = stime_value;
+    riscv::sbi::set_timer(stime_value);
+}
+
+/// Park the hart until one of the interrupts enabled in the guest's `sie` is pending. Host interrupts
+/// that arrive in the meantime are handled here, since the hypervisor runs with them disabled.
+fn wait_for_interrupt(state: &mut Context) {
+    // With nothing enabled the guest could never be woken, so treat `wfi` as a no-op instead.
+    if state.csrs.sie == 0 {
+        return;
+    }
+
+    loop {
+        // Check before parking, so an interrupt which arrived before the `wfi` isn't missed.
+        if !state.csrs.sip.get(IP_SEIP) && state.plic.interrupt_pending() {
+            state.csrs.sip.set(IP_SEIP, true);
+        }
+        if state.csrs.sie & state.csrs.sip != 0 {
+            state.no_interrupt = false;
+            return;
+        }
+
+        // Host `wfi` returns once an interrupt is pending in `sie` even though `sstatus.SIE` is
+        // clear, so nothing can be lost between the check above and here.
+        riscv::wfi();
+
+        let pending = csrr!(sip);
+        if pending.get(IP_SSIP) {
+            handle_interrupt(state, (1 << 63) | 1);
+        }
+        if pending.get(IP_STIP) {
+            handle_interrupt(state, (1 << 63) | 5);
+        }
+        if pending.get(IP_SEIP) {
+            handle_interrupt(state, (1 << 63) | 9);
+        }
+    }
+}
+
+/// Guest interrupts that can be injected by `inject_interrupt`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptCause {
+    Software,
+    Timer,
+    External,
+}
+impl InterruptCause {
+    fn sip_bit(self) -> u64 {
+        match self {
+            InterruptCause::Software => IP_SSIP,
+            InterruptCause::Timer => IP_STIP,
+            InterruptCause::External => IP_SEIP,
+        }
+    }
+}
+
+/// Make `cause` pending for the guest running on host hart `hartid`. If that isn't the current hart,
+/// it is sent an IPI so that the interrupt is noticed promptly even if the guest is parked in `wfi`.
+pub fn inject_interrupt(state: &mut Context, hartid: u64, cause: InterruptCause) {
+    if hartid == state.hartid {
+        state.csrs.sip |= cause.sip_bit();
+        state.no_interrupt = false;
+    } else {
+        SHARED_STATICS.injected_interrupts[hartid as usize].fetch_or(cause.sip_bit(), Ordering::SeqCst);
+        riscv::sbi::send_ipi_to_hart(hartid);
+    }
+}
+
+/// Move interrupts injected by other harts into the guest's `sip`.
+fn collect_injected_interrupts(state: &mut Context) {
+    let injected = SHARED_STATICS.injected_interrupts[state.hartid as usize].swap(0, Ordering::SeqCst);
+    if injected != 0 {
+        state.csrs.sip |= injected;
+        state.no_interrupt = false;
+    }
+}
+
+/// Returns the guest interrupts that are pending, enabled in `sie`, and allowed to be taken at the
+/// guest's current privilege level. Supervisor interrupts are always taken from user mode, but only
+/// while `sstatus.SIE` is set from supervisor mode. The guest must never be sent to its trap vector
+/// for an interrupt not included here.
+pub fn deliverable_interrupts(state: &Context) -> u64 {
+    deliverable(state.current_mode(), state.csrs.sstatus, state.csrs.sip, state.csrs.sie)
+}
+
+fn deliverable(mode: PrivilegeMode, sstatus: u64, sip: u64, sie: u64) -> u64 {
+    if mode == PrivilegeMode::Supervisor && !sstatus.get(STATUS_SIE) {
+        return 0;
+    }
+    sip & sie & (IP_SEIP | IP_STIP | IP_SSIP)
+}
+
+/// The cause of the interrupt to take out of the nonempty `deliverable` set. Supervisor interrupts
+/// are taken in the order external, software, timer.
+fn interrupt_cause(deliverable: u64) -> u64 {
+    if deliverable.get(IP_SEIP) {
+        9
+    } else if deliverable.get(IP_SSIP) {
+        1
+    } else {
+        5
+    }
+}
+
+/// Check interrupt delivery and prioritization and the fetching of instructions that straddle a
+/// page boundary against known results, using the start of `guest_memory` as scratch space.
+/// Panics on any mismatch.
+pub fn selftest(guest_memory: &mut MemoryRegion) {
+    let all = IP_SEIP | IP_STIP | IP_SSIP;
+    assert_eq!(deliverable(PrivilegeMode::Supervisor, 0, all, all), 0);
+    assert_eq!(deliverable(PrivilegeMode::Supervisor, STATUS_SIE, all, IP_STIP), IP_STIP);
+    assert_eq!(deliverable(PrivilegeMode::User, 0, IP_SSIP, all), IP_SSIP);
+    assert_eq!(deliverable(PrivilegeMode::User, 0, all, 0), 0);
+    assert_eq!(deliverable(PrivilegeMode::User, 0, !0, !0), all);
+    assert_eq!(interrupt_cause(all), 9);
+    assert_eq!(interrupt_cause(IP_STIP | IP_SSIP), 1);
+    assert_eq!(interrupt_cause(IP_STIP), 5);
+
+    // An Sv39 guest page table mapping only va 0x1000, as executable, to the fourth scratch page.
+    let base = guest_memory.base();
+    guest_memory.zero_range(base, 0x4000).unwrap();
+    let pte = |pa: u64, flags: u64| ((pa >> 12) << 10) | flags;
+    guest_memory[base] = pte(base + 0x1000, PTE_VALID);
+    guest_memory[base + 0x1000] = pte(base + 0x2000, PTE_VALID);
+    guest_memory[base + 0x2008] = pte(base + 0x3000, PTE_ACCESSED | PTE_RXV);
+    let satp = (8 << 60) | (base >> 12);
+
+    // c.addi a0, 1 in the last parcel of the page fetches fine, but the first half of a 4-byte
+    // addi a0, a0, 1 there faults on the unmapped page holding its second half.
+    guest_memory[base + 0x3ff8] = 0x0505 << 48;
+    assert_eq!(pmap::fetch_guest_instruction(guest_memory, satp, 0x1ffe, false), Ok(0x0505));
+    guest_memory[base + 0x3ff8] = 0x0513 << 48;
+    match pmap::fetch_guest_instruction(guest_memory, satp, 0x1ffe, false) {
+        Err((error, stval)) => {
+            assert_eq!(error.cause(AccessType::Execute), SCAUSE_INSN_PAGE_FAULT);
+            assert_eq!(stval, 0x2000);
+        }
+        Ok(instruction) => panic!("Fetched {:#x} from an unmapped page", instruction),
+    }
+    guest_memory.zero_range(base, 0x4000).unwrap();
+}
+
 fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
     if state.no_interrupt {
         return;
@@ -338,55 +543,141 @@ fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
         state.csrs.sip.set(IP_SEIP, true);
     }
 
-    if (!state.smode || state.csrs.sstatus.get(STATUS_SIE)) && (state.csrs.sie & state.csrs.sip != 0) {
-        let cause = if state.csrs.sip.get(IP_SEIP) {
-            9
-        } else if state.csrs.sip.get(IP_STIP) {
-            5
-        } else if state.csrs.sip.get(IP_SSIP) {
-            1
-        } else {
-            unreachable!()
-        };
+    let deliverable = deliverable_interrupts(state);
+    if deliverable != 0 {
+        let cause = interrupt_cause(deliverable);
 
-        // println!("||> Forwarding timer interrupt! (state.smode={}, sepc={:#x})", state.smode, sepc);
+        // println!("||> Forwarding timer interrupt! (mode={:?}, sepc={:#x})", state.current_mode(), sepc);
         // forward interrupt
+        state.reservation = None;
         state.csrs.push_sie();
         state.csrs.sepc = sepc;
         state.csrs.scause = (1 << 63) | cause;
-        state.csrs.sstatus.set(STATUS_SPP, state.smode);
         state.csrs.stval = 0;
-        state.smode = true;
+        state.trap_to_supervisor();
 
-        match state.csrs.stvec & TVEC_MODE {
-            0 => riscv::set_sepc(state.csrs.stvec & TVEC_BASE),
-            1 => riscv::set_sepc((state.csrs.stvec & TVEC_BASE) + 4 * cause),
-            _ => unreachable!(),
-        }
+        riscv::set_sepc(trap_vector(state.csrs.stvec, state.csrs.scause));
     } else {
         state.no_interrupt = true;
     }
 }
 
+/// Returns the address of the guest's trap handler for `scause`. In vectored mode interrupts go to
+/// BASE+4*cause, but synchronous exceptions still go to BASE.
+fn trap_vector(stvec: u64, scause: u64) -> u64 {
+    match stvec & TVEC_MODE {
+        0 => stvec & TVEC_BASE,
+        1 if (scause as i64) < 0 => (stvec & TVEC_BASE) + 4 * (scause & 0xff),
+        1 => stvec & TVEC_BASE,
+        _ => unreachable!(),
+    }
+}
+
 fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
+    reflect_exception(state, cause, sepc, csrr!(stval));
+}
+
+/// Deliver a page fault at `va` to the guest, as though the guest's own page tables had caused it.
+pub fn reflect_page_fault(state: &mut Context, va: u64, access: AccessType) {
+    reflect_exception(state, access.page_fault_cause(), csrr!(sepc), va);
+}
+
+/// Deliver an illegal instruction exception for the instruction at `sepc` to the guest. Whether the
+/// hardware reports the instruction in `stval` is up to the platform, so the guest is always given
+/// the bits itself: all 32 for a full instruction, or just the low 16 for a compressed one.
+pub fn reflect_illegal_instruction(state: &mut Context, instruction: u32) {
+    let stval = match riscv_decode::instruction_length(instruction as u16) {
+        2 => instruction & 0xffff,
+        _ => instruction,
+    };
+    reflect_exception(state, SCAUSE_ILLEGAL_INSN, csrr!(sepc), stval as u64);
+}
+
+pub fn reflect_exception(state: &mut Context, cause: u64, sepc: u64, stval: u64) {
     // println!("||> Forward exception sepc={:#x}", sepc);
+    state.reservation = None;
     state.csrs.push_sie();
     state.csrs.sepc = sepc;
     state.csrs.scause = cause;
-    state.csrs.sstatus.set(STATUS_SPP, state.smode);
-    state.csrs.stval = csrr!(stval);
-    state.smode = true;
-    riscv::set_sepc(state.csrs.stvec & TVEC_BASE);
+    state.csrs.stval = stval;
+    state.trap_to_supervisor();
+    riscv::set_sepc(trap_vector(state.csrs.stvec, cause));
 }
 
-pub unsafe fn load_instruction_at_address(_state: &mut Context, guest_va: u64) -> (u32, u64) {
+/// Advance `sepc` past `instruction`. Compressed instructions are only 2 bytes long.
+pub fn skip_instruction(instruction: u32) {
+    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+}
+
+/// Expand a compressed load or store into the equivalent full instruction, so that the MMIO and
+/// page table emulation only has to handle one form. Anything else is returned unchanged. On RV32
+/// the encodings of c.ld and c.ldsp are c.flw and c.flwsp instead, and the doubleword stores are
+/// c.fsw and c.fswsp, so those are left alone for `rv32` guests.
+pub fn expand_compressed(instruction: u32, rv32: bool) -> u32 {
+    if riscv_decode::instruction_length(instruction as u16) != 2 {
+        return instruction;
+    }
+
+    let c = instruction & 0xffff;
+    let field = |hi: u32, lo: u32| (c >> lo) & ((1 << (hi - lo + 1)) - 1);
+    let load = |imm: u32, rs1: u32, funct3: u32, rd: u32|
+        (imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x03;
+    let store = |imm: u32, rs1: u32, funct3: u32, rs2: u32| {
+        ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | 0x23
+    };
+
+    // Registers in the three bit fields are x8-x15, while the stack pointer relative forms use
+    // full five bit fields and x2 as the base.
+    let (rd_rs2, rs1) = (field(4, 2) + 8, field(9, 7) + 8);
+    let (sp_rd, sp_rs2) = (field(11, 7), field(6, 2));
+    let word_imm = (field(12, 10) << 3) | (field(6, 6) << 2) | (field(5, 5) << 6);
+    let double_imm = (field(12, 10) << 3) | (field(6, 5) << 6);
+    let lwsp_imm = (field(12, 12) << 5) | (field(6, 4) << 2) | (field(3, 2) << 6);
+    let ldsp_imm = (field(12, 12) << 5) | (field(6, 5) << 3) | (field(4, 2) << 6);
+    let swsp_imm = (field(12, 9) << 2) | (field(8, 7) << 6);
+    let sdsp_imm = (field(12, 10) << 3) | (field(9, 7) << 6);
+    match (c & 0x3, field(15, 13)) {
+        (0b00, 0b010) => load(word_imm, rs1, 2, rd_rs2),
+        (0b00, 0b011) if !rv32 => load(double_imm, rs1, 3, rd_rs2),
+        (0b00, 0b110) => store(word_imm, rs1, 2, rd_rs2),
+        (0b00, 0b111) if !rv32 => store(double_imm, rs1, 3, rd_rs2),
+        (0b10, 0b010) if sp_rd != 0 => load(lwsp_imm, 2, 2, sp_rd),
+        (0b10, 0b011) if sp_rd != 0 && !rv32 => load(ldsp_imm, 2, 3, sp_rd),
+        (0b10, 0b110) => store(swsp_imm, 2, 2, sp_rs2),
+        (0b10, 0b111) if !rv32 => store(sdsp_imm, 2, 3, sp_rs2),
+        _ => instruction,
+    }
+}
+
+/// Decode a load, store or atomic `instruction` from an RV32 or RV64 guest, expanding it first if
+/// it is compressed. Callers should still pass the original `instruction` to `skip_instruction`
+/// so that `sepc` advances by its real length.
+pub fn decode(instruction: u32, rv32: bool) -> Result<Instruction, DecodingError> {
+    riscv_decode::decode(expand_compressed(instruction, rv32))
+}
+
+/// Fetch the instruction at `guest_va` and return it along with its length. Compressed instructions
+/// are returned in their 16-bit form; use `decode` to interpret them.
+///
+/// An instruction that straddles a page boundary is fetched through the guest page tables instead,
+/// since its second half may be mapped differently from the first (or not at all) and reading it
+/// directly could fault inside the hypervisor. If that fails, returns the fault the guest should see
+/// along with the faulting address.
+pub unsafe fn load_instruction_at_address(state: &mut Context, guest_va: u64)
+                                          -> Result<(u32, u64), (GuestAccessError, u64)> {
+    if guest_va & 0xfff == 0xffe {
+        let instruction = pmap::fetch_guest_instruction(&state.guest_memory, state.csrs.satp, guest_va,
+                                                        state.current_mode() == PrivilegeMode::User)?;
+        return Ok((instruction, riscv_decode::instruction_length(instruction as u16) as u64));
+    }
+
     let pc_ptr = guest_va as *const u16;
-    sum::access_user_memory(||{
+    Ok(sum::access_user_memory(||{
         let il: u16 = *pc_ptr;
         match riscv_decode::instruction_length(il) {
             2 => (il as u32, 2),
             4 => (il as u32 | ((*pc_ptr.offset(1) as u32) << 16), 4),
             _ => unreachable!(),
         }
-    })
+    }))
 }
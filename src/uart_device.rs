@@ -0,0 +1,229 @@
+use crate::context::{Context, HostClint};
+use crate::{gdb, input};
+use crate::print::GuestOutput;
+
+/// Size of the UART's MMIO window.
+pub const UART_SIZE: u64 = 0x100;
+
+/// Emulated NS16550 UART for the guest.
+pub struct Uart {
+    /// Guest physical address of the UART's registers.
+    pub base: u64,
+
+    pub line_control: u8,
+    pub scratch: u8,
+
+    pub divisor_latch: u16,
+    pub interrupt_enable: u8,
+
+    pub next_interrupt_time: u64,
+
+    pub input_fifo: [u8; 16],
+    pub input_bytes_ready: usize,
+    /// Set when host input was dropped, and cleared when the guest reads the line status register.
+    pub overrun: bool,
+
+    /// Watches host input for GDB. Only used with the `gdb_stub` feature.
+    pub gdb: gdb::Listener,
+
+    pub output: GuestOutput,
+}
+
+impl Uart {
+    const IRQ: u32 = 10;
+
+    fn tx_interrupt(&self, current_time: u64) -> bool {
+        self.next_interrupt_time  <= current_time && self.interrupt_enable & 0x2 != 0
+    }
+    fn rx_interrupt(&self) -> bool {
+        self.input_bytes_ready >= 1 && self.interrupt_enable & 0x1 != 0
+    }
+    pub fn timer(state: &mut Context, current_time: u64) {
+        state.uart.fill_fifo();
+        if state.uart.tx_interrupt(current_time) || state.uart.rx_interrupt() {
+            state.plic.set_pending(Uart::IRQ, true);
+            state.no_interrupt = false;
+        }
+    }
+
+    pub fn fill_fifo(&mut self) {
+        while self.input_bytes_ready < self.input_fifo.len() {
+            if let Some(ch) = input::getchar() {
+                if !(cfg!(feature = "gdb_stub") && self.gdb.receive(ch)) {
+                    self.input_fifo[self.input_bytes_ready] = ch;
+                    self.input_bytes_ready += 1;
+                }
+                if self.gdb.request.is_some() {
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        self.overrun |= input::take_overrun();
+    }
+
+    pub fn new(base: u64, guestid: Option<u64>) -> Self {
+        Self {
+            base,
+            line_control: Uart::LCR_EIGHT_BIT_WORDS,
+            scratch: 0,
+            interrupt_enable: 0,
+            divisor_latch: 1,
+            next_interrupt_time: 0,
+            input_fifo: [0; 16],
+            input_bytes_ready: 0,
+            overrun: false,
+            gdb: gdb::Listener::new(),
+            output: GuestOutput::new(guestid),
+        }
+    }
+
+    /// Return the registers to their power-on values. Buffered output is kept.
+    pub fn reset(&mut self) {
+        self.line_control = Uart::LCR_EIGHT_BIT_WORDS;
+        self.scratch = 0;
+        self.interrupt_enable = 0;
+        self.divisor_latch = 1;
+        self.next_interrupt_time = 0;
+        self.input_bytes_ready = 0;
+        self.overrun = false;
+    }
+
+    /// Whether `addr` falls within the UART's MMIO window.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.base && addr < self.base + UART_SIZE
+    }
+
+    /// Whether the divisor latch access bit is set, in which case the first two registers alias
+    /// the divisor latch instead of the data and interrupt enable registers.
+    fn dlab(&self) -> bool {
+        self.line_control & Uart::LCR_DIVISOR_LATCH_ACCESS != 0
+    }
+
+    // register offsets
+    const TRANSMIT_HOLDING_REGISTER: u64 = 0;
+    const RECEIVE_BUFFER_REGISTER: u64 = 0;
+    const DIVISOR_LATCH_LSB: u64 = 0;
+    const INTERRUPT_ENABLE_REGISTER: u64 = 1;
+    const DIVISOR_LATCH_MSB: u64 = 1;
+    const FIFO_CONTROL_REGISTER: u64 = 2;
+    const INTERRUPT_IDENTIFICATION_REGISTER: u64 = 2;
+    const LINE_CONTROL_REGISTER: u64 = 3;
+    const MODEM_CONTROL_REGISTER: u64 = 4;
+    const LINE_STATUS_REGISTER: u64 = 5;
+    const MODEM_STATUS_REGISTER: u64 = 6;
+    const SCRATCH_REGISTER: u64 = 7;
+
+    // bits for interrupt identification register
+    const IIR_FIFOS_ENABLED: u8 = 0xC0;
+    const IIR_INTERRUPT_NOT_PENDING: u8 = 0x01; // set to zero for interrupt pending
+    // note: bits 1-3 are an enumeration as follows, not a bitmask
+    const IIR_TX_INTERRUPT: u8 = 0x02; // transmit fifo has room for more data
+    const IIR_RX_INTERRUPT: u8 = 0x04; // receive fifo contains data
+
+    // bits for line control register
+    const LCR_EIGHT_BIT_WORDS: u8 = 0x03; // eight bit words
+    const LCR_DIVISOR_LATCH_ACCESS: u8 = 0x80; // divisor latch access bit (DLAB)
+
+    // bits for line status register
+    const LSR_DATA_READY: u8 = 0x01;
+    const LSR_OVERRUN_ERROR: u8 = 0x02;
+    #[allow(unused)]
+    const LSR_BREAK_INTERRUPT: u8 = 0x10;
+    const LSR_TRANSMITTER_HAS_ROOM: u8 = 0x20;
+    const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+    // bits for modem status register
+    const MSR_CLEAR_TO_SEND: u8 = 0x10;
+
+    // bits for modem control register
+    const MCR_LOOPBACK_ENABLE: u8 = 0x10;
+    const MCR_RESERVED_BITS: u8 = 0xe0;
+
+    pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> u8 {
+        match (self.dlab(), addr - self.base) {
+            (false, Uart::RECEIVE_BUFFER_REGISTER) => {
+                if self.input_bytes_ready > 0 {
+                    let ret = self.input_fifo[0];
+                    self.input_bytes_ready -= 1;
+                    for i in 0..(self.input_bytes_ready) {
+                        self.input_fifo[i] = self.input_fifo[i+1];
+                    }
+                    ret
+                } else {
+                    0
+                }
+            }
+            (true, Uart::DIVISOR_LATCH_LSB) => (self.divisor_latch & 0xff) as u8,
+            (true, Uart::DIVISOR_LATCH_MSB) => (self.divisor_latch >> 8) as u8,
+            (false, Uart::INTERRUPT_ENABLE_REGISTER) => self.interrupt_enable, // (top four should always be zero)
+            (_, Uart::INTERRUPT_IDENTIFICATION_REGISTER) => {
+                if self.rx_interrupt() {
+                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_RX_INTERRUPT
+                } else if self.tx_interrupt(host_clint.get_mtime()) {
+                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_TX_INTERRUPT
+                } else {
+                    Uart::IIR_FIFOS_ENABLED | Uart::IIR_INTERRUPT_NOT_PENDING
+                }
+            },
+            (_, Uart::LINE_CONTROL_REGISTER) => self.line_control,
+            (_, Uart::LINE_STATUS_REGISTER) => {
+                self.fill_fifo();
+
+                let mut lsr = 0;
+                if self.input_bytes_ready > 0 {
+                    lsr |= Uart::LSR_DATA_READY;
+                }
+                if self.overrun {
+                    self.overrun = false;
+                    lsr |= Uart::LSR_OVERRUN_ERROR;
+                }
+                if host_clint.get_mtime() >= self.next_interrupt_time {
+                    lsr |= Uart::LSR_TRANSMITTER_HAS_ROOM | Uart::LSR_TRANSMITTER_EMPTY;
+                }
+                lsr
+            }
+            (_, Uart::MODEM_STATUS_REGISTER) => Uart::MSR_CLEAR_TO_SEND, // other bits don't matter to Linux
+            (_, Uart::SCRATCH_REGISTER) => self.scratch,
+            (dlab, _) => {
+                println!("UART: Read uimplemented ?? <- {:#x} (dlab={})", addr, dlab);
+                loop {}
+            }
+        }
+    }
+    pub fn write(&mut self, host_clint: &HostClint, addr: u64, value: u8) {
+        match (self.dlab(), addr - self.base, value) {
+            (false, Uart::TRANSMIT_HOLDING_REGISTER, _) => {
+                self.output_byte(value as u8);
+
+                let current_time = host_clint.get_mtime();
+                let transmit_time = self.divisor_latch as u64 * 5;
+                self.next_interrupt_time =
+                    self.next_interrupt_time.max(current_time) + transmit_time;
+            }
+            (false, Uart::INTERRUPT_ENABLE_REGISTER, _) => {
+                self.interrupt_enable = value;
+            }
+            (true, Uart::DIVISOR_LATCH_LSB, _) => {
+                self.divisor_latch = (self.divisor_latch & 0xff00) | (value as u16);
+            }
+            (true, Uart::DIVISOR_LATCH_MSB, _) => {
+                self.divisor_latch = (self.divisor_latch & 0x00ff) | ((value as u16) << 8);
+            }
+            (_, Uart::FIFO_CONTROL_REGISTER, _) => {}
+            (_, Uart::LINE_CONTROL_REGISTER, _) => self.line_control = value,
+            (_, Uart::MODEM_CONTROL_REGISTER, _) if value & (Uart::MCR_LOOPBACK_ENABLE | Uart::MCR_RESERVED_BITS) == 0 => {}
+            (_, Uart::SCRATCH_REGISTER, _) => self.scratch = value,
+            _ => {
+                println!("UART: Write unimplemented {:#x} -> {:#x} (dlab={})",
+                         value, addr, self.dlab());
+                loop {}
+            }
+        }
+    }
+
+    pub fn output_byte(&mut self, value: u8) {
+        self.output.output_byte(value);
+    }
+}
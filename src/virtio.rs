@@ -1,12 +1,66 @@
-use byteorder::{NativeEndian, ByteOrder};
+use arrayvec::ArrayVec;
 use riscv_decode::Instruction;
-use crate::context::Context;
+use crate::context::{Context, SavedRegisters};
 use crate::memory_region::MemoryRegion;
+use crate::drivers::balloon::{BalloonDriver, BalloonStats};
+use crate::drivers::block::BlockDriver;
+use crate::drivers::console::ConsoleDriver;
 use crate::drivers::macb::MacbDriver;
-use crate::{pmap, riscv, drivers};
+use crate::drivers::net::NetDriver;
+use crate::drivers::rng::RngDriver;
+use crate::drivers::{Driver, GuestDevice};
+use crate::riscv::bits::SATP_PPN;
+use crate::{pfault, pmap, drivers, trap};
 
 pub const MAX_QUEUES: usize = 4;
-pub const MAX_DEVICES: usize = 4;
+pub const MAX_DEVICES: usize = 9;
+
+/// Slot of the first emulated device. The slots before it belong to the host's own virtio devices.
+pub const FIRST_EMULATED_SLOT: usize = 4;
+
+/// Guest physical address of the registers of the device in `slot`.
+pub const fn slot_address(slot: usize) -> u64 {
+    0x10001000 + 0x1000 * slot as u64
+}
+
+/// Kinds of emulated device a guest can be given.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EmulatedDevice {
+    Block,
+    Console,
+    Rng,
+    Net,
+    Balloon,
+}
+
+/// The emulated devices to give each guest, in the order they are assigned slots starting from
+/// `FIRST_EMULATED_SLOT`. A block device is only included if the host provided a disk image.
+pub fn emulated_devices(disk: bool) -> ArrayVec<[EmulatedDevice; MAX_DEVICES - FIRST_EMULATED_SLOT]> {
+    let mut devices = ArrayVec::new();
+    if disk {
+        devices.push(EmulatedDevice::Block);
+    }
+    if cfg!(feature = "virtio_console") {
+        devices.push(EmulatedDevice::Console);
+    }
+    if RngDriver::is_available() {
+        devices.push(EmulatedDevice::Rng);
+    }
+    devices.push(EmulatedDevice::Net);
+    devices.push(EmulatedDevice::Balloon);
+    devices
+}
+
+/// MAC address of the emulated network device of guest `guestid`, from the locally administered
+/// range QEMU also uses.
+pub fn guest_mac(guestid: Option<u64>) -> [u8; 6] {
+    [0x52, 0x54, 0x00, 0x12, 0x34, 0x56 + guestid.unwrap_or(0) as u8]
+}
+
+/// Transmit callback for emulated network devices. There is no host network to forward frames to,
+/// so guests can only receive what the hypervisor passes to `receive_frame`, and anything they send
+/// is dropped.
+pub fn discard_frame(_frame: &[u8]) {}
 
 #[derive(Copy, Clone)]
 pub struct Queue {
@@ -27,6 +81,31 @@ pub enum Device {
     },
     Unmapped,
     Macb(drivers::GuestDevice<MacbDriver>),
+    Block {
+        device: drivers::GuestDevice<BlockDriver>,
+        /// Interrupt raised on the guest PLIC when requests complete.
+        guest_irq: u32,
+    },
+    Console {
+        device: drivers::GuestDevice<ConsoleDriver>,
+        /// Interrupt raised on the guest PLIC when input arrives or output completes.
+        guest_irq: u32,
+    },
+    Balloon {
+        device: drivers::GuestDevice<BalloonDriver>,
+        /// Interrupt raised on the guest PLIC when requests complete or the target size changes.
+        guest_irq: u32,
+    },
+    Net {
+        device: drivers::GuestDevice<NetDriver>,
+        /// Interrupt raised on the guest PLIC when frames are sent or received.
+        guest_irq: u32,
+    },
+    Rng {
+        device: drivers::GuestDevice<RngDriver>,
+        /// Interrupt raised on the guest PLIC when entropy requests complete.
+        guest_irq: u32,
+    },
 }
 impl Device {
     pub unsafe fn new(host_base_address: u64) -> Self {
@@ -36,15 +115,65 @@ impl Device {
             device_registers: MemoryRegion::with_base_address(pmap::pa2va(host_base_address), 0, 0x1000),
         }
     }
-}
 
-#[inline(always)]
-pub fn is_device_access(state: &mut Context, guest_pa: u64) -> bool {
-    guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * state.virtio.devices.len() as u64
+    /// Create an emulated block device whose contents are held in `backing`.
+    pub fn new_block(backing: MemoryRegion<u8>, guest_irq: u32) -> Self {
+        Device::Block {
+            device: drivers::GuestDevice::new(BlockDriver::new(backing)),
+            guest_irq,
+        }
+    }
+
+    /// Create an emulated console device connected to the host UART.
+    pub fn new_console(guestid: Option<u64>, guest_irq: u32) -> Self {
+        Device::Console {
+            device: drivers::GuestDevice::new(ConsoleDriver::new(guestid)),
+            guest_irq,
+        }
+    }
+
+    /// Create an emulated network device with the given MAC address. Every frame the guest sends is
+    /// passed to `transmit`.
+    pub fn new_net(mac: [u8; 6], transmit: fn(&[u8]), guest_irq: u32) -> Self {
+        Device::Net {
+            device: drivers::GuestDevice::new(NetDriver::new(mac, transmit)),
+            guest_irq,
+        }
+    }
+
+    /// Create an emulated entropy device, or return None if no entropy source was enabled.
+    pub fn new_rng(guest_irq: u32) -> Option<Self> {
+        Some(Device::Rng {
+            device: drivers::GuestDevice::new(RngDriver::new()?),
+            guest_irq,
+        })
+    }
+
+    /// Create an emulated memory balloon.
+    pub fn new_balloon(guest_irq: u32) -> Self {
+        Device::Balloon {
+            device: drivers::GuestDevice::new(BalloonDriver::new()),
+            guest_irq,
+        }
+    }
+
+    /// Whether the rings of any of this emulated device's queues lie within `[start, start+len)` of
+    /// guest physical memory. Passthrough queues are tracked in `queue_guest_pages` instead.
+    fn queues_overlap(&self, start: u64, len: u64) -> bool {
+        match *self {
+            Device::Passthrough { .. } | Device::Unmapped => false,
+            Device::Macb(ref device) => device.queues_overlap(start, len),
+            Device::Block { ref device, .. } => device.queues_overlap(start, len),
+            Device::Console { ref device, .. } => device.queues_overlap(start, len),
+            Device::Balloon { ref device, .. } => device.queues_overlap(start, len),
+            Device::Net { ref device, .. } => device.queues_overlap(start, len),
+            Device::Rng { ref device, .. } => device.queues_overlap(start, len),
+        }
+    }
 }
 
 pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
-    let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
+    let device = ((guest_pa - slot_address(0)) / 0x1000) as usize;
     let offset = guest_pa & 0xfff;
 
     match state.virtio.devices[device] {
@@ -56,7 +185,7 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                 current = current.min(256); // ensure queues take up at most one page
             }
 
-            match riscv_decode::decode(instruction).ok() {
+            match trap::decode(instruction, state.rv32).ok() {
                 Some(Instruction::Lw(i)) => {
                     state.saved_registers.set(i.rd(), current as u64)
                 }
@@ -93,7 +222,7 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                         // Sad, but necessary because we don't know all the places this page is mapped.
                         pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
 
-                        state.virtio.queue_guest_pages.push(queue.guest_pa);
+                        register_queue_pages(state, queue.guest_pa, queue.size * 16);
                         for i in 0..queue.size {
                             let value = &mut state.guest_memory[queue.guest_pa + i * 16];
                             *value = (*value).wrapping_add(state.guest_shift);
@@ -112,7 +241,7 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
             }
         }
         Device::Unmapped => {
-            match riscv_decode::decode(instruction).ok() {
+            match trap::decode(instruction, state.rv32).ok() {
                 Some(Instruction::Lw(i)) => state.saved_registers.set(i.rd(), 0),
                 Some(Instruction::Lb(i)) => state.saved_registers.set(i.rd(), 0),
                 Some(Instruction::Sw(_)) => {}
@@ -126,40 +255,249 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                 }
             }
         }
-        Device::Macb(ref mut macb) => match riscv_decode::decode(instruction).ok() {
-            Some(Instruction::Lb(i)) => state.saved_registers.set(i.rd(), macb.read_u8(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Lw(i)) => state.saved_registers.set(i.rd(), macb.read_u32(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Sb(i)) => macb.write_u8(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u8),
-            Some(Instruction::Sw(i)) => macb.write_u32(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u32),
-            Some(_) | None => {}
+        Device::Macb(ref mut macb) => {
+            handle_guest_device_access(macb, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
+        }
+        Device::Block { ref mut device, guest_irq } => {
+            handle_guest_device_access(device, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+        }
+        Device::Console { ref mut device, guest_irq } => {
+            handle_guest_device_access(device, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+        }
+        Device::Net { ref mut device, guest_irq } => {
+            handle_guest_device_access(device, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+        }
+        Device::Rng { ref mut device, guest_irq } => {
+            handle_guest_device_access(device, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+        }
+        Device::Balloon { ref mut device, .. } => {
+            handle_guest_device_access(device, &mut state.guest_memory, &mut state.saved_registers, offset,
+                                       instruction, state.rv32);
         }
     }
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    process_balloon_requests(state, device);
+    trap::skip_instruction(instruction);
     true
 }
 
-pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
-    for i in 0..state.virtio.queue_guest_pages.len() {
-        if state.virtio.queue_guest_pages[i] == guest_page {
+/// Handle anything waiting on the queues of the device in `slot` if it is a balloon, and raise its
+/// interrupt if that completed any requests.
+fn process_balloon_requests(state: &mut Context, slot: usize) {
+    // Split the balloon off from the other devices, so that their queues can be checked while it
+    // handles requests.
+    let (before, rest) = state.virtio.devices.split_at_mut(slot);
+    let (balloon, after) = match rest.split_first_mut() {
+        Some(split) => split,
+        None => return,
+    };
+    if let Device::Balloon { ref mut device, guest_irq } = *balloon {
+        // Never reclaim pages the hypervisor itself reads or writes on the guest's behalf. The
+        // balloon checks its own queues.
+        let root = (state.csrs.satp & SATP_PPN) << 12;
+        let protected_page_tables = &state.protected_page_tables;
+        let queue_guest_pages = &state.virtio.queue_guest_pages;
+        let (before, after) = (&*before, &*after);
+        let reserved = |guest_pa: u64| {
+            guest_pa == root
+                || protected_page_tables.iter().any(|p| p.guest_pa == guest_pa)
+                || queue_guest_pages.contains(&guest_pa)
+                || before.iter().chain(after.iter()).any(|d| d.queues_overlap(guest_pa, 0x1000))
+        };
+        if device.process_requests(&mut state.guest_memory, &mut state.shadow_page_tables,
+                                   state.guest_shift, reserved) {
+            pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+        }
+        if device.take_interrupt() {
+            state.plic.set_pending(guest_irq, true);
+            state.no_interrupt = false;
+        }
+    }
+}
+
+/// Ask the guest's balloon to hold `pages` 4KB pages. Returns false if the guest has no balloon.
+pub fn set_balloon_target(state: &mut Context, pages: u32) -> bool {
+    for device in &mut state.virtio.devices {
+        if let Device::Balloon { ref mut device, guest_irq } = *device {
+            device.set_target_pages(pages);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
             return true;
         }
     }
     false
 }
 
-pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
-    let mut hit_queue = false;
-    for d in &state.virtio.devices {
+/// Returns the target size, number of reclaimed pages and last reported stats of the guest's
+/// balloon, and asks the guest for a fresh report. Returns None if the guest has no balloon.
+pub fn balloon_status(state: &mut Context) -> Option<(u32, u64, BalloonStats)> {
+    for device in &mut state.virtio.devices {
+        if let Device::Balloon { ref mut device, guest_irq } = *device {
+            let status = (device.target_pages(), device.reclaimed_pages(), device.stats());
+            device.request_stats(&mut state.guest_memory);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+            return Some(status);
+        }
+    }
+    None
+}
+
+fn handle_guest_device_access<D: Driver>(device: &mut GuestDevice<D>, guest_memory: &mut MemoryRegion,
+                                         saved_registers: &mut SavedRegisters, offset: u64, instruction: u32,
+                                         rv32: bool) {
+    match trap::decode(instruction, rv32).ok() {
+        Some(Instruction::Lb(i)) => saved_registers.set(i.rd(), device.read_u8(guest_memory, offset) as u64),
+        Some(Instruction::Lw(i)) => saved_registers.set(i.rd(), device.read_u32(guest_memory, offset) as u64),
+        Some(Instruction::Sb(i)) => device.write_u8(guest_memory, offset, saved_registers.get(i.rs2()) as u8),
+        Some(Instruction::Sw(i)) => device.write_u32(guest_memory, offset, saved_registers.get(i.rs2()) as u32),
+        Some(_) | None => {}
+    }
+}
+
+/// Whether any guest page in `[guest_pa, guest_pa+len)` is currently held by a balloon device, in
+/// which case it has no backing host memory and must not be mapped.
+pub fn is_reclaimed(state: &Context, guest_pa: u64, len: u64) -> bool {
+    let base = state.guest_memory.base();
+    state.virtio.devices.iter().any(|d| match d {
+        Device::Balloon { ref device, .. } => device.reclaimed_in_range(base, guest_pa, len),
+        _ => false,
+    })
+}
+
+/// Hand a frame from the host to the guest's emulated network device. Returns false if the frame
+/// was dropped, either because there is no such device or because the guest had no free buffers.
+pub fn receive_frame(state: &mut Context, frame: &[u8]) -> bool {
+    for device in &mut state.virtio.devices {
+        if let Device::Net { ref mut device, guest_irq } = *device {
+            let received = device.receive(&mut state.guest_memory, frame);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+            return received;
+        }
+    }
+    false
+}
+
+/// Give emulated devices a chance to deliver input that has arrived from the host, and hand back any
+/// pages still held by a balloon that has been reset.
+pub fn poll_devices(state: &mut Context) {
+    for device in &mut state.virtio.devices {
+        if let Device::Console { ref mut device, guest_irq } = *device {
+            device.poll_input(&mut state.guest_memory);
+            if device.take_interrupt() {
+                state.plic.set_pending(guest_irq, true);
+                state.no_interrupt = false;
+            }
+        }
+    }
+    for slot in 0..state.virtio.devices.len() {
+        process_balloon_requests(state, slot);
+    }
+}
+
+/// Reset every device back to the state it had when the guest booted, and forget about the queues
+/// the guest had set up.
+pub fn reset_devices(state: &mut Context) {
+    for device in &mut state.virtio.devices {
+        match *device {
+            Device::Passthrough { ref mut queue_sel, ref mut queues, ref mut device_registers } => {
+                device_registers[0x70] = 0; // Status
+                *queue_sel = 0;
+                *queues = [Queue {guest_pa: 0, host_pa: 0, size: 0}; MAX_QUEUES];
+            }
+            Device::Unmapped => {}
+            Device::Macb(ref mut device) => device.reset_device(&mut state.guest_memory),
+            Device::Block { ref mut device, .. } => device.reset_device(&mut state.guest_memory),
+            Device::Console { ref mut device, .. } => device.reset_device(&mut state.guest_memory),
+            Device::Balloon { ref mut device, .. } => device.reset_device(&mut state.guest_memory),
+            Device::Net { ref mut device, .. } => device.reset_device(&mut state.guest_memory),
+            Device::Rng { ref mut device, .. } => device.reset_device(&mut state.guest_memory),
+        }
+    }
+    state.virtio.queue_guest_pages.clear();
+    state.virtio.queue_bounds = (0, 0);
+}
+
+/// Write out any console output that emulated devices are still buffering. Block devices need no
+/// equivalent since requests are applied to their backing memory before being completed.
+pub fn flush_devices(state: &mut Context) {
+    for device in &mut state.virtio.devices {
+        if let Device::Console { ref mut device, .. } = *device {
+            device.flush_output();
+        }
+    }
+}
+
+/// Record that the `len` bytes at `guest_pa` hold a passthrough descriptor table, so that every
+/// access to the pages containing it traps and is handled by `handle_queue_access`.
+fn register_queue_pages(state: &mut Context, guest_pa: u64, len: u64) {
+    let first = guest_pa & !0xfff;
+    let end = (guest_pa + len.max(1) + 0xfff) & !0xfff;
+    for page in (first..end).step_by(0x1000) {
+        if !state.virtio.queue_guest_pages.contains(&page) {
+            state.virtio.queue_guest_pages.push(page);
+        }
+    }
+
+    let (start, stop) = state.virtio.queue_bounds;
+    state.virtio.queue_bounds = if start == stop { (first, end) } else { (start.min(first), stop.max(end)) };
+}
+
+/// Whether `guest_pa` falls on a page holding a passthrough virtqueue.
+pub fn is_queue_access(state: &Context, guest_pa: u64) -> bool {
+    let (start, end) = state.virtio.queue_bounds;
+    guest_pa >= start && guest_pa < end && state.virtio.queue_guest_pages.contains(&(guest_pa & !0xfff))
+}
+
+/// Returns the device and queue indices of the passthrough descriptor table containing `guest_pa`.
+pub fn queue_for_addr(state: &Context, guest_pa: u64) -> Option<(usize, usize)> {
+    if !is_queue_access(state, guest_pa) {
+        return None;
+    }
+    for (i, d) in state.virtio.devices.iter().enumerate() {
         if let Device::Passthrough { ref queues, .. } = d {
-            for q in queues {
-                if guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * 16 && guest_pa & 0xf < 8 {
-                    hit_queue = true;
+            for (j, q) in queues.iter().enumerate() {
+                if q.host_pa != 0 && guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * 16 {
+                    return Some((i, j));
                 }
             }
         }
     }
+    None
+}
 
-    let decoded = riscv_decode::decode(instruction);
+pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
+    // Only the address field in the first half of each 16 byte descriptor needs translating.
+    let hit_queue = queue_for_addr(state, guest_pa).is_some() && guest_pa & 0xf < 8;
+
+    let decoded = trap::decode(instruction, state.rv32);
     if let Err(err) = decoded {
         println!("Unrecognized instruction targetting VQUEUE {:#x} at {:#x} (error: {:?})!",
                  instruction, csrr!(sepc), err);
@@ -169,10 +507,11 @@ pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, ins
     if hit_queue {
         match decoded.unwrap() {
             Instruction::Ld(i) => {
-                state.saved_registers.set(i.rd(), state.guest_memory[guest_pa].wrapping_sub(state.guest_shift));
+                let value = state.guest_memory[guest_pa].wrapping_sub(state.guest_shift);
+                state.set_rd(&i, value);
             }
             Instruction::Sd(i) => {
-                let value = state.saved_registers.get(i.rs2());
+                let value = state.rs2_value(&i);
                 if value == 0 {
                     state.guest_memory[guest_pa] = 0;
                 } else if state.guest_memory.in_region(value) {
@@ -188,38 +527,35 @@ pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, ins
             }
         }
     } else {
-        let index = guest_pa & !0x7;
-        let offset = (guest_pa % 8) as usize;
-        let mut current = state.guest_memory[index].to_ne_bytes();
-        match decoded.as_ref().unwrap() {
-            Instruction::Ld(i) => state.saved_registers.set(i.rd(), u64::from_ne_bytes(current)),
-            Instruction::Lwu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u32(&current[offset..]) as u64),
-            Instruction::Lhu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u16(&current[offset..]) as u64),
-            Instruction::Lbu(i) => state.saved_registers.set(i.rd(), current[offset] as u64),
-            Instruction::Lw(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i32(&current[offset..]) as i64 as u64),
-            Instruction::Lh(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i16(&current[offset..]) as i64 as u64),
-            Instruction::Lb(i) => state.saved_registers.set(i.rd(), current[offset] as i8 as i64 as u64),
-            Instruction::Sd(i) => state.guest_memory[index] = state.saved_registers.get(i.rs2()),
-            Instruction::Sw(i) => {
-                NativeEndian::write_u32(&mut current[offset..], state.saved_registers.get(i.rs2()) as u32);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sh(i) => {
-                NativeEndian::write_u16(&mut current[offset..], state.saved_registers.get(i.rs2()) as u16);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sb(i) => {
-                current[offset] = state.saved_registers.get(i.rs2()) as u8;
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            instr => {
+        // Accesses may straddle a word, so each width goes through the unaligned accessors. One
+        // that runs off the end of guest memory is reflected to the guest as a fault.
+        let (memory, registers) = (&mut state.guest_memory, &mut state.saved_registers);
+        let ok = match decoded.as_ref().unwrap() {
+            Instruction::Ld(i) => memory.get_u64(guest_pa).map(|v| registers.set(i.rd(), v)).is_some(),
+            Instruction::Lwu(i) => memory.get_u32(guest_pa).map(|v| registers.set(i.rd(), v as u64)).is_some(),
+            Instruction::Lhu(i) => memory.get_u16(guest_pa).map(|v| registers.set(i.rd(), v as u64)).is_some(),
+            Instruction::Lbu(i) => memory.get_u8(guest_pa).map(|v| registers.set(i.rd(), v as u64)).is_some(),
+            Instruction::Lw(i) => memory.get_u32(guest_pa).map(|v| registers.set(i.rd(), v as i32 as i64 as u64)).is_some(),
+            Instruction::Lh(i) => memory.get_u16(guest_pa).map(|v| registers.set(i.rd(), v as i16 as i64 as u64)).is_some(),
+            Instruction::Lb(i) => memory.get_u8(guest_pa).map(|v| registers.set(i.rd(), v as i8 as i64 as u64)).is_some(),
+            Instruction::Sd(i) => memory.set_u64(guest_pa, registers.get(i.rs2())).is_ok(),
+            Instruction::Sw(i) => memory.set_u32(guest_pa, registers.get(i.rs2()) as u32).is_ok(),
+            Instruction::Sh(i) => memory.set_u16(guest_pa, registers.get(i.rs2()) as u16).is_ok(),
+            Instruction::Sb(i) => memory.set_u8(guest_pa, registers.get(i.rs2()) as u8).is_ok(),
+            _ => {
+                if pfault::emulate_atomic(state, guest_pa, instruction) {
+                    return true;
+                }
                 println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
-                         instr, host_pa, csrr!(sepc));
+                         decoded.unwrap(), host_pa, csrr!(sepc));
                 loop {}
             }
+        };
+        if !ok {
+            return false;
         }
     }
 
-    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    trap::skip_instruction(instruction);
     true
 }